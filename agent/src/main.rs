@@ -7,15 +7,16 @@ use std::thread;
 use std::time::Duration;
 
 use axum::{
-    extract::{Path as AxumPath, State},
+    extract::{Path as AxumPath, Query, State},
     http::{header, Method, StatusCode},
+    response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
 use directories::ProjectDirs;
-use muda::{Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem};
+use muda::{CheckMenuItem, Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem};
 use notify::RecursiveMode;
-use notify_debouncer_mini::{new_debouncer, DebouncedEventKind};
+use notify_debouncer_mini::{new_debouncer_opt, DebounceEventHandler, DebouncedEventKind};
 use serde::{Deserialize, Serialize};
 use tao::event::Event;
 use tao::event_loop::{ControlFlow, EventLoop};
@@ -31,6 +32,162 @@ use tray_icon::{Icon, TrayIconBuilder};
 enum StorageMode {
     Cloud,
     PrivateVault,
+    // 다른 기기의 Private Vault에 연결 토큰으로 직접 동기화 (클라우드 없이)
+    PeerVault,
+}
+
+/// 동기화 허용 시간대 (로컬 시각). 예: {"days": ["mon", "fri"], "start": "09:00", "end": "18:00"}
+/// end가 start보다 이르면 자정을 넘기는 시간대.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScheduleWindow {
+    // 요일 (mon..sun), 비어 있으면 매일
+    #[serde(default)]
+    days: Vec<String>,
+    start: String,
+    end: String,
+}
+
+/// Whether `now` falls in any window (always true when no windows are configured).
+fn in_sync_window(schedule: &[ScheduleWindow], now: chrono::DateTime<chrono::Local>) -> bool {
+    use chrono::{Datelike, NaiveTime, Weekday};
+    if schedule.is_empty() {
+        return true;
+    }
+    let time = now.time();
+    let today = now.weekday();
+    schedule.iter().any(|w| {
+        let (Ok(start), Ok(end)) = (
+            NaiveTime::parse_from_str(&w.start, "%H:%M"),
+            NaiveTime::parse_from_str(&w.end, "%H:%M"),
+        ) else {
+            return false;
+        };
+        let on = |day: Weekday| w.days.is_empty() || w.days.iter().any(|d| d.parse::<Weekday>() == Ok(day));
+        if start <= end {
+            on(today) && time >= start && time < end
+        } else {
+            // 자정 넘김: 오늘 start 이후 또는 어제 시작된 구간의 end 이전
+            (on(today) && time >= start) || (on(today.pred()) && time < end)
+        }
+    })
+}
+
+/// export_on_sync 대상 형식 - 노트 옆에 올리는 파생 파일
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ExportFormat {
+    Html,
+    // 마크다운 원문 그대로 .txt로
+    Txt,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Html => "html",
+            ExportFormat::Txt => "txt",
+        }
+    }
+
+    fn render(self, note: &str, markdown: &str) -> String {
+        match self {
+            ExportFormat::Html => {
+                use pulldown_cmark::{html, Options, Parser};
+                let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+                let mut body = String::new();
+                html::push_html(&mut body, Parser::new_ext(markdown, options));
+                let title = note.rsplit('/').next().unwrap_or(note).trim_end_matches(".md");
+                format!(
+                    "<!DOCTYPE html>\n<!-- mdflare:derived from {} (원본 노트가 바뀌면 덮어씀) -->\n\
+                     <html><head><meta charset=\"utf-8\"><title>{}</title></head><body>\n{}</body></html>\n",
+                    note,
+                    title.replace('&', "&amp;").replace('<', "&lt;"),
+                    body
+                )
+            }
+            ExportFormat::Txt => markdown.to_string(),
+        }
+    }
+}
+
+/// 로컬 폴더 감시 방식
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum WatchMode {
+    // OS 기본 감시 (FSEvents/inotify/ReadDirectoryChangesW)
+    Native,
+    // 주기적으로 폴더를 훑음 - 네트워크 드라이브(SMB/NFS)에서도 동작, CPU 사용 증가
+    Poll,
+    // 네트워크 파일시스템이면 poll, 아니면 native
+    #[default]
+    Auto,
+}
+
+/// 첫 동기화에서 양쪽에 모두 있는 파일을 어느 쪽 기준으로 맞출지 (1회용)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SyncAuthority {
+    Local,
+    Cloud,
+}
+
+/// 다른 기기/웹에서 삭제된 파일을 로컬에 반영하는 방식
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum DeletePolicy {
+    // 바로 로컬에서도 삭제
+    #[default]
+    Apply,
+    // .mdflare-pending-deletes/로 옮겨두고 사용자가 승인(삭제) 또는 거부(복원 후 재업로드)
+    Review,
+}
+
+/// 변경 감지용 내용 해시 방식
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum HashAlgo {
+    // 웹/서버와 같은 32비트 JS 문자열 해시 (큰 파일에서 충돌 가능)
+    #[default]
+    SimpleJs,
+    Sha256,
+}
+
+impl HashAlgo {
+    /// Name sent to / read from the server (`algo`; missing = `simple_js`).
+    fn as_str(self) -> &'static str {
+        match self {
+            HashAlgo::SimpleJs => "simple_js",
+            HashAlgo::Sha256 => "sha256",
+        }
+    }
+
+    fn from_name(name: Option<&str>) -> Option<Self> {
+        match name.unwrap_or("simple_js") {
+            "simple_js" => Some(HashAlgo::SimpleJs),
+            "sha256" => Some(HashAlgo::Sha256),
+            _ => None,
+        }
+    }
+
+    fn hash(self, s: &str) -> String {
+        match self {
+            HashAlgo::SimpleJs => SyncEngine::simple_hash(s),
+            HashAlgo::Sha256 => sha256_hex(s.as_bytes()),
+        }
+    }
+}
+
+/// Obsidian 설정 폴더(.obsidian) 동기화 범위
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ObsidianSync {
+    // 동기화 안 함 (다른 숨김 폴더와 동일)
+    #[default]
+    Off,
+    // 설정 JSON, 테마, CSS 스니펫, 플러그인 설정(data.json)만
+    Settings,
+    // 플러그인 코드까지 전부 (작업 공간/캐시 제외)
+    All,
 }
 
 impl Default for StorageMode {
@@ -43,6 +200,28 @@ impl Default for StorageMode {
 // Config
 // ============================================================================
 
+/// Per-user config directory. If the OS gives none (no HOME, some sandboxes/containers),
+/// fall back to `{temp}/mdflare-agent` with a warning instead of panicking.
+fn agent_config_dir() -> PathBuf {
+    static DIR: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+    let mut fell_back = false;
+    let dir = DIR
+        .get_or_init(|| match ProjectDirs::from("com", "mdflare", "agent") {
+            Some(proj) => proj.config_dir().to_path_buf(),
+            None => {
+                fell_back = true;
+                std::env::temp_dir().join("mdflare-agent")
+            }
+        })
+        .clone();
+    // 경고는 초기화 밖에서 (log_to_file도 이 함수를 씀)
+    if fell_back {
+        eprintln!("⚠️ 설정 폴더를 찾을 수 없어 임시 폴더를 사용합니다: {}", dir.display());
+        log_to_file(&format!("config: no user config dir, using {}", dir.display()));
+    }
+    dir
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ServerSettings {
     api_base: String,
@@ -58,10 +237,8 @@ impl Default for ServerSettings {
 
 impl ServerSettings {
     fn settings_path() -> PathBuf {
-        let proj = ProjectDirs::from("com", "mdflare", "agent")
-            .expect("Failed to get config directory");
-        let dir = proj.config_dir();
-        fs::create_dir_all(dir).ok();
+        let dir = agent_config_dir();
+        fs::create_dir_all(&dir).ok();
         dir.join("server_settings.json")
     }
 
@@ -82,21 +259,424 @@ impl ServerSettings {
     }
 }
 
+/// 삭제된 파일 하나의 기록: 삭제 시각 (epoch ms) + 삭제 당시 내용 해시
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Tombstone {
+    deleted_at: i64,
+    // 해시를 모르면 (구 서버, 원격 전용 파일) 로컬 파일을 지우지 않음
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    algo: Option<String>,
+}
+
+/// tombstones.json: 계정+폴더(`vault_key`)별 path → 삭제 기록
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TombstoneFile {
+    #[serde(default)]
+    vaults: HashMap<String, HashMap<String, Tombstone>>,
+}
+
+/// 삭제 기록 (한 계정+폴더 분). 오프라인이던 기기가 full_sync에서
+/// 로컬 전용 파일을 재업로드(부활)하지 않도록 사용.
+#[derive(Debug, Clone, Default)]
+struct Tombstones {
+    vault: String,
+    deleted: HashMap<String, Tombstone>,
+}
+
+impl Tombstones {
+    fn tombstones_path() -> PathBuf {
+        let dir = agent_config_dir();
+        fs::create_dir_all(&dir).ok();
+        dir.join("tombstones.json")
+    }
+
+    fn load_file() -> TombstoneFile {
+        fs::read_to_string(Self::tombstones_path())
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn load(vault: &str) -> Self {
+        let deleted = Self::load_file().vaults.remove(vault).unwrap_or_default();
+        Self { vault: vault.to_string(), deleted }
+    }
+
+    fn save(&self) {
+        // 다른 계정/폴더의 기록은 그대로 두고 이 vault 분만 교체
+        let mut file = Self::load_file();
+        file.vaults.insert(self.vault.clone(), self.deleted.clone());
+        if let Ok(data) = serde_json::to_string_pretty(&file) {
+            fs::write(Self::tombstones_path(), data).ok();
+        }
+    }
+
+    /// Record a deletion, keeping the newest one per path.
+    fn record(&mut self, path: &str, tombstone: Tombstone) {
+        match self.deleted.get_mut(path) {
+            Some(existing) if existing.deleted_at > tombstone.deleted_at => {}
+            Some(existing) if existing.deleted_at == tombstone.deleted_at && tombstone.hash.is_none() => {}
+            _ => {
+                self.deleted.insert(path.to_string(), tombstone);
+            }
+        }
+    }
+
+    /// Drop tombstones older than the TTL.
+    fn prune(&mut self, ttl_days: u64) {
+        let cutoff = chrono::Utc::now().timestamp_millis() - (ttl_days as i64) * 86_400_000;
+        self.deleted.retain(|_, t| t.deleted_at >= cutoff);
+    }
+}
+
+/// 한 계정+폴더에서 마지막으로 성공한 full_sync 시각과 그때 양쪽에 있던 경로.
+/// `orphans`가 이 기기에서 지워진 파일만 고르는 데 사용.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SyncedPaths {
+    #[serde(skip)]
+    vault: String,
+    synced_at: Option<i64>,
+    paths: std::collections::HashSet<String>,
+}
+
+impl SyncedPaths {
+    fn synced_paths_path() -> PathBuf {
+        let dir = agent_config_dir();
+        fs::create_dir_all(&dir).ok();
+        dir.join("synced_paths.json")
+    }
+
+    fn load_all() -> HashMap<String, SyncedPaths> {
+        fs::read_to_string(Self::synced_paths_path())
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn load(vault: &str) -> Self {
+        let synced = Self::load_all().remove(vault).unwrap_or_default();
+        Self { vault: vault.to_string(), ..synced }
+    }
+
+    fn save(&self) {
+        let mut all = Self::load_all();
+        all.insert(self.vault.clone(), self.clone());
+        if let Ok(data) = serde_json::to_string_pretty(&all) {
+            fs::write(Self::synced_paths_path(), data).ok();
+        }
+    }
+}
+
+/// 마지막으로 처리한 RTDB 이벤트의 modified (epoch ms).
+/// 재연결 시 이 시점 이후 변경분만 받아오는 데 사용.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RtdbCursor {
+    last_seen_ts: Option<u64>,
+}
+
+impl RtdbCursor {
+    fn cursor_path() -> PathBuf {
+        let dir = agent_config_dir();
+        fs::create_dir_all(&dir).ok();
+        dir.join("rtdb_cursor.json")
+    }
+
+    fn load() -> Self {
+        let path = Self::cursor_path();
+        if let Ok(data) = fs::read_to_string(&path) {
+            serde_json::from_str(&data).unwrap_or_default()
+        } else {
+            Self::default()
+        }
+    }
+
+    fn save(&self) {
+        let path = Self::cursor_path();
+        if let Ok(data) = serde_json::to_string_pretty(self) {
+            fs::write(path, data).ok();
+        }
+    }
+
+    /// Advance to `ts` if newer; persists only when it moved.
+    fn advance(&mut self, ts: Option<u64>) {
+        if let Some(ts) = ts {
+            if self.last_seen_ts.is_none_or(|seen| ts > seen) {
+                self.last_seen_ts = Some(ts);
+                self.save();
+            }
+        }
+    }
+}
+
+/// 최근 자체 재시작 시각 (epoch ms) - 재시작 직후 실패 → 다시 재시작하는 폭주 방지
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RestartLog {
+    times: Vec<i64>,
+}
+
+impl RestartLog {
+    fn restart_log_path() -> PathBuf {
+        let dir = agent_config_dir();
+        fs::create_dir_all(&dir).ok();
+        dir.join("restart_log.json")
+    }
+
+    fn load() -> Self {
+        let path = Self::restart_log_path();
+        if let Ok(data) = fs::read_to_string(&path) {
+            serde_json::from_str(&data).unwrap_or_default()
+        } else {
+            Self::default()
+        }
+    }
+
+    fn save(&self) {
+        let path = Self::restart_log_path();
+        if let Ok(data) = serde_json::to_string_pretty(self) {
+            fs::write(path, data).ok();
+        }
+    }
+
+    /// Record a restart unless `limit` restarts already happened within the window.
+    fn try_record(&mut self, limit: usize, window_secs: u64) -> bool {
+        let now = chrono::Utc::now().timestamp_millis();
+        let cutoff = now - (window_secs as i64) * 1000;
+        self.times.retain(|t| *t >= cutoff);
+        if limit > 0 && self.times.len() >= limit {
+            return false;
+        }
+        self.times.push(now);
+        self.save();
+        true
+    }
+}
+
+/// flatten_local 모드의 이름 매핑: 로컬 파일명 → 원격 경로
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FlatNames {
+    names: HashMap<String, String>,
+}
+
+impl FlatNames {
+    fn flat_names_path() -> PathBuf {
+        let dir = agent_config_dir();
+        fs::create_dir_all(&dir).ok();
+        dir.join("flat_names.json")
+    }
+
+    fn load() -> Self {
+        let path = Self::flat_names_path();
+        if let Ok(data) = fs::read_to_string(&path) {
+            serde_json::from_str(&data).unwrap_or_default()
+        } else {
+            Self::default()
+        }
+    }
+
+    fn save(&self) {
+        let path = Self::flat_names_path();
+        if let Ok(data) = serde_json::to_string_pretty(self) {
+            fs::write(path, data).ok();
+        }
+    }
+
+    fn local_for(&self, remote: &str) -> Option<&String> {
+        self.names.iter().find(|(_, r)| r.as_str() == remote).map(|(l, _)| l)
+    }
+
+    /// Pick a flat local name for `remote`, adding " (2)", " (3)"... on collisions.
+    fn assign(&mut self, remote: &str) -> String {
+        if let Some(local) = self.local_for(remote) {
+            return local.clone();
+        }
+        let file_name = remote.rsplit('/').next().unwrap_or(remote);
+        let (stem, ext) = match file_name.rsplit_once('.') {
+            Some((stem, ext)) => (stem, format!(".{}", ext)),
+            None => (file_name, String::new()),
+        };
+        let mut candidate = file_name.to_string();
+        let mut n = 2;
+        while self.names.contains_key(&candidate) {
+            candidate = format!("{} ({}){}", stem, n, ext);
+            n += 1;
+        }
+        self.names.insert(candidate.clone(), remote.to_string());
+        self.save();
+        candidate
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 struct Config {
     // 공통
     storage_mode: StorageMode,
     local_path: String,
+    // 숨김 파일/폴더(.으로 시작) 포함 여부 (.git, .obsidian 등은 항상 제외)
+    include_hidden: bool,
+    // 동기화할 파일 확장자 (점 없이, 예: ["md", "canvas", "txt"])
+    extensions: Vec<String>,
+    // full_sync 완료마다 요약 JSON을 POST할 URL (모니터링용)
+    sync_webhook_url: Option<String>,
+    // 다운로드 전 여유 공간 확인 시 남겨둘 안전 여유분 (MB)
+    disk_space_margin_mb: u64,
+    // Cloud 로그인 시 기본 폴더로 자동 지정하지 않고 항상 폴더 선택 창 표시
+    force_folder_prompt: bool,
+    // E2E 암호화 (실험적) - 패스프레이즈는 OS 키체인에 저장
+    encryption_enabled: bool,
+    // 로컬 상태 서버 포트 (127.0.0.1, 디버깅용 - None이면 비활성)
+    status_port: Option<u16>,
+    // 새 계정/폴더의 첫 동기화 전에 동기화 대상 노트를 zip으로 백업
+    // 필드가 없는 (업데이트 전부터 쓰던) 설정 파일은 꺼짐, 새 설정은 Default의 true
+    #[serde(default)]
+    backup_before_first_sync: bool,
+    // 첫 동기화 백업을 마친 "계정@폴더" 목록 (반복 방지)
+    first_sync_backups: Vec<String>,
+    // `init`으로 의도적으로 빈 상태로 시작한 "계정@폴더" → 기록 시각 (노트가 생기면 자동 해제)
+    empty_vault_inits: HashMap<String, String>,
+    // full_sync 로컬 전용 파일 동시 업로드 수 (1이면 순차)
+    upload_concurrency: usize,
+    // full_sync에서 서버 → 로컬 다운로드를 동시에 받을 개수 (1이면 순차)
+    sync_concurrency: usize,
+    // full_sync 업로드를 작은 파일부터
+    upload_small_first: bool,
+    // 시작 시 초기 동기화 실패하면 재시도 간격을 5초부터 두 배씩, 최대 이 값(초)까지
+    startup_retry_max_secs: u64,
+    // 편집 중 업로드 묶기 (ms) - 마지막 변경 후 이 시간 동안 조용하면 업로드 (0이면 즉시 업로드)
+    upload_idle_ms: u64,
+    // 같은 파일이 upload_burst_window_secs 안에 이 횟수보다 많이 업로드되면 폭주(외부 프로세스의 반복 쓰기)로 보고
+    // upload_throttle_secs마다 한 번으로 묶음 (0이면 끔)
+    upload_burst_limit: usize,
+    upload_burst_window_secs: u64,
+    upload_throttle_secs: u64,
+    // 이벤트 없이 바뀐 파일(에이전트 종료 중 수정, 감시 누락)을 찾는 해시 재검사 주기 (분, 0이면 끔)
+    reconcile_interval_mins: u64,
+    // 로컬 삭제 감지 후 원격 삭제 전 대기 시간 (ms) - 에디터의 "삭제 후 재생성" 저장 방식 오인 방지
+    delete_grace_ms: u64,
+    // RTDB SSE 무응답 허용 시간 (초) - keep-alive 포함 아무 줄도 없으면 끊긴 것으로 보고 재연결
+    sse_stall_timeout_secs: u64,
+    // 삭제 기록(tombstone) 보관 기간 - 이 기간 내 삭제된 파일은 오프라인 기기가 되살리지 않음
+    tombstone_ttl_days: u64,
+    // 충돌 시 양쪽 추가 줄을 이어붙이는 파일 (append-only 노트 전용, 예: "inbox.md", "logs/*.md")
+    append_merge_globs: Vec<String>,
+    // 원격 폴더 구조를 무시하고 local_path 한 폴더에 평평하게 저장 (업로드 시 원래 경로로 매핑)
+    flatten_local: bool,
+    // 읽기 전용(구독) 파일 - 로컬 수정은 업로드하지 않고 서버 버전으로 되돌림
+    readonly_globs: Vec<String>,
+    // 다운로드로 덮어쓰지 않을 파일 (템플릿 구독 중 로컬 수정본 유지) - 로컬에 있으면 항상 로컬 우선
+    download_exclude_globs: Vec<String>,
+    // "📂 폴더 열기"가 폴더 대신 가장 최근 수정된 노트를 Finder/탐색기에서 선택해 보여줌 (macOS/Windows)
+    folder_open_reveals_recent: bool,
+    // local_path 안 파일이 이보다 많으면 잘못 고른 폴더로 보고 확인 요청 (0이면 검사 안 함)
+    max_safe_file_count: usize,
+    // 동기화 대상 노트(확장자/무시 규칙 적용 후)가 이 개수나 총 크기를 넘으면 시작 전에 확인 요청 (0이면 검사 안 함)
+    max_sync_files: usize,
+    max_sync_total_bytes: u64,
+    // 위험 경고(홈/루트/프로젝트 폴더, 동기화 한도 초과 등)를 사용자가 이미 승인한 local_path 목록
+    confirmed_local_paths: Vec<String>,
+    // local_path가 이 이름의 폴더 안에 있으면 (Dropbox/iCloud/OneDrive 등) 경고 - '.'으로 시작하면 상위 폴더 안의 표시 파일
+    cloud_sync_markers: Vec<String>,
+    // 클라우드 동기화 폴더 경고를 "다시 보지 않기" 한 local_path 목록
+    cloud_sync_warning_dismissed: Vec<String>,
+    // 다음 full_sync 한 번만 적용: 양쪽에 있는 파일은 이 쪽 내용으로 덮어씀 (성공 후 자동 해제)
+    initial_sync_authority: Option<SyncAuthority>,
+    // .obsidian 설정 동기화: off / settings / all - workspace.json 등 클릭마다 바뀌는 상태는 항상 제외
+    obsidian_sync: ObsidianSync,
+    // 노트와 한 묶음으로 동기화할 메타데이터 파일 접미사 (예: ".meta" → foo.md.meta, 빈 값이면 사용 안 함)
+    metadata_suffix: String,
+    // 이 시간대에만 동기화 (비어 있으면 항상) - 밖에서는 로컬 변경만 기록했다가 시간대가 열리면 반영
+    sync_schedule: Vec<ScheduleWindow>,
+    // 파일 감시 방식 (native / poll / auto)
+    watch_mode: WatchMode,
+    // watch_mode가 poll일 때 폴더를 훑는 간격 (ms)
+    watch_poll_interval_ms: u64,
+    // 파일 변경을 모아 처리하는 대기 시간 (ms, 최소 100) - 느린 네트워크는 길게, 빠른 LAN은 짧게
+    watch_debounce_ms: u64,
+    // 한 번에 이보다 많은 파일을 지우려 하면 중단 (tombstone 정리, orphans --delete) - 0이면 제한 없음
+    max_deletes_per_sync: usize,
+    // 원격 삭제 반영: apply(바로 삭제) / review(검토 대기 폴더로 옮기고 승인 후 삭제)
+    delete_policy: DeletePolicy,
+    // 변경 감지 해시: simple_js(기본, 서버 호환) 또는 sha256 (업로드에 algo 필드 첨부)
+    hash_algo: HashAlgo,
+    // restart_window_secs 안에 재시작이 이 횟수를 넘으면 재시작 대신 오류 안내 (0이면 제한 없음)
+    restart_limit: usize,
+    restart_window_secs: u64,
+    // diff 기준 내용 캐시를 디스크의 내용 주소 blob으로 (같은 내용 노트는 blob 공유, 메모리 절약)
+    content_blob_cache: bool,
+    // diff 기준 내용 캐시 총량 상한 (바이트, 0이면 무제한) - 넘치면 오래 안 쓴 노트부터 제외 (다음 수정은 전체 업로드)
+    max_cache_bytes: u64,
+    // 에이전트 캐시 폴더(blobs)에 CACHEDIR.TAG / Spotlight·Time Machine 제외 표시
+    mark_cache_dirs: bool,
+    // 동기화 이벤트를 OS 로그로도 보냄 (Linux journald/syslog, macOS 통합 로그, Windows 이벤트 로그)
+    system_log: bool,
+    // 업로드/다운로드/삭제/이름 변경을 한 줄씩 JSON으로 남기는 감사 로그 경로 (None이면 끔)
+    // 에이전트가 절대 자르거나 회전하지 않음 → 무제한으로 커지므로 보존 기간은 사용자가 외부에서 관리
+    audit_log: Option<String>,
+    // 업로드 후 서버 사본을 다시 읽어 내용 확인 (요청이 두 배 → 기본 꺼짐)
+    verify_uploads: bool,
+    // 로컬에만 있는 파일과 서버에만 있는 파일의 내용이 같으면 (대소문자/정규화만 다른 경로)
+    // 같은 노트로 보고 로컬 파일을 서버 경로로 옮김 → 업로드+다운로드 중복 방지
+    match_by_content: bool,
+    // 다운로드한 내용을 쓰기 직전에 로컬 파일을 다시 확인 - 받는 사이 수정됐으면 덮어쓰지 않고 충돌 처리
+    recheck_before_download_write: bool,
+    // Vault 최상위의 이 폴더는 로컬 전용 - 업로드/다운로드/삭제 모두 안 함 (빈 문자열이면 끔)
+    local_only_dir: String,
+    // 노트가 업로드될 때마다 렌더링한 파생 파일(foo.md → foo.html/foo.txt)도 서버에 올림 (로컬에는 안 만듦)
+    export_on_sync: Option<ExportFormat>,
+    // 여러 에이전트를 구분하는 이름 (트레이 툴팁/메뉴, heartbeat에 표시)
+    vault_name: Option<String>,
 
     // Cloud 모드 전용 (api_base는 server_settings.json에서 로드)
     #[serde(skip)]
     api_base: String,
+    // 경로 기반 리버스 프록시 뒤 셀프호스팅용 API 경로 접두사 (예: "/mdflare" → {base}/mdflare/api/...)
+    api_path_prefix: String,
+    // 업로드 요청 본문을 gzip으로 압축 (서버가 지원할 때만) - diff 업로드 상한(10KB)도 압축 후 크기 기준
+    compress_uploads: bool,
+    // 이보다 큰 본문만 압축 (바이트)
+    compress_min_bytes: usize,
+    // 업로드 요청 본문 상한 (바이트, 0이면 끔) - 넘으면 청크로 나눠 올림 (서버가 지원할 때만, 아니면 한 번에 PUT)
+    max_upload_chunk_bytes: usize,
+    // RTDB 루트 노드 ({rtdb_url}/{rtdb_root}/{user}/files.json)
+    rtdb_root: String,
     username: String,
     api_token: String,
+    // 이 설치본 고유 ID (처음 실행 시 생성) - 업로드/heartbeat의 deviceId, RTDB에 되돌아온 내 변경 무시
+    device_id: String,
 
     // Private Vault 모드 전용
     server_port: u16,
     server_token: String,
+    // server_token을 config.json에 평문 대신 솔트 해시로만 저장 (원문은 OS 키체인 - 연결 토큰 표시/요청 서명용)
+    hash_server_token: bool,
+    // "솔트hex$sha256hex" - 비어 있으면 server_token(평문)으로 인증
+    server_token_hash: String,
+    // 외부 터널(cloudflared) 사용 여부 - false면 localhost/LAN 전용
+    enable_tunnel: bool,
+    // 외부 터널(cloudflared) URL 대기 제한 시간 (초)
+    tunnel_timeout_secs: u64,
+    // Vault 쓰기 API에서 이식성 없는 파일명(제어 문자, 끝 공백/마침표, ./..) 거부
+    safe_filenames_only: bool,
+    // Vault API 요청에 timestamp+nonce HMAC 서명 요구 (터널 재전송 방지, 웹 에디터는 아직 미지원)
+    require_signed_requests: bool,
+    // Vault 안의 symlink 파일을 따라가 읽기/쓰기 (기본: vault 밖으로 보고 거부) - 켜도 대상이 vault 안일 때만
+    follow_file_symlinks: bool,
+    // Vault 쓰기(저장/삭제/이름 변경/폴더 생성) 동시 실행 상한 (0이면 무제한) - 같은 경로는 항상 도착 순서대로
+    vault_write_concurrency: usize,
+    // 상한이 찼을 때 기다리지 않고 503으로 거절 (클라이언트가 나중에 재시도)
+    vault_write_reject_when_busy: bool,
+    // Vault를 WebDAV(/dav)로도 노출 (Basic 인증: 비밀번호 = server_token)
+    webdav_enabled: bool,
+
+    // "연결 토큰 복사"를 v2 형식으로 (vault_name, token_remote_subpath 포함 - 구버전 에이전트는 못 읽음)
+    connection_token_v2: bool,
+    // v2 토큰에 넣을 기본 원격 하위 경로 (받는 쪽 로컬 폴더 이름 제안에 사용)
+    token_remote_subpath: String,
+    // 연결 모드 전용 (연결 토큰에서 복원한 상대 Vault 주소/토큰)
+    peer_url: String,
+    peer_token: String,
 }
 
 impl Default for Config {
@@ -104,11 +684,87 @@ impl Default for Config {
         Self {
             storage_mode: StorageMode::Cloud,
             local_path: String::new(),
+            include_hidden: false,
+            extensions: vec!["md".to_string()],
+            sync_webhook_url: None,
+            disk_space_margin_mb: 100,
+            force_folder_prompt: false,
+            encryption_enabled: false,
+            status_port: None,
+            backup_before_first_sync: true,
+            first_sync_backups: Vec::new(),
+            empty_vault_inits: HashMap::new(),
+            upload_concurrency: 1,
+            sync_concurrency: 8,
+            upload_small_first: false,
+            startup_retry_max_secs: 60,
+            upload_idle_ms: 0,
+            upload_burst_limit: 20,
+            upload_burst_window_secs: 60,
+            upload_throttle_secs: 30,
+            reconcile_interval_mins: 10,
+            delete_grace_ms: 2000,
+            sse_stall_timeout_secs: 60,
+            tombstone_ttl_days: 30,
+            append_merge_globs: Vec::new(),
+            readonly_globs: Vec::new(),
+            download_exclude_globs: Vec::new(),
+            folder_open_reveals_recent: false,
+            flatten_local: false,
+            max_safe_file_count: 5000,
+            max_sync_files: 20000,
+            max_sync_total_bytes: 1024 * 1024 * 1024,
+            confirmed_local_paths: Vec::new(),
+            cloud_sync_markers: DEFAULT_CLOUD_SYNC_MARKERS.iter().map(|m| m.to_string()).collect(),
+            cloud_sync_warning_dismissed: Vec::new(),
+            initial_sync_authority: None,
+            obsidian_sync: ObsidianSync::Off,
+            metadata_suffix: String::new(),
+            watch_mode: WatchMode::Auto,
+            watch_poll_interval_ms: 2000,
+            watch_debounce_ms: 1000,
+            sync_schedule: Vec::new(),
+            max_deletes_per_sync: 50,
+            delete_policy: DeletePolicy::Apply,
+            hash_algo: HashAlgo::SimpleJs,
+            restart_limit: 5,
+            restart_window_secs: 60,
+            content_blob_cache: false,
+            max_cache_bytes: 64 * 1024 * 1024,
+            mark_cache_dirs: true,
+            system_log: false,
+            audit_log: None,
+            verify_uploads: false,
+            match_by_content: true,
+            recheck_before_download_write: true,
+            local_only_dir: "_local".to_string(),
+            export_on_sync: None,
+            vault_name: None,
             api_base: String::new(),
+            api_path_prefix: String::new(),
+            max_upload_chunk_bytes: 0,
+            compress_uploads: false,
+            compress_min_bytes: 1024,
+            rtdb_root: "mdflare".to_string(),
             username: String::new(),
             api_token: String::new(),
+            device_id: String::new(),
             server_port: 7779,
             server_token: generate_token(),
+            hash_server_token: false,
+            server_token_hash: String::new(),
+            enable_tunnel: true,
+            tunnel_timeout_secs: 20,
+            safe_filenames_only: true,
+            require_signed_requests: false,
+            follow_file_symlinks: false,
+            vault_write_concurrency: 8,
+            vault_write_reject_when_busy: false,
+            webdav_enabled: false,
+            connection_token_v2: false,
+            token_remote_subpath: String::new(),
+            peer_url: String::new(),
+            peer_token: String::new(),
         }
     }
 }
@@ -119,6 +775,32 @@ fn generate_token() -> String {
     format!("{:x}{:x}", now.as_secs(), now.subsec_nanos())
 }
 
+fn gzip_bytes(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Whether an RTDB save/create for a path that exists locally collides with a note this
+/// device created and hasn't uploaded yet. `local_hashes` starts empty on every launch, so
+/// until a `full_sync` succeeded (`synced_once`) any existing note would look new; after
+/// that, a path the server already listed is just a stale note, not a new one.
+fn is_unsynced_new_local(synced_once: bool, tracked: bool, listed_remotely: bool) -> bool {
+    synced_once && !tracked && !listed_remotely
+}
+
+/// Largest line diff sent along with an upload (bigger edits go as full content only).
+const MAX_DIFF_UPLOAD_BYTES: usize = 10240;
+
+/// Random 128-bit hex id for this install.
+fn generate_device_id() -> String {
+    use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+    let mut raw = [0u8; 16];
+    OsRng.fill_bytes(&mut raw);
+    raw.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 // 연결 토큰 생성: base64(serverUrl|token)
 fn generate_connection_token(port: u16, token: &str) -> String {
     use base64::{Engine as _, engine::general_purpose::STANDARD};
@@ -126,6 +808,68 @@ fn generate_connection_token(port: u16, token: &str) -> String {
     STANDARD.encode(plain.as_bytes())
 }
 
+/// 연결 토큰 내용. v2는 base64(JSON)이고 받는 쪽 폴더 설정을 미리 채울 정보를 함께 담음.
+/// v1(기존)은 base64(serverUrl|token).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionToken {
+    v: u32,
+    url: String,
+    token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_subpath: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vault_name: Option<String>,
+}
+
+impl ConnectionToken {
+    fn encode(&self) -> String {
+        use base64::{Engine as _, engine::general_purpose::STANDARD};
+        STANDARD.encode(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Peer용 "연결 토큰 복사" 값: `connection_token_v2`면 폴더/이름 정보를 담은 v2, 아니면 v1.
+/// 웹 에디터(`?pvtoken=`)는 v1만 이해하므로 항상 generate_connection_token 사용.
+fn peer_connection_token(config: &Config) -> String {
+    if !config.connection_token_v2 {
+        return generate_connection_token(vault_port(config), &config.server_token);
+    }
+    ConnectionToken {
+        v: 2,
+        url: format!("http://localhost:{}", vault_port(config)),
+        token: config.server_token.clone(),
+        remote_subpath: Some(config.token_remote_subpath.trim_matches('/').to_string()).filter(|p| !p.is_empty()),
+        vault_name: config.vault_name.clone(),
+    }
+    .encode()
+}
+
+// 연결 토큰 해석: v2 base64(JSON) 또는 v1 base64(serverUrl|token)
+fn parse_connection_token(token: &str) -> Option<ConnectionToken> {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    // 채팅/메일에서 복사하면 줄바꿈·공백이 섞여 들어옴
+    let compact: String = token.chars().filter(|c| !c.is_whitespace()).collect();
+    let bytes = STANDARD.decode(compact).ok()?;
+    let plain = String::from_utf8(bytes).ok()?;
+    let mut parsed = if plain.starts_with('{') {
+        serde_json::from_str::<ConnectionToken>(&plain).ok().filter(|t| t.v >= 2)?
+    } else {
+        let (url, token) = plain.rsplit_once('|')?;
+        ConnectionToken { v: 1, url: url.to_string(), token: token.to_string(), remote_subpath: None, vault_name: None }
+    };
+    // localhost든 cloudflared(https://*.trycloudflare.com)든 host 있는 http(s) URL만 허용
+    let url = url::Url::parse(parsed.url.trim()).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+    if parsed.token.is_empty() || parsed.token.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+    parsed.url = url.as_str().trim_end_matches('/').to_string();
+    Some(parsed)
+}
+
 impl Config {
     fn is_configured(&self) -> bool {
         match self.storage_mode {
@@ -135,14 +879,15 @@ impl Config {
             StorageMode::PrivateVault => {
                 !self.local_path.is_empty()
             }
+            StorageMode::PeerVault => {
+                !self.local_path.is_empty() && !self.peer_url.is_empty() && !self.peer_token.is_empty()
+            }
         }
     }
 
     fn config_path() -> PathBuf {
-        let proj = ProjectDirs::from("com", "mdflare", "agent")
-            .expect("Failed to get config directory");
-        let dir = proj.config_dir();
-        fs::create_dir_all(dir).ok();
+        let dir = agent_config_dir();
+        fs::create_dir_all(&dir).ok();
         dir.join("config.json")
     }
 
@@ -154,8 +899,29 @@ impl Config {
             Self::default()
         };
         config.api_base = ServerSettings::load().api_base;
+        if config.server_token.is_empty() && !config.server_token_hash.is_empty() {
+            // 해시만 저장된 경우 원문은 키체인에서 (없으면 인증은 해시로 계속, 연결 토큰 표시/서명만 불가)
+            match server_token_keychain_entry().and_then(|entry| entry.get_password()) {
+                Ok(token) => config.server_token = token,
+                Err(e) => log_to_file(&format!("config: server token not in keychain ({})", e)),
+            }
+        } else if config.hash_server_token && !config.server_token.is_empty() {
+            // 평문으로 저장돼 있던 토큰 → 바로 해시로 전환
+            config.save();
+        }
+        if config.device_id.is_empty() {
+            config.device_id = generate_device_id();
+            // 설정 파일이 아직 없으면 첫 저장 때 함께 기록
+            if path.exists() {
+                config.save();
+            }
+        }
+        // 빈 목록이면 아무것도 동기화하지 않게 되므로 기본값으로
+        if config.extensions.is_empty() {
+            config.extensions = vec!["md".to_string()];
+        }
         // server_token이 비어있으면 새로 생성 (연결 해제 후 재설정 시)
-        if config.server_token.is_empty() {
+        if config.server_token.is_empty() && config.server_token_hash.is_empty() {
             config.server_token = generate_token();
         }
         config
@@ -163,10 +929,34 @@ impl Config {
 
     fn save(&self) {
         let path = Self::config_path();
-        if let Ok(data) = serde_json::to_string_pretty(self) {
+        let mut on_disk = self.clone();
+        if !self.server_token.is_empty() {
+            if !self.hash_server_token {
+                on_disk.server_token_hash.clear();
+            } else if server_token_keychain_entry().and_then(|entry| entry.set_password(&self.server_token)).is_ok() {
+                if !verify_server_token_hash(&self.server_token_hash, &self.server_token) {
+                    on_disk.server_token_hash = hash_server_token(&self.server_token);
+                }
+                on_disk.server_token.clear();
+            } else {
+                // 키체인 없이 해시만 남기면 연결 토큰을 다시 보여줄 수 없음 → 평문 유지
+                log_to_file("config: keychain unavailable, server_token kept in plaintext");
+                on_disk.server_token_hash.clear();
+            }
+        }
+        if let Ok(data) = serde_json::to_string_pretty(&on_disk) {
             fs::write(path, data).ok();
         }
     }
+
+    /// Forget the vault token (disconnect): a new one is generated on the next load.
+    fn clear_server_token(&mut self) {
+        self.server_token.clear();
+        self.server_token_hash.clear();
+        if let Ok(entry) = server_token_keychain_entry() {
+            entry.delete_credential().ok();
+        }
+    }
 }
 
 // ============================================================================
@@ -181,10 +971,14 @@ struct FileItem {
     file_type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    // 서버에 따라 RFC3339 문자열 또는 epoch 숫자 → 문자열로 받아 parse_modified로 해석
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "de_modified")]
     modified: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     children: Option<Vec<FileItem>>,
+    /// `?depth=` 로 잘린 폴더: 하위 항목은 `?path=` 로 따로 조회
+    #[serde(skip_serializing_if = "Option::is_none")]
+    has_more: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -193,12 +987,32 @@ struct FilesResponse {
     files: Vec<FileItem>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeletionEntry {
+    path: String,
+    deleted_at: i64,
+    // 삭제 당시 내용 해시 (서버가 주는 경우)
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    algo: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeletionsResponse {
+    deletions: Vec<DeletionEntry>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct FileContent {
     path: String,
     content: String,
     size: u64,
     modified: String,
+    // UTF-8이 아닌 파일(이미지, PDF 등)은 "base64" - 없으면 평문
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -206,792 +1020,5059 @@ struct PutFileRequest {
     content: String,
 }
 
-struct ApiClient {
-    client: reqwest::blocking::Client,
-    base_url: String,
-    username: String,
-    token: String,
-}
+// ============================================================================
+// Remote backend
+// ============================================================================
 
-impl ApiClient {
-    fn new(base_url: &str, username: &str, token: &str) -> Self {
-        Self {
-            client: reqwest::blocking::Client::new(),
-            base_url: base_url.trim_end_matches('/').to_string(),
-            username: username.to_string(),
-            token: token.to_string(),
-        }
-    }
+/// Remote side of the sync. `SyncEngine` only talks to this trait; `ApiClient`
+/// (MDFlare cloud REST API or a peer vault) is the built-in implementation, and
+/// other stores (S3-compatible bucket, git, a test mock) can plug in here.
+trait RemoteBackend: Send + Sync {
+    /// Full file tree (folders with `children`).
+    fn list_files(&self) -> Result<Vec<FileItem>, Box<dyn std::error::Error>>;
 
-    fn list_files(&self) -> Result<Vec<FileItem>, reqwest::Error> {
-        let url = format!("{}/api/{}/files", self.base_url, self.username);
-        let resp: FilesResponse = self.client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
-            .send()?
-            .json()?;
-        Ok(resp.files)
-    }
+    fn get_file(&self, path: &str) -> Result<FileContent, Box<dyn std::error::Error>>;
 
-    fn get_file(&self, path: &str) -> Result<FileContent, reqwest::Error> {
-        let encoded = urlencoding::encode(path);
-        let url = format!("{}/api/{}/file/{}", self.base_url, self.username, encoded);
-        self.client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
-            .send()?
-            .json()
+    /// Download for large files that can resume after an interruption.
+    fn get_file_resumable(&self, path: &str) -> Result<FileContent, Box<dyn std::error::Error>> {
+        self.get_file(path)
     }
 
     fn put_file(&self, path: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
         self.put_file_with_diff(path, content, None, None)
     }
 
+    /// Upload, optionally with the previous hash and a line diff (backends may ignore both).
     fn put_file_with_diff(
         &self,
         path: &str,
         content: &str,
         old_hash: Option<&str>,
         diff: Option<&serde_json::Value>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let encoded = urlencoding::encode(path);
-        let url = format!("{}/api/{}/file/{}", self.base_url, self.username, encoded);
-        let mut body = serde_json::json!({ "content": content });
-        if let Some(oh) = old_hash {
-            body["oldHash"] = serde_json::json!(oh);
-        }
-        if let Some(d) = diff {
-            body["diff"] = d.clone();
-        }
-        self.client
-            .put(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
-            .json(&body)
-            .send()?;
-        Ok(())
-    }
+    ) -> Result<(), Box<dyn std::error::Error>>;
 
-    fn delete_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let encoded = urlencoding::encode(path);
-        let url = format!("{}/api/{}/file/{}", self.base_url, self.username, encoded);
-        self.client
-            .delete(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
-            .send()?;
-        Ok(())
+    /// Create only if absent. Returns false when another device created it first.
+    fn create_file(&self, path: &str, content: &str) -> Result<bool, Box<dyn std::error::Error>>;
+
+    fn delete_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn rename_file(&self, old_path: &str, new_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        rename_by_copy(self, old_path, new_path)
     }
 
-    fn put_heartbeat(&self) {
-        let url = format!("{}/api/{}/agent-status", self.base_url, self.username);
-        self.client
-            .put(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
-            .send()
-            .ok();
+    /// Deletions since `since_ms` (tombstones). Backends without a deletion log return none.
+    fn list_deletions(&self, _since_ms: i64) -> Result<Vec<DeletionEntry>, Box<dyn std::error::Error>> {
+        Ok(Vec::new())
     }
 
-    fn get_sync_config(&self) -> Result<RtdbConfig, Box<dyn std::error::Error>> {
-        let url = format!("{}/api/{}/sync-config", self.base_url, self.username);
-        let resp: RtdbConfig = self.client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
-            .send()?
-            .json()?;
-        Ok(resp)
+    /// Liveness ping after each sync.
+    fn put_heartbeat(&self) {}
+
+    /// Real-time change feed (RTDB SSE) connection info. None = periodic sync only.
+    fn watch_config(&self) -> Result<Option<RtdbConfig>, Box<dyn std::error::Error>> {
+        Ok(None)
     }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct RtdbConfig {
-    rtdb_url: String,
-    rtdb_auth: String,
-    user_id: String,
+/// Rename for backends without a native move: get → put under the new path → delete.
+fn rename_by_copy<B: RemoteBackend + ?Sized>(
+    backend: &B,
+    old_path: &str,
+    new_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = backend.get_file(old_path)?;
+    backend.put_file(new_path, &file.content)?;
+    backend.delete_file(old_path)
 }
 
-// ============================================================================
-// Local File System Helpers
-// ============================================================================
+/// Backend wrapper that appends every successful remote write to the audit log.
+struct AuditedBackend {
+    inner: Box<dyn RemoteBackend>,
+    audit: Arc<AuditLog>,
+}
 
-fn scan_local_md_files(local_path: &Path) -> Vec<FileItem> {
-    fn scan_dir(dir: &Path, base: &Path) -> Vec<FileItem> {
-        let mut items = Vec::new();
-        
-        if let Ok(entries) = fs::read_dir(dir) {
-            let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).collect();
-            entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
-            
-            for entry in entries {
-                let path = entry.path();
-                let name = entry.file_name().to_string_lossy().to_string();
-                
-                // 숨김 파일/폴더 스킵
-                if name.starts_with('.') {
-                    continue;
-                }
-                
-                if path.is_dir() {
-                    let children = scan_dir(&path, base);
-                    if !children.is_empty() || has_md_files(&path) {
-                        let rel_path = path.strip_prefix(base).unwrap_or(&path);
-                        items.push(FileItem {
-                            name,
-                            path: rel_path.to_string_lossy().replace('\\', "/"),
-                            file_type: "folder".to_string(),
-                            size: None,
-                            modified: None,
-                            children: Some(children),
-                        });
-                    }
-                } else if path.extension().map_or(false, |e| e == "md") {
-                    let rel_path = path.strip_prefix(base).unwrap_or(&path);
-                    let metadata = fs::metadata(&path).ok();
-                    items.push(FileItem {
-                        name,
-                        path: rel_path.to_string_lossy().replace('\\', "/"),
-                        file_type: "file".to_string(),
-                        size: metadata.as_ref().map(|m| m.len()),
-                        modified: metadata.and_then(|m| {
-                            m.modified().ok().map(|t| {
-                                let datetime: chrono::DateTime<chrono::Utc> = t.into();
-                                datetime.to_rfc3339()
-                            })
-                        }),
-                        children: None,
-                    });
-                }
-            }
-        }
-        
-        // 폴더 먼저, 그 다음 파일
-        items.sort_by(|a, b| {
-            match (&a.file_type[..], &b.file_type[..]) {
-                ("folder", "file") => std::cmp::Ordering::Less,
-                ("file", "folder") => std::cmp::Ordering::Greater,
-                _ => a.name.cmp(&b.name),
-            }
-        });
-        
-        items
+impl RemoteBackend for AuditedBackend {
+    fn list_files(&self) -> Result<Vec<FileItem>, Box<dyn std::error::Error>> {
+        self.inner.list_files()
     }
-    
-    fn has_md_files(dir: &Path) -> bool {
-        if let Ok(entries) = fs::read_dir(dir) {
-            for entry in entries.filter_map(|e| e.ok()) {
-                let path = entry.path();
-                if path.is_file() && path.extension().map_or(false, |e| e == "md") {
-                    return true;
-                }
-                if path.is_dir() && has_md_files(&path) {
-                    return true;
-                }
-            }
-        }
-        false
+
+    fn get_file(&self, path: &str) -> Result<FileContent, Box<dyn std::error::Error>> {
+        self.inner.get_file(path)
     }
-    
-    scan_dir(local_path, local_path)
-}
 
-fn flatten_file_paths(items: &[FileItem]) -> Vec<String> {
-    let mut result = Vec::new();
-    for item in items {
-        if item.file_type == "folder" {
-            if let Some(children) = &item.children {
-                result.extend(flatten_file_paths(children));
-            }
-        } else {
-            result.push(item.path.clone());
+    fn get_file_resumable(&self, path: &str) -> Result<FileContent, Box<dyn std::error::Error>> {
+        self.inner.get_file_resumable(path)
+    }
+
+    fn put_file_with_diff(
+        &self,
+        path: &str,
+        content: &str,
+        old_hash: Option<&str>,
+        diff: Option<&serde_json::Value>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.put_file_with_diff(path, content, old_hash, diff)?;
+        self.audit.record("upload", "up", path, Some(content), None);
+        Ok(())
+    }
+
+    fn create_file(&self, path: &str, content: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let created = self.inner.create_file(path, content)?;
+        if created {
+            self.audit.record("upload", "up", path, Some(content), None);
         }
+        Ok(created)
     }
-    result
-}
 
-// ============================================================================
-// Private Vault Server
-// ============================================================================
+    fn delete_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.delete_file(path)?;
+        self.audit.record("delete", "up", path, None, None);
+        Ok(())
+    }
 
-#[derive(Clone)]
-struct ServerState {
-    local_path: PathBuf,
+    fn rename_file(&self, old_path: &str, new_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.rename_file(old_path, new_path)?;
+        self.audit.record("rename", "up", new_path, None, Some(old_path));
+        Ok(())
+    }
+
+    fn list_deletions(&self, since_ms: i64) -> Result<Vec<DeletionEntry>, Box<dyn std::error::Error>> {
+        self.inner.list_deletions(since_ms)
+    }
+
+    fn put_heartbeat(&self) {
+        self.inner.put_heartbeat()
+    }
+
+    fn watch_config(&self) -> Result<Option<RtdbConfig>, Box<dyn std::error::Error>> {
+        self.inner.watch_config()
+    }
+}
+
+struct ApiClient {
+    client: reqwest::blocking::Client,
+    base_url: String,
+    // "/prefix" 형태 (없으면 빈 문자열)
+    path_prefix: String,
+    username: String,
     token: String,
+    // E2E 암호화: Some이면 업로드 전 암호화, 다운로드 후 복호화
+    cipher: Option<ContentCipher>,
+    require_encryption: bool,
+    // heartbeat에 실어 보낼 에이전트 이름
+    vault_name: Option<String>,
+    // 요청마다 timestamp+nonce HMAC 서명 헤더 첨부 (vault의 require_signed_requests)
+    sign_requests: bool,
+    // 0 = 끔. 업로드 본문이 이보다 크면 청크 업로드 (서버가 지원할 때)
+    max_upload_chunk: usize,
+    // GET /api/capabilities 결과 (처음 필요할 때 한 번 확인, 없으면 빈 객체)
+    capabilities: std::sync::OnceLock<serde_json::Value>,
+    // Some(n) = 요청 본문이 n바이트를 넘으면 gzip (서버가 gzipUpload를 알릴 때만)
+    compress_min: Option<usize>,
+    // gzip 본문을 서버가 거부함 → 이후 압축 안 함
+    gzip_rejected: std::sync::atomic::AtomicBool,
+    // oldHash를 만든 방식 (simple_js가 아니면 algo 필드로 알림)
+    hash_algo: HashAlgo,
+    // 업로드/heartbeat에 deviceId로 첨부
+    device_id: Option<String>,
 }
 
-async fn check_auth(
-    state: &ServerState,
-    auth_header: Option<&str>,
-) -> Result<(), StatusCode> {
-    match auth_header {
-        Some(h) if h.starts_with("Bearer ") => {
-            let token = &h[7..];
-            if token == state.token {
-                Ok(())
-            } else {
-                Err(StatusCode::UNAUTHORIZED)
-            }
+impl ApiClient {
+    fn new(base_url: &str, username: &str, token: &str) -> Self {
+        Self {
+            client: reqwest::blocking::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            path_prefix: String::new(),
+            username: username.to_string(),
+            token: token.to_string(),
+            cipher: None,
+            require_encryption: false,
+            vault_name: None,
+            sign_requests: false,
+            max_upload_chunk: 0,
+            capabilities: std::sync::OnceLock::new(),
+            compress_min: None,
+            gzip_rejected: std::sync::atomic::AtomicBool::new(false),
+            hash_algo: HashAlgo::SimpleJs,
+            device_id: None,
         }
-        _ => Err(StatusCode::UNAUTHORIZED),
     }
-}
 
-async fn api_list_files(
-    State(state): State<ServerState>,
-    headers: axum::http::HeaderMap,
-) -> Result<Json<FilesResponse>, StatusCode> {
-    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
-    check_auth(&state, auth).await?;
-    let files = scan_local_md_files(&state.local_path);
-    Ok(Json(FilesResponse {
-        user: "local".to_string(),
-        files,
-    }))
-}
+    /// Sign every request with the shared token (vaults with `require_signed_requests`).
+    fn with_request_signing(mut self, enabled: bool) -> Self {
+        self.sign_requests = enabled;
+        self
+    }
 
-async fn api_get_file(
-    State(state): State<ServerState>,
-    headers: axum::http::HeaderMap,
-    AxumPath(path): AxumPath<String>,
-) -> Result<Json<FileContent>, StatusCode> {
-    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
-    check_auth(&state, auth).await?;
-    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path);
-    let file_path = state.local_path.join(&decoded);
-    
-    // 보안: local_path 밖으로 나가지 못하게
-    if !file_path.starts_with(&state.local_path) {
-        return Err(StatusCode::FORBIDDEN);
+    /// Split uploads whose request body exceeds `bytes` (0 = never), for servers behind a
+    /// proxy with a small body limit.
+    fn with_max_upload_chunk(mut self, bytes: usize) -> Self {
+        self.max_upload_chunk = bytes;
+        self
     }
-    
-    let content = fs::read_to_string(&file_path).map_err(|_| StatusCode::NOT_FOUND)?;
-    let metadata = fs::metadata(&file_path).map_err(|_| StatusCode::NOT_FOUND)?;
-    
-    let modified: chrono::DateTime<chrono::Utc> = metadata.modified()
-        .map(|t| t.into())
-        .unwrap_or_else(|_| chrono::Utc::now());
-    
-    Ok(Json(FileContent {
-        path: decoded.to_string(),
-        content: content.clone(),
-        size: content.len() as u64,
-        modified: modified.to_rfc3339(),
-    }))
-}
 
-async fn api_put_file(
-    State(state): State<ServerState>,
-    headers: axum::http::HeaderMap,
-    AxumPath(path): AxumPath<String>,
-    Json(body): Json<PutFileRequest>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    // 인증 체크
-    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
-    check_auth(&state, auth).await?;
-    
-    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path.clone());
-    let file_path = state.local_path.join(&decoded);
-    
-    // 보안: local_path 밖으로 나가지 못하게
-    if !file_path.starts_with(&state.local_path) {
-        return Err(StatusCode::FORBIDDEN);
+    /// gzip upload bodies larger than `min_bytes` (`Content-Encoding: gzip`).
+    fn with_upload_compression(mut self, enabled: bool, min_bytes: usize) -> Self {
+        self.compress_min = enabled.then_some(min_bytes);
+        self
     }
-    
-    // 상위 폴더 생성
-    if let Some(parent) = file_path.parent() {
-        fs::create_dir_all(parent).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    fn with_device_id(mut self, device_id: &str) -> Self {
+        self.device_id = (!device_id.is_empty()).then(|| device_id.to_string());
+        self
     }
-    
-    fs::write(&file_path, &body.content).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    Ok(Json(serde_json::json!({
-        "path": decoded.to_string(),
-        "saved": true,
-        "size": body.content.len()
-    })))
-}
 
-async fn api_delete_file(
-    State(state): State<ServerState>,
-    headers: axum::http::HeaderMap,
-    AxumPath(path): AxumPath<String>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    // 인증 체크
-    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
-    check_auth(&state, auth).await?;
-    
-    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path.clone());
-    let file_path = state.local_path.join(&decoded);
-    
-    // 보안: local_path 밖으로 나가지 못하게
-    if !file_path.starts_with(&state.local_path) {
-        return Err(StatusCode::FORBIDDEN);
+    fn with_hash_algo(mut self, algo: HashAlgo) -> Self {
+        self.hash_algo = algo;
+        self
     }
-    
-    if file_path.is_dir() {
-        fs::remove_dir_all(&file_path).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    } else {
-        fs::remove_file(&file_path).map_err(|_| StatusCode::NOT_FOUND)?;
+
+    fn with_vault_name(mut self, name: Option<&str>) -> Self {
+        self.vault_name = name.map(str::to_string);
+        self
     }
-    
-    Ok(Json(serde_json::json!({
-        "path": decoded.to_string(),
-        "deleted": true
-    })))
-}
 
-#[derive(Deserialize)]
-struct RenameRequest {
-    #[serde(rename = "oldPath")]
-    old_path: String,
-    #[serde(rename = "newPath")]
-    new_path: String,
-}
+    /// Insert a path prefix between the base URL and `/api` (reverse-proxy routing).
+    fn with_path_prefix(mut self, prefix: &str) -> Self {
+        let prefix = prefix.trim_matches('/');
+        self.path_prefix = if prefix.is_empty() { String::new() } else { format!("/{}", prefix) };
+        self
+    }
 
-async fn api_rename(
-    State(state): State<ServerState>,
-    headers: axum::http::HeaderMap,
-    Json(body): Json<RenameRequest>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    // 인증 체크
-    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
-    check_auth(&state, auth).await?;
-    
-    let old_decoded = urlencoding::decode(&body.old_path).map(|s| s.into_owned()).unwrap_or(body.old_path.clone());
-    let new_decoded = urlencoding::decode(&body.new_path).map(|s| s.into_owned()).unwrap_or(body.new_path.clone());
-    
-    let old_file_path = state.local_path.join(&old_decoded);
-    let new_file_path = state.local_path.join(&new_decoded);
-    
-    // 보안: local_path 밖으로 나가지 못하게
-    if !old_file_path.starts_with(&state.local_path) || !new_file_path.starts_with(&state.local_path) {
-        return Err(StatusCode::FORBIDDEN);
+    /// Enable E2E encryption using the passphrase stored in the OS keychain.
+    /// If the passphrase is missing, uploads are refused rather than sent as plaintext.
+    fn with_encryption(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.require_encryption = true;
+            self.cipher = ContentCipher::from_keychain(&self.username);
+            if self.cipher.is_none() {
+                log_to_file("encryption: enabled but no passphrase in keychain → uploads blocked");
+                eprintln!("❌ 암호화가 켜져 있지만 키체인에 패스프레이즈가 없습니다 (--set-encryption-passphrase)");
+            }
+        }
+        self
     }
-    
-    // 원본 파일/폴더 존재 확인
-    if !old_file_path.exists() {
-        return Err(StatusCode::NOT_FOUND);
+
+    /// API URL: `/api/{user}/...` for the cloud, `/api/...` for a peer vault (no username).
+    fn api_url(&self, tail: &str) -> String {
+        if self.username.is_empty() {
+            format!("{}{}/api/{}", self.base_url, self.path_prefix, tail)
+        } else {
+            format!("{}{}/api/{}/{}", self.base_url, self.path_prefix, self.username, tail)
+        }
     }
-    
-    // 상위 폴더 생성
-    if let Some(parent) = new_file_path.parent() {
-        fs::create_dir_all(parent).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    /// Authorized request builder. With signing on, adds `X-Mdflare-Timestamp`/`-Nonce`/`-Signature`
+    /// over method, path+query, timestamp and nonce, keyed by the bearer token.
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::blocking::RequestBuilder {
+        let mut req = self.client
+            .request(method.clone(), url)
+            .header("Authorization", format!("Bearer {}", self.token));
+        if self.sign_requests {
+            use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+            // 실제로 전송될 형태(정규화된 URL) 기준으로 서명
+            if let Ok(parsed) = reqwest::Url::parse(url) {
+                let path = match parsed.query() {
+                    Some(q) => format!("{}?{}", parsed.path(), q),
+                    None => parsed.path().to_string(),
+                };
+                let timestamp = chrono::Utc::now().timestamp().to_string();
+                let mut raw = [0u8; 16];
+                OsRng.fill_bytes(&mut raw);
+                let nonce: String = raw.iter().map(|b| format!("{:02x}", b)).collect();
+                let message = signed_request_message(method.as_str(), &path, &timestamp, &nonce);
+                let signature = hmac_sha256_hex(self.token.as_bytes(), message.as_bytes());
+                req = req
+                    .header(SIGNED_TIMESTAMP_HEADER, timestamp)
+                    .header(SIGNED_NONCE_HEADER, nonce)
+                    .header(SIGNED_SIGNATURE_HEADER, signature);
+            }
+        }
+        req
+    }
+
+    /// `.part` file for a resumable download (config_dir/partial, outside the synced folder).
+    fn part_path(&self, path: &str) -> PathBuf {
+        let dir = agent_config_dir().join("partial");
+        fs::create_dir_all(&dir).ok();
+        dir.join(format!("{}.part", sha256_hex(format!("{}|{}", self.base_url, path).as_bytes())))
+    }
+
+    fn send_put(
+        &self,
+        path: &str,
+        content: &str,
+        old_hash: Option<&str>,
+        diff: Option<&serde_json::Value>,
+        if_absent: bool,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let encoded = urlencoding::encode(path);
+        let url = self.api_url(&format!("file/{}", encoded));
+        let mut body = if let Some(cipher) = &self.cipher {
+            // 서버는 암호문만 보관 → 평문 기준 oldHash/diff는 의미 없으므로 보내지 않음
+            serde_json::json!({ "content": cipher.encrypt(content)? })
+        } else if self.require_encryption {
+            return Err("암호화 패스프레이즈 없음: 평문 업로드 차단".into());
+        } else {
+            let mut body = serde_json::json!({ "content": content });
+            if let Some(oh) = old_hash {
+                body["oldHash"] = serde_json::json!(oh);
+            }
+            if self.hash_algo != HashAlgo::SimpleJs {
+                // oldHash (및 서버가 기록할 hash) 방식
+                body["algo"] = serde_json::json!(self.hash_algo.as_str());
+            }
+            if let Some(d) = diff {
+                body["diff"] = d.clone();
+            }
+            body
+        };
+        if let Some(device_id) = &self.device_id {
+            body["deviceId"] = serde_json::json!(device_id);
+        }
+        let json = serde_json::to_vec(&body)?;
+        let compressed = self.compress_body(&json);
+        let wire_len = compressed.as_ref().map_or(json.len(), Vec::len);
+        if self.max_upload_chunk > 0 && wire_len > self.max_upload_chunk && self.supports_chunked_upload() {
+            // 큰 본문은 청크로 (전체 내용만 보냄 - oldHash/diff 없음)
+            let wire = body["content"].as_str().unwrap_or_default();
+            return self.send_chunked(path, wire, if_absent);
+        }
+        let put = |payload: Vec<u8>, gzip: bool| -> Result<reqwest::blocking::Response, Box<dyn std::error::Error>> {
+            let mut req = self
+                .request(reqwest::Method::PUT, &url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(payload);
+            if gzip {
+                req = req.header(reqwest::header::CONTENT_ENCODING, "gzip");
+            }
+            if if_absent {
+                req = req.header(reqwest::header::IF_NONE_MATCH, "*");
+            }
+            Ok(check_rate_limit(req.send()?)?)
+        };
+        let mut resp = match compressed {
+            Some(gz) => put(gz, true)?,
+            None => put(json.clone(), false)?,
+        };
+        if wire_len != json.len()
+            && matches!(resp.status(), reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE | reqwest::StatusCode::BAD_REQUEST)
+        {
+            // 압축 본문을 못 읽는 서버 (프록시가 헤더를 떼는 경우 등) → 이후 압축 없이
+            log_to_file(&format!("upload: gzip body rejected ({}), sending uncompressed from now on", resp.status()));
+            self.gzip_rejected.store(true, std::sync::atomic::Ordering::Relaxed);
+            resp = put(json, false)?;
+        }
+        Ok(resp.status() != reqwest::StatusCode::PRECONDITION_FAILED)
+    }
+
+    /// gzip `json` when compression is on, the body is over the threshold and the server
+    /// accepts it; None = send as is.
+    fn compress_body(&self, json: &[u8]) -> Option<Vec<u8>> {
+        let min = self.compress_min?;
+        if json.len() <= min
+            || self.gzip_rejected.load(std::sync::atomic::Ordering::Relaxed)
+            || !self.has_capability("gzipUpload")
+        {
+            return None;
+        }
+        gzip_bytes(json).ok().filter(|gz| gz.len() < json.len())
+    }
+
+    /// Optional features from `GET /api/capabilities`, fetched once per client. Servers
+    /// without the endpoint (404 on older vaults, network error) have none.
+    fn has_capability(&self, name: &str) -> bool {
+        let caps = self.capabilities.get_or_init(|| {
+            let url = self.api_url("capabilities");
+            let caps = self
+                .request(reqwest::Method::GET, &url)
+                .send()
+                .ok()
+                .filter(|resp| resp.status().is_success())
+                .and_then(|resp| resp.json::<serde_json::Value>().ok())
+                .unwrap_or_else(|| serde_json::json!({}));
+            log_to_file(&format!("upload: server capabilities = {}", caps));
+            caps
+        });
+        caps[name] == true
+    }
+
+    fn supports_chunked_upload(&self) -> bool {
+        self.has_capability("chunkedUpload")
+    }
+
+    /// Upload `content` (already encrypted if E2E is on) as `max_upload_chunk`-byte pieces:
+    /// `POST file/{path}/chunk?id=&index=&total=` for each, then `POST file/{path}/finalize`
+    /// with the SHA-256 of the whole so the server can check the reassembled file.
+    fn send_chunked(&self, path: &str, content: &str, if_absent: bool) -> Result<bool, Box<dyn std::error::Error>> {
+        use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+        let encoded = urlencoding::encode(path);
+        let mut raw = [0u8; 16];
+        OsRng.fill_bytes(&mut raw);
+        let id: String = raw.iter().map(|b| format!("{:02x}", b)).collect();
+        let chunks: Vec<&[u8]> = content.as_bytes().chunks(self.max_upload_chunk).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let url = self.api_url(&format!("file/{}/chunk?id={}&index={}&total={}", encoded, id, index, chunks.len()));
+            let resp = self
+                .request(reqwest::Method::POST, &url)
+                .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+                .body(chunk.to_vec())
+                .send()?;
+            check_rate_limit(resp)?.error_for_status()?;
+        }
+        let url = self.api_url(&format!("file/{}/finalize?id={}&total={}", encoded, id, chunks.len()));
+        let mut req = self
+            .request(reqwest::Method::POST, &url)
+            .json(&serde_json::json!({ "sha256": sha256_hex(content.as_bytes()), "deviceId": self.device_id }));
+        if if_absent {
+            req = req.header(reqwest::header::IF_NONE_MATCH, "*");
+        }
+        let resp = check_rate_limit(req.send()?)?;
+        if resp.status() == reqwest::StatusCode::PRECONDITION_FAILED {
+            return Ok(false);
+        }
+        resp.error_for_status()?;
+        log_to_file(&format!("upload: {} sent in {} chunks", path, chunks.len()));
+        Ok(true)
+    }
+}
+
+impl RemoteBackend for ApiClient {
+    fn list_files(&self) -> Result<Vec<FileItem>, Box<dyn std::error::Error>> {
+        let url = self.api_url("files");
+        let resp = self.request(reqwest::Method::GET, &url).send()?;
+        if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(Box::new(AuthExpired));
+        }
+        let resp: FilesResponse = check_rate_limit(resp)?.json()?;
+        Ok(resp.files)
+    }
+
+    fn get_file(&self, path: &str) -> Result<FileContent, Box<dyn std::error::Error>> {
+        let encoded = urlencoding::encode(path);
+        let url = self.api_url(&format!("file/{}", encoded));
+        let resp = self.request(reqwest::Method::GET, &url).send()?;
+        let mut file: FileContent = check_rate_limit(resp)?.json()?;
+        if let Some(encoding) = file.encoding.take() {
+            // content는 문자열로만 다루므로 UTF-8로 풀리지 않는 파일은 base64 텍스트를 쓰지 않고 실패 처리
+            if encoding != "base64" {
+                return Err(format!("unsupported encoding {} for {}", encoding, path).into());
+            }
+            use base64::{Engine as _, engine::general_purpose::STANDARD};
+            let bytes = STANDARD.decode(file.content.trim())?;
+            file.content = String::from_utf8(bytes).map_err(|_| format!("binary file not supported: {}", path))?;
+        }
+        if let Some(cipher) = &self.cipher {
+            file.content = cipher.decrypt(&file.content)?;
+        }
+        Ok(file)
+    }
+
+    /// Download `path` from `/raw/{path}` into a `.part` file, resuming with `Range: bytes=N-`
+    /// when a previous attempt was cut off. The finished file is checked against
+    /// `X-Content-Sha256` (if sent) before use. Servers without the raw endpoint, or that
+    /// reject the range, fall back to a full `get_file`.
+    fn get_file_resumable(&self, path: &str) -> Result<FileContent, Box<dyn std::error::Error>> {
+        let part = self.part_path(path);
+        let have = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+        let url = self.api_url(&format!("raw/{}", urlencoding::encode(path)));
+        let mut req = self.request(reqwest::Method::GET, &url);
+        if have > 0 {
+            req = req.header(reqwest::header::RANGE, format!("bytes={}-", have));
+        }
+        let mut resp = check_rate_limit(req.send()?)?;
+        let mut file = match resp.status() {
+            // 이어받기
+            reqwest::StatusCode::PARTIAL_CONTENT => fs::OpenOptions::new().append(true).create(true).open(&part)?,
+            // Range 미지원 → 처음부터
+            reqwest::StatusCode::OK => fs::File::create(&part)?,
+            _ => {
+                // raw 엔드포인트 없음(404 등) 또는 범위 오류(416) → 전체 다운로드
+                fs::remove_file(&part).ok();
+                return self.get_file(path);
+            }
+        };
+        let expected = resp
+            .headers()
+            .get("x-content-sha256")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        // 중간에 끊기면 받은 만큼 .part에 남음 → 다음 동기화에서 이어받기
+        resp.copy_to(&mut file)?;
+        drop(file);
+
+        let bytes = fs::read(&part)?;
+        fs::remove_file(&part).ok();
+        if let Some(expected) = expected {
+            let actual = sha256_hex(&bytes);
+            if !actual.eq_ignore_ascii_case(&expected) {
+                return Err(format!("resumed download hash mismatch: {}", path).into());
+            }
+        }
+        let mut content = String::from_utf8(bytes)?;
+        if let Some(cipher) = &self.cipher {
+            content = cipher.decrypt(&content)?;
+        }
+        Ok(FileContent {
+            path: path.to_string(),
+            size: content.len() as u64,
+            content,
+            modified: String::new(),
+            encoding: None,
+        })
+    }
+
+    fn put_file(&self, path: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_file_with_diff(path, content, None, None)
+    }
+
+    fn put_file_with_diff(
+        &self,
+        path: &str,
+        content: &str,
+        old_hash: Option<&str>,
+        diff: Option<&serde_json::Value>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_put(path, content, old_hash, diff, false).map(|_| ())
+    }
+
+    /// Create a file only if it doesn't exist yet (`If-None-Match: *`).
+    /// Returns false when another device created it first (412). Servers that
+    /// ignore the header simply create/overwrite, same as `put_file`.
+    fn create_file(&self, path: &str, content: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        self.send_put(path, content, None, None, true)
+    }
+
+    fn delete_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let encoded = urlencoding::encode(path);
+        let url = self.api_url(&format!("file/{}", encoded));
+        let resp = self.request(reqwest::Method::DELETE, &url).send()?;
+        check_rate_limit(resp)?;
+        Ok(())
+    }
+
+    /// A peer vault renames in place (`POST /api/rename`); the cloud API has no rename,
+    /// so it falls back to copy + delete.
+    fn rename_file(&self, old_path: &str, new_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.username.is_empty() || self.cipher.is_some() {
+            return rename_by_copy(self, old_path, new_path);
+        }
+        let url = self.api_url("rename");
+        let mut body = serde_json::json!({ "oldPath": old_path, "newPath": new_path });
+        if self.hash_algo != HashAlgo::SimpleJs {
+            // 서버가 rename 기록에 남길 hash 방식
+            body["algo"] = serde_json::json!(self.hash_algo.as_str());
+        }
+        let resp = self.request(reqwest::Method::POST, &url).json(&body).send()?;
+        check_rate_limit(resp)?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Recent deletions since `since_ms` (GET /api/{user}/deletions?since=).
+    /// Servers without the endpoint (404) yield an empty list.
+    fn list_deletions(&self, since_ms: i64) -> Result<Vec<DeletionEntry>, Box<dyn std::error::Error>> {
+        let url = self.api_url(&format!("deletions?since={}", since_ms));
+        let resp = self.request(reqwest::Method::GET, &url).send()?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let resp: DeletionsResponse = check_rate_limit(resp)?.error_for_status()?.json()?;
+        Ok(resp.deletions)
+    }
+
+    fn put_heartbeat(&self) {
+        let url = self.api_url("agent-status");
+        let mut req = self.request(reqwest::Method::PUT, &url);
+        if self.vault_name.is_some() || self.device_id.is_some() {
+            req = req.json(&serde_json::json!({ "vaultName": self.vault_name, "deviceId": self.device_id }));
+        }
+        req.send().ok();
+    }
+
+    /// RTDB connection info from `sync-config` (cloud only; a peer vault has no change feed).
+    fn watch_config(&self) -> Result<Option<RtdbConfig>, Box<dyn std::error::Error>> {
+        if self.username.is_empty() {
+            return Ok(None);
+        }
+        let url = self.api_url("sync-config");
+        let resp: RtdbConfig = self.request(reqwest::Method::GET, &url).send()?.json()?;
+        Ok(Some(resp))
+    }
+}
+
+// ============================================================================
+// E2E 암호화 (실험적, Cloud 모드용)
+// ============================================================================
+
+const ENCRYPTED_PREFIX: &str = "mdflare-enc:v1:";
+const KEYCHAIN_SERVICE: &str = "com.mdflare.agent";
+
+/// Symmetric AEAD (ChaCha20-Poly1305) for note content.
+/// The key is derived from the user's passphrase with Argon2id, salted by username,
+/// so every device (and the web editor) holding the same passphrase derives the same key.
+struct ContentCipher {
+    cipher: chacha20poly1305::ChaCha20Poly1305,
+}
+
+impl ContentCipher {
+    fn from_passphrase(passphrase: &str, username: &str) -> Option<Self> {
+        use chacha20poly1305::KeyInit;
+        let salt = format!("mdflare-e2e:{}", username);
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+            .ok()?;
+        Some(Self {
+            cipher: chacha20poly1305::ChaCha20Poly1305::new(&key.into()),
+        })
+    }
+
+    fn from_keychain(username: &str) -> Option<Self> {
+        let passphrase = keychain_entry(username).ok()?.get_password().ok()?;
+        Self::from_passphrase(&passphrase, username)
+    }
+
+    /// Encrypt with a fresh random nonce: `mdflare-enc:v1:` + base64(nonce || ciphertext).
+    fn encrypt(&self, plain: &str) -> Result<String, Box<dyn std::error::Error>> {
+        use base64::{Engine as _, engine::general_purpose::STANDARD};
+        use chacha20poly1305::aead::{Aead, AeadCore, OsRng};
+        let nonce = chacha20poly1305::ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plain.as_bytes())
+            .map_err(|_| "암호화 실패")?;
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(payload)))
+    }
+
+    /// Decrypt content produced by `encrypt`. Content without the prefix is
+    /// returned unchanged so notes uploaded before encryption was enabled still sync.
+    fn decrypt(&self, content: &str) -> Result<String, Box<dyn std::error::Error>> {
+        use base64::{Engine as _, engine::general_purpose::STANDARD};
+        use chacha20poly1305::aead::Aead;
+        let encoded = match content.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(e) => e,
+            None => return Ok(content.to_string()),
+        };
+        let payload = STANDARD.decode(encoded.trim())?;
+        if payload.len() < 12 {
+            return Err("암호문 형식 오류".into());
+        }
+        let (nonce, ciphertext) = payload.split_at(12);
+        let plain = self
+            .cipher
+            .decrypt(chacha20poly1305::Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "복호화 실패 (패스프레이즈 불일치?)")?;
+        Ok(String::from_utf8(plain)?)
+    }
+}
+
+fn keychain_entry(username: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(KEYCHAIN_SERVICE, &format!("e2e:{}", username))
+}
+
+/// Raw Private Vault token when `hash_server_token` keeps only its hash in config.json.
+fn server_token_keychain_entry() -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(KEYCHAIN_SERVICE, "vault-server-token")
+}
+
+/// `salt$sha256(salt:token)` with a fresh random 16-byte salt.
+fn hash_server_token(token: &str) -> String {
+    use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+    let mut raw = [0u8; 16];
+    OsRng.fill_bytes(&mut raw);
+    let salt: String = raw.iter().map(|b| format!("{:02x}", b)).collect();
+    let digest = sha256_hex(format!("{}:{}", salt, token).as_bytes());
+    format!("{}${}", salt, digest)
+}
+
+fn verify_server_token_hash(stored: &str, token: &str) -> bool {
+    let Some((salt, digest)) = stored.split_once('$') else { return false };
+    constant_time_eq(&sha256_hex(format!("{}:{}", salt, token).as_bytes()), digest)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RtdbConfig {
+    rtdb_url: String,
+    rtdb_auth: String,
+    user_id: String,
+}
+
+/// 서버가 429로 응답함 (Retry-After 만큼 대기 필요)
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: Duration,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "서버 혼잡 (429), {}초 후 재시도", self.retry_after.as_secs())
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+// 이 크기 이상은 .part + Range로 이어받기 가능한 다운로드
+const RESUMABLE_MIN_BYTES: u64 = 1024 * 1024;
+
+/// 서버가 401로 응답함 (토큰 만료/폐기 → 다시 로그인 필요)
+#[derive(Debug)]
+struct AuthExpired;
+
+impl std::fmt::Display for AuthExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "인증 만료 (401)")
+    }
+}
+
+impl std::error::Error for AuthExpired {}
+
+/// Parse a `Retry-After` header: either delta-seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let secs = (at.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_seconds();
+    Some(Duration::from_secs(secs.max(0) as u64))
+}
+
+/// Turn a 429 response into a `RateLimited` error; pass other responses through.
+fn check_rate_limit(resp: reqwest::blocking::Response) -> Result<reqwest::blocking::Response, RateLimited> {
+    if resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Ok(resp);
+    }
+    let retry_after = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after)
+        .unwrap_or(DEFAULT_RETRY_AFTER);
+    Err(RateLimited { retry_after })
+}
+
+// ============================================================================
+// Local File System Helpers
+// ============================================================================
+
+// include_hidden이어도 항상 제외하는 숨김 항목 (도구/앱 내부 상태)
+const ALWAYS_IGNORED_NAMES: &[&str] = &[".git", ".obsidian", ".trash", ".DS_Store", PENDING_DELETES_DIR];
+
+/// delete_policy = review: 원격 삭제를 승인 전까지 보관하는 폴더 (local_path 최상위, 원래 경로 구조 유지)
+const PENDING_DELETES_DIR: &str = ".mdflare-pending-deletes";
+
+/// Whether a single path component should be skipped by the scanner/watcher.
+fn is_ignored_name(name: &str, include_hidden: bool) -> bool {
+    if ALWAYS_IGNORED_NAMES.contains(&name) {
+        return true;
+    }
+    !include_hidden && name.starts_with('.')
+}
+
+/// Whether any component of a vault-relative path should be skipped.
+fn is_ignored_path(rel_path: &Path, include_hidden: bool) -> bool {
+    rel_path.components().any(|c| {
+        is_ignored_name(&c.as_os_str().to_string_lossy(), include_hidden)
+    })
+}
+
+/// gitignore 형식 동기화 제외 목록 (local_path 최상위)
+const IGNORE_FILE_NAME: &str = ".mdflareignore";
+
+/// Rules from `<local_path>/.mdflareignore`, gitignore style: later rules win, `!` re-includes,
+/// a trailing `/` matches folders only, and a pattern without `/` matches at any depth.
+/// Case-insensitive on macOS/Windows to match their file systems.
+#[derive(Default)]
+struct IgnoreMatcher {
+    rules: Vec<IgnoreRule>,
+    // 마지막으로 읽은 .mdflareignore의 mtime (바뀌었을 때만 다시 읽음)
+    loaded_mtime: Option<std::time::SystemTime>,
+}
+
+struct IgnoreRule {
+    matcher: globset::GlobMatcher,
+    negate: bool,
+    dir_only: bool,
+}
+
+impl IgnoreMatcher {
+    fn load(local_path: &Path) -> Self {
+        let file = local_path.join(IGNORE_FILE_NAME);
+        let Ok(text) = fs::read_to_string(&file) else {
+            return Self::default();
+        };
+        Self {
+            rules: Self::parse(&text),
+            loaded_mtime: fs::metadata(&file).and_then(|m| m.modified()).ok(),
+        }
+    }
+
+    fn parse(text: &str) -> Vec<IgnoreRule> {
+        let mut rules = Vec::new();
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negate, pattern) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                // \! \# → 문자 그대로
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let dir_only = pattern.ends_with('/');
+            let pattern = pattern.trim_end_matches('/');
+            // 중간/앞에 '/'가 있으면 최상위 기준, 없으면 모든 깊이
+            let anchored = pattern.contains('/');
+            let pattern = pattern.trim_start_matches('/');
+            if pattern.is_empty() {
+                continue;
+            }
+            let glob = if anchored { pattern.to_string() } else { format!("**/{}", pattern) };
+            match globset::GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .case_insensitive(cfg!(any(target_os = "macos", windows)))
+                .build()
+            {
+                Ok(g) => rules.push(IgnoreRule { matcher: g.compile_matcher(), negate, dir_only }),
+                Err(e) => log::warn!("{} 패턴 무시 {:?}: {}", IGNORE_FILE_NAME, line, e),
+            }
+        }
+        rules
+    }
+
+    /// Re-read the file if its mtime changed (or it appeared/disappeared). True when reloaded.
+    fn refresh(&mut self, local_path: &Path) -> bool {
+        let mtime = fs::metadata(local_path.join(IGNORE_FILE_NAME)).and_then(|m| m.modified()).ok();
+        if mtime == self.loaded_mtime {
+            return false;
+        }
+        *self = Self::load(local_path);
+        true
+    }
+
+    /// Whether vault-relative `rel` is excluded, directly or through an excluded parent folder
+    /// (like git, a file inside an excluded folder can't be re-included).
+    fn is_ignored(&self, rel: &str, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let parts: Vec<&str> = rel.split('/').filter(|p| !p.is_empty()).collect();
+        (1..parts.len()).any(|i| self.last_match(&parts[..i].join("/"), true))
+            || self.last_match(&parts.join("/"), is_dir)
+    }
+
+    fn last_match(&self, rel: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            if (!rule.dir_only || is_dir) && rule.matcher.is_match(rel) {
+                ignored = !rule.negate;
+            }
+        }
+        ignored
+    }
+}
+
+/// Files the agent syncs and the vault serves (`extensions`, `.md` by default).
+fn is_note_file(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .is_some_and(|e| extensions.iter().any(|ext| e == ext.trim_start_matches('.')))
+}
+
+/// Obsidian 설정 폴더 (vault 최상위)
+const OBSIDIAN_DIR: &str = ".obsidian";
+
+/// Whether vault-relative `rel` is an Obsidian config file synced under `mode`.
+/// Workspace state (open panes, recent files) and caches change on every click and never sync.
+fn is_obsidian_synced(rel: &str, mode: ObsidianSync) -> bool {
+    let Some(inner) = rel.strip_prefix(OBSIDIAN_DIR).and_then(|r| r.strip_prefix('/')) else {
+        return false;
+    };
+    let parts: Vec<&str> = inner.split('/').collect();
+    if parts.iter().any(|p| p.is_empty() || p.starts_with('.')) {
+        return false;
+    }
+    let volatile = matches!(parts.as_slice(), ["workspace.json" | "workspace-mobile.json"] | ["cache", ..]);
+    match mode {
+        ObsidianSync::Off => false,
+        _ if volatile => false,
+        ObsidianSync::All => true,
+        ObsidianSync::Settings => match parts.as_slice() {
+            // app.json, appearance.json, hotkeys.json, core-plugins.json, community-plugins.json ...
+            [file] => file.ends_with(".json"),
+            ["themes", ..] | ["snippets", ..] => true,
+            ["plugins", _, "data.json"] => true,
+            _ => false,
+        },
+    }
+}
+
+/// `.obsidian` files synced under `mode`, as a single folder item (empty when none).
+fn scan_obsidian_config(local_path: &Path, mode: ObsidianSync) -> Vec<FileItem> {
+    fn scan_dir(dir: &Path, base: &Path, mode: ObsidianSync) -> Vec<FileItem> {
+        let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+        let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).collect();
+        entries.sort_by_key(|e| e.file_name());
+        let mut items = Vec::new();
+        for entry in entries {
+            let path = entry.path();
+            let rel = path.strip_prefix(base).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+            let name = entry.file_name().to_string_lossy().to_string();
+            if path.is_dir() {
+                let children = scan_dir(&path, base, mode);
+                if !children.is_empty() {
+                    items.push(FileItem {
+                        name,
+                        path: rel,
+                        file_type: "folder".to_string(),
+                        size: None,
+                        modified: None,
+                        children: Some(children),
+                        has_more: None,
+                    });
+                }
+            } else if is_obsidian_synced(&rel, mode) {
+                let metadata = fs::metadata(&path).ok();
+                items.push(FileItem {
+                    name,
+                    path: rel,
+                    file_type: "file".to_string(),
+                    size: metadata.as_ref().map(|m| m.len()),
+                    modified: metadata
+                        .and_then(|m| m.modified().ok())
+                        .map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339()),
+                    children: None,
+                    has_more: None,
+                });
+            }
+        }
+        items
+    }
+
+    if mode == ObsidianSync::Off {
+        return Vec::new();
+    }
+    let children = scan_dir(&local_path.join(OBSIDIAN_DIR), local_path, mode);
+    if children.is_empty() {
+        return Vec::new();
+    }
+    vec![FileItem {
+        name: OBSIDIAN_DIR.to_string(),
+        path: OBSIDIAN_DIR.to_string(),
+        file_type: "folder".to_string(),
+        size: None,
+        modified: None,
+        children: Some(children),
+        has_more: None,
+    }]
+}
+
+fn scan_local_md_files(local_path: &Path, include_hidden: bool, extensions: &[String]) -> Vec<FileItem> {
+    fn scan_dir(dir: &Path, base: &Path, include_hidden: bool, extensions: &[String], ignore: &IgnoreMatcher) -> Vec<FileItem> {
+        let mut items = Vec::new();
+        
+        if let Ok(entries) = fs::read_dir(dir) {
+            let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).collect();
+            entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+            
+            for entry in entries {
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().to_string();
+                
+                // 숨김 파일/폴더 스킵 (include_hidden 설정 시 무시 목록만 스킵)
+                if is_ignored_name(&name, include_hidden) {
+                    continue;
+                }
+                // .mdflareignore 규칙
+                let rel = path.strip_prefix(base).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+                if ignore.is_ignored(&rel, path.is_dir()) {
+                    continue;
+                }
+                
+                if path.is_dir() {
+                    let children = scan_dir(&path, base, include_hidden, extensions, ignore);
+                    if !children.is_empty() || has_md_files(&path, base, include_hidden, extensions, ignore) {
+                        let rel_path = path.strip_prefix(base).unwrap_or(&path);
+                        items.push(FileItem {
+                            name,
+                            path: rel_path.to_string_lossy().replace('\\', "/"),
+                            file_type: "folder".to_string(),
+                            size: None,
+                            modified: None,
+                            children: Some(children),
+                            has_more: None,
+                        });
+                    }
+                } else if is_note_file(&path, extensions) {
+                    let rel_path = path.strip_prefix(base).unwrap_or(&path);
+                    let metadata = fs::metadata(&path).ok();
+                    items.push(FileItem {
+                        name,
+                        path: rel_path.to_string_lossy().replace('\\', "/"),
+                        file_type: "file".to_string(),
+                        size: metadata.as_ref().map(|m| m.len()),
+                        modified: metadata.and_then(|m| {
+                            m.modified().ok().map(|t| {
+                                let datetime: chrono::DateTime<chrono::Utc> = t.into();
+                                datetime.to_rfc3339()
+                            })
+                        }),
+                        children: None,
+                        has_more: None,
+                    });
+                }
+            }
+        }
+        
+        // 폴더 먼저, 그 다음 파일
+        items.sort_by(|a, b| {
+            match (&a.file_type[..], &b.file_type[..]) {
+                ("folder", "file") => std::cmp::Ordering::Less,
+                ("file", "folder") => std::cmp::Ordering::Greater,
+                _ => a.name.cmp(&b.name),
+            }
+        });
+        
+        items
+    }
+    
+    fn has_md_files(dir: &Path, base: &Path, include_hidden: bool, extensions: &[String], ignore: &IgnoreMatcher) -> bool {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if is_ignored_name(&entry.file_name().to_string_lossy(), include_hidden) {
+                    continue;
+                }
+                let path = entry.path();
+                let rel = path.strip_prefix(base).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+                if ignore.is_ignored(&rel, path.is_dir()) {
+                    continue;
+                }
+                if path.is_file() && is_note_file(&path, extensions) {
+                    return true;
+                }
+                if path.is_dir() && has_md_files(&path, base, include_hidden, extensions, ignore) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    
+    // 스캔마다 한 번 읽음
+    let ignore = IgnoreMatcher::load(local_path);
+    scan_dir(local_path, local_path, include_hidden, extensions, &ignore)
+}
+
+/// Fill folder `size`/`modified` with the total size and newest mtime of their contents.
+/// Returns the aggregate for `items` itself.
+fn aggregate_folder_stats(items: &mut [FileItem]) -> (u64, Option<String>) {
+    let mut total = 0;
+    let mut newest: Option<String> = None;
+    for item in items.iter_mut() {
+        if let Some(children) = item.children.as_mut() {
+            let (size, modified) = aggregate_folder_stats(children);
+            item.size = Some(size);
+            item.modified = modified;
+        }
+        total += item.size.unwrap_or(0);
+        // 모두 UTC RFC3339 → 문자열 비교로 최신 판단 가능
+        if item.modified > newest {
+            newest = item.modified.clone();
+        }
+    }
+    (total, newest)
+}
+
+/// Windows: add the `\\?\` extended-length prefix to paths over MAX_PATH (260).
+fn long_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        let s = path.to_string_lossy();
+        if s.len() >= 260 && !s.starts_with(r"\\?\") && path.is_absolute() {
+            return PathBuf::from(format!(r"\\?\{}", s.replace('/', "\\")));
+        }
+    }
+    path.to_path_buf()
+}
+
+/// `<name>.conflict-<ts>.<ext>` next to `local_file`.
+fn conflict_copy_path(local_file: &Path) -> PathBuf {
+    let stem = local_file.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = local_file.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_else(|| "md".to_string());
+    local_file.with_file_name(format!("{}.conflict-{}.{}", stem, chrono::Local::now().format("%Y%m%d-%H%M%S"), ext))
+}
+
+/// Write a downloaded note, creating parent folders. A path that is still too long
+/// for the OS gets a notification naming it so the user can shorten it.
+/// The write goes to a temp file renamed over the target, so a failure (disk full,
+/// permissions) leaves the existing note untouched instead of half-written.
+fn write_local_file(path: &Path, content: &str) -> std::io::Result<()> {
+    let target = long_path(path);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    let result = write_atomic(&target, content);
+    if let Err(e) = &result {
+        // ERROR_PATH_NOT_FOUND(3) / ERROR_FILENAME_EXCED_RANGE(206), ENAMETOOLONG(36/63)
+        let too_long = match e.raw_os_error() {
+            Some(code) if cfg!(windows) => code == 206 || (code == 3 && path.as_os_str().len() >= 260),
+            Some(code) => code == 36 || code == 63,
+            None => false,
+        };
+        if too_long {
+            notify_user(NotifyKind::SyncError, &format!("⚠️ 경로가 너무 길어 저장할 수 없습니다. 이름을 줄여 주세요: {}", path.display()));
+        }
+    }
+    result
+}
+
+fn write_atomic(target: &Path, content: &str) -> std::io::Result<()> {
+    let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    // 숨김 + .md 아님 → 스캐너/감시 대상 아님
+    let tmp = target.with_file_name(format!(".{}.mdflare-tmp", name));
+    let result = fs::write(&tmp, content).and_then(|()| {
+        if let Ok(meta) = fs::metadata(target) {
+            fs::set_permissions(&tmp, meta.permissions()).ok();
+        }
+        fs::rename(&tmp, target)
+    });
+    if result.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    result
+}
+
+fn flatten_file_paths(items: &[FileItem]) -> Vec<String> {
+    let mut result = Vec::new();
+    for item in items {
+        if item.file_type == "folder" {
+            if let Some(children) = &item.children {
+                result.extend(flatten_file_paths(children));
+            }
+        } else {
+            result.push(item.path.clone());
+        }
+    }
+    result
+}
+
+// ============================================================================
+// Private Vault Server
+// ============================================================================
+
+#[derive(Clone)]
+struct ServerState {
+    local_path: PathBuf,
+    extensions: Vec<String>,
+    // 원문 토큰 (요청 서명 검증용 - 해시 모드에서 키체인을 못 읽으면 비어 있음)
+    token: String,
+    // hash_server_token: 인증은 이 해시로 비교
+    token_hash: String,
+    include_hidden: bool,
+    safe_filenames_only: bool,
+    require_signed_requests: bool,
+    follow_file_symlinks: bool,
+    // 목록에 .obsidian 설정 파일 포함 (연결 모드 기기와 설정 동기화)
+    obsidian_sync: ObsidianSync,
+    write_queue: VaultWriteQueue,
+    // 서명 요청에서 이미 본 nonce → timestamp (창 밖으로 밀려나면 정리)
+    seen_nonces: Arc<Mutex<HashMap<String, i64>>>,
+    link_index: LinkIndex,
+}
+
+/// 노트 경로 → (mtime, 나가는 링크) - 바뀐 노트만 다시 읽음 (backlinks)
+type LinkIndex = Arc<Mutex<HashMap<String, (std::time::SystemTime, Vec<NoteLink>)>>>;
+
+type PathLocks = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Gate for vault filesystem writes: at most N run at once, and writes to the same path
+/// run in arrival order (tokio's Mutex is FIFO).
+#[derive(Clone)]
+struct VaultWriteQueue {
+    // None = 동시 실행 제한 없음
+    slots: Option<Arc<tokio::sync::Semaphore>>,
+    reject_when_busy: bool,
+    path_locks: PathLocks,
+}
+
+/// Held for the duration of one write; frees the slot and the path locks on drop.
+struct VaultWriteGuard {
+    _permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    path_guards: Vec<(String, tokio::sync::OwnedMutexGuard<()>)>,
+    path_locks: PathLocks,
+}
+
+impl Drop for VaultWriteGuard {
+    fn drop(&mut self) {
+        let keys: Vec<String> = self.path_guards.drain(..).map(|(key, _)| key).collect();
+        if let Ok(mut locks) = self.path_locks.lock() {
+            // 기다리는 요청이 없는 경로 잠금은 정리
+            for key in keys {
+                if locks.get(&key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+                    locks.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl VaultWriteQueue {
+    fn new(concurrency: usize, reject_when_busy: bool) -> Self {
+        Self {
+            slots: (concurrency > 0).then(|| Arc::new(tokio::sync::Semaphore::new(concurrency))),
+            reject_when_busy,
+            path_locks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Wait behind earlier writes to the same paths, then for a free slot. With
+    /// `reject_when_busy`, a full queue is a 503 instead of a wait.
+    async fn acquire(&self, paths: &[&str]) -> Result<VaultWriteGuard, StatusCode> {
+        let mut keys: Vec<String> = paths.iter().map(|p| p.trim_matches('/').to_string()).collect();
+        // 항상 같은 순서로 잠가서 rename(a→b)과 rename(b→a)가 서로 기다리지 않게
+        keys.sort();
+        keys.dedup();
+        let mut guard = VaultWriteGuard { _permit: None, path_guards: Vec::new(), path_locks: self.path_locks.clone() };
+        for key in keys {
+            let lock = {
+                let mut locks = self.path_locks.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                locks.entry(key.clone()).or_default().clone()
+            };
+            guard.path_guards.push((key, lock.lock_owned().await));
+        }
+        if let Some(slots) = &self.slots {
+            let permit = if self.reject_when_busy {
+                slots.clone().try_acquire_owned().ok()
+            } else {
+                slots.clone().acquire_owned().await.ok()
+            };
+            guard._permit = Some(permit.ok_or_else(|| {
+                log_to_file("vault: write queue full → 503");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?);
+        }
+        Ok(guard)
+    }
+}
+
+/// First path component that isn't a portable filename, with the reason.
+fn invalid_path_component(path: &str) -> Option<(String, &'static str)> {
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let reason = if component == "." || component == ".." {
+            Some("relative component")
+        } else if component.chars().any(char::is_control) {
+            Some("control character")
+        } else if component.starts_with(' ') || component.ends_with(' ') {
+            Some("leading or trailing space")
+        } else if component.ends_with('.') {
+            Some("trailing dot")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Some((component.to_string(), reason));
+        }
+    }
+    None
+}
+
+/// Why a vault rename would create or move a non-note file, if it would.
+/// Folder renames ignore the same names the scanner skips (e.g. `.DS_Store`).
+fn rename_forbidden_reason(old_path: &Path, new_path: &Path, include_hidden: bool, extensions: &[String]) -> Option<String> {
+    if old_path.is_dir() {
+        let outsider = walkdir::WalkDir::new(old_path)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|e| !is_ignored_name(&e.file_name().to_string_lossy(), include_hidden))
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_type().is_file() && !is_note_file(e.path(), extensions))?;
+        let rel = outsider.path().strip_prefix(old_path).unwrap_or(outsider.path());
+        return Some(format!("folder contains a non-note file: {}", rel.display()));
+    }
+    if !is_note_file(old_path, extensions) {
+        return Some("source is not a note file".to_string());
+    }
+    if !is_note_file(new_path, extensions) {
+        return Some(format!("target must keep a note extension ({})", extensions.join(", ")));
+    }
+    None
+}
+
+/// Why a vault folder can't be deleted over WebDAV: it holds something besides notes and
+/// folders, including names the scanner skips (those were never synced, so nothing could restore them).
+fn folder_delete_forbidden_reason(dir: &Path, include_hidden: bool, extensions: &[String]) -> Option<String> {
+    for entry in walkdir::WalkDir::new(dir).min_depth(1) {
+        let Ok(entry) = entry else {
+            return Some("folder has an unreadable entry".to_string());
+        };
+        let rel = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        let note = entry.file_type().is_file() && is_note_file(entry.path(), extensions);
+        if is_ignored_path(rel, include_hidden) || !(entry.file_type().is_dir() || note) {
+            return Some(format!("folder contains a non-note or ignored entry: {}", rel.display()));
+        }
+    }
+    None
+}
+
+/// Delete a folder cleared by `folder_delete_forbidden_reason`: its note files, then the
+/// emptied folders. Anything else that appeared meanwhile makes `remove_dir` fail instead.
+fn remove_note_folder(dir: &Path, extensions: &[String]) -> std::io::Result<()> {
+    for entry in walkdir::WalkDir::new(dir).contents_first(true) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            fs::remove_dir(entry.path())?;
+        } else if entry.file_type().is_file() && is_note_file(entry.path(), extensions) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// 400 with the offending component when `safe_filenames_only` is on.
+fn check_safe_path(state: &ServerState, path: &str) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if !state.safe_filenames_only {
+        return Ok(());
+    }
+    match invalid_path_component(path) {
+        Some((component, reason)) => {
+            let body = Json(serde_json::json!({
+                "error": "invalid path component",
+                "component": component,
+                "reason": reason,
+            }));
+            Err((StatusCode::BAD_REQUEST, body))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Vault-relative API path → path inside `local_path` (403 otherwise).
+/// Rejects `..`/absolute components and paths whose existing parent resolves outside the
+/// vault through a directory symlink. A symlinked file is treated as out-of-vault unless
+/// `follow_file_symlinks` is on, and even then its canonical target must stay inside the
+/// vault, so a planted link can't turn into arbitrary read/write.
+fn resolve_vault_path(state: &ServerState, rel: &str) -> Result<PathBuf, StatusCode> {
+    use std::path::Component;
+    let rel_path = Path::new(rel);
+    if rel_path.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let full = state.local_path.join(rel_path);
+    let root = state.local_path.canonicalize().map_err(|_| StatusCode::NOT_FOUND)?;
+    // 가장 가까운 기존 상위 폴더를 실제 경로로 풀어서 확인 (아직 없는 폴더는 PUT에서 생성됨)
+    let mut ancestor = full.parent();
+    while let Some(dir) = ancestor {
+        if let Ok(real) = dir.canonicalize() {
+            if !real.starts_with(&root) {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            break;
+        }
+        ancestor = dir.parent();
+    }
+    let is_link = fs::symlink_metadata(&full).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    if is_link && !state.follow_file_symlinks {
+        log_to_file(&format!("vault: symlink rejected ({})", rel));
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if is_link {
+        // 대상이 없는 링크에 PUT하면 링크를 따라 vault 밖에 파일이 생김 → 거부
+        let escapes = full.canonicalize().map(|real| !real.starts_with(&root)).unwrap_or(true);
+        if escapes {
+            log_to_file(&format!("vault: symlink escapes vault ({})", rel));
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+    Ok(full)
+}
+
+async fn check_auth(
+    state: &ServerState,
+    auth_header: Option<&str>,
+) -> Result<(), StatusCode> {
+    match auth_header {
+        Some(h) if h.starts_with("Bearer ") => {
+            let token = &h[7..];
+            if server_token_matches(state, token) {
+                Ok(())
+            } else {
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Check a presented vault token against the stored hash (`hash_server_token`) or the plaintext token.
+fn server_token_matches(state: &ServerState, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    if state.token_hash.is_empty() {
+        constant_time_eq(token, &state.token)
+    } else {
+        verify_server_token_hash(&state.token_hash, token)
+    }
+}
+
+const SIGNED_TIMESTAMP_HEADER: &str = "x-mdflare-timestamp";
+const SIGNED_NONCE_HEADER: &str = "x-mdflare-nonce";
+const SIGNED_SIGNATURE_HEADER: &str = "x-mdflare-signature";
+/// 서명 timestamp 허용 오차 (초)
+const SIGNED_REQUEST_WINDOW_SECS: i64 = 60;
+
+/// Middleware for `require_signed_requests`: the signature must match method, path+query,
+/// timestamp and nonce under the vault token, the timestamp must be within the window, and
+/// each nonce is accepted once. Rejections are 401 with a JSON reason.
+async fn verify_signed_request(
+    State(state): State<ServerState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if !state.require_signed_requests || req.method() == Method::OPTIONS {
+        return next.run(req).await;
+    }
+    let reject = |reason: &str| {
+        let body = Json(serde_json::json!({ "error": "signature required", "reason": reason }));
+        (StatusCode::UNAUTHORIZED, body).into_response()
+    };
+    // Request(Body)는 Sync가 아님 → 참조를 await 너머로 들고 가지 않도록 별도 함수에서 검사
+    if let Err(reason) = check_request_signature(&state, &req) {
+        return reject(reason);
+    }
+    next.run(req).await
+}
+
+fn check_request_signature(state: &ServerState, req: &axum::extract::Request) -> Result<(), &'static str> {
+    let header_str = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let (Some(timestamp), Some(nonce), Some(signature)) = (
+        header_str(SIGNED_TIMESTAMP_HEADER),
+        header_str(SIGNED_NONCE_HEADER),
+        header_str(SIGNED_SIGNATURE_HEADER),
+    ) else {
+        return Err("missing signature headers");
+    };
+    if state.token.is_empty() {
+        // 해시 모드에서 키체인의 원문 토큰을 못 읽음 → 서명 키가 없음
+        return Err("server token unavailable");
+    }
+    let ts: i64 = timestamp.parse().map_err(|_| "bad timestamp")?;
+    let now = chrono::Utc::now().timestamp();
+    if (now - ts).abs() > SIGNED_REQUEST_WINDOW_SECS {
+        return Err("timestamp outside window");
+    }
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let message = signed_request_message(req.method().as_str(), path, timestamp, nonce);
+    if !verify_hmac_sha256_hex(state.token.as_bytes(), message.as_bytes(), signature) {
+        return Err("bad signature");
+    }
+    let mut seen = state.seen_nonces.lock().unwrap();
+    // 창 밖 nonce는 timestamp 검사에서 이미 걸러지므로 기억할 필요 없음
+    seen.retain(|_, t| (now - *t).abs() <= SIGNED_REQUEST_WINDOW_SECS);
+    if seen.insert(nonce.to_string(), ts).is_some() {
+        log_to_file(&format!("vault: replayed request rejected ({} {})", req.method(), path));
+        return Err("nonce already used");
+    }
+    Ok(())
+}
+
+async fn api_list_files(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await?;
+    let mut files = scan_local_md_files(&state.local_path, state.include_hidden, &state.extensions);
+    files.splice(0..0, scan_obsidian_config(&state.local_path, state.obsidian_sync));
+
+    // ?path=sub/dir → 해당 폴더의 하위 항목만 (지연 로딩용)
+    if let Some(scope) = params.get("path").map(|p| p.trim_matches('/')).filter(|p| !p.is_empty()) {
+        files = find_folder_children(files, scope).ok_or(StatusCode::NOT_FOUND)?;
+    }
+    // ?depth=N → N단계까지만, 잘린 폴더는 has_more: true
+    if let Some(depth) = params.get("depth") {
+        let depth: usize = depth.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+        truncate_file_tree(&mut files, depth.max(1));
+    }
+
+    let mut body = serde_json::to_value(FilesResponse {
+        user: "local".to_string(),
+        files,
+    })
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // ?fields=name,path → 요청한 필드만 남김 (경량 클라이언트용)
+    if let Some(fields) = params.get("fields") {
+        let fields: Vec<&str> = fields.split(',').map(str::trim).collect();
+        if let Some(files) = body.get_mut("files").and_then(|f| f.as_array_mut()) {
+            project_file_items(files, &fields);
+        }
+    }
+    Ok(Json(body))
+}
+
+/// `modified` as sent by any server: a string, or an epoch number (kept as its decimal text).
+fn de_modified<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Ok(match Option::<serde_json::Value>::deserialize(d)? {
+        Some(serde_json::Value::String(s)) => Some(s),
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    })
+}
+
+/// Optional string where `""` (e.g. a rename record from an older server) means absent.
+fn de_non_empty<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(d)?.filter(|s| !s.is_empty()))
+}
+
+/// Remote `modified` → epoch ms. Accepts RFC3339, `YYYY-MM-DD HH:MM:SS` (UTC) and epoch seconds/millis.
+fn parse_modified(raw: &str) -> Option<i64> {
+    let raw = raw.trim();
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(raw) {
+        return Some(t.timestamp_millis());
+    }
+    if let Ok(t) = chrono::NaiveDateTime::parse_from_str(raw, "%Y-%m-%d %H:%M:%S") {
+        return Some(t.and_utc().timestamp_millis());
+    }
+    let n: f64 = raw.parse().ok()?;
+    // 1e11 미만은 초 단위 (ms로는 1973년 이전이라 실제로 나오지 않음)
+    Some(if n.abs() < 1e11 { (n * 1000.0) as i64 } else { n as i64 })
+}
+
+/// 같은 시각으로 볼 차이 (초 단위로만 주는 서버 대비)
+const MODIFIED_TOLERANCE_MS: i64 = 1000;
+
+fn find_folder_children(items: Vec<FileItem>, path: &str) -> Option<Vec<FileItem>> {
+    for item in items {
+        if item.file_type != "folder" {
+            continue;
+        }
+        if item.path == path {
+            return item.children;
+        }
+        if path.starts_with(&format!("{}/", item.path)) {
+            return find_folder_children(item.children.unwrap_or_default(), path);
+        }
+    }
+    None
+}
+
+fn truncate_file_tree(items: &mut [FileItem], depth: usize) {
+    for item in items {
+        if let Some(children) = item.children.as_mut() {
+            if depth <= 1 {
+                if !children.is_empty() {
+                    children.clear();
+                    item.has_more = Some(true);
+                }
+            } else {
+                truncate_file_tree(children, depth - 1);
+            }
+        }
+    }
+}
+
+/// Keep only `fields` on each serialized FileItem. `children` is always kept
+/// so the tree shape survives the projection.
+fn project_file_items(items: &mut [serde_json::Value], fields: &[&str]) {
+    for item in items {
+        if let Some(obj) = item.as_object_mut() {
+            obj.retain(|key, _| key == "children" || fields.contains(&key.as_str()));
+            if let Some(children) = obj.get_mut("children").and_then(|c| c.as_array_mut()) {
+                project_file_items(children, fields);
+            }
+        }
+    }
+}
+
+/// GET /api/stat-tree - like /api/files, with aggregate folder size/mtime
+async fn api_stat_tree(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<FilesResponse>, StatusCode> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await?;
+    let mut files = scan_local_md_files(&state.local_path, state.include_hidden, &state.extensions);
+    aggregate_folder_stats(&mut files);
+    Ok(Json(FilesResponse {
+        user: "local".to_string(),
+        files,
+    }))
+}
+
+async fn api_get_file(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    AxumPath(path): AxumPath<String>,
+) -> Result<Json<FileContent>, StatusCode> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await?;
+    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path);
+    let file_path = resolve_vault_path(&state, &decoded)?;
+    
+    // 폴더는 파일로 읽을 수 없음
+    if file_path.is_dir() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
+    let bytes = fs::read(&file_path).map_err(|_| StatusCode::NOT_FOUND)?;
+    let metadata = fs::metadata(&file_path).map_err(|_| StatusCode::NOT_FOUND)?;
+    
+    let modified: chrono::DateTime<chrono::Utc> = metadata.modified()
+        .map(|t| t.into())
+        .unwrap_or_else(|_| chrono::Utc::now());
+    
+    let size = bytes.len() as u64;
+    // 첨부파일 등 바이너리는 실패 대신 base64로 전달
+    let (content, encoding) = match String::from_utf8(bytes) {
+        Ok(text) => (text, None),
+        Err(e) => {
+            use base64::{Engine as _, engine::general_purpose::STANDARD};
+            (STANDARD.encode(e.into_bytes()), Some("base64".to_string()))
+        }
+    };
+    
+    Ok(Json(FileContent {
+        path: decoded.to_string(),
+        content,
+        size,
+        modified: modified.to_rfc3339(),
+        encoding,
+    }))
+}
+
+/// Raw file bytes with `Range: bytes=N-` support, so large downloads can resume.
+/// `X-Content-Sha256` is the hash of the whole file for verifying the resumed result.
+async fn api_get_raw(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    AxumPath(path): AxumPath<String>,
+) -> Result<Response, StatusCode> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await?;
+    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path);
+    let file_path = resolve_vault_path(&state, &decoded)?;
+    if file_path.is_dir() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let bytes = fs::read(&file_path).map_err(|_| StatusCode::NOT_FOUND)?;
+    let total = bytes.len();
+    let hash = (axum::http::HeaderName::from_static("x-content-sha256"), sha256_hex(&bytes));
+    let accept = (header::ACCEPT_RANGES, "bytes".to_string());
+    // "bytes=N-" 형식만 지원 (이어받기 용도)
+    let start = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|r| r.strip_prefix("bytes="))
+        .and_then(|r| r.strip_suffix('-'))
+        .and_then(|n| n.parse::<usize>().ok());
+    let response = match start {
+        Some(start) if start >= total => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", total)), accept],
+        )
+            .into_response(),
+        Some(start) => (
+            StatusCode::PARTIAL_CONTENT,
+            [(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, total - 1, total)), accept, hash],
+            bytes[start..].to_vec(),
+        )
+            .into_response(),
+        None => (StatusCode::OK, [accept, hash], bytes).into_response(),
+    };
+    Ok(response)
+}
+
+async fn api_put_file(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    AxumPath(path): AxumPath<String>,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, Response> {
+    // 인증 체크
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await.map_err(IntoResponse::into_response)?;
+    let body: PutFileRequest = decode_json_body(&headers, &body).map_err(IntoResponse::into_response)?;
+    
+    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path.clone());
+    check_safe_path(&state, &decoded).map_err(IntoResponse::into_response)?;
+    store_vault_file(&state, &headers, &decoded, &body.content).await
+}
+
+/// Gzipped request bodies are expanded up to this size.
+const MAX_DECOMPRESSED_BODY: u64 = 64 * 1024 * 1024;
+
+/// JSON request body, optionally `Content-Encoding: gzip` (`compress_uploads` clients).
+/// Other encodings get 415 so the client can resend uncompressed.
+fn decode_json_body<T: serde::de::DeserializeOwned>(headers: &axum::http::HeaderMap, body: &[u8]) -> Result<T, StatusCode> {
+    let encoding = headers.get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()).unwrap_or("identity");
+    let json = match encoding.trim().to_ascii_lowercase().as_str() {
+        "identity" | "" => body.to_vec(),
+        "gzip" => {
+            use std::io::Read;
+            let mut out = Vec::new();
+            flate2::read::GzDecoder::new(body)
+                .take(MAX_DECOMPRESSED_BODY + 1)
+                .read_to_end(&mut out)
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            if out.len() as u64 > MAX_DECOMPRESSED_BODY {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            out
+        }
+        _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    };
+    serde_json::from_slice(&json).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// Write a note for PUT / chunk finalize (honours `If-None-Match: *`).
+async fn store_vault_file(
+    state: &ServerState,
+    headers: &axum::http::HeaderMap,
+    decoded: &str,
+    content: &str,
+) -> Result<Json<serde_json::Value>, Response> {
+    let file_path = resolve_vault_path(state, decoded).map_err(IntoResponse::into_response)?;
+    let _write = state.write_queue.acquire(&[decoded]).await.map_err(IntoResponse::into_response)?;
+    
+    // 같은 이름의 폴더가 있으면 쓰기 불가
+    if file_path.is_dir() {
+        let body = Json(serde_json::json!({ "error": "path is a directory" }));
+        return Err((StatusCode::CONFLICT, body).into_response());
+    }
+    
+    // 조건부 생성 (If-None-Match: *): 이미 있으면 412 → 먼저 만든 기기가 우선
+    let if_none_match = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok());
+    if if_none_match == Some("*") && file_path.exists() {
+        return Err(StatusCode::PRECONDITION_FAILED.into_response());
+    }
+    
+    // 상위 폴더 생성
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    }
+    
+    fs::write(&file_path, content).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    
+    Ok(Json(serde_json::json!({
+        "path": decoded.to_string(),
+        "saved": true,
+        "size": content.len()
+    })))
+}
+
+/// Upper bound on pieces per chunked upload.
+const MAX_UPLOAD_CHUNKS: usize = 10_000;
+/// Staged chunks of uploads that were never finalized are dropped after this long.
+const STALE_CHUNK_SECS: u64 = 3600;
+
+#[derive(Deserialize)]
+struct ChunkQuery {
+    id: String,
+    #[serde(default)]
+    index: usize,
+    total: usize,
+}
+
+#[derive(Deserialize)]
+struct FinalizeRequest {
+    sha256: Option<String>,
+}
+
+/// Staging folder for one chunked upload (config dir, outside the vault).
+fn chunk_staging_dir(path: &str, id: &str) -> PathBuf {
+    agent_config_dir().join("chunks").join(sha256_hex(format!("{}|{}", path, id).as_bytes()))
+}
+
+fn prune_stale_chunks() {
+    let Ok(entries) = fs::read_dir(agent_config_dir().join("chunks")) else { return };
+    for entry in entries.flatten() {
+        let stale = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age.as_secs() > STALE_CHUNK_SECS);
+        if stale {
+            fs::remove_dir_all(entry.path()).ok();
+        }
+    }
+}
+
+/// GET /api/capabilities - optional protocol features this vault understands.
+async fn api_capabilities(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await?;
+    Ok(Json(serde_json::json!({ "chunkedUpload": true, "gzipUpload": true })))
+}
+
+/// POST /api/file/{path}/chunk?id=&index=&total= stores one piece of a large upload;
+/// POST /api/file/{path}/finalize?id=&total= reassembles the pieces in order, checks the
+/// optional `sha256` and writes the note like PUT. For proxies with a small body limit.
+async fn api_post_file(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    AxumPath(path): AxumPath<String>,
+    Query(query): Query<ChunkQuery>,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, Response> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await.map_err(IntoResponse::into_response)?;
+
+    let (target, finalize) = if let Some(target) = path.strip_suffix("/chunk") {
+        (target, false)
+    } else if let Some(target) = path.strip_suffix("/finalize") {
+        (target, true)
+    } else {
+        return Err(StatusCode::METHOD_NOT_ALLOWED.into_response());
+    };
+    let decoded = urlencoding::decode(target).map(|s| s.into_owned()).unwrap_or(target.to_string());
+    check_safe_path(&state, &decoded).map_err(IntoResponse::into_response)?;
+    resolve_vault_path(&state, &decoded).map_err(IntoResponse::into_response)?;
+    let valid_id = !query.id.is_empty() && query.id.len() <= 64 && query.id.chars().all(|c| c.is_ascii_alphanumeric());
+    if !valid_id || query.total == 0 || query.total > MAX_UPLOAD_CHUNKS {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    let staging = chunk_staging_dir(&decoded, &query.id);
+
+    if !finalize {
+        if query.index >= query.total {
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+        if query.index == 0 {
+            prune_stale_chunks();
+        }
+        fs::create_dir_all(&staging).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+        fs::write(staging.join(query.index.to_string()), &body)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+        return Ok(Json(serde_json::json!({ "path": decoded, "index": query.index, "received": true })));
+    }
+
+    let mut content = Vec::new();
+    for index in 0..query.total {
+        match fs::read(staging.join(index.to_string())) {
+            Ok(part) => content.extend_from_slice(&part),
+            Err(_) => {
+                let body = Json(serde_json::json!({ "error": "missing chunk", "index": index }));
+                return Err((StatusCode::BAD_REQUEST, body).into_response());
+            }
+        }
+    }
+    fs::remove_dir_all(&staging).ok();
+    let expected = serde_json::from_slice::<FinalizeRequest>(&body).ok().and_then(|b| b.sha256);
+    if expected.is_some_and(|hash| !sha256_hex(&content).eq_ignore_ascii_case(&hash)) {
+        let body = Json(serde_json::json!({ "error": "sha256 mismatch" }));
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, body).into_response());
+    }
+    let content = String::from_utf8(content).map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    store_vault_file(&state, &headers, &decoded, &content).await
+}
+
+async fn api_delete_file(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    AxumPath(path): AxumPath<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    // 인증 체크
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await?;
+    
+    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path.clone());
+    let file_path = resolve_vault_path(&state, &decoded)?;
+    let _write = state.write_queue.acquire(&[&decoded]).await?;
+    
+    if file_path.is_dir() {
+        fs::remove_dir_all(&file_path).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    } else {
+        fs::remove_file(&file_path).map_err(|_| StatusCode::NOT_FOUND)?;
+    }
+    
+    Ok(Json(serde_json::json!({
+        "path": decoded.to_string(),
+        "deleted": true
+    })))
+}
+
+#[derive(Deserialize)]
+struct RenameRequest {
+    #[serde(rename = "oldPath")]
+    old_path: String,
+    #[serde(rename = "newPath")]
+    new_path: String,
+}
+
+async fn api_rename(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    Json(body): Json<RenameRequest>,
+) -> Result<Json<serde_json::Value>, Response> {
+    // 인증 체크
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await.map_err(IntoResponse::into_response)?;
+    
+    let old_decoded = urlencoding::decode(&body.old_path).map(|s| s.into_owned()).unwrap_or(body.old_path.clone());
+    let new_decoded = urlencoding::decode(&body.new_path).map(|s| s.into_owned()).unwrap_or(body.new_path.clone());
+    // 새 이름만 검사 (기존 파일은 이름이 이상해도 고칠 수 있어야 함)
+    check_safe_path(&state, &new_decoded).map_err(IntoResponse::into_response)?;
+    
+    let old_file_path = resolve_vault_path(&state, &old_decoded).map_err(IntoResponse::into_response)?;
+    let new_file_path = resolve_vault_path(&state, &new_decoded).map_err(IntoResponse::into_response)?;
+    let _write = state
+        .write_queue
+        .acquire(&[&old_decoded, &new_decoded])
+        .await
+        .map_err(IntoResponse::into_response)?;
+    
+    // 원본 파일/폴더 존재 확인
+    if !old_file_path.exists() {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+
+    // 노트가 아닌 파일을 만들거나 옮기지 못하게 (외부 터널 노출 시 .md → .sh 등 방지)
+    if let Some(reason) = rename_forbidden_reason(&old_file_path, &new_file_path, state.include_hidden, &state.extensions) {
+        let body = Json(serde_json::json!({ "error": "rename not allowed", "reason": reason }));
+        return Err((StatusCode::FORBIDDEN, body).into_response());
+    }
+    
+    // 상위 폴더 생성
+    if let Some(parent) = new_file_path.parent() {
+        fs::create_dir_all(parent).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    }
+    
+    // 이름 변경 (파일/폴더 모두 지원)
+    fs::rename(&old_file_path, &new_file_path).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    
+    Ok(Json(serde_json::json!({
+        "renamed": true,
+        "oldPath": old_decoded,
+        "newPath": new_decoded
+    })))
+}
+
+/// One outgoing link in a note: the raw target (`[[name]]` or `](path)`) and where it is.
+#[derive(Clone)]
+struct NoteLink {
+    target: String,
+    wiki: bool,
+    line: usize,
+    text: String,
+}
+
+/// Wikilinks and relative markdown links in `content` (external URLs and `#anchors` skipped).
+fn extract_note_links(content: &str) -> Vec<NoteLink> {
+    let mut links = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let mut push = |target: &str, wiki: bool| {
+            let target = target.trim();
+            if !target.is_empty() {
+                links.push(NoteLink { target: target.to_string(), wiki, line: i + 1, text: line.trim().to_string() });
+            }
+        };
+        let mut rest = line;
+        while let Some(start) = rest.find("[[") {
+            let Some(len) = rest[start + 2..].find("]]") else { break };
+            let inner = &rest[start + 2..start + 2 + len];
+            // [[이름#제목|별칭]] → 이름
+            push(inner.split(['|', '#']).next().unwrap_or(""), true);
+            rest = &rest[start + 2 + len + 2..];
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find("](") {
+            let Some(len) = rest[start + 2..].find(')') else { break };
+            let url = rest[start + 2..start + 2 + len].trim().trim_start_matches('<').trim_end_matches('>');
+            // 제목 ("title") 제거
+            let url = url.split(" \"").next().unwrap_or(url);
+            if !url.contains("://") && !url.starts_with("mailto:") && !url.starts_with('#') {
+                let url = url.split('#').next().unwrap_or(url);
+                push(&urlencoding::decode(url).map(|s| s.into_owned()).unwrap_or_else(|_| url.to_string()), false);
+            }
+            rest = &rest[start + 2 + len + 1..];
+        }
+    }
+    links
+}
+
+/// Whether a link written in note `source` points at note `target` (vault-relative paths).
+/// Wikilinks match by file name or by path without `.md`, case-insensitively;
+/// markdown links resolve relative to `source`'s folder (`/` = vault root).
+fn link_points_to(link: &NoteLink, source: &str, target: &str) -> bool {
+    let target_lower = target.to_lowercase();
+    let target_stem = target_lower.strip_suffix(".md").unwrap_or(&target_lower);
+    if link.wiki {
+        let name = link.target.to_lowercase();
+        let name = name.strip_suffix(".md").unwrap_or(&name);
+        let file_stem = target_stem.rsplit('/').next().unwrap_or(target_stem);
+        return name == file_stem || name == target_stem;
+    }
+    let mut parts: Vec<&str> = if link.target.starts_with('/') {
+        Vec::new()
+    } else {
+        source.split('/').collect()
+    };
+    if !link.target.starts_with('/') {
+        parts.pop();
+    }
+    for part in link.target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    let resolved = parts.join("/").to_lowercase();
+    resolved == target_lower || resolved == target_stem
+}
+
+/// Refresh `link_index` from disk (only notes whose mtime changed are re-read) and
+/// return the notes linking to `target` as (source path, line, line text).
+fn find_backlinks(state: &ServerState, target: &str) -> Vec<(String, usize, String)> {
+    let Ok(mut index) = state.link_index.lock() else { return Vec::new() };
+    let mut present = std::collections::HashSet::new();
+    let notes = walkdir::WalkDir::new(&state.local_path)
+        .into_iter()
+        .filter_entry(|e| {
+            e.path()
+                .strip_prefix(&state.local_path)
+                .map(|rel| rel.as_os_str().is_empty() || !is_ignored_path(rel, state.include_hidden))
+                .unwrap_or(false)
+        })
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && is_note_file(e.path(), &state.extensions));
+    for entry in notes {
+        let Ok(rel) = entry.path().strip_prefix(&state.local_path) else { continue };
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        let Some(mtime) = entry.metadata().ok().and_then(|m| m.modified().ok()) else { continue };
+        if index.get(&rel).is_none_or(|(seen, _)| *seen != mtime) {
+            let links = fs::read_to_string(entry.path()).map(|c| extract_note_links(&c)).unwrap_or_default();
+            index.insert(rel.clone(), (mtime, links));
+        }
+        present.insert(rel);
+    }
+    // 삭제된 노트 정리
+    index.retain(|path, _| present.contains(path));
+
+    let mut backlinks: Vec<(String, usize, String)> = index
+        .iter()
+        .filter(|(source, _)| source.as_str() != target)
+        .flat_map(|(source, (_, links))| {
+            links
+                .iter()
+                .filter(|link| link_points_to(link, source, target))
+                .map(|link| (source.clone(), link.line, link.text.clone()))
+        })
+        .collect();
+    backlinks.sort();
+    backlinks
+}
+
+/// Notes that link to `path` via `[[wikilink]]` or a relative markdown link.
+async fn api_backlinks(
+    State(state): State<ServerState>,
+    headers: axum::http::HeaderMap,
+    AxumPath(path): AxumPath<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_auth(&state, auth).await?;
+    let decoded = urlencoding::decode(&path).map(|s| s.into_owned()).unwrap_or(path);
+    let file_path = resolve_vault_path(&state, &decoded)?;
+    // 노트만 (대상 파일이 아직 없어도 됨 - 끊어진 링크 확인용)
+    if !is_note_file(&file_path, &state.extensions) || file_path.is_dir() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let target = decoded.trim_matches('/').to_string();
+    let backlinks: Vec<serde_json::Value> = find_backlinks(&state, &target)
+        .into_iter()
+        .map(|(source, line, text)| serde_json::json!({ "path": source, "line": line, "text": text }))
+        .collect();
+    Ok(Json(serde_json::json!({ "path": target, "backlinks": backlinks })))
+}
+
+// ============================================================================
+// WebDAV (Private Vault, webdav_enabled)
+// ============================================================================
+
+/// WebDAV 요청 본문 상한 (PUT)
+const WEBDAV_MAX_BODY: usize = 64 * 1024 * 1024;
+const WEBDAV_ALLOW: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, MOVE";
+
+/// WebDAV auth: HTTP Basic with the server token as the password (any user name),
+/// or the same `Authorization: Bearer` header the JSON API takes.
+async fn webdav_authorized(state: &ServerState, headers: &axum::http::HeaderMap) -> bool {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    let Some(auth) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    if let Some(encoded) = auth.strip_prefix("Basic ") {
+        let Some(decoded) = STANDARD.decode(encoded.trim()).ok().and_then(|b| String::from_utf8(b).ok()) else {
+            return false;
+        };
+        return decoded
+            .split_once(':')
+            .is_some_and(|(_, password)| server_token_matches(state, password));
+    }
+    check_auth(state, Some(auth)).await.is_ok()
+}
+
+/// Vault-relative path from a `/dav/...` URL path (percent-decoded, no leading/trailing slash).
+fn webdav_rel_path(url_path: &str) -> Option<String> {
+    let rest = url_path.strip_prefix("/dav")?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let decoded = urlencoding::decode(rest).ok()?;
+    Some(decoded.trim_matches('/').to_string())
+}
+
+/// `/dav/` href for a vault-relative path, each segment percent-encoded.
+fn webdav_href(rel: &str, collection: bool) -> String {
+    let mut href = String::from("/dav/");
+    href.push_str(&rel.split('/').filter(|s| !s.is_empty()).map(|s| urlencoding::encode(s).into_owned()).collect::<Vec<_>>().join("/"));
+    if collection && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// One `<D:response>` for PROPFIND.
+fn webdav_propfind_entry(rel: &str, full: &Path) -> Option<String> {
+    let meta = fs::metadata(full).ok()?;
+    let modified: chrono::DateTime<chrono::Utc> = meta.modified().map(|t| t.into()).unwrap_or_else(|_| chrono::Utc::now());
+    let name = rel.rsplit('/').next().unwrap_or("");
+    let props = if meta.is_dir() {
+        "<D:resourcetype><D:collection/></D:resourcetype>".to_string()
+    } else {
+        format!(
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>text/markdown; charset=utf-8</D:getcontenttype>",
+            meta.len()
+        )
+    };
+    Some(format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>{}<D:getlastmodified>{}</D:getlastmodified></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        xml_escape(&webdav_href(rel, meta.is_dir())),
+        xml_escape(name),
+        props,
+        modified.format("%a, %d %b %Y %H:%M:%S GMT"),
+    ))
+}
+
+/// Mount point for WebDAV clients (`/dav`). Reuses the vault guards: token auth,
+/// `resolve_vault_path` (traversal/symlinks), `safe_filenames_only` and the note-only
+/// rule, so only `.md` files and folders are visible or writable.
+async fn webdav_handler(State(state): State<ServerState>, req: axum::extract::Request) -> Response {
+    let method = req.method().as_str().to_string();
+    let headers = req.headers().clone();
+    let Some(rel) = webdav_rel_path(req.uri().path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if method == "OPTIONS" {
+        return (StatusCode::OK, [("DAV", "1"), ("Allow", WEBDAV_ALLOW), ("MS-Author-Via", "DAV")]).into_response();
+    }
+    if !webdav_authorized(&state, &headers).await {
+        return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"MDFlare\"")]).into_response();
+    }
+    let body = match axum::body::to_bytes(req.into_body(), WEBDAV_MAX_BODY).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    let full = match resolve_vault_path(&state, &rel) {
+        Ok(full) => full,
+        // 루트(/dav/)는 resolve_vault_path 대상이 아님
+        Err(_) if rel.is_empty() => state.local_path.clone(),
+        Err(status) => return status.into_response(),
+    };
+    let hidden = !rel.is_empty() && is_ignored_path(Path::new(&rel), state.include_hidden);
+    if hidden {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match method.as_str() {
+        "PROPFIND" => {
+            if !full.is_dir() && !is_note_file(&full, &state.extensions) {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            let Some(own) = webdav_propfind_entry(&rel, &full) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let mut responses = vec![own];
+            // Depth: 0 → 자기 자신만, 그 외(1/infinity)는 한 단계만
+            let depth0 = headers.get("depth").and_then(|v| v.to_str().ok()) == Some("0");
+            if full.is_dir() && !depth0 {
+                let mut entries: Vec<_> = fs::read_dir(&full).map(|d| d.filter_map(|e| e.ok()).collect()).unwrap_or_default();
+                entries.sort_by_key(|e| e.file_name());
+                for entry in entries {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    let path = entry.path();
+                    if is_ignored_name(&name, state.include_hidden) || (!path.is_dir() && !is_note_file(&path, &state.extensions)) {
+                        continue;
+                    }
+                    let child = if rel.is_empty() { name } else { format!("{}/{}", rel, name) };
+                    responses.extend(webdav_propfind_entry(&child, &path));
+                }
+            }
+            let xml = format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+                responses.concat()
+            );
+            (
+                StatusCode::MULTI_STATUS,
+                [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+                xml,
+            )
+                .into_response()
+        }
+        "GET" | "HEAD" => {
+            if full.is_dir() {
+                return (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, WEBDAV_ALLOW)]).into_response();
+            }
+            if !is_note_file(&full, &state.extensions) {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            match fs::read(&full) {
+                Ok(bytes) if method == "HEAD" => (
+                    StatusCode::OK,
+                    [(header::CONTENT_LENGTH, bytes.len().to_string())],
+                )
+                    .into_response(),
+                Ok(bytes) => (StatusCode::OK, [(header::CONTENT_TYPE, "text/markdown; charset=utf-8")], bytes).into_response(),
+                Err(_) => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        "PUT" => {
+            if let Err(e) = check_safe_path(&state, &rel) {
+                return e.into_response();
+            }
+            let _write = match state.write_queue.acquire(&[&rel]).await {
+                Ok(guard) => guard,
+                Err(status) => return status.into_response(),
+            };
+            // 노트가 아닌 파일은 만들 수 없음 (JSON API의 rename 규칙과 동일)
+            if !is_note_file(&full, &state.extensions) {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            if full.is_dir() {
+                return StatusCode::CONFLICT.into_response();
+            }
+            let existed = full.exists();
+            if let Some(parent) = full.parent() {
+                if fs::create_dir_all(parent).is_err() {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+            match fs::write(&full, &body) {
+                Ok(()) if existed => StatusCode::NO_CONTENT.into_response(),
+                Ok(()) => StatusCode::CREATED.into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        }
+        "DELETE" => {
+            if rel.is_empty() {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            let _write = match state.write_queue.acquire(&[&rel]).await {
+                Ok(guard) => guard,
+                Err(status) => return status.into_response(),
+            };
+            let result = if full.is_dir() {
+                // 노트 폴더만 지움 - 첨부/숨김 파일이 섞여 있으면 통째로 날리지 않고 거부
+                if let Some(reason) = folder_delete_forbidden_reason(&full, state.include_hidden, &state.extensions) {
+                    log_to_file(&format!("webdav: delete {} refused: {}", rel, reason));
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                remove_note_folder(&full, &state.extensions)
+            } else if is_note_file(&full, &state.extensions) {
+                fs::remove_file(&full)
+            } else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            match result {
+                Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                Err(_) => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        "MKCOL" => {
+            if let Err(e) = check_safe_path(&state, &rel) {
+                return e.into_response();
+            }
+            if !body.is_empty() {
+                return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+            }
+            let _write = match state.write_queue.acquire(&[&rel]).await {
+                Ok(guard) => guard,
+                Err(status) => return status.into_response(),
+            };
+            if full.exists() {
+                return (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, WEBDAV_ALLOW)]).into_response();
+            }
+            match fs::create_dir(&full) {
+                Ok(()) => StatusCode::CREATED.into_response(),
+                // 상위 폴더 없음
+                Err(_) => StatusCode::CONFLICT.into_response(),
+            }
+        }
+        "MOVE" => {
+            // Destination: 절대 URL 또는 경로
+            let Some(dest) = headers.get("destination").and_then(|v| v.to_str().ok()) else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            let dest_path = match dest.find("://") {
+                Some(i) => dest[i + 3..].find('/').map(|j| &dest[i + 3 + j..]).unwrap_or("/"),
+                None => dest,
+            };
+            let Some(dest_rel) = webdav_rel_path(dest_path).filter(|r| !r.is_empty()) else {
+                return StatusCode::BAD_GATEWAY.into_response();
+            };
+            if let Err(e) = check_safe_path(&state, &dest_rel) {
+                return e.into_response();
+            }
+            let dest_full = match resolve_vault_path(&state, &dest_rel) {
+                Ok(p) => p,
+                Err(status) => return status.into_response(),
+            };
+            let _write = match state.write_queue.acquire(&[&rel, &dest_rel]).await {
+                Ok(guard) => guard,
+                Err(status) => return status.into_response(),
+            };
+            if rel.is_empty() || !full.exists() {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            if let Some(reason) = rename_forbidden_reason(&full, &dest_full, state.include_hidden, &state.extensions) {
+                log_to_file(&format!("webdav: move {} → {} refused: {}", rel, dest_rel, reason));
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            let overwrite = headers.get("overwrite").and_then(|v| v.to_str().ok()) != Some("F");
+            let existed = dest_full.exists();
+            if existed && !overwrite {
+                return StatusCode::PRECONDITION_FAILED.into_response();
+            }
+            if let Some(parent) = dest_full.parent() {
+                if !parent.is_dir() {
+                    return StatusCode::CONFLICT.into_response();
+                }
+            }
+            match fs::rename(&full, &dest_full) {
+                Ok(()) if existed => StatusCode::NO_CONTENT.into_response(),
+                Ok(()) => StatusCode::CREATED.into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        }
+        _ => (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, WEBDAV_ALLOW)]).into_response(),
+    }
+}
+
+/// 외부 터널 상태 (트레이 표시용)
+#[derive(Debug, Clone, PartialEq)]
+enum TunnelState {
+    Starting,
+    Connected(String),
+    Failed,
+    Disabled,
+}
+
+impl TunnelState {
+    fn label(&self) -> String {
+        match self {
+            TunnelState::Starting => "🌍 외부 접속 연결 중...".to_string(),
+            TunnelState::Connected(url) => format!("🌍 {}", url.replace("https://", "")),
+            TunnelState::Failed => "⚠️ 외부 접속 불가 (로컬만)".to_string(),
+            TunnelState::Disabled => "🏠 외부 접속 꺼짐 (로컬만)".to_string(),
+        }
+    }
+}
+
+type TunnelStatus = Arc<Mutex<TunnelState>>;
+
+/// How many ports after `server_port` to try when it's taken (e.g. a second instance).
+const VAULT_PORT_ATTEMPTS: u16 = 10;
+
+/// Port the vault server actually bound (0 = not started); may differ from `server_port`.
+static VAULT_PORT: std::sync::atomic::AtomicU16 = std::sync::atomic::AtomicU16::new(0);
+
+/// Port to put in connection tokens: the bound one once the server is up.
+fn vault_port(config: &Config) -> u16 {
+    match VAULT_PORT.load(std::sync::atomic::Ordering::Relaxed) {
+        0 => config.server_port,
+        port => port,
+    }
+}
+
+/// Bind `port`, moving up to `VAULT_PORT_ATTEMPTS` ports higher while the address is in use.
+async fn bind_vault_listener(port: u16) -> std::io::Result<tokio::net::TcpListener> {
+    let mut last_err = None;
+    for candidate in port..=port.saturating_add(VAULT_PORT_ATTEMPTS) {
+        match tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], candidate))).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                log_to_file(&format!("vault: port {} in use", candidate));
+                last_err = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| std::io::Error::from(std::io::ErrorKind::AddrInUse)))
+}
+
+async fn run_private_vault_server(mut config: Config, tunnel: TunnelStatus) {
+    let state = ServerState {
+        local_path: PathBuf::from(&config.local_path),
+        extensions: config.extensions.clone(),
+        token: config.server_token.clone(),
+        token_hash: if config.hash_server_token || config.server_token.is_empty() {
+            config.server_token_hash.clone()
+        } else {
+            String::new()
+        },
+        include_hidden: config.include_hidden,
+        safe_filenames_only: config.safe_filenames_only,
+        require_signed_requests: config.require_signed_requests,
+        follow_file_symlinks: config.follow_file_symlinks,
+        obsidian_sync: config.obsidian_sync,
+        write_queue: VaultWriteQueue::new(config.vault_write_concurrency, config.vault_write_reject_when_busy),
+        seen_nonces: Arc::new(Mutex::new(HashMap::new())),
+        link_index: Arc::new(Mutex::new(HashMap::new())),
+    };
+    if config.require_signed_requests {
+        println!("🔏 서명된 요청만 허용 (timestamp ±{}초, nonce 재사용 거부)", SIGNED_REQUEST_WINDOW_SECS);
     }
     
-    // 이름 변경 (파일/폴더 모두 지원)
-    fs::rename(&old_file_path, &new_file_path).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            header::HeaderName::from_static(SIGNED_TIMESTAMP_HEADER),
+            header::HeaderName::from_static(SIGNED_NONCE_HEADER),
+            header::HeaderName::from_static(SIGNED_SIGNATURE_HEADER),
+        ]);
+    
+    let mut app = Router::new()
+        .route("/api/files", get(api_list_files))
+        .route("/api/stat-tree", get(api_stat_tree))
+        .route("/api/capabilities", get(api_capabilities))
+        .route(
+            "/api/file/*path",
+            get(api_get_file).put(api_put_file).delete(api_delete_file).post(api_post_file),
+        )
+        .route("/api/raw/*path", get(api_get_raw))
+        .route("/api/rename", axum::routing::post(api_rename))
+        .route("/api/backlinks/*path", get(api_backlinks));
+    if config.webdav_enabled {
+        app = app
+            .route("/dav", axum::routing::any(webdav_handler))
+            .route("/dav/", axum::routing::any(webdav_handler))
+            .route("/dav/*path", axum::routing::any(webdav_handler));
+    }
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(state.clone(), verify_signed_request))
+        .layer(cors)
+        .with_state(state);
+    
+    let listener = match bind_vault_listener(config.server_port).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            let msg = format!(
+                "포트 {}~{}를 열 수 없습니다: {}\n\n다른 MDFlare Agent가 실행 중인지 확인하거나 config.json의 server_port를 바꿔 주세요.",
+                config.server_port,
+                config.server_port.saturating_add(VAULT_PORT_ATTEMPTS),
+                e
+            );
+            eprintln!("❌ {}", msg);
+            log_to_file(&format!("vault: bind failed from port {}: {}", config.server_port, e));
+            *tunnel.lock().unwrap() = TunnelState::Failed;
+            if has_display() {
+                rfd::MessageDialog::new()
+                    .set_title("MDFlare Agent - Private Vault")
+                    .set_description(msg)
+                    .set_level(rfd::MessageLevel::Error)
+                    .set_buttons(rfd::MessageButtons::Ok)
+                    .show();
+            }
+            return;
+        }
+    };
+    let port = listener.local_addr().map(|a| a.port()).unwrap_or(config.server_port);
+    if port != config.server_port {
+        println!("⚠️ 포트 {} 사용 중 → {} 사용", config.server_port, port);
+        log_to_file(&format!("vault: port {} busy, settled on {}", config.server_port, port));
+        config.server_port = port;
+    }
+    VAULT_PORT.store(port, std::sync::atomic::Ordering::Relaxed);
+    if config.webdav_enabled {
+        println!("📂 WebDAV: http://localhost:{}/dav/ (비밀번호 = 서버 토큰)", port);
+        if config.require_signed_requests {
+            // 일반 WebDAV 클라이언트는 서명 헤더를 못 붙임
+            eprintln!("⚠️ require_signed_requests가 켜져 있어 WebDAV 클라이언트 요청은 거부됩니다");
+        }
+    }
+    
+    // 로컬 연결 토큰
+    let local_token = generate_connection_token(config.server_port, &config.server_token);
+    println!("🔐 Private Vault 서버 시작: http://localhost:{}", config.server_port);
+    println!("🔑 로컬 연결 토큰: {}", local_token);
+    
+    // localtunnel 터널 시작 (외부 접속용) - 비활성화 시 공개 URL을 만들지 않음
+    if config.enable_tunnel {
+        let server_token = config.server_token.clone();
+        let timeout = Duration::from_secs(config.tunnel_timeout_secs.max(1));
+        tokio::spawn(async move {
+            match start_tunnel(config.server_port, &server_token, timeout).await {
+                Ok((url, external_token)) => {
+                    println!("🌍 외부 접속: {}", url);
+                    println!("🔑 외부 연결 토큰: {}", external_token);
+                    *tunnel.lock().unwrap() = TunnelState::Connected(url);
+                }
+                Err(e) => {
+                    println!("⚠️ 터널 연결 실패 (로컬만 사용): {}", e);
+                    log_to_file(&format!("vault: tunnel failed → local only: {}", e));
+                    *tunnel.lock().unwrap() = TunnelState::Failed;
+                }
+            }
+        });
+    } else {
+        println!("🏠 외부 접속 꺼짐 (로컬만 사용)");
+        *tunnel.lock().unwrap() = TunnelState::Disabled;
+    }
+    
+    if let Err(e) = axum::serve(listener, app).await {
+        log_to_file(&format!("vault: server stopped: {}", e));
+    }
+}
+
+// cloudflared Quick Tunnel 시작 (timeout 안에 URL을 못 받으면 프로세스 종료 후 실패)
+async fn start_tunnel(local_port: u16, token: &str, timeout: Duration) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
+    use std::process::Stdio;
+    use tokio::process::Command;
+    use tokio::io::{BufReader, AsyncBufReadExt};
+    
+    let mut child = Command::new("cloudflared")
+        .args(["tunnel", "--url", &format!("http://localhost:{}", local_port)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    
+    let stderr = child.stderr.take().ok_or("stderr 없음")?;
+    let mut reader = BufReader::new(stderr).lines();
+    
+    // URL 파싱 (stderr에서 trycloudflare.com URL 찾기)
+    let wait_for_url = async {
+        loop {
+            if let Some(line) = reader.next_line().await? {
+                if line.contains("trycloudflare.com") {
+                    // URL 추출: https://xxx.trycloudflare.com
+                    if let Some(start) = line.find("https://") {
+                        let url_part = &line[start..];
+                        if let Some(end) = url_part.find(|c: char| c.is_whitespace() || c == '|') {
+                            break Ok::<String, Box<dyn std::error::Error + Send + Sync>>(url_part[..end].to_string());
+                        } else {
+                            break Ok(url_part.trim().to_string());
+                        }
+                    }
+                }
+            } else {
+                break Err("cloudflared URL을 받지 못함".into());
+            }
+        }
+    };
+    let url = match tokio::time::timeout(timeout, wait_for_url).await {
+        Ok(Ok(url)) => url,
+        Ok(Err(e)) => {
+            child.kill().await.ok();
+            return Err(e);
+        }
+        Err(_) => {
+            child.kill().await.ok();
+            return Err(format!("cloudflared가 {}초 안에 URL을 주지 않음", timeout.as_secs()).into());
+        }
+    };
+    
+    let external_token = generate_connection_token_with_url(&url, token);
+    
+    // 프로세스 유지 (백그라운드) - stderr 계속 읽어서 drain
+    tokio::spawn(async move {
+        // stderr를 계속 읽어서 프로세스가 block되지 않도록 함
+        while let Ok(Some(_)) = reader.next_line().await {}
+        let _ = child.wait().await;
+    });
     
+    Ok((url, external_token))
+}
+
+// URL 기반 연결 토큰 생성
+fn generate_connection_token_with_url(url: &str, token: &str) -> String {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    let plain = format!("{}|{}", url, token);
+    STANDARD.encode(plain.as_bytes())
+}
+
+// ============================================================================
+// Status Server (Cloud 모드 디버깅용, 127.0.0.1 전용)
+// ============================================================================
+
+#[derive(Clone)]
+struct StatusState {
+    events: EventLog,
+    engine: Arc<Mutex<SyncEngine>>,
+}
+
+async fn status_recent_events(State(state): State<StatusState>) -> Json<Vec<SyncEvent>> {
+    Json(state.events.recent())
+}
+
+/// Entry counts and approximate in-memory bytes (path + value) of the engine caches.
+async fn status_cache_stats(State(state): State<StatusState>) -> Result<Json<serde_json::Value>, StatusCode> {
+    let eng = state.engine.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let approx = |map: &HashMap<String, String>| map.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+    let (content_entries, content_bytes, blob_bytes) = eng.local_content_cache.stats();
     Ok(Json(serde_json::json!({
-        "renamed": true,
-        "oldPath": old_decoded,
-        "newPath": new_decoded
+        "localHashes": { "entries": eng.local_hashes.len(), "bytes": approx(&eng.local_hashes) },
+        "localContentCache": { "entries": content_entries, "bytes": content_bytes, "blobBytesOnDisk": blob_bytes },
+        "remoteModified": {
+            "entries": eng.remote_modified.len(),
+            "bytes": eng.remote_modified.keys().map(|k| k.len() + 8).sum::<usize>(),
+        },
+        "pendingUploads": eng.pending_uploads.len(),
+    })))
+}
+
+/// Last `full_sync` stats and totals since the agent started.
+async fn status_sync_stats(State(state): State<StatusState>) -> Result<Json<serde_json::Value>, StatusCode> {
+    let eng = state.engine.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(serde_json::json!({
+        "last": eng.last_stats.as_ref().map(SyncStats::to_json),
+        "lastSync": eng.last_sync.map(|t| t.to_rfc3339()),
+        "total": eng.total_stats.to_json(),
+        "runs": eng.sync_runs,
+        "conflicts": eng.conflicts(),
     })))
 }
 
-async fn run_private_vault_server(config: Config) {
-    let state = ServerState {
-        local_path: PathBuf::from(&config.local_path),
-        token: config.server_token.clone(),
-    };
-    
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
-        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]);
-    
-    let app = Router::new()
-        .route("/api/files", get(api_list_files))
-        .route("/api/file/*path", get(api_get_file).put(api_put_file).delete(api_delete_file))
-        .route("/api/rename", axum::routing::post(api_rename))
-        .layer(cors)
-        .with_state(state);
-    
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.server_port));
-    
-    // 로컬 연결 토큰
-    let local_token = generate_connection_token(config.server_port, &config.server_token);
-    println!("🔐 Private Vault 서버 시작: http://localhost:{}", config.server_port);
-    println!("🔑 로컬 연결 토큰: {}", local_token);
-    
-    // localtunnel 터널 시작 (외부 접속용)
-    let server_token = config.server_token.clone();
-    tokio::spawn(async move {
-        match start_tunnel(config.server_port, &server_token).await {
-            Ok((url, external_token)) => {
-                println!("🌍 외부 접속: {}", url);
-                println!("🔑 외부 연결 토큰: {}", external_token);
+/// Drop the diff-base content cache to reclaim memory. Hashes stay, so nothing is
+/// re-synced; the next edit of each note is uploaded in full instead of as a diff.
+async fn status_cache_clear(State(state): State<StatusState>) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut eng = state.engine.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (entries, bytes, _) = eng.local_content_cache.stats();
+    eng.local_content_cache.clear();
+    log_to_file(&format!("status: content cache cleared ({} entries, ~{} bytes)", entries, bytes));
+    Ok(Json(serde_json::json!({ "cleared": entries, "bytes": bytes })))
+}
+
+/// Deletes from other devices waiting for review (`delete_policy = review`).
+async fn status_pending_deletes(State(state): State<StatusState>) -> Result<Json<serde_json::Value>, StatusCode> {
+    let eng = state.engine.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(serde_json::json!({ "pending": eng.pending_deletes() })))
+}
+
+/// Body `{"paths": [...]}` selects staged deletes; an empty body means all of them.
+fn pending_delete_paths(body: &[u8]) -> Result<Vec<String>, StatusCode> {
+    #[derive(Deserialize)]
+    struct Selection {
+        #[serde(default)]
+        paths: Vec<String>,
+    }
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Vec::new());
+    }
+    serde_json::from_slice::<Selection>(body).map(|s| s.paths).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+async fn status_approve_deletes(
+    State(state): State<StatusState>,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let paths = pending_delete_paths(&body)?;
+    let mut eng = state.engine.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(serde_json::json!({ "deleted": eng.approve_pending_deletes(&paths) })))
+}
+
+async fn status_reject_deletes(
+    State(state): State<StatusState>,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let paths = pending_delete_paths(&body)?;
+    let mut eng = state.engine.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(serde_json::json!({ "restored": eng.reject_pending_deletes(&paths) })))
+}
+
+/// 상태 서버의 상태 변경 POST에 필요한 헤더 (브라우저는 preflight 없이 못 붙임)
+const STATUS_WRITE_HEADER: &str = "x-mdflare-status";
+
+/// Middleware for the status server's POST routes. 127.0.0.1 is reachable from any web page
+/// via no-cors requests, so require a custom header (forces a CORS preflight, which this
+/// server never answers) and reject anything a browser marked with an `Origin`.
+async fn status_write_guard(req: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    if req.headers().contains_key(header::ORIGIN) || !req.headers().contains_key(STATUS_WRITE_HEADER) {
+        log_to_file(&format!("status: rejected {} (origin or missing {})", req.uri().path(), STATUS_WRITE_HEADER));
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(req).await
+}
+
+fn start_status_server(port: u16, engine: Arc<Mutex<SyncEngine>>) {
+    let events = engine.lock().unwrap().events.clone();
+    let state = StatusState { events, engine };
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let writes = Router::new()
+                .route("/cache/clear", axum::routing::post(status_cache_clear))
+                .route("/deletes/approve", axum::routing::post(status_approve_deletes))
+                .route("/deletes/reject", axum::routing::post(status_reject_deletes))
+                .route_layer(axum::middleware::from_fn(status_write_guard));
+            let app = Router::new()
+                .route("/events/recent", get(status_recent_events))
+                .route("/sync/stats", get(status_sync_stats))
+                .route("/cache/stats", get(status_cache_stats))
+                .route("/deletes/pending", get(status_pending_deletes))
+                .merge(writes)
+                .with_state(state);
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    println!("📊 상태 서버: http://127.0.0.1:{}", port);
+                    axum::serve(listener, app).await.ok();
+                }
+                Err(e) => eprintln!("⚠️ 상태 서버 시작 실패 (포트 {}): {}", port, e),
+            }
+        });
+    });
+}
+
+// ============================================================================
+// Sync Engine (Cloud 모드용)
+// ============================================================================
+
+// ============================================================================
+// RTDB types and diff helpers
+// ============================================================================
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RtdbFileEntry {
+    path: String,
+    action: String,
+    #[serde(default, deserialize_with = "de_non_empty")]
+    hash: Option<String>,
+    old_hash: Option<String>,
+    // hash/oldHash를 만든 방식 (없으면 simple_js)
+    algo: Option<String>,
+    // 변경을 올린 기기 (서버가 업로드의 deviceId를 그대로 기록)
+    device_id: Option<String>,
+    diff: Option<Vec<serde_json::Value>>,
+    old_path: Option<String>,
+    modified: Option<u64>,
+    #[allow(dead_code)]
+    size: Option<u64>,
+}
+
+/// Apply a line-based diff to content.
+/// diff ops: {"eq": N}, {"del": N}, {"ins": ["line1", ...]}
+fn apply_line_diff(old_content: &str, diff: &[serde_json::Value]) -> Option<String> {
+    let old_lines: Vec<&str> = old_content.split('\n').collect();
+    let mut result = Vec::new();
+    let mut pos = 0;
+
+    for op in diff {
+        if let Some(eq) = op.get("eq").and_then(|v| v.as_u64()) {
+            let eq = eq as usize;
+            if pos + eq > old_lines.len() {
+                return None; // diff doesn't match
+            }
+            result.extend_from_slice(&old_lines[pos..pos + eq]);
+            pos += eq;
+        } else if let Some(del) = op.get("del").and_then(|v| v.as_u64()) {
+            let del = del as usize;
+            if pos + del > old_lines.len() {
+                return None;
+            }
+            pos += del;
+        } else if let Some(ins) = op.get("ins").and_then(|v| v.as_array()) {
+            for line in ins {
+                if let Some(s) = line.as_str() {
+                    result.push(s);
+                } else {
+                    return None;
+                }
+            }
+        } else {
+            return None; // unknown op
+        }
+    }
+    // remaining lines (if any eq ops missed)
+    result.extend_from_slice(&old_lines[pos..]);
+    Some(result.join("\n"))
+}
+
+/// Merge two versions of an append-only note: keep the common leading lines,
+/// then append each side's unique trailing lines, older side first.
+fn append_merge(older: &str, newer: &str) -> String {
+    let older_lines: Vec<&str> = older.lines().collect();
+    let newer_lines: Vec<&str> = newer.lines().collect();
+    let base_len = older_lines
+        .iter()
+        .zip(&newer_lines)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut merged: Vec<&str> = older_lines.clone();
+    for line in &newer_lines[base_len..] {
+        if !older_lines[base_len..].contains(line) {
+            merged.push(line);
+        }
+    }
+    let mut result = merged.join("\n");
+    if older.ends_with('\n') || newer.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+/// Build a glob matcher from config patterns, skipping invalid ones.
+fn build_globset(patterns: &[String]) -> Option<globset::GlobSet> {
+    if patterns.is_empty() {
+        return None;
+    }
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        match globset::Glob::new(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => eprintln!("⚠️ 잘못된 glob 무시 {}: {}", pattern, e),
+        }
+    }
+    builder.build().ok()
+}
+
+/// Generate a line-based diff using the `similar` crate.
+fn generate_line_diff(old_content: &str, new_content: &str) -> serde_json::Value {
+    use similar::{ChangeTag, TextDiff};
+
+    // apply_line_diff와 같은 기준('\n' 분리)으로 비교 → 마지막 줄 개행 유무/CRLF도 정확히 왕복
+    let old_lines: Vec<&str> = old_content.split('\n').collect();
+    let new_lines: Vec<&str> = new_content.split('\n').collect();
+    let text_diff = TextDiff::configure().diff_slices(&old_lines, &new_lines);
+    let mut ops: Vec<serde_json::Value> = Vec::new();
+    let mut eq_count = 0usize;
+    let mut del_count = 0usize;
+    let mut ins_lines: Vec<String> = Vec::new();
+
+    let flush_del = |ops: &mut Vec<serde_json::Value>, del: &mut usize| {
+        if *del > 0 {
+            ops.push(serde_json::json!({"del": *del}));
+            *del = 0;
+        }
+    };
+    let flush_ins = |ops: &mut Vec<serde_json::Value>, ins: &mut Vec<String>| {
+        if !ins.is_empty() {
+            ops.push(serde_json::json!({"ins": ins.clone()}));
+            ins.clear();
+        }
+    };
+    let flush_eq = |ops: &mut Vec<serde_json::Value>, eq: &mut usize| {
+        if *eq > 0 {
+            ops.push(serde_json::json!({"eq": *eq}));
+            *eq = 0;
+        }
+    };
+
+    for change in text_diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Equal => {
+                flush_del(&mut ops, &mut del_count);
+                flush_ins(&mut ops, &mut ins_lines);
+                eq_count += 1;
+            }
+            ChangeTag::Delete => {
+                flush_eq(&mut ops, &mut eq_count);
+                flush_ins(&mut ops, &mut ins_lines);
+                del_count += 1;
+            }
+            ChangeTag::Insert => {
+                flush_eq(&mut ops, &mut eq_count);
+                ins_lines.push(change.value().to_string());
+            }
+        }
+    }
+    flush_eq(&mut ops, &mut eq_count);
+    flush_del(&mut ops, &mut del_count);
+    flush_ins(&mut ops, &mut ins_lines);
+
+    serde_json::json!(ops)
+}
+
+/// Convert i32 to base-36 string, matching JS `Number.prototype.toString(36)`.
+/// Negative numbers are prefixed with '-'.
+fn to_base36(n: i32) -> String {
+    if n == 0 {
+        return "0".to_string();
+    }
+    let negative = n < 0;
+    let mut val = if negative { (n as i64).abs() as u64 } else { n as u64 };
+    let digits = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    let mut buf = Vec::new();
+    while val > 0 {
+        buf.push(digits[(val % 36) as usize]);
+        val /= 36;
+    }
+    if negative {
+        buf.push(b'-');
+    }
+    buf.reverse();
+    String::from_utf8(buf).unwrap()
+}
+
+/// POST a sync summary to the configured webhook without blocking the sync.
+fn post_sync_webhook(url: &str, payload: serde_json::Value) {
+    let url = url.to_string();
+    thread::spawn(move || {
+        let client = match reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+        {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+        if let Err(e) = client.post(&url).json(&payload).send() {
+            log::warn!("sync webhook 전송 실패: {}", e);
+        }
+    });
+}
+
+const EVENT_LOG_CAPACITY: usize = 500;
+
+#[derive(Debug, Clone, Serialize)]
+struct SyncEvent {
+    ts: String,
+    kind: &'static str,
+    path: String,
+    detail: String,
+}
+
+/// Bounded ring buffer of recent sync activity, shared with the status server.
+#[derive(Clone, Default)]
+struct EventLog {
+    events: Arc<Mutex<std::collections::VecDeque<SyncEvent>>>,
+    // 이벤트를 OS 로그(syslog/journald, macOS 통합 로그, Windows 이벤트 로그)에도 기록
+    system_log: bool,
+}
+
+impl EventLog {
+    fn push(&self, kind: &'static str, path: &str, detail: &str) {
+        if let Ok(mut events) = self.events.lock() {
+            if events.len() >= EVENT_LOG_CAPACITY {
+                events.pop_front();
+            }
+            events.push_back(SyncEvent {
+                ts: chrono::Utc::now().to_rfc3339(),
+                kind,
+                path: path.to_string(),
+                detail: detail.to_string(),
+            });
+        }
+        if self.system_log {
+            write_system_log(kind, path, detail);
+        }
+    }
+
+    fn recent(&self) -> Vec<SyncEvent> {
+        self.events
+            .lock()
+            .map(|events| events.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Forward one sync event to the platform log (`system_log`). Unix: the local syslog socket,
+/// which journald (Linux) and the unified log (macOS) collect. Windows: Application event log
+/// via `eventcreate`.
+fn write_system_log(kind: &str, path: &str, detail: &str) {
+    let message = format!("{} {} {}", kind, path, detail);
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+        // facility user(1) * 8 + severity (err 3 / warning 4 / info 6)
+        let severity = match kind {
+            "error" => 3,
+            "conflict" => 4,
+            _ => 6,
+        };
+        let line = format!("<{}>mdflare-agent[{}]: {}", 8 + severity, std::process::id(), message);
+        if let Ok(socket) = UnixDatagram::unbound() {
+            for target in ["/dev/log", "/var/run/syslog"] {
+                if socket.send_to(line.as_bytes(), target).is_ok() {
+                    break;
+                }
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        let level = match kind {
+            "error" => "ERROR",
+            "conflict" => "WARNING",
+            _ => "INFORMATION",
+        };
+        std::process::Command::new("eventcreate")
+            .args(["/T", level, "/ID", "100", "/L", "APPLICATION", "/SO", "MDFlare Agent", "/D", &message])
+            .creation_flags(CREATE_NO_WINDOW)
+            .spawn()
+            .ok();
+    }
+}
+
+/// Append-only JSONL audit trail of everything that synced (`audit_log`). The agent never
+/// rotates or truncates it. Each line carries the SHA-256 of the previous line (`prev`), so
+/// editing or removing a line breaks the chain.
+struct AuditLog {
+    path: PathBuf,
+    // 마지막으로 쓴 줄의 SHA-256 (첫 줄이면 빈 문자열)
+    last_line_hash: Mutex<String>,
+}
+
+impl AuditLog {
+    fn open(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).ok();
+        }
+        let last_line_hash = Self::last_line(&path)
+            .map(|line| sha256_hex(line.as_bytes()))
+            .unwrap_or_default();
+        Self { path, last_line_hash: Mutex::new(last_line_hash) }
+    }
+
+    /// Last line of an existing log, read from the tail (the file grows without bound).
+    fn last_line(path: &Path) -> Option<String> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = fs::File::open(path).ok()?;
+        let len = file.metadata().ok()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(64 * 1024))).ok()?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).ok()?;
+        String::from_utf8_lossy(&tail)
+            .lines()
+            .rev()
+            .find(|l| !l.trim().is_empty())
+            .map(|l| l.to_string())
+    }
+
+    /// `op`: upload / download / delete / rename, `direction`: up (local → remote) / down.
+    fn record(&self, op: &str, direction: &str, path: &str, content: Option<&str>, from: Option<&str>) {
+        use std::io::Write;
+        let Ok(mut last) = self.last_line_hash.lock() else { return };
+        let mut entry = serde_json::json!({
+            "ts": chrono::Utc::now().to_rfc3339(),
+            "op": op,
+            "direction": direction,
+            "path": path,
+        });
+        if let Some(content) = content {
+            entry["size"] = content.len().into();
+            entry["hash"] = sha256_hex(content.as_bytes()).into();
+        }
+        if let Some(from) = from {
+            entry["from"] = from.into();
+        }
+        entry["prev"] = last.as_str().into();
+        let line = entry.to_string();
+        let written = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| writeln!(f, "{}", line));
+        match written {
+            Ok(()) => *last = sha256_hex(line.as_bytes()),
+            Err(e) => log_to_file(&format!("audit log 기록 실패 ({}): {}", self.path.display(), e)),
+        }
+    }
+}
+
+const SYNC_STATUS_OK: &str = "✅ 동기화 중";
+const SYNC_STATUS_RATE_LIMITED: &str = "⏳ 서버 혼잡, 잠시 후 재시도";
+const SYNC_STATUS_OFFLINE_RETRY: &str = "📴 오프라인 — 재시도 중";
+const SYNC_STATUS_SCHEDULED: &str = "🕘 예약 대기 중";
+const SYNC_STATUS_LIMIT_EXCEEDED: &str = "⛔ 동기화 한도 초과 — 폴더 확인 필요";
+
+/// 트레이에 표시할 동기화 상태 문구 (엔진 잠금과 별개로 읽기 위해 분리)
+type SyncStatus = Arc<Mutex<String>>;
+
+/// Identifies an account + folder pair (first-sync backup, empty-vault marker, tombstones).
+fn vault_key(config: &Config) -> String {
+    match config.storage_mode {
+        StorageMode::PeerVault => format!("{}@{}", config.peer_url, config.local_path),
+        _ => format!("{}@{}", config.username, config.local_path),
+    }
+}
+
+/// 첫 동기화 백업 최대 크기 - 넘으면 백업 없이 경고만 (설정 폴더가 vault 크기만큼 불어나지 않게)
+const FIRST_SYNC_BACKUP_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Zip the given note files under `local_path` into `<config dir>/backups/<timestamp>.zip`.
+/// Returns None when there is nothing to protect; errors when they exceed `max_bytes`.
+fn backup_local_folder(local_path: &Path, files: &[PathBuf], max_bytes: u64) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let total: u64 = files.iter().filter_map(|f| fs::metadata(f).ok()).map(|m| m.len()).sum();
+    if total > max_bytes {
+        return Err(format!("노트가 {}로 백업 한도({})를 넘습니다", format_bytes(total), format_bytes(max_bytes)).into());
+    }
+
+    let dir = Config::config_path()
+        .parent()
+        .map(|p| p.join("backups"))
+        .ok_or("config dir not found")?;
+    fs::create_dir_all(&dir)?;
+    let archive = dir.join(format!("{}.zip", chrono::Local::now().format("%Y%m%d-%H%M%S")));
+
+    let mut zip = zip::ZipWriter::new(fs::File::create(&archive)?);
+    let options = SimpleFileOptions::default();
+    for file in files {
+        let rel = file.strip_prefix(local_path)?;
+        zip.start_file(rel.to_string_lossy().replace('\\', "/"), options)?;
+        zip.write_all(&fs::read(file)?)?;
+    }
+    zip.finish()?;
+    Ok(Some(archive))
+}
+
+/// Mark a regenerable scratch dir so backup tools and search indexers skip it:
+/// CACHEDIR.TAG (borg/restic/tar --exclude-caches) everywhere,
+/// plus Spotlight (.metadata_never_index) and Time Machine exclusion on macOS.
+fn mark_cache_dir(dir: &Path) {
+    let tag = dir.join("CACHEDIR.TAG");
+    if !tag.exists() {
+        fs::write(
+            &tag,
+            "Signature: 8a477f597d28d172789f06886806bc55\n\
+             # This file is a cache directory tag created by mdflare-agent.\n\
+             # For information about cache directory tags see https://bford.info/cachedir/\n",
+        )
+        .ok();
+    }
+    if cfg!(target_os = "macos") {
+        let never_index = dir.join(".metadata_never_index");
+        if !never_index.exists() {
+            fs::write(&never_index, "").ok();
+            // 처음 한 번만 (tmutil 제외는 폴더 속성으로 유지됨)
+            let excluded = std::process::Command::new("tmutil")
+                .arg("addexclusion")
+                .arg(dir)
+                .status()
+                .is_ok_and(|s| s.success());
+            log_to_file(&format!("cache dir: marked {} (time machine exclusion: {})", dir.display(), excluded));
+        }
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> HmacSha256 {
+    use hmac::Mac;
+    // HMAC은 어떤 길이의 키도 받음 → 실패하지 않음
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(msg);
+    mac
+}
+
+/// HMAC-SHA256 (RFC 2104) as lowercase hex.
+fn hmac_sha256_hex(key: &[u8], msg: &[u8]) -> String {
+    use hmac::Mac;
+    hmac_sha256(key, msg).finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Constant-time check of a hex HMAC-SHA256 `signature` over `msg`.
+fn verify_hmac_sha256_hex(key: &[u8], msg: &[u8], signature: &str) -> bool {
+    use hmac::Mac;
+    let bytes: Option<Vec<u8>> = signature
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok().filter(|p| p.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect();
+    bytes.is_some_and(|bytes| hmac_sha256(key, msg).verify_slice(&bytes).is_ok())
+}
+
+/// Message covered by a request signature (see `require_signed_requests`).
+fn signed_request_message(method: &str, path_and_query: &str, timestamp: &str, nonce: &str) -> String {
+    format!("{}\n{}\n{}\n{}", method.to_uppercase(), path_and_query, timestamp, nonce)
+}
+
+/// Compare without short-circuiting, so timing doesn't leak how much of a signature matched.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Counters for one `full_sync`; also accumulated per engine for the status server.
+#[derive(Debug, Clone, Default)]
+struct SyncStats {
+    downloaded: usize,
+    uploaded: usize,
+    deleted: usize,
+    conflicts: usize,
+    errors: usize,
+    bytes_down: u64,
+    bytes_up: u64,
+    duration: Duration,
+}
+
+impl SyncStats {
+    fn add(&mut self, other: &SyncStats) {
+        self.downloaded += other.downloaded;
+        self.uploaded += other.uploaded;
+        self.deleted += other.deleted;
+        self.conflicts += other.conflicts;
+        self.errors += other.errors;
+        self.bytes_down += other.bytes_down;
+        self.bytes_up += other.bytes_up;
+        self.duration += other.duration;
+    }
+
+    /// One-line form for logs and the diagnostics "마지막 동기화" line.
+    fn summary(&self) -> String {
+        let mut line = format!("⬇️{} ⬆️{}", self.downloaded, self.uploaded);
+        if self.deleted > 0 {
+            line.push_str(&format!(" 🗑️{}", self.deleted));
+        }
+        if self.conflicts > 0 {
+            line.push_str(&format!(" ⚠️{}", self.conflicts));
+        }
+        if self.errors > 0 {
+            line.push_str(&format!(" ❌{}", self.errors));
+        }
+        line
+    }
+
+    /// Webhook / status server payload (snake_case, same keys the webhook always sent).
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "downloaded": self.downloaded,
+            "uploaded": self.uploaded,
+            "deleted": self.deleted,
+            "conflicts": self.conflicts,
+            "errors": self.errors,
+            "bytes_down": self.bytes_down,
+            "bytes_up": self.bytes_up,
+            "duration_ms": self.duration.as_millis() as u64,
+        })
+    }
+}
+
+/// Result of one full_sync upload, sent back from the upload workers.
+enum UploadOutcome {
+    Uploaded(String),
+    // 다른 기기가 먼저 생성함 (412) → 업로드 대신 다운로드/병합
+    AlreadyExists(String),
+    ReadFailed(String),
+    Failed(String),
+    RateLimited(Duration),
+}
+
+/// Result of one full_sync download, sent back from the download workers.
+enum DownloadOutcome {
+    Fetched(FileContent),
+    Failed(String),
+    RateLimited(Duration),
+}
+
+/// path → 마지막 동기화 내용 (diff 기준).
+/// blob 모드면 내용은 config_dir/blobs/{sha256} 에 한 번만 저장하고 메모리에는 path → 해시만 둠
+/// (같은 내용의 노트는 blob 하나를 공유).
+struct ContentCache {
+    blob_dir: Option<PathBuf>,
+    inline: HashMap<String, String>,
+    blobs: HashMap<String, String>,
+    // 내용 합계 상한 (0이면 무제한), 넘으면 가장 오래 안 쓴 항목부터 제거
+    max_bytes: u64,
+    used_bytes: u64,
+    // path → (마지막 사용 순번, 내용 크기) / 순번 → path (LRU 순서)
+    usage: HashMap<String, (u64, u64)>,
+    lru: std::collections::BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl ContentCache {
+    fn new(use_blobs: bool, mark_dir: bool, max_bytes: u64) -> Self {
+        let blob_dir = use_blobs.then(|| {
+            let dir = agent_config_dir().join("blobs");
+            fs::create_dir_all(&dir).ok();
+            if mark_dir {
+                mark_cache_dir(&dir);
+            }
+            dir
+        });
+        let cache = Self {
+            blob_dir,
+            inline: HashMap::new(),
+            blobs: HashMap::new(),
+            max_bytes,
+            used_bytes: 0,
+            usage: HashMap::new(),
+            lru: std::collections::BTreeMap::new(),
+            tick: 0,
+        };
+        // 캐시는 프로세스마다 새로 채워지므로 이전 실행의 blob은 전부 미참조
+        cache.gc();
+        cache
+    }
+
+    fn blob_key(content: &str) -> String {
+        sha256_hex(content.as_bytes())
+    }
+
+    /// Cached content (marks the entry as recently used).
+    fn get(&mut self, path: &str) -> Option<String> {
+        let content = match &self.blob_dir {
+            Some(dir) => fs::read_to_string(dir.join(self.blobs.get(path)?)).ok(),
+            None => self.inline.get(path).cloned(),
+        };
+        if let Some(content) = &content {
+            self.touch(path, content.len() as u64);
+        }
+        content
+    }
+
+    fn insert(&mut self, path: String, content: String) {
+        self.touch(&path, content.len() as u64);
+        match &self.blob_dir {
+            None => {
+                self.inline.insert(path, content);
+            }
+            Some(dir) => {
+                let key = Self::blob_key(&content);
+                let blob = dir.join(&key);
+                if !blob.exists() {
+                    fs::write(&blob, &content).ok();
+                }
+                if let Some(old) = self.blobs.insert(path, key) {
+                    self.release(&old);
+                }
+            }
+        }
+        self.evict();
+    }
+
+    fn remove(&mut self, path: &str) {
+        self.forget(path);
+        self.inline.remove(path);
+        if let Some(old) = self.blobs.remove(path) {
+            self.release(&old);
+        }
+    }
+
+    fn rename(&mut self, old_path: &str, new_path: &str) {
+        if let Some((_, size)) = self.forget(old_path) {
+            self.touch(new_path, size);
+        }
+        if let Some(content) = self.inline.remove(old_path) {
+            self.inline.insert(new_path.to_string(), content);
+        }
+        if let Some(key) = self.blobs.remove(old_path) {
+            self.blobs.insert(new_path.to_string(), key);
+        }
+    }
+
+    /// Drop every cached content (blob files included); `local_hashes` is unaffected.
+    fn clear(&mut self) {
+        self.inline.clear();
+        self.blobs.clear();
+        self.usage.clear();
+        self.lru.clear();
+        self.used_bytes = 0;
+        self.gc();
+    }
+
+    /// Mark `path` as most recently used with `size` bytes of content.
+    fn touch(&mut self, path: &str, size: u64) {
+        self.forget(path);
+        self.tick += 1;
+        self.usage.insert(path.to_string(), (self.tick, size));
+        self.lru.insert(self.tick, path.to_string());
+        self.used_bytes += size;
+    }
+
+    fn forget(&mut self, path: &str) -> Option<(u64, u64)> {
+        let (tick, size) = self.usage.remove(path)?;
+        self.lru.remove(&tick);
+        self.used_bytes -= size;
+        Some((tick, size))
+    }
+
+    /// Over `max_bytes`: drop least-recently-used entries. An evicted note just loses its
+    /// diff base, so its next edit is uploaded in full once.
+    fn evict(&mut self) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        // 방금 넣은 항목 하나는 크기와 무관하게 유지
+        while self.used_bytes > self.max_bytes && self.lru.len() > 1 {
+            let Some((_, path)) = self.lru.pop_first() else { break };
+            self.remove(&path);
+        }
+    }
+
+    /// (entries, approximate bytes held in memory, bytes in blob files)
+    fn stats(&self) -> (usize, usize, u64) {
+        let memory = self.inline.iter().chain(self.blobs.iter()).map(|(k, v)| k.len() + v.len()).sum();
+        let on_disk = self
+            .blob_dir
+            .as_ref()
+            .and_then(|dir| fs::read_dir(dir).ok())
+            .map(|entries| entries.filter_map(|e| e.ok()?.metadata().ok()).map(|m| m.len()).sum())
+            .unwrap_or(0);
+        (self.inline.len() + self.blobs.len(), memory, on_disk)
+    }
+
+    /// Delete a blob once no path refers to it.
+    fn release(&self, key: &str) {
+        if let Some(dir) = &self.blob_dir {
+            if !self.blobs.values().any(|k| k == key) {
+                fs::remove_file(dir.join(key)).ok();
+            }
+        }
+    }
+
+    /// Remove blob files that no cached path refers to.
+    fn gc(&self) {
+        let Some(dir) = &self.blob_dir else { return };
+        let Ok(entries) = fs::read_dir(dir) else { return };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // blob(SHA-256 hex)만 - CACHEDIR.TAG 등 표시 파일은 유지
+            let is_blob = name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit());
+            if is_blob && !self.blobs.values().any(|k| *k == name) {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+    }
+}
+
+struct SyncEngine {
+    api: Box<dyn RemoteBackend>,
+    local_path: PathBuf,
+    include_hidden: bool,
+    extensions: Vec<String>,
+    sync_webhook_url: Option<String>,
+    disk_space_margin_mb: u64,
+    events: EventLog,
+    // Some이면 원격 쓰기(AuditedBackend)와 로컬 반영(다운로드/삭제/이름 변경)을 감사 로그에 기록
+    audit: Option<Arc<AuditLog>>,
+    append_merge: Option<globset::GlobSet>,
+    readonly: Option<globset::GlobSet>,
+    download_exclude: Option<globset::GlobSet>,
+    obsidian_sync: ObsidianSync,
+    // local_path/.mdflareignore - 일치하는 경로는 업로드/다운로드/원격 삭제 모두 안 함
+    ignore: IgnoreMatcher,
+    // Some이면 flatten_local 모드 (로컬 파일명 ↔ 원격 경로)
+    flat_names: Option<FlatNames>,
+    delete_grace: Duration,
+    upload_idle: Duration,
+    upload_concurrency: usize,
+    // diff 업로드 상한을 gzip 후 크기로 판단
+    compress_uploads: bool,
+    sync_concurrency: usize,
+    upload_small_first: bool,
+    // Some(계정 키)면 첫 full_sync 전에 백업 필요
+    pending_first_backup: Option<String>,
+    // Some이면 다음 sync_once에서 겹치는 파일을 이 쪽 기준으로 (성공 후 해제)
+    initial_authority: Option<SyncAuthority>,
+    // Some((계정 키, 날짜))면 빈 vault가 의도된 상태 → 빈 폴더 안전 검사 생략 (노트가 생기면 해제)
+    empty_init: Option<(String, String)>,
+    // 이번 실행의 full_sync에서 로컬 노트를 한 번이라도 봤는지 (빈 폴더 안전 검사용)
+    seen_local_notes: bool,
+    // Some이면 foo.md + foo.md{suffix} 를 한 묶음으로 동기화
+    metadata_suffix: Option<String>,
+    verify_uploads: bool,
+    match_by_content: bool,
+    recheck_before_download_write: bool,
+    max_sync_files: usize,
+    max_sync_total_bytes: u64,
+    // 사용자가 이 폴더를 확인함 (confirmed_local_paths) → 한도 검사 생략
+    local_path_confirmed: bool,
+    local_only_dir: Option<String>,
+    export_on_sync: Option<ExportFormat>,
+    max_deletes_per_sync: usize,
+    delete_policy: DeletePolicy,
+    hash_algo: HashAlgo,
+    device_id: String,
+    sync_schedule: Vec<ScheduleWindow>,
+    // 예약 시간 밖에서 감지한 로컬 변경: (경로, 폴더 삭제 여부)
+    deferred_changes: Vec<(PathBuf, bool)>,
+    // upload_idle 대기 중인 변경: 원격 경로 → (로컬 파일, 마지막 변경 시각)
+    pending_uploads: HashMap<String, (PathBuf, std::time::Instant)>,
+    // delete_grace 대기 중인 로컬 삭제: 원격 경로 → (로컬 파일, 확정 시각)
+    pending_local_deletes: HashMap<String, (PathBuf, std::time::Instant)>,
+    upload_burst_limit: usize,
+    upload_burst_window: Duration,
+    upload_throttle: Duration,
+    // 경로별 최근 upload_burst_window 안의 업로드 시각
+    upload_history: HashMap<String, std::collections::VecDeque<std::time::Instant>>,
+    // 폭주로 묶인 파일 → 마지막 업로드 시각 (upload_throttle 동안 조용하면 해제)
+    throttled_uploads: HashMap<String, std::time::Instant>,
+    // 충돌로 생긴 원격 사본 (vault 기준 경로)
+    conflicts: Vec<String>,
+    tombstones: Tombstones,
+    tombstone_ttl_days: u64,
+    status: SyncStatus,
+    // 429 Retry-After: 이 시각 전에는 full_sync 하지 않음
+    backoff_until: Option<std::time::Instant>,
+    // 진단 정보용: 마지막 성공 동기화 시각, RTDB 연결 상태 (None = 구독 안 함)
+    last_sync: Option<chrono::DateTime<chrono::Local>>,
+    // 마지막 full_sync 때 서버 목록에 있던 경로 (RTDB 이벤트의 "이 기기에서 새로 만든 파일" 판단용)
+    remote_listed: std::collections::HashSet<String>,
+    // 마지막 성공한 full_sync에서 양쪽에 있던 경로 (orphans 판단용, 영속)
+    synced_paths: SyncedPaths,
+    // 마지막 full_sync 결과 (실패 포함) / 실행 이후 누적
+    last_stats: Option<SyncStats>,
+    total_stats: SyncStats,
+    sync_runs: u64,
+    rtdb_connected: Option<bool>,
+    // 슬립에서 깨어난 뒤 RTDB 스트림 강제 재연결 요청 (start_wake_monitor → SSE 루프)
+    rtdb_reconnect: Arc<std::sync::atomic::AtomicBool>,
+    local_hashes: HashMap<String, String>,
+    local_content_cache: ContentCache,
+    // 마지막으로 받은 원격 modified (epoch ms)
+    remote_modified: HashMap<String, i64>,
+}
+
+impl SyncEngine {
+    fn new(config: &Config) -> Self {
+        let api = match config.storage_mode {
+            // 서명 헤더는 요구하지 않는 Vault에선 무시되므로 항상 첨부
+            StorageMode::PeerVault => ApiClient::new(&config.peer_url, "", &config.peer_token)
+                .with_request_signing(true),
+            _ => ApiClient::new(&config.api_base, &config.username, &config.api_token)
+                .with_path_prefix(&config.api_path_prefix)
+                .with_vault_name(config.vault_name.as_deref())
+                .with_encryption(config.encryption_enabled),
+        }
+        .with_max_upload_chunk(config.max_upload_chunk_bytes)
+        .with_hash_algo(config.hash_algo)
+        .with_device_id(&config.device_id)
+        .with_upload_compression(config.compress_uploads, config.compress_min_bytes);
+        Self::with_backend(config, Box::new(api))
+    }
+
+    /// Engine over any `RemoteBackend` (the default `new` picks `ApiClient` from config).
+    fn with_backend(config: &Config, api: Box<dyn RemoteBackend>) -> Self {
+        let audit = config
+            .audit_log
+            .as_deref()
+            .filter(|p| !p.is_empty())
+            .map(|p| Arc::new(AuditLog::open(p)));
+        let api: Box<dyn RemoteBackend> = match &audit {
+            Some(audit) => Box::new(AuditedBackend { inner: api, audit: audit.clone() }),
+            None => api,
+        };
+        Self {
+            api,
+            audit,
+            local_path: PathBuf::from(&config.local_path),
+            include_hidden: config.include_hidden,
+            extensions: config.extensions.clone(),
+            sync_webhook_url: config.sync_webhook_url.clone().filter(|u| !u.is_empty()),
+            disk_space_margin_mb: config.disk_space_margin_mb,
+            events: EventLog { system_log: config.system_log, ..Default::default() },
+            append_merge: build_globset(&config.append_merge_globs),
+            readonly: build_globset(&config.readonly_globs),
+            download_exclude: build_globset(&config.download_exclude_globs),
+            ignore: IgnoreMatcher::load(Path::new(&config.local_path)),
+            obsidian_sync: config.obsidian_sync,
+            flat_names: config.flatten_local.then(FlatNames::load),
+            delete_grace: Duration::from_millis(config.delete_grace_ms),
+            upload_idle: Duration::from_millis(config.upload_idle_ms),
+            upload_concurrency: config.upload_concurrency,
+            compress_uploads: config.compress_uploads,
+            sync_concurrency: config.sync_concurrency,
+            pending_first_backup: {
+                let key = vault_key(config);
+                (config.backup_before_first_sync && !config.first_sync_backups.contains(&key)).then_some(key)
+            },
+            upload_small_first: config.upload_small_first,
+            initial_authority: config.initial_sync_authority,
+            empty_init: {
+                let key = vault_key(config);
+                config.empty_vault_inits.get(&key).map(|date| (key, date.clone()))
+            },
+            seen_local_notes: false,
+            metadata_suffix: Some(config.metadata_suffix.clone()).filter(|s| !s.is_empty()),
+            verify_uploads: config.verify_uploads,
+            match_by_content: config.match_by_content,
+            recheck_before_download_write: config.recheck_before_download_write,
+            max_sync_files: config.max_sync_files,
+            max_sync_total_bytes: config.max_sync_total_bytes,
+            local_path_confirmed: config.confirmed_local_paths.contains(&config.local_path),
+            local_only_dir: Some(config.local_only_dir.trim_matches('/').to_string()).filter(|d| !d.is_empty()),
+            export_on_sync: config.export_on_sync,
+            max_deletes_per_sync: config.max_deletes_per_sync,
+            delete_policy: config.delete_policy,
+            hash_algo: config.hash_algo,
+            device_id: config.device_id.clone(),
+            sync_schedule: config.sync_schedule.clone(),
+            deferred_changes: Vec::new(),
+            pending_uploads: HashMap::new(),
+            pending_local_deletes: HashMap::new(),
+            upload_burst_limit: config.upload_burst_limit,
+            upload_burst_window: Duration::from_secs(config.upload_burst_window_secs),
+            upload_throttle: Duration::from_secs(config.upload_throttle_secs),
+            upload_history: HashMap::new(),
+            throttled_uploads: HashMap::new(),
+            conflicts: Vec::new(),
+            tombstones: Tombstones::load(&vault_key(config)),
+            tombstone_ttl_days: config.tombstone_ttl_days,
+            status: Arc::new(Mutex::new(SYNC_STATUS_OK.to_string())),
+            backoff_until: None,
+            last_sync: None,
+            remote_listed: std::collections::HashSet::new(),
+            synced_paths: SyncedPaths::load(&vault_key(config)),
+            last_stats: None,
+            total_stats: SyncStats::default(),
+            sync_runs: 0,
+            rtdb_connected: None,
+            rtdb_reconnect: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            local_hashes: HashMap::new(),
+            local_content_cache: ContentCache::new(config.content_blob_cache, config.mark_cache_dirs, config.max_cache_bytes),
+            remote_modified: HashMap::new(),
+        }
+    }
+
+    fn simple_hash(s: &str) -> String {
+        let mut hash: i32 = 0;
+        for c in s.chars() {
+            hash = ((hash << 5).wrapping_sub(hash)).wrapping_add(c as i32);
+        }
+        // JS의 hash.toString(36)과 동일한 base-36 출력
+        to_base36(hash)
+    }
+
+    /// Size a diff counts against `MAX_DIFF_UPLOAD_BYTES`: gzipped when `compress_uploads` is on.
+    fn diff_upload_size(&self, diff: &str) -> usize {
+        if self.compress_uploads {
+            gzip_bytes(diff.as_bytes()).map_or(diff.len(), |gz| gz.len())
+        } else {
+            diff.len()
+        }
+    }
+
+    /// Change-detection hash with the configured `hash_algo`.
+    fn content_hash(&self, s: &str) -> String {
+        self.hash_algo.hash(s)
+    }
+
+    /// Does the local copy of `path` have `server_hash`, computed with `algo` (another
+    /// device may use a different `hash_algo` than ours)?
+    fn local_matches_hash(&self, path: &str, server_hash: &str, algo: Option<&str>) -> bool {
+        match HashAlgo::from_name(algo) {
+            Some(algo) if algo == self.hash_algo => self.local_hashes.get(path).is_some_and(|h| h == server_hash),
+            Some(algo) => fs::read_to_string(self.local_file(path)).is_ok_and(|c| algo.hash(&c) == server_hash),
+            None => false,
+        }
+    }
+
+    fn flatten_files(items: &[FileItem]) -> Vec<(String, Option<String>, Option<u64>)> {
+        let mut result = Vec::new();
+        for item in items {
+            if item.file_type == "folder" {
+                if let Some(children) = &item.children {
+                    result.extend(Self::flatten_files(children));
+                }
+            } else if item.file_type == "file" {
+                result.push((item.path.clone(), item.modified.clone(), item.size));
+            }
+        }
+        result
+    }
+
+    /// Abort before downloading if the target filesystem can't hold `needed` bytes plus the margin.
+    fn ensure_free_space(&self, needed: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if needed == 0 {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.local_path).ok();
+        let available = fs2::available_space(&self.local_path)?;
+        let margin = self.disk_space_margin_mb * 1024 * 1024;
+        if available < needed.saturating_add(margin) {
+            let msg = format!(
+                "디스크 공간 부족: 다운로드 {} 필요, 사용 가능 {} (여유분 {} MB)",
+                format_bytes(needed),
+                format_bytes(available),
+                self.disk_space_margin_mb
+            );
+            notify_user(NotifyKind::DiskFull, &msg);
+            return Err(msg.into());
+        }
+        Ok(())
+    }
+
+    fn scan_local_md_files(&self) -> Vec<String> {
+        let notes: Vec<String> = flatten_file_paths(&scan_local_md_files(&self.local_path, self.include_hidden, &self.extensions))
+            .iter()
+            .filter(|local| !self.is_local_only(local))
+            .map(|local| self.remote_path(local))
+            .collect();
+        // 메타데이터 사이드카도 일반 파일처럼 업로드 대상
+        let sidecars: Vec<String> = notes
+            .iter()
+            .filter_map(|note| self.sidecar_path(note))
+            .filter(|sidecar| self.local_file(sidecar).exists())
+            .collect();
+        let obsidian = flatten_file_paths(&scan_obsidian_config(&self.local_path, self.obsidian_sync));
+        notes.into_iter().chain(sidecars).chain(obsidian).collect()
+    }
+
+    /// `foo.md` → `foo.md{metadata_suffix}` when sidecars are enabled.
+    fn sidecar_path(&self, note: &str) -> Option<String> {
+        let suffix = self.metadata_suffix.as_ref()?;
+        note.ends_with(".md").then(|| format!("{}{}", note, suffix))
+    }
+
+    /// Write downloaded content, recording its hash *before* the write so the watcher
+    /// event it triggers matches `local_hashes` and isn't uploaded back (feedback loop).
+    /// On failure the previous hash is restored.
+    fn write_downloaded(&mut self, path: &str, local_file: &Path, content: String) -> std::io::Result<()> {
+        if self.is_local_only(path) {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "local-only folder"));
+        }
+        if self.is_download_excluded(path) {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "download excluded"));
+        }
+        if self.is_sync_ignored(path) {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "ignored (.mdflareignore)"));
+        }
+        let prev_hash = self.local_hashes.insert(path.to_string(), self.content_hash(&content));
+        let result = write_local_file(local_file, &content);
+        match (&result, prev_hash) {
+            (Ok(()), _) => {
+                if let Some(audit) = &self.audit {
+                    audit.record("download", "down", path, Some(&content), None);
+                }
+                self.local_content_cache.insert(path.to_string(), content);
+            }
+            (Err(_), Some(hash)) => {
+                self.local_hashes.insert(path.to_string(), hash);
+            }
+            (Err(_), None) => {
+                self.local_hashes.remove(path);
+            }
+        }
+        result
+    }
+
+    /// Fetch the note's sidecar after the note itself was downloaded (missing on server → ignore).
+    fn download_sidecar(&mut self, note: &str) {
+        let Some(sidecar) = self.sidecar_path(note) else {
+            return;
+        };
+        if let Ok(content) = self.api.get_file(&sidecar) {
+            let local_file = self.assign_local_file(&sidecar);
+            self.write_downloaded(&sidecar, &local_file, content.content).ok();
+        }
+    }
+
+    /// Upload the note's sidecar along with the note (no-op when unchanged).
+    fn upload_sidecar(&mut self, note: &str) {
+        if let Some(sidecar) = self.sidecar_path(note) {
+            let local_file = self.local_file(&sidecar);
+            if local_file.exists() {
+                self.upload_local_file(&sidecar, &local_file);
+            }
+        }
+    }
+
+    /// Delete the note's sidecar locally and remotely (note was deleted).
+    fn delete_sidecar(&mut self, note: &str) {
+        let Some(sidecar) = self.sidecar_path(note) else {
+            return;
+        };
+        let local_file = self.local_file(&sidecar);
+        if local_file.exists() {
+            fs::remove_file(&local_file).ok();
+        }
+        if self.local_hashes.remove(&sidecar).is_some() {
+            self.local_content_cache.remove(&sidecar);
+            self.api.delete_file(&sidecar).ok();
+            self.forget_flat_name(&sidecar);
+        }
+    }
+
+    /// `foo.md` → `foo.html` / `foo.txt` when `export_on_sync` is set.
+    fn export_path(&self, note: &str) -> Option<String> {
+        let format = self.export_on_sync?;
+        note.strip_suffix(".md").map(|stem| format!("{}.{}", stem, format.extension()))
+    }
+
+    /// The note a derived export would come from (`foo.html` → `foo.md`).
+    fn export_source(&self, path: &str) -> Option<String> {
+        let format = self.export_on_sync?;
+        path.strip_suffix(&format!(".{}", format.extension())).map(|stem| format!("{}.md", stem))
+    }
+
+    /// Drop remote derived exports (their note is also listed) - never downloaded.
+    fn without_exports(&self, items: Vec<(String, Option<String>, Option<u64>)>) -> Vec<(String, Option<String>, Option<u64>)> {
+        if self.export_on_sync.is_none() {
+            return items;
+        }
+        let paths: std::collections::HashSet<String> = items.iter().map(|(p, _, _)| p.clone()).collect();
+        items
+            .into_iter()
+            .filter(|(path, _, _)| self.export_source(path).is_none_or(|note| !paths.contains(&note)))
+            .collect()
+    }
+
+    /// Render and upload the note's derived export (remote only).
+    fn upload_export(&self, note: &str, content: &str) {
+        let (Some(format), Some(export)) = (self.export_on_sync, self.export_path(note)) else {
+            return;
+        };
+        match self.api.put_file(&export, &format.render(note, content)) {
+            Ok(()) => self.events.push("export", &export, note),
+            Err(e) => self.events.push("error", &export, &format!("export: {}", e)),
+        }
+    }
+
+    fn delete_export(&self, note: &str) {
+        if let Some(export) = self.export_path(note) {
+            self.api.delete_file(&export).ok();
+        }
+    }
+
+    /// Local file for a remote path (flatten_local: mapped flat name).
+    fn local_file(&self, remote: &str) -> PathBuf {
+        match &self.flat_names {
+            Some(flat) => {
+                let name = flat
+                    .local_for(remote)
+                    .map(String::as_str)
+                    .unwrap_or_else(|| remote.rsplit('/').next().unwrap_or(remote));
+                self.local_path.join(name)
+            }
+            None => self.local_path.join(remote),
+        }
+    }
+
+    /// Like `local_file`, but reserves a collision-free flat name before writing.
+    fn assign_local_file(&mut self, remote: &str) -> PathBuf {
+        match self.flat_names.as_mut() {
+            Some(flat) => self.local_path.join(flat.assign(remote)),
+            None => self.local_path.join(remote),
+        }
+    }
+
+    fn forget_flat_name(&mut self, remote: &str) {
+        if let Some(flat) = self.flat_names.as_mut() {
+            flat.names.retain(|_, r| r != remote);
+            flat.save();
+        }
+    }
+
+    /// Remote path for a local relative path (flatten_local: reverse of the name mapping).
+    fn remote_path(&self, local: &str) -> String {
+        self.flat_names
+            .as_ref()
+            .and_then(|flat| flat.names.get(local).cloned())
+            .unwrap_or_else(|| local.to_string())
+    }
+
+    /// Remaining 429 back-off, if the server asked us to wait.
+    fn rate_limit_remaining(&self) -> Option<Duration> {
+        self.backoff_until
+            .map(|until| until.saturating_duration_since(std::time::Instant::now()))
+            .filter(|d| !d.is_zero())
+    }
+
+    fn set_status(&self, text: &str) {
+        if let Ok(mut status) = self.status.lock() {
+            *status = text.to_string();
+        }
+    }
+
+    fn full_sync(&mut self) -> Result<SyncStats, Box<dyn std::error::Error>> {
+        if let Some(remaining) = self.rate_limit_remaining() {
+            return Err(Box::new(RateLimited { retry_after: remaining }));
+        }
+        if let Some(key) = self.pending_first_backup.clone() {
+            // 동기화 대상 노트만 (무시 규칙 적용) - 실패해도 알리고 동기화는 계속
+            let files: Vec<PathBuf> = self
+                .scan_local_md_files()
+                .iter()
+                .filter(|path| !self.is_sync_ignored(path))
+                .map(|path| self.local_file(path))
+                .collect();
+            match backup_local_folder(&self.local_path, &files, FIRST_SYNC_BACKUP_MAX_BYTES) {
+                Ok(Some(archive)) => {
+                    println!("💾 첫 동기화 전 백업: {}", archive.display());
+                    log_to_file(&format!("backup: {} → {}", key, archive.display()));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log_to_file(&format!("backup: {} failed: {}", key, e));
+                    notify_user(NotifyKind::Warning, &format!("💾 첫 동기화 전 백업 실패 (동기화는 계속): {}", e));
+                }
+            }
+            let mut config = Config::load();
+            config.first_sync_backups.push(key);
+            config.save();
+            self.pending_first_backup = None;
+        }
+        let started = std::time::Instant::now();
+        let mut stats = SyncStats::default();
+        let result = self.sync_once(&mut stats);
+        match &result {
+            Ok(()) => {
+                if let Some(authority) = self.initial_authority.take() {
+                    log_to_file(&format!("sync: initial authority {:?} applied", authority));
+                    let mut config = Config::load();
+                    config.initial_sync_authority = None;
+                    config.save();
+                }
+                if self.empty_init.is_some() && self.seen_local_notes {
+                    // 노트가 생겼으니 이후로는 빈 폴더를 다시 이상 징후로 취급
+                    if let Some((key, date)) = self.empty_init.take() {
+                        log_to_file(&format!("sync: empty vault init ({}) cleared for {}", date, key));
+                        let mut config = Config::load();
+                        config.empty_vault_inits.remove(&key);
+                        config.save();
+                    }
+                }
+                self.backoff_until = None;
+                self.last_sync = Some(chrono::Local::now());
+                self.remember_synced_paths();
+                self.set_status(SYNC_STATUS_OK);
+            }
+            Err(e) => {
+                if let Some(limited) = e.downcast_ref::<RateLimited>() {
+                    log_to_file(&format!("sync: 429 → back off {}s", limited.retry_after.as_secs()));
+                    self.backoff_until = Some(std::time::Instant::now() + limited.retry_after);
+                    self.set_status(SYNC_STATUS_RATE_LIMITED);
+                } else if e.is::<AuthExpired>() {
+                    notify_user(NotifyKind::AuthExpired, "로그인이 만료되었습니다. 다시 로그인해 주세요.");
+                } else {
+                    notify_user(NotifyKind::SyncError, &format!("동기화 실패: {}", e));
+                }
+                stats.errors += 1;
+            }
+        }
+        stats.duration = started.elapsed();
+        log_to_file(&format!("sync: {} ({}ms)", stats.summary(), stats.duration.as_millis()));
+
+        if let Some(url) = &self.sync_webhook_url {
+            let mut payload = stats.to_json();
+            payload["timestamp"] = serde_json::json!(chrono::Utc::now().to_rfc3339());
+            post_sync_webhook(url, payload);
+        }
+        self.total_stats.add(&stats);
+        self.sync_runs += 1;
+        self.last_stats = Some(stats.clone());
+        result.map(|()| stats)
+    }
+
+    fn sync_once(&mut self, stats: &mut SyncStats) -> Result<(), Box<dyn std::error::Error>> {
+        self.refresh_ignore();
+        let remote_files = self.api.list_files()?;
+        let remote_items: Vec<_> = Self::flatten_files(&remote_files)
+            .into_iter()
+            .filter(|(path, _, _)| !self.is_local_only(path) && !self.is_sync_ignored(path))
+            .collect();
+        let remote_items = self.without_exports(remote_items);
+        let remote_paths: Vec<String> = remote_items.iter().map(|(p, _, _)| p.clone()).collect();
+        self.remote_listed = remote_paths.iter().cloned().collect();
+
+        let mut local_paths = self.scan_local_md_files();
+        if let Some(problem) = self.empty_side_problem(local_paths.is_empty(), remote_items.is_empty()) {
+            // 드라이브 분리/마운트 실패로 폴더가 비면 서버 내용을 엉뚱한 디스크에 전부 받게 됨
+            log_to_file(&format!("sync: empty-side guard: {}", problem));
+            notify_user(NotifyKind::Warning, &problem);
+            return Err(problem.into());
+        }
+        self.seen_local_notes |= !local_paths.is_empty();
+        // 매 스캔마다 검사 (vault가 커지는 경우도 막음) - 이 폴더로 계속하겠다고 확인했으면 생략
+        if !self.local_path_confirmed && (self.max_sync_files > 0 || self.max_sync_total_bytes > 0) {
+            let total: u64 = local_paths.iter().filter_map(|p| fs::metadata(self.local_file(p)).ok()).map(|m| m.len()).sum();
+            if let Some(problem) = sync_limit_excess(local_paths.len(), total, self.max_sync_files, self.max_sync_total_bytes) {
+                log_to_file(&format!("sync: limit exceeded: {} ({})", problem, self.local_path.display()));
+                self.set_status(SYNC_STATUS_LIMIT_EXCEEDED);
+                return Err(problem.into());
+            }
+        }
+        self.reconcile_by_content(&remote_items, &mut local_paths);
+        let authority = self.initial_authority;
+
+        // 첫 동기화 로컬 기준: 양쪽에 있는 파일은 다운로드하지 않고 로컬 내용으로 덮어씀
+        let mut local_wins = Vec::new();
+        let pending: Vec<_> = remote_items
+            .iter()
+            .filter(|(path, modified, _)| {
+                if self.pending_local_deletes.contains_key(path) {
+                    // 삭제 확정 대기 중 → 다시 받지 않음 (flush_idle_uploads가 처리)
+                    false
+                } else if !self.local_file(path).exists() {
+                    true
+                } else if self.is_download_excluded(path) {
+                    false
+                } else if authority == Some(SyncAuthority::Local) {
+                    local_wins.push(path.clone());
+                    false
+                } else if authority == Some(SyncAuthority::Cloud) {
+                    true
+                } else if self.is_readonly(path) && self.has_unsynced_local_edit(path) {
+                    // 읽기 전용 파일의 로컬 수정 → 서버 버전으로 되돌림
+                    true
+                } else if let Some(mod_ms) = modified.as_deref().and_then(parse_modified) {
+                    self.remote_modified
+                        .get(path)
+                        .is_none_or(|seen| (seen - mod_ms).abs() > MODIFIED_TOLERANCE_MS)
+                } else {
+                    false
+                }
+            })
+            .collect();
+
+        // 다운로드 전 디스크 여유 공간 확인 (중간에 가득 차서 부분 동기화되는 것 방지)
+        let needed: u64 = pending.iter().filter_map(|(_, _, size)| *size).sum();
+        self.ensure_free_space(needed)?;
+
+        // 서버 → 로컬: 받기는 동시에, 결과 반영은 목록 순서대로
+        let mut fetched = self.fetch_all(pending.iter().map(|(path, _, size)| (path.clone(), *size)).collect());
+        let mut rate_limited = None;
+        for (path, modified, _) in pending {
+            // 429로 중단돼 못 받은 파일은 다음 동기화에서
+            let Some(outcome) = fetched.remove(path) else { continue };
+            let local_file = self.assign_local_file(path);
+            match outcome {
+                DownloadOutcome::Fetched(content) => {
+                    let remote_ms = modified.as_deref().and_then(parse_modified);
+                    // 첫 동기화 클라우드 기준이면 병합 없이 서버 내용 그대로
+                    let conflict = authority != Some(SyncAuthority::Cloud) && self.has_unsynced_local_edit(path);
+                    if conflict && self.is_readonly(path) {
+                        notify_user(NotifyKind::Warning, &format!("🔒 읽기 전용 파일 수정을 되돌렸습니다: {}", path));
+                    } else if conflict {
+                        stats.conflicts += 1;
+                    }
+                    stats.bytes_down += content.content.len() as u64;
+                    let resolved = self
+                        .resolve_or_keep_local(path, &local_file, content.content, remote_ms, conflict)
+                        .and_then(|content| {
+                            // 판단 이후 쓰기 직전까지 로컬이 수정됐으면 (동시 편집) 덮어쓰지 않고 충돌 처리
+                            if authority == Some(SyncAuthority::Cloud)
+                                || self.is_readonly(path)
+                                || !self.edited_during_download(path, conflict)
+                            {
+                                return Some(content);
+                            }
+                            stats.conflicts += 1;
+                            self.resolve_or_keep_local(path, &local_file, content, remote_ms, true)
+                        });
+                    let Some(content) = resolved else {
+                        if let Some(mod_ms) = remote_ms {
+                            self.remote_modified.insert(path.clone(), mod_ms);
+                        }
+                        continue;
+                    };
+                    if let Err(e) = self.write_downloaded(path, &local_file, content) {
+                        log::error!("파일 쓰기 실패 {}: {}", path, e);
+                        self.events.push("error", path, &format!("write: {}", e));
+                        stats.errors += 1;
+                        continue;
+                    }
+                    if let Some(mod_ms) = remote_ms {
+                        self.remote_modified.insert(path.clone(), mod_ms);
+                    }
+                    println!("⬇️ {}", path);
+                    self.events.push("download", path, "full_sync");
+                    if !remote_paths.iter().any(|p| Some(p) == self.sidecar_path(path).as_ref()) {
+                        // 목록에 사이드카가 안 나오는 서버 → 노트와 함께 직접 가져옴
+                        self.download_sidecar(path);
+                    }
+                    stats.downloaded += 1;
+                }
+                DownloadOutcome::RateLimited(retry_after) => rate_limited = Some(retry_after),
+                DownloadOutcome::Failed(e) => {
+                    log::error!("파일 다운로드 실패 {}: {}", path, e);
+                    self.events.push("error", path, &format!("download: {}", e));
+                    stats.errors += 1;
+                }
+            }
+        }
+        if let Some(retry_after) = rate_limited {
+            return Err(Box::new(RateLimited { retry_after }));
+        }
+
+        for path in local_wins {
+            let local_file = self.local_file(&path);
+            let content = match fs::read_to_string(&local_file) {
+                Ok(content) => content,
+                Err(e) => {
+                    log::error!("파일 읽기 실패 {}: {}", path, e);
+                    stats.errors += 1;
+                    continue;
+                }
+            };
+            match self.api.put_file(&path, &content).and_then(|()| Ok(self.verify_upload(&path, &content)?)) {
+                Ok(()) => {
+                    self.upload_export(&path, &content);
+                    stats.bytes_up += content.len() as u64;
+                    self.local_hashes.insert(path.clone(), self.content_hash(&content));
+                    self.local_content_cache.insert(path.clone(), content);
+                    println!("⬆️ {} (로컬 기준)", path);
+                    self.events.push("upload", &path, "initial authority: local");
+                    stats.uploaded += 1;
+                }
+                Err(e) => {
+                    log::error!("파일 업로드 실패 {}: {}", path, e);
+                    self.events.push("error", &path, &format!("upload: {}", e));
+                    stats.errors += 1;
+                }
+            }
+        }
+
+        self.refresh_tombstones();
+
+        // 로컬 → 서버
+        let mut to_upload: Vec<(String, PathBuf)> = Vec::new();
+        let mut tombstoned: Vec<(String, PathBuf)> = Vec::new();
+        for path in &local_paths {
+            if !remote_paths.contains(path) && !self.is_readonly(path) {
+                let local_file = self.local_file(path);
+                if self.is_tombstoned(path, &local_file) && !self.is_download_excluded(path) {
+                    tombstoned.push((path.clone(), local_file));
+                    continue;
+                }
+                to_upload.push((path.clone(), local_file));
+            }
+        }
+        if self.exceeds_delete_limit(tombstoned.len()) {
+            // 대량 삭제는 잘못된 tombstone일 가능성 → 지우지도 올리지도 않고 확인 요청
+            let msg = format!("삭제 예정 파일이 {}개로 한도({})를 넘어 건너뜀", tombstoned.len(), self.max_deletes_per_sync);
+            log_to_file(&format!("sync: {}", msg));
+            notify_user(NotifyKind::Warning, &msg);
+        } else {
+            for (path, local_file) in tombstoned {
+                // 다른 기기에서 삭제된 파일 → 재업로드 대신 검토 대기로 (delete_policy와 무관하게 바로 지우지 않음)
+                if self.stage_pending_delete(&path, &local_file) {
+                    self.local_hashes.remove(&path);
+                    self.local_content_cache.remove(&path);
+                    println!("🗑️ {} (tombstone)", path);
+                    self.events.push("delete", &path, "tombstone");
+                    if let Some(audit) = &self.audit {
+                        audit.record("delete", "down", &path, None, None);
+                    }
+                    stats.deleted += 1;
+                }
+            }
+        }
+        if self.upload_small_first {
+            // 작은 파일부터 → 대량 가져오기 시 진행이 빨리 보임
+            to_upload.sort_by_key(|(_, file)| fs::metadata(file).map(|m| m.len()).unwrap_or(u64::MAX));
+        }
+
+        let mut rate_limited = None;
+        for (path, outcome) in self.upload_all(to_upload) {
+            match outcome {
+                UploadOutcome::Uploaded(content) => {
+                    self.upload_export(&path, &content);
+                    stats.bytes_up += content.len() as u64;
+                    self.local_hashes.insert(path.clone(), self.content_hash(&content));
+                    self.local_content_cache.insert(path.clone(), content);
+                    println!("⬆️ {}", path);
+                    self.events.push("upload", &path, "full_sync");
+                    stats.uploaded += 1;
+                }
+                UploadOutcome::AlreadyExists(local) => {
+                    if self.adopt_remote_copy(&path, local) {
+                        stats.conflicts += 1;
+                    }
+                }
+                UploadOutcome::ReadFailed(e) => {
+                    log::error!("파일 읽기 실패 {}: {}", path, e);
+                    self.events.push("error", &path, &format!("read: {}", e));
+                    stats.errors += 1;
+                }
+                UploadOutcome::Failed(e) => {
+                    log::error!("파일 업로드 실패 {}: {}", path, e);
+                    self.events.push("error", &path, &format!("upload: {}", e));
+                    stats.errors += 1;
+                }
+                UploadOutcome::RateLimited(retry_after) => rate_limited = Some(retry_after),
+            }
+        }
+        if let Some(retry_after) = rate_limited {
+            return Err(Box::new(RateLimited { retry_after }));
+        }
+
+        self.api.put_heartbeat();
+        Ok(())
+    }
+
+    /// A local-only and a remote-only file with identical content (paths differing only in
+    /// case or Unicode normalization) are the same note: move the local file to the remote
+    /// path instead of uploading one and downloading the other.
+    fn reconcile_by_content(&mut self, remote_items: &[(String, Option<String>, Option<u64>)], local_paths: &mut [String]) {
+        // flatten_local은 이름 매핑이 따로 있어 제외
+        if !self.match_by_content || self.flat_names.is_some() {
+            return;
+        }
+        let mut local_only: Vec<(usize, u64)> = local_paths
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| !remote_items.iter().any(|(r, _, _)| r == *p))
+            .filter_map(|(i, p)| fs::metadata(self.local_file(p)).ok().map(|m| (i, m.len())))
+            .collect();
+        for (remote, modified, size) in remote_items {
+            if local_only.is_empty() {
+                break;
+            }
+            if local_paths.contains(remote) {
+                continue;
+            }
+            // 목록에 해시가 없으므로 크기가 같은 후보가 있을 때만 서버 내용을 받아 비교
+            let Some(size) = size else { continue };
+            if !local_only.iter().any(|(_, len)| len == size) {
+                continue;
+            }
+            let Ok(file) = self.api.get_file(remote) else { continue };
+            let hash = self.content_hash(&file.content);
+            let Some(pos) = local_only.iter().position(|(i, len)| {
+                len == size
+                    && fs::read_to_string(self.local_file(&local_paths[*i]))
+                        .is_ok_and(|c| self.content_hash(&c) == hash)
+            }) else {
+                continue;
+            };
+            let (i, _) = local_only.remove(pos);
+            let local = local_paths[i].clone();
+            let target = self.local_file(remote);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).ok();
+            }
+            if let Err(e) = fs::rename(self.local_file(&local), &target) {
+                log::error!("경로 맞춤 실패 {} → {}: {}", local, remote, e);
+                continue;
+            }
+            self.move_cached_state(&local, remote);
+            self.local_hashes.insert(remote.clone(), hash);
+            self.local_content_cache.insert(remote.clone(), file.content);
+            if let Some(mod_ms) = modified.as_deref().and_then(parse_modified) {
+                self.remote_modified.insert(remote.clone(), mod_ms);
+            }
+            local_paths[i] = remote.clone();
+            println!("🔗 {} → {} (같은 내용)", local, remote);
+            self.events.push("rename", remote, &format!("matched by content: {}", local));
+            if let Some(audit) = &self.audit {
+                audit.record("rename", "down", remote, None, Some(&local));
             }
+        }
+    }
+
+    /// Another device created `path` first: take the remote version. If our copy
+    /// differs and can't be append-merged, it is kept as `<name>.conflict-<ts>.md`
+    /// (returns true in that case).
+    fn adopt_remote_copy(&mut self, path: &str, local: String) -> bool {
+        let remote = match self.api.get_file(path) {
+            Ok(file) => file.content,
             Err(e) => {
-                println!("⚠️ 터널 연결 실패 (로컬만 사용): {}", e);
+                log::error!("R2 fetch 실패 {}: {}", path, e);
+                self.events.push("error", path, &format!("r2 fetch: {}", e));
+                return false;
+            }
+        };
+        let mut kept_copy = false;
+        let content = if remote == local {
+            remote
+        } else {
+            let merged = self.resolve_incoming(path, remote.clone(), None, true);
+            if merged == remote {
+                let copy = conflict_copy_path(&self.local_file(path));
+                fs::write(&copy, &local).ok();
+                println!("⚠️ {} 다른 기기가 먼저 생성 → 내 사본: {}", path, copy.display());
+                notify_user(NotifyKind::Conflict, &format!("⚠️ 충돌: {} (내 사본: {})", path, copy.display()));
+                self.events.push("conflict", path, &format!("local copy → {}", copy.display()));
+                kept_copy = true;
+            }
+            merged
+        };
+        let local_file = self.local_file(path);
+        if self.write_downloaded(path, &local_file, content).is_ok() {
+            println!("⬇️ {} (already created remotely)", path);
+            self.events.push("download", path, "claim lost (412)");
+        }
+        kept_copy
+    }
+
+    /// `verify_uploads`: read the server copy back; on mismatch re-send the full content once.
+    fn verify_upload(&self, path: &str, sent: &str) -> Result<(), String> {
+        if !self.verify_uploads {
+            return Ok(());
+        }
+        let check = || -> Result<(), String> {
+            let stored = self.api.get_file(path).map_err(|e| format!("verify: {}", e))?.content;
+            if stored == sent {
+                return Ok(());
             }
+            let msg = format!(
+                "verify mismatch {}: sent {}B #{}, server {}B #{}",
+                path,
+                sent.len(),
+                self.content_hash(sent),
+                stored.len(),
+                self.content_hash(&stored)
+            );
+            log_to_file(&msg);
+            Err(msg)
+        };
+        if let Err(msg) = check() {
+            eprintln!("⚠️ {} → 재업로드", msg);
+            self.api.put_file(path, sent).map_err(|e| format!("verify re-upload: {}", e))?;
+            check()?;
         }
-    });
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
+        Ok(())
+    }
 
-// cloudflared Quick Tunnel 시작
-async fn start_tunnel(local_port: u16, token: &str) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
-    use std::process::Stdio;
-    use tokio::process::Command;
-    use tokio::io::{BufReader, AsyncBufReadExt};
-    
-    let mut child = Command::new("cloudflared")
-        .args(["tunnel", "--url", &format!("http://localhost:{}", local_port)])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-    
-    let stderr = child.stderr.take().ok_or("stderr 없음")?;
-    let mut reader = BufReader::new(stderr).lines();
-    
-    // URL 파싱 (stderr에서 trycloudflare.com URL 찾기)
-    let url = loop {
-        if let Some(line) = reader.next_line().await? {
-            if line.contains("trycloudflare.com") {
-                // URL 추출: https://xxx.trycloudflare.com
-                if let Some(start) = line.find("https://") {
-                    let url_part = &line[start..];
-                    if let Some(end) = url_part.find(|c: char| c.is_whitespace() || c == '|') {
-                        break url_part[..end].to_string();
-                    } else {
-                        break url_part.trim().to_string();
+    /// Upload files with up to `upload_concurrency` workers. Results are applied to
+    /// the caches by the caller, so workers only share the (immutable) API client.
+    fn upload_all(&self, files: Vec<(String, PathBuf)>) -> Vec<(String, UploadOutcome)> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let queue = Mutex::new(files.into_iter());
+        let results = Mutex::new(Vec::new());
+        let stop = AtomicBool::new(false);
+        let workers = self.upload_concurrency.max(1);
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let Some((path, file)) = queue.lock().unwrap().next() else { break };
+                    let outcome = match fs::read_to_string(&file) {
+                        Ok(content) => match self.api.create_file(&path, &content) {
+                            Ok(true) => match self.verify_upload(&path, &content) {
+                                Ok(()) => UploadOutcome::Uploaded(content),
+                                Err(e) => UploadOutcome::Failed(e),
+                            },
+                            Ok(false) => UploadOutcome::AlreadyExists(content),
+                            Err(e) => match e.downcast_ref::<RateLimited>() {
+                                Some(limited) => {
+                                    // 429 → 남은 업로드 중단
+                                    stop.store(true, Ordering::Relaxed);
+                                    UploadOutcome::RateLimited(limited.retry_after)
+                                }
+                                None => UploadOutcome::Failed(e.to_string()),
+                            },
+                        },
+                        Err(e) => UploadOutcome::ReadFailed(e.to_string()),
+                    };
+                    results.lock().unwrap().push((path, outcome));
+                });
+            }
+        });
+
+        results.into_inner().unwrap()
+    }
+
+    /// Download files with up to `sync_concurrency` workers. Only the (immutable) API client
+    /// is shared; the caller applies results to the caches and the local folder.
+    fn fetch_all(&self, files: Vec<(String, Option<u64>)>) -> HashMap<String, DownloadOutcome> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let workers = self.sync_concurrency.clamp(1, files.len().max(1));
+        let queue = Mutex::new(files.into_iter());
+        let results = Mutex::new(HashMap::new());
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
                     }
+                    let Some((path, size)) = queue.lock().unwrap().next() else { break };
+                    // 큰 파일은 끊겨도 이어받을 수 있게 raw + Range
+                    let fetched = if size.is_some_and(|s| s >= RESUMABLE_MIN_BYTES) {
+                        self.api.get_file_resumable(&path)
+                    } else {
+                        self.api.get_file(&path)
+                    };
+                    let outcome = match fetched {
+                        Ok(content) => DownloadOutcome::Fetched(content),
+                        Err(e) => match e.downcast_ref::<RateLimited>() {
+                            Some(limited) => {
+                                // 429 → 남은 다운로드 중단
+                                stop.store(true, Ordering::Relaxed);
+                                DownloadOutcome::RateLimited(limited.retry_after)
+                            }
+                            None => DownloadOutcome::Failed(e.to_string()),
+                        },
+                    };
+                    results.lock().unwrap().insert(path, outcome);
+                });
+            }
+        });
+
+        results.into_inner().unwrap()
+    }
+
+    /// Merge server-side deletions into the local tombstone list and prune expired ones.
+    fn refresh_tombstones(&mut self) {
+        let ttl_ms = (self.tombstone_ttl_days as i64) * 86_400_000;
+        let since = chrono::Utc::now().timestamp_millis() - ttl_ms;
+        match self.api.list_deletions(since) {
+            Ok(deletions) => {
+                for d in deletions {
+                    let tombstone = Tombstone { deleted_at: d.deleted_at, hash: d.hash, algo: d.algo };
+                    self.tombstones.record(&d.path, tombstone);
                 }
             }
-        } else {
-            return Err("cloudflared URL을 받지 못함".into());
+            Err(e) => log::warn!("삭제 기록 조회 실패: {}", e),
         }
-    };
-    
-    let external_token = generate_connection_token_with_url(&url, token);
-    
-    // 프로세스 유지 (백그라운드) - stderr 계속 읽어서 drain
-    tokio::spawn(async move {
-        // stderr를 계속 읽어서 프로세스가 block되지 않도록 함
-        while let Ok(Some(_)) = reader.next_line().await {}
-        let _ = child.wait().await;
-    });
-    
-    Ok((url, external_token))
-}
+        self.tombstones.prune(self.tombstone_ttl_days);
+        self.tombstones.save();
+    }
 
-// URL 기반 연결 토큰 생성
-fn generate_connection_token_with_url(url: &str, token: &str) -> String {
-    use base64::{Engine as _, engine::general_purpose::STANDARD};
-    let plain = format!("{}|{}", url, token);
-    STANDARD.encode(plain.as_bytes())
-}
+    /// Apply a delete that came from another device: remove the local file, or with
+    /// `delete_policy = review` move it to `PENDING_DELETES_DIR` until approved or rejected.
+    fn remove_for_remote_delete(&mut self, path: &str, local_file: &Path) -> bool {
+        if self.delete_policy == DeletePolicy::Apply {
+            return fs::remove_file(local_file).is_ok();
+        }
+        self.stage_pending_delete(path, local_file)
+    }
 
-// ============================================================================
-// Sync Engine (Cloud 모드용)
-// ============================================================================
+    /// Move `local_file` into `PENDING_DELETES_DIR` and tell the user it awaits review.
+    fn stage_pending_delete(&mut self, path: &str, local_file: &Path) -> bool {
+        let staged = self.local_path.join(PENDING_DELETES_DIR).join(path);
+        if staged.exists() {
+            // 같은 경로가 이미 검토 대기 중 → 같은 내용이면 로컬만 정리, 다르면 덮어쓰지 않고 로컬 유지
+            let same = matches!((fs::read(&staged), fs::read(local_file)), (Ok(a), Ok(b)) if a == b);
+            if same {
+                return fs::remove_file(local_file).is_ok();
+            }
+            log_to_file(&format!("delete review: {} already staged with other content, kept local", path));
+            notify_user(
+                NotifyKind::PendingDelete,
+                &format!("🗑️ {} 는 검토 대기 중인 이전 사본이 있어 삭제를 보류했습니다 (mdflare-agent deletes)", path),
+            );
+            return false;
+        }
+        if let Some(parent) = staged.parent() {
+            fs::create_dir_all(parent).ok();
+        }
+        if let Err(e) = fs::rename(local_file, &staged) {
+            log::error!("삭제 검토 대기로 옮기기 실패 {}: {}", path, e);
+            return false;
+        }
+        log_to_file(&format!("delete review: {} staged", path));
+        let pending = self.pending_deletes().len();
+        notify_user(
+            NotifyKind::PendingDelete,
+            &format!("🗑️ 다른 기기에서 삭제된 파일 {}개가 검토 대기 중 (mdflare-agent deletes)", pending),
+        );
+        true
+    }
 
-// ============================================================================
-// RTDB types and diff helpers
-// ============================================================================
+    /// Remote paths waiting in `PENDING_DELETES_DIR` (delete_policy = review).
+    fn pending_deletes(&self) -> Vec<String> {
+        let dir = self.local_path.join(PENDING_DELETES_DIR);
+        let mut paths: Vec<String> = walkdir::WalkDir::new(&dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| e.path().strip_prefix(&dir).ok().map(|rel| rel.to_string_lossy().replace('\\', "/")))
+            .collect();
+        paths.sort();
+        paths
+    }
 
-#[derive(Debug, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-struct RtdbFileEntry {
-    path: String,
-    action: String,
-    #[allow(dead_code)]
-    hash: Option<String>,
-    old_hash: Option<String>,
-    diff: Option<Vec<serde_json::Value>>,
-    old_path: Option<String>,
-    #[allow(dead_code)]
-    modified: Option<u64>,
-    #[allow(dead_code)]
-    size: Option<u64>,
-}
+    /// Requested staged paths (all when empty); anything that could escape the staging folder is dropped.
+    fn pending_delete_targets(&self, paths: &[String]) -> Vec<String> {
+        use std::path::Component;
+        if paths.is_empty() {
+            return self.pending_deletes();
+        }
+        paths
+            .iter()
+            .map(|p| p.trim_matches('/').to_string())
+            .filter(|p| !p.is_empty() && Path::new(p).components().all(|c| matches!(c, Component::Normal(_))))
+            .collect()
+    }
 
-/// Apply a line-based diff to content.
-/// diff ops: {"eq": N}, {"del": N}, {"ins": ["line1", ...]}
-fn apply_line_diff(old_content: &str, diff: &[serde_json::Value]) -> Option<String> {
-    let old_lines: Vec<&str> = old_content.split('\n').collect();
-    let mut result = Vec::new();
-    let mut pos = 0;
+    /// Approve staged deletes (`paths` empty = all): the files are removed for good.
+    fn approve_pending_deletes(&mut self, paths: &[String]) -> usize {
+        let dir = self.local_path.join(PENDING_DELETES_DIR);
+        let mut done = 0;
+        for path in self.pending_delete_targets(paths) {
+            if fs::remove_file(dir.join(&path)).is_ok() {
+                log_to_file(&format!("delete review: {} approved", path));
+                self.events.push("delete", &path, "review approved");
+                done += 1;
+            }
+        }
+        remove_empty_dirs(&dir);
+        done
+    }
 
-    for op in diff {
-        if let Some(eq) = op.get("eq").and_then(|v| v.as_u64()) {
-            let eq = eq as usize;
-            if pos + eq > old_lines.len() {
-                return None; // diff doesn't match
+    /// Reject staged deletes (`paths` empty = all): move each file back and upload it again.
+    fn reject_pending_deletes(&mut self, paths: &[String]) -> usize {
+        let dir = self.local_path.join(PENDING_DELETES_DIR);
+        let mut done = 0;
+        for path in self.pending_delete_targets(paths) {
+            let staged = dir.join(&path);
+            let local_file = self.assign_local_file(&path);
+            if !staged.is_file() {
+                continue;
             }
-            result.extend_from_slice(&old_lines[pos..pos + eq]);
-            pos += eq;
-        } else if let Some(del) = op.get("del").and_then(|v| v.as_u64()) {
-            let del = del as usize;
-            if pos + del > old_lines.len() {
-                return None;
+            if local_file.exists() {
+                // 그 사이 같은 경로에 새 파일이 생김 → 덮어쓰지 않고 대기 상태 유지
+                println!("⚠️ {} 이미 있음 - 복원 건너뜀", path);
+                continue;
             }
-            pos += del;
-        } else if let Some(ins) = op.get("ins").and_then(|v| v.as_array()) {
-            for line in ins {
-                if let Some(s) = line.as_str() {
-                    result.push(s);
-                } else {
-                    return None;
-                }
+            if let Some(parent) = local_file.parent() {
+                fs::create_dir_all(parent).ok();
             }
-        } else {
-            return None; // unknown op
+            if fs::rename(&staged, &local_file).is_err() {
+                continue;
+            }
+            // 사용자가 되살린 파일 → 삭제 기록을 지워 다음 full_sync에서 다시 치우지 않게
+            if self.tombstones.deleted.remove(&path).is_some() {
+                self.tombstones.save();
+            }
+            self.local_hashes.remove(&path);
+            log_to_file(&format!("delete review: {} rejected → restored", path));
+            self.events.push("restore", &path, "review rejected");
+            self.upload_local_file(&path, &local_file);
+            done += 1;
         }
+        remove_empty_dirs(&dir);
+        done
     }
-    // remaining lines (if any eq ops missed)
-    result.extend_from_slice(&old_lines[pos..]);
-    Some(result.join("\n"))
-}
-
-/// Generate a line-based diff using the `similar` crate.
-fn generate_line_diff(old_content: &str, new_content: &str) -> serde_json::Value {
-    use similar::{ChangeTag, TextDiff};
 
-    let text_diff = TextDiff::from_lines(old_content, new_content);
-    let mut ops: Vec<serde_json::Value> = Vec::new();
-    let mut eq_count = 0usize;
-    let mut del_count = 0usize;
-    let mut ins_lines: Vec<String> = Vec::new();
+    /// A local-only file is tombstoned when it was deleted elsewhere with exactly its current
+    /// content. Without a recorded hash nothing matches, so an edit is never lost.
+    fn is_tombstoned(&self, path: &str, local_file: &Path) -> bool {
+        let Some(tombstone) = self.tombstones.deleted.get(path) else {
+            return false;
+        };
+        let (Some(hash), Some(algo)) = (&tombstone.hash, HashAlgo::from_name(tombstone.algo.as_deref())) else {
+            return false;
+        };
+        fs::read_to_string(local_file).is_ok_and(|c| algo.hash(&c) == *hash)
+    }
 
-    let flush_del = |ops: &mut Vec<serde_json::Value>, del: &mut usize| {
-        if *del > 0 {
-            ops.push(serde_json::json!({"del": *del}));
-            *del = 0;
+    /// Why syncing should stop because the local side is unexpectedly empty (None = go ahead).
+    /// Skipped when `init` recorded the vault as intentionally empty.
+    fn empty_side_problem(&self, local_empty: bool, remote_empty: bool) -> Option<String> {
+        if let Some((_, date)) = &self.empty_init {
+            if !self.local_path.is_dir() {
+                log_to_file(&format!("sync: creating {} (initialized empty {})", self.local_path.display(), date));
+                fs::create_dir_all(&self.local_path).ok();
+            }
+            return None;
         }
-    };
-    let flush_ins = |ops: &mut Vec<serde_json::Value>, ins: &mut Vec<String>| {
-        if !ins.is_empty() {
-            ops.push(serde_json::json!({"ins": ins.clone()}));
-            ins.clear();
+        if !self.local_path.is_dir() {
+            return Some(format!(
+                "동기화 폴더가 없습니다: {} - 드라이브 연결을 확인하세요 (새로 시작하려면 mdflare-agent init)",
+                self.local_path.display()
+            ));
         }
-    };
-    let flush_eq = |ops: &mut Vec<serde_json::Value>, eq: &mut usize| {
-        if *eq > 0 {
-            ops.push(serde_json::json!({"eq": *eq}));
-            *eq = 0;
+        // 이번 실행에서 노트를 봤는데 폴더가 통째로 비고 서버엔 남아 있음 → 마운트 해제 의심
+        if local_empty && !remote_empty && self.seen_local_notes {
+            return Some(format!(
+                "동기화 폴더가 갑자기 비었습니다: {} - 드라이브 연결을 확인하세요",
+                self.local_path.display()
+            ));
         }
-    };
+        None
+    }
 
-    for change in text_diff.iter_all_changes() {
-        match change.tag() {
-            ChangeTag::Equal => {
-                flush_del(&mut ops, &mut del_count);
-                flush_ins(&mut ops, &mut ins_lines);
-                eq_count += 1;
+    fn exceeds_delete_limit(&self, count: usize) -> bool {
+        self.max_deletes_per_sync > 0 && count > self.max_deletes_per_sync
+    }
+
+    /// Persist the paths present on both sides after a successful `full_sync` (for `orphans`).
+    fn remember_synced_paths(&mut self) {
+        let paths = self.remote_listed.iter().filter(|p| self.local_file(p).exists()).cloned().collect();
+        self.synced_paths.paths = paths;
+        self.synced_paths.synced_at = Some(chrono::Utc::now().timestamp_millis());
+        self.synced_paths.save();
+    }
+
+    /// Remote files this device synced before and has since lost locally. Files changed on
+    /// the server after the last successful `full_sync`, or outside the sync scope, are skipped.
+    fn find_orphans(&self) -> Result<Vec<Orphan>, Box<dyn std::error::Error>> {
+        let local_paths = self.scan_local_md_files();
+        let remote_items = self.without_exports(Self::flatten_files(&self.api.list_files()?));
+        Ok(remote_items
+            .into_iter()
+            .filter(|(path, _, _)| !local_paths.contains(path) && !self.local_file(path).exists())
+            .filter(|(path, _, _)| {
+                !self.is_local_only(path)
+                    && !self.is_sync_ignored(path)
+                    && !self.download_exclude.as_ref().is_some_and(|g| g.is_match(path))
+            })
+            .filter(|(path, modified, _)| {
+                // 이 기기가 동기화했던 경로 (또는 삭제 기록) 중 그 뒤로 서버에서 안 바뀐 것만
+                let known_at = if self.synced_paths.paths.contains(path) { self.synced_paths.synced_at } else { None };
+                let deleted_at = self.tombstones.deleted.get(path).map(|t| t.deleted_at);
+                let Some(cutoff) = known_at.max(deleted_at) else { return false };
+                modified.as_deref().and_then(parse_modified).is_some_and(|m| m <= cutoff)
+            })
+            .map(|(path, _, size)| Orphan {
+                tombstoned: self.tombstones.deleted.contains_key(&path),
+                path,
+                size,
+            })
+            .collect())
+    }
+
+    /// What the next `full_sync` after an agent start would do with one file (`diff` command).
+    /// `remote` is the server copy and its `modified`, `local` the file on disk.
+    fn sync_decision(&self, path: &str, remote: Option<(&str, Option<&str>)>, local: Option<&str>) -> String {
+        if self.is_local_only(path) {
+            return "동기화 안 함 (local_only_dir)".to_string();
+        }
+        match (remote, local) {
+            (None, None) => "양쪽 모두 없음".to_string(),
+            (Some(_), None) => "다운로드 (로컬에 없음)".to_string(),
+            (None, Some(_)) if self.is_readonly(path) => "건너뜀 (읽기 전용, 서버에 없음)".to_string(),
+            (None, Some(_)) if self.is_tombstoned(path, &self.local_file(path)) => {
+                "로컬 삭제 (다른 기기에서 삭제됨, tombstone)".to_string()
             }
-            ChangeTag::Delete => {
-                flush_eq(&mut ops, &mut eq_count);
-                flush_ins(&mut ops, &mut ins_lines);
-                del_count += 1;
+            (None, Some(_)) => "업로드 (서버에 없음)".to_string(),
+            (Some(_), Some(_)) if self.is_download_excluded(path) => {
+                "다운로드 건너뜀 (download_exclude_globs) - 로컬 수정은 감시기가 업로드".to_string()
             }
-            ChangeTag::Insert => {
-                flush_eq(&mut ops, &mut eq_count);
-                // strip trailing newline that similar adds
-                let val = change.value();
-                let line = if val.ends_with('\n') { &val[..val.len()-1] } else { val };
-                ins_lines.push(line.to_string());
+            (Some((remote, _)), Some(local)) if remote == local => "변경 없음 (내용 같음)".to_string(),
+            (Some(_), Some(_)) if self.initial_authority == Some(SyncAuthority::Local) => {
+                "로컬 내용으로 서버 덮어씀 (initial_sync_authority = local)".to_string()
+            }
+            (Some(_), Some(_)) if self.initial_authority == Some(SyncAuthority::Cloud) => {
+                "서버 내용으로 로컬 덮어씀 (initial_sync_authority = cloud)".to_string()
             }
+            (Some((_, modified)), Some(_)) if modified.and_then(parse_modified).is_some() => {
+                // remote_modified는 실행 중에만 있음 → 시작 직후 full_sync는 서버 내용을 받음
+                "다운로드: 서버 내용으로 로컬 덮어씀 (시작 시 full_sync 기준). 실행 중에는 서버 modified가 바뀔 때만 다운로드하고, 로컬 수정은 감시기가 업로드".to_string()
+            }
+            (Some(_), Some(_)) => "건너뜀 (서버가 modified를 주지 않음) - 로컬 수정은 감시기가 업로드".to_string(),
         }
     }
-    flush_eq(&mut ops, &mut eq_count);
-    flush_del(&mut ops, &mut del_count);
-    flush_ins(&mut ops, &mut ins_lines);
 
-    serde_json::json!(ops)
-}
+    /// `.mdflareignore` match, or a `.obsidian` file outside the `obsidian_sync` scope:
+    /// never uploaded, downloaded or deleted remotely.
+    fn is_sync_ignored(&self, path: &str) -> bool {
+        let obsidian_excluded = path.split('/').next() == Some(OBSIDIAN_DIR) && !is_obsidian_synced(path, self.obsidian_sync);
+        obsidian_excluded || self.ignore.is_ignored(path, false)
+    }
 
-/// Convert i32 to base-36 string, matching JS `Number.prototype.toString(36)`.
-/// Negative numbers are prefixed with '-'.
-fn to_base36(n: i32) -> String {
-    if n == 0 {
-        return "0".to_string();
+    /// Re-read `.mdflareignore` if it changed since the last load.
+    fn refresh_ignore(&mut self) {
+        if self.ignore.refresh(&self.local_path) {
+            println!("📋 {} 다시 읽음 (규칙 {}개)", IGNORE_FILE_NAME, self.ignore.rules.len());
+            log_to_file(&format!("ignore: {} reloaded ({} rules)", IGNORE_FILE_NAME, self.ignore.rules.len()));
+        }
     }
-    let negative = n < 0;
-    let mut val = if negative { (n as i64).abs() as u64 } else { n as u64 };
-    let digits = b"0123456789abcdefghijklmnopqrstuvwxyz";
-    let mut buf = Vec::new();
-    while val > 0 {
-        buf.push(digits[(val % 36) as usize]);
-        val /= 36;
+
+    /// Paths under `local_only_dir` never leave (or get written by) the cloud side.
+    fn is_local_only(&self, path: &str) -> bool {
+        let Some(dir) = &self.local_only_dir else { return false };
+        path.split('/').next().is_some_and(|top| top.eq_ignore_ascii_case(dir))
     }
-    if negative {
-        buf.push(b'-');
+
+    /// `download_exclude_globs` match with a local copy present: downloads never touch it.
+    /// Only the first copy of a missing file is pulled; uploads are unaffected.
+    fn is_download_excluded(&self, path: &str) -> bool {
+        self.download_exclude.as_ref().is_some_and(|g| g.is_match(path)) && self.local_file(path).exists()
     }
-    buf.reverse();
-    String::from_utf8(buf).unwrap()
-}
 
-struct SyncEngine {
-    api: ApiClient,
-    local_path: PathBuf,
-    local_hashes: HashMap<String, String>,
-    local_content_cache: HashMap<String, String>,
-    remote_modified: HashMap<String, String>,
-}
+    fn is_readonly(&self, path: &str) -> bool {
+        self.readonly.as_ref().is_some_and(|g| g.is_match(path))
+    }
 
-impl SyncEngine {
-    fn new(config: &Config) -> Self {
-        Self {
-            api: ApiClient::new(&config.api_base, &config.username, &config.api_token),
-            local_path: PathBuf::from(&config.local_path),
-            local_hashes: HashMap::new(),
-            local_content_cache: HashMap::new(),
-            remote_modified: HashMap::new(),
+    /// Remember a deletion; `hash` is the content that was deleted (our `hash_algo`).
+    fn record_tombstone(&mut self, path: &str, hash: Option<String>) {
+        let tombstone = Tombstone {
+            deleted_at: chrono::Utc::now().timestamp_millis(),
+            hash,
+            algo: Some(self.hash_algo.as_str().to_string()),
+        };
+        self.tombstones.record(path, tombstone);
+        self.tombstones.save();
+    }
+
+    fn in_schedule(&self) -> bool {
+        in_sync_window(&self.sync_schedule, chrono::Local::now())
+    }
+
+    /// Remember a local change seen outside `sync_schedule`; replayed when the window opens.
+    fn defer_change(&mut self, path: &Path, folder: bool) {
+        if !self.deferred_changes.iter().any(|(p, _)| p == path) {
+            self.deferred_changes.push((path.to_path_buf(), folder));
         }
     }
 
-    fn simple_hash(s: &str) -> String {
-        let mut hash: i32 = 0;
-        for c in s.chars() {
-            hash = ((hash << 5).wrapping_sub(hash)).wrapping_add(c as i32);
+    fn replay_deferred_changes(&mut self) {
+        let changes = std::mem::take(&mut self.deferred_changes);
+        if !changes.is_empty() {
+            log_to_file(&format!("schedule: replaying {} deferred changes", changes.len()));
+        }
+        for (path, folder) in changes {
+            if folder {
+                self.handle_local_folder_delete(&path);
+            } else {
+                self.handle_local_change(&path);
+            }
+        }
+    }
+
+    /// `full_sync` gated by `sync_schedule`: None (tray shows "예약 대기 중") outside the
+    /// window. Changes deferred outside the window are pushed first.
+    fn scheduled_sync(&mut self) -> Option<Result<SyncStats, Box<dyn std::error::Error>>> {
+        if !self.in_schedule() {
+            self.set_status(SYNC_STATUS_SCHEDULED);
+            return None;
+        }
+        if self.status.lock().is_ok_and(|s| *s == SYNC_STATUS_SCHEDULED) {
+            self.set_status(SYNC_STATUS_OK);
+        }
+        self.replay_deferred_changes();
+        Some(self.full_sync())
+    }
+
+    fn handle_local_change(&mut self, full_path: &Path) {
+        if !self.in_schedule() {
+            self.defer_change(full_path, false);
+            return;
+        }
+        if let Ok(rel) = full_path.strip_prefix(&self.local_path) {
+            let rel_local = rel.to_string_lossy().replace('\\', "/");
+            if is_ignored_path(rel, self.include_hidden) && !is_obsidian_synced(&rel_local, self.obsidian_sync) {
+                return;
+            }
+            if self.ignore.is_ignored(&rel_local, false) {
+                return;
+            }
+            let rel_str = self.remote_path(&rel_local);
+            if self.is_local_only(&rel_str) {
+                return;
+            }
+
+            if self.is_readonly(&rel_str) {
+                // 읽기 전용: 업로드/삭제 대신 서버 버전으로 되돌림 (자기 쓰기로 인한 이벤트는 해시 동일 → 무시)
+                let unchanged = fs::read_to_string(full_path)
+                    .is_ok_and(|c| self.local_hashes.get(&rel_str) == Some(&self.content_hash(&c)));
+                if !unchanged && self.local_hashes.contains_key(&rel_str) {
+                    notify_user(NotifyKind::Warning, &format!("🔒 읽기 전용 파일 수정을 되돌렸습니다: {}", rel_str));
+                    self.fetch_from_r2_with(&rel_str, None, false);
+                    self.events.push("revert", &rel_str, "readonly");
+                }
+                return;
+            }
+
+            if full_path.exists() {
+                if self.pending_local_deletes.remove(&rel_str).is_some() {
+                    println!("↩️ {} 재생성됨 (삭제 취소)", rel_str);
+                }
+                if !self.upload_idle.is_zero() || self.upload_throttled(&rel_str) {
+                    // 편집이 멈출 때까지(폭주 파일은 upload_throttle까지) 미뤘다가 최종 상태만 업로드 (flush_idle_uploads)
+                    self.pending_uploads.insert(rel_str, (full_path.to_path_buf(), std::time::Instant::now()));
+                    return;
+                }
+                self.upload_local_file(&rel_str, full_path);
+            } else {
+                self.pending_uploads.remove(&rel_str);
+                // atomic save(삭제 → 새 파일 생성)일 수 있으므로 delete_grace 뒤에 재확인 (flush_idle_uploads)
+                // - 여기서 기다리면 엔진 잠금을 쥔 채 멈춤
+                if !self.delete_grace.is_zero() {
+                    let due = std::time::Instant::now() + self.delete_grace;
+                    self.pending_local_deletes.insert(rel_str, (full_path.to_path_buf(), due));
+                    return;
+                }
+                self.propagate_local_delete(&rel_str, full_path);
+            }
         }
-        // JS의 hash.toString(36)과 동일한 base-36 출력
-        to_base36(hash)
     }
 
-    fn flatten_files(items: &[FileItem]) -> Vec<(String, Option<String>)> {
-        let mut result = Vec::new();
-        for item in items {
-            if item.file_type == "folder" {
-                if let Some(children) = &item.children {
-                    result.extend(Self::flatten_files(children));
+    /// Push a confirmed local deletion: a remote rename when the note reappeared under a new
+    /// name in the same folder, otherwise a remote delete.
+    fn propagate_local_delete(&mut self, rel_str: &str, full_path: &Path) {
+        // 같은 폴더에 같은 내용의 새 파일 → 삭제+업로드 대신 원격 이름 변경
+        if let Some(new_rel) = self.find_local_rename_target(rel_str, full_path) {
+            match self.api.rename_file(rel_str, &new_rel) {
+                Ok(()) => {
+                    self.move_cached_state(rel_str, &new_rel);
+                    let hash = self.local_hashes.get(&new_rel).cloned();
+                    self.record_tombstone(rel_str, hash);
+                    println!("📝 {} → {}", rel_str, new_rel);
+                    self.events.push("rename", &new_rel, &format!("from {} (local)", rel_str));
+                    return;
                 }
-            } else if item.file_type == "file" {
-                result.push((item.path.clone(), item.modified.clone()));
+                Err(e) => log::warn!("원격 이름 변경 실패 {} → {}: {} (삭제 후 업로드)", rel_str, new_rel, e),
             }
         }
-        result
+        if self.api.delete_file(rel_str).is_ok() {
+            let hash = self.local_hashes.remove(rel_str);
+            self.local_content_cache.remove(rel_str);
+            self.forget_flat_name(rel_str);
+            self.record_tombstone(rel_str, hash);
+            println!("🗑️ {}", rel_str);
+            self.events.push("delete", rel_str, "local → remote");
+            self.delete_sidecar(rel_str);
+            self.delete_export(rel_str);
+        }
     }
 
-    fn scan_local_md_files(&self) -> Vec<String> {
-        flatten_file_paths(&scan_local_md_files(&self.local_path))
+    /// Self-healing pass: re-hash every synced note and push the ones whose disk content
+    /// no longer matches `local_hashes` (edits made while the agent was stopped or missed
+    /// by the watcher). Files without a recorded hash are left to `full_sync`.
+    fn reconcile_local_hashes(&mut self) -> usize {
+        let mut stale = Vec::new();
+        for path in self.scan_local_md_files() {
+            let Some(known) = self.local_hashes.get(&path) else { continue };
+            if self.pending_uploads.contains_key(&path) {
+                continue;
+            }
+            let local_file = self.local_file(&path);
+            if let Ok(content) = fs::read_to_string(&local_file) {
+                if self.content_hash(&content) != *known {
+                    stale.push((path, local_file));
+                }
+            }
+        }
+        for (path, local_file) in &stale {
+            log_to_file(&format!("reconcile: {} changed on disk without an event", path));
+            self.events.push("reconcile", path, "hash mismatch");
+            self.handle_local_change(local_file);
+        }
+        stale.len()
     }
 
-    fn full_sync(&mut self) -> Result<(usize, usize), Box<dyn std::error::Error>> {
-        let mut downloaded = 0;
-        let mut uploaded = 0;
-
-        let remote_files = self.api.list_files()?;
-        let remote_items = Self::flatten_files(&remote_files);
-        let remote_paths: Vec<String> = remote_items.iter().map(|(p, _)| p.clone()).collect();
-
-        let local_paths = self.scan_local_md_files();
+    /// A local rename shows up as delete + create. When `old_rel` disappeared and exactly one
+    /// not-yet-synced note in the same folder has its last synced content, that's the new name.
+    fn find_local_rename_target(&self, old_rel: &str, old_file: &Path) -> Option<String> {
+        let known = self.local_hashes.get(old_rel)?;
+        let dir = old_file.parent()?;
+        let mut found = None;
+        for entry in fs::read_dir(dir).ok()?.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !path.is_file() || !is_note_file(&path, &self.extensions) {
+                continue;
+            }
+            let Ok(rel) = path.strip_prefix(&self.local_path) else { continue };
+            if is_ignored_path(rel, self.include_hidden) {
+                continue;
+            }
+            let rel = self.remote_path(&rel.to_string_lossy().replace('\\', "/"));
+            if self.local_hashes.contains_key(&rel) || self.is_local_only(&rel) || self.is_sync_ignored(&rel) {
+                continue;
+            }
+            if fs::read_to_string(&path).is_ok_and(|c| self.content_hash(&c) == *known) {
+                if found.is_some() {
+                    return None;
+                }
+                found = Some(rel);
+            }
+        }
+        found
+    }
 
-        // 서버 → 로컬
-        for (path, modified) in &remote_items {
-            let local_file = self.local_path.join(path);
-            let should_download = if !local_file.exists() {
-                true
-            } else if let Some(mod_time) = modified {
-                self.remote_modified.get(path) != Some(mod_time)
+    /// Upload deferred edits that have been idle for `upload_idle` (all of them when `force`).
+    /// Throttled files wait for their `upload_throttle` slot unless forced.
+    /// Also settles local deletes whose `delete_grace` has passed.
+    fn flush_idle_uploads(&mut self, force: bool) {
+        let now = std::time::Instant::now();
+        let due_deletes: Vec<String> = self
+            .pending_local_deletes
+            .iter()
+            .filter(|(_, (_, due))| force || *due <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for rel_str in due_deletes {
+            let Some((full_path, _)) = self.pending_local_deletes.remove(&rel_str) else { continue };
+            if full_path.exists() {
+                println!("↩️ {} 재생성됨 (삭제 취소)", rel_str);
+                self.handle_local_change(&full_path);
             } else {
-                false
-            };
-
-            if should_download {
-                match self.api.get_file(path) {
-                    Ok(content) => {
-                        if let Some(parent) = local_file.parent() {
-                            fs::create_dir_all(parent).ok();
-                        }
-                        if let Err(e) = fs::write(&local_file, &content.content) {
-                            log::error!("파일 쓰기 실패 {}: {}", path, e);
-                            continue;
-                        }
-                        self.local_hashes.insert(path.clone(), Self::simple_hash(&content.content));
-                        self.local_content_cache.insert(path.clone(), content.content);
-                        if let Some(mod_time) = modified {
-                            self.remote_modified.insert(path.clone(), mod_time.clone());
-                        }
-                        println!("⬇️ {}", path);
-                        downloaded += 1;
-                    }
-                    Err(e) => log::error!("파일 다운로드 실패 {}: {}", path, e),
-                }
+                self.propagate_local_delete(&rel_str, &full_path);
             }
         }
 
-        // 로컬 → 서버
-        for path in &local_paths {
-            if !remote_paths.contains(path) {
-                let local_file = self.local_path.join(path);
-                match fs::read_to_string(&local_file) {
-                    Ok(content) => {
-                        if let Err(e) = self.api.put_file(path, &content) {
-                            log::error!("파일 업로드 실패 {}: {}", path, e);
-                            continue;
-                        }
-                        self.local_hashes.insert(path.clone(), Self::simple_hash(&content));
-                        self.local_content_cache.insert(path.clone(), content);
-                        println!("⬆️ {}", path);
-                        uploaded += 1;
-                    }
-                    Err(e) => log::error!("파일 읽기 실패 {}: {}", path, e),
+        let due: Vec<String> = self
+            .pending_uploads
+            .iter()
+            .filter(|(path, (_, touched))| {
+                force || (touched.elapsed() >= self.upload_idle && !self.upload_throttled(path))
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        for rel_str in due {
+            if let Some((full_path, _)) = self.pending_uploads.remove(&rel_str) {
+                if full_path.exists() {
+                    self.upload_local_file(&rel_str, &full_path);
                 }
             }
         }
+        self.release_quiet_throttles();
+    }
 
-        self.api.put_heartbeat();
-        Ok((downloaded, uploaded))
+    /// True while a throttled file's last upload is younger than `upload_throttle`.
+    fn upload_throttled(&self, path: &str) -> bool {
+        self.throttled_uploads
+            .get(path)
+            .is_some_and(|last| last.elapsed() < self.upload_throttle)
     }
 
-    fn handle_local_change(&mut self, full_path: &Path) {
-        if let Ok(rel) = full_path.strip_prefix(&self.local_path) {
-            let rel_str = rel.to_string_lossy().replace('\\', "/");
+    /// Count an upload of `path`. More than `upload_burst_limit` uploads within
+    /// `upload_burst_window` (a runaway write loop) switches the file to one upload per
+    /// `upload_throttle` until it goes quiet.
+    fn record_upload_burst(&mut self, path: &str) {
+        if self.upload_burst_limit == 0 {
+            return;
+        }
+        let now = std::time::Instant::now();
+        if let Some(last) = self.throttled_uploads.get_mut(path) {
+            *last = now;
+            return;
+        }
+        let history = self.upload_history.entry(path.to_string()).or_default();
+        history.push_back(now);
+        while history.front().is_some_and(|t| now.duration_since(*t) > self.upload_burst_window) {
+            history.pop_front();
+        }
+        if history.len() > self.upload_burst_limit {
+            let count = history.len();
+            self.upload_history.remove(path);
+            self.throttled_uploads.insert(path.to_string(), now);
+            let msg = format!(
+                "{}이(가) {}초 동안 {}번 업로드됨 → {}초에 한 번으로 묶음 (다른 프로그램이 계속 쓰고 있는지 확인)",
+                path,
+                self.upload_burst_window.as_secs(),
+                count,
+                self.upload_throttle.as_secs()
+            );
+            log::warn!("{}", msg);
+            log_to_file(&format!("upload throttle: {}", msg));
+            self.events.push("throttle", path, &format!("{} uploads / {}s", count, self.upload_burst_window.as_secs()));
+        }
+    }
 
-            if full_path.exists() {
-                if let Ok(content) = fs::read_to_string(full_path) {
-                    let new_hash = Self::simple_hash(&content);
-                    if self.local_hashes.get(&rel_str) != Some(&new_hash) {
-                        let old_hash = self.local_hashes.get(&rel_str).cloned();
-                        // 이전 내용 읽어서 diff 생성 (해시가 있으면 이전 버전 존재)
-                        let diff = if old_hash.is_some() {
-                            let diff_val = generate_line_diff(
-                                &self.local_content_cache.get(&rel_str).map(|s| s.as_str()).unwrap_or(""),
-                                &content,
-                            );
-                            let diff_str = diff_val.to_string();
-                            if diff_str.len() <= 10240 { Some(diff_val) } else { None }
-                        } else {
-                            None
-                        };
-                        self.local_hashes.insert(rel_str.clone(), new_hash);
-                        self.local_content_cache.insert(rel_str.clone(), content.clone());
-                        let result = self.api.put_file_with_diff(
-                            &rel_str,
-                            &content,
-                            old_hash.as_deref(),
-                            diff.as_ref(),
-                        );
-                        if result.is_ok() {
-                            println!("⬆️ {}", rel_str);
+    /// Lift throttling for files with nothing pending for a whole `upload_throttle`,
+    /// and drop burst history that fell out of the window.
+    fn release_quiet_throttles(&mut self) {
+        let quiet: Vec<String> = self
+            .throttled_uploads
+            .iter()
+            .filter(|(path, last)| last.elapsed() >= self.upload_throttle && !self.pending_uploads.contains_key(*path))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in quiet {
+            self.throttled_uploads.remove(&path);
+            log_to_file(&format!("upload throttle: {} 해제", path));
+        }
+        let window = self.upload_burst_window;
+        self.upload_history
+            .retain(|_, history| history.back().is_some_and(|t| t.elapsed() <= window));
+    }
+
+    fn upload_local_file(&mut self, rel_str: &str, full_path: &Path) {
+        if let Ok(content) = fs::read_to_string(full_path) {
+            let new_hash = self.content_hash(&content);
+            if self.local_hashes.get(rel_str) != Some(&new_hash) {
+                let old_hash = self.local_hashes.get(rel_str).cloned();
+                // 이전 내용 읽어서 diff 생성 (해시가 있으면 이전 버전 존재)
+                // 캐시에 이전 내용이 없으면 (캐시 비움 등) diff 없이 전체 업로드
+                let previous = old_hash.as_ref().and_then(|_| self.local_content_cache.get(rel_str));
+                let diff = if let Some(previous) = previous {
+                    let diff_val = generate_line_diff(&previous, &content);
+                    let diff_str = diff_val.to_string();
+                    if self.diff_upload_size(&diff_str) <= MAX_DIFF_UPLOAD_BYTES { Some(diff_val) } else { None }
+                } else {
+                    None
+                };
+                if old_hash.is_none() {
+                    // 이 기기에서 처음 보는 파일 → 조건부 생성. 다른 기기가 같은 경로를 먼저
+                    // 만들었으면(412) 덮어쓰지 않고 충돌 처리 (adopt_remote_copy)
+                    match self.api.create_file(rel_str, &content) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            self.adopt_remote_copy(rel_str, content);
+                            return;
+                        }
+                        Err(e) => {
+                            self.events.push("error", rel_str, &format!("upload: {}", e));
+                            return;
                         }
                     }
                 }
-            } else {
-                if self.api.delete_file(&rel_str).is_ok() {
-                    self.local_hashes.remove(&rel_str);
-                    self.local_content_cache.remove(&rel_str);
-                    println!("🗑️ {}", rel_str);
+                self.local_hashes.insert(rel_str.to_string(), new_hash);
+                self.local_content_cache.insert(rel_str.to_string(), content.clone());
+                let result = if old_hash.is_none() {
+                    Ok(())
+                } else {
+                    self.api.put_file_with_diff(rel_str, &content, old_hash.as_deref(), diff.as_ref())
+                };
+                match result {
+                    Ok(()) => {
+                        println!("⬆️ {}", rel_str);
+                        let detail = if diff.is_some() { "watcher (diff)" } else { "watcher" };
+                        self.events.push("upload", rel_str, detail);
+                        self.record_upload_burst(rel_str);
+                        if let Err(e) = self.verify_upload(rel_str, &content) {
+                            self.events.push("error", rel_str, &e);
+                        }
+                        self.upload_export(rel_str, &content);
+                        self.upload_sidecar(rel_str);
+                    }
+                    Err(e) => self.events.push("error", rel_str, &format!("upload: {}", e)),
                 }
             }
         }
     }
 
     fn handle_local_folder_delete(&mut self, folder_path: &Path) {
+        if !self.in_schedule() {
+            self.defer_change(folder_path, true);
+            return;
+        }
         if let Ok(rel) = folder_path.strip_prefix(&self.local_path) {
             let prefix = rel.to_string_lossy().replace('\\', "/");
             let prefix_with_slash = if prefix.ends_with('/') { prefix.clone() } else { format!("{}/", prefix) };
             let to_delete: Vec<String> = self.local_hashes.keys()
-                .filter(|k| k.starts_with(&prefix_with_slash))
+                .filter(|k| k.starts_with(&prefix_with_slash) && !self.is_readonly(k) && !self.is_sync_ignored(k))
                 .cloned()
                 .collect();
             for path in to_delete {
                 if self.api.delete_file(&path).is_ok() {
-                    self.local_hashes.remove(&path);
+                    let hash = self.local_hashes.remove(&path);
                     self.local_content_cache.remove(&path);
+                    self.record_tombstone(&path, hash);
                     println!("🗑️ {}", path);
+                    self.events.push("delete", &path, "local folder → remote");
                 }
             }
         }
@@ -999,64 +6080,130 @@ impl SyncEngine {
 
     /// Handle an RTDB event (from SSE subscription)
     fn handle_rtdb_event(&mut self, entry: &RtdbFileEntry) {
+        if !self.device_id.is_empty() && entry.device_id.as_deref() == Some(self.device_id.as_str()) {
+            // 이 기기가 방금 올린 변경이 되돌아온 것 → 다시 받지 않음
+            log_to_file(&format!("rtdb: {} {} ignored (own device)", entry.action, entry.path));
+            return;
+        }
+        if self.is_local_only(&entry.path) || entry.old_path.as_deref().is_some_and(|p| self.is_local_only(p)) {
+            log_to_file(&format!("rtdb: {} {} ignored (local-only folder)", entry.action, entry.path));
+            return;
+        }
+        if self.is_download_excluded(&entry.path) || entry.old_path.as_deref().is_some_and(|p| self.is_download_excluded(p)) {
+            log_to_file(&format!("rtdb: {} {} ignored (download excluded)", entry.action, entry.path));
+            return;
+        }
+        if self.is_sync_ignored(&entry.path) || entry.old_path.as_deref().is_some_and(|p| self.is_sync_ignored(p)) {
+            log_to_file(&format!("rtdb: {} {} ignored ({})", entry.action, entry.path, IGNORE_FILE_NAME));
+            return;
+        }
+        if self.export_source(&entry.path).is_some_and(|note| self.local_file(&note).exists()) {
+            // export_on_sync 파생 파일 (이 기기 또는 다른 기기가 올림) → 로컬에 만들지 않음
+            return;
+        }
+        if matches!(entry.action.as_str(), "save" | "create") {
+            if let Some(local) = self.untracked_local_content(&entry.path) {
+                // 같은 경로를 이 기기에서도 새로 만들었음 (아직 업로드 전) → 나중 쓰기가 이기지 않게 충돌 처리
+                log_to_file(&format!("rtdb: {} {} vs new local file → conflict path", entry.action, entry.path));
+                self.pending_uploads.remove(&entry.path);
+                self.adopt_remote_copy(&entry.path, local);
+                return;
+            }
+        }
         match entry.action.as_str() {
             "save" => {
-                let local_file = self.local_path.join(&entry.path);
-                let local_hash = self.local_hashes.get(&entry.path).cloned();
+                let local_file = self.local_file(&entry.path);
+                let base_matches = entry
+                    .old_hash
+                    .as_deref()
+                    .map(|old| self.local_matches_hash(&entry.path, old, entry.algo.as_deref()));
 
                 // diff 적용 가능: 로컬 해시 == oldHash
-                if let (Some(old_hash), Some(diff), Some(ref lh)) = (&entry.old_hash, &entry.diff, &local_hash) {
-                    if lh == old_hash {
-                        if let Ok(old_content) = fs::read_to_string(&local_file) {
-                            if let Some(new_content) = apply_line_diff(&old_content, diff) {
-                                if let Some(parent) = local_file.parent() {
-                                    fs::create_dir_all(parent).ok();
-                                }
-                                if fs::write(&local_file, &new_content).is_ok() {
-                                    let hash = Self::simple_hash(&new_content);
-                                    self.local_hashes.insert(entry.path.clone(), hash);
-                                    self.local_content_cache.insert(entry.path.clone(), new_content);
-                                    println!("⬇️ {} (diff applied)", entry.path);
-                                    return;
-                                }
+                if let (Some(true), Some(diff)) = (base_matches, &entry.diff) {
+                    if let Ok(old_content) = fs::read_to_string(&local_file) {
+                        if let Some(new_content) = apply_line_diff(&old_content, diff) {
+                            if self.write_downloaded(&entry.path, &local_file, new_content).is_ok() {
+                                println!("⬇️ {} (diff applied)", entry.path);
+                                self.events.push("download", &entry.path, "rtdb diff");
+                                return;
                             }
                         }
                     }
                 }
 
+                // 원격 수정이 이 기기가 모르는 버전 기준 → 동시 편집 충돌
+                let diverged = base_matches == Some(false) && self.local_hashes.contains_key(&entry.path);
+                let conflict = diverged || self.has_unsynced_local_edit(&entry.path);
+
                 // fallback: R2에서 전체 파일 fetch
-                self.fetch_from_r2(&entry.path);
+                self.fetch_from_r2_with(&entry.path, entry.modified.map(|m| m as i64), conflict);
             }
             "create" => {
                 self.fetch_from_r2(&entry.path);
             }
             "delete" => {
-                let local_file = self.local_path.join(&entry.path);
+                let local_file = self.local_file(&entry.path);
                 if local_file.exists() {
-                    if fs::remove_file(&local_file).is_ok() {
-                        self.local_hashes.remove(&entry.path);
+                    if self.remove_for_remote_delete(&entry.path, &local_file) {
+                        let hash = self.local_hashes.remove(&entry.path);
                         self.local_content_cache.remove(&entry.path);
+                        self.forget_flat_name(&entry.path);
+                        self.record_tombstone(&entry.path, hash);
                         println!("🗑️ {} (rtdb)", entry.path);
+                        self.events.push("delete", &entry.path, "rtdb → local");
+                        if let Some(audit) = &self.audit {
+                            audit.record("delete", "down", &entry.path, None, None);
+                        }
+                    }
+                }
+                if let Some(sidecar) = self.sidecar_path(&entry.path) {
+                    // 노트가 삭제되면 사이드카도 로컬에서 제거 (원격은 삭제한 기기가 처리)
+                    let sidecar_file = self.local_file(&sidecar);
+                    if sidecar_file.exists() && self.remove_for_remote_delete(&sidecar, &sidecar_file) {
+                        self.local_hashes.remove(&sidecar);
+                        self.local_content_cache.remove(&sidecar);
                     }
                 }
             }
             "rename" => {
+                // 다른 hash_algo로 만든 해시는 local_hashes와 비교할 수 없음
+                let hash = entry.hash.as_deref().filter(|_| HashAlgo::from_name(entry.algo.as_deref()) == Some(self.hash_algo));
                 if let Some(old_path) = &entry.old_path {
-                    let old_file = self.local_path.join(old_path);
-                    let new_file = self.local_path.join(&entry.path);
+                    let old_file = self.local_file(old_path);
+                    let new_file = self.assign_local_file(&entry.path);
                     if old_file.exists() {
                         if let Some(parent) = new_file.parent() {
                             fs::create_dir_all(parent).ok();
                         }
                         if fs::rename(&old_file, &new_file).is_ok() {
-                            // 해시 이전
-                            if let Some(h) = self.local_hashes.remove(old_path) {
-                                self.local_hashes.insert(entry.path.clone(), h);
+                            self.move_cached_state(old_path, &entry.path);
+                            self.rename_sidecar(old_path, &entry.path);
+                            println!("📝 {} → {} (rtdb)", old_path, entry.path);
+                            self.events.push("rename", &entry.path, &format!("from {}", old_path));
+                            if let Some(audit) = &self.audit {
+                                audit.record("rename", "down", &entry.path, None, Some(old_path));
                             }
-                            if let Some(c) = self.local_content_cache.remove(old_path) {
-                                self.local_content_cache.insert(entry.path.clone(), c);
+                        }
+                    } else if new_file.exists()
+                        && hash.is_some()
+                        && self.local_hashes.get(&entry.path).map(String::as_str) == hash
+                    {
+                        // 이 기기가 먼저 이름을 바꿨음 → 이미 수렴된 상태
+                    } else if let Some(local_match) = hash.and_then(|h| self.find_unique_local_by_hash(h, &entry.path)) {
+                        // 같은 내용의 파일이 다른 이름으로 있음 → 중복 다운로드 대신 로컬 이름 변경
+                        let match_file = self.local_file(&local_match);
+                        if let Some(parent) = new_file.parent() {
+                            fs::create_dir_all(parent).ok();
+                        }
+                        if !new_file.exists() && fs::rename(&match_file, &new_file).is_ok() {
+                            self.move_cached_state(&local_match, &entry.path);
+                            println!("📝 {} → {} (rtdb, hash match)", local_match, entry.path);
+                            self.events.push("rename", &entry.path, &format!("from {} (hash match)", local_match));
+                            if let Some(audit) = &self.audit {
+                                audit.record("rename", "down", &entry.path, None, Some(&local_match));
                             }
-                            println!("📝 {} → {} (rtdb)", old_path, entry.path);
+                        } else {
+                            self.fetch_from_r2(&entry.path);
                         }
                     } else {
                         // 이전 파일 없으면 R2에서 fetch
@@ -1068,20 +6215,183 @@ impl SyncEngine {
         }
     }
 
+    /// Follow a note rename with its sidecar (local file + cached state).
+    fn rename_sidecar(&mut self, old_note: &str, new_note: &str) {
+        let (Some(old_sidecar), Some(new_sidecar)) = (self.sidecar_path(old_note), self.sidecar_path(new_note)) else {
+            return;
+        };
+        let old_file = self.local_file(&old_sidecar);
+        if old_file.exists() && fs::rename(&old_file, self.assign_local_file(&new_sidecar)).is_ok() {
+            self.move_cached_state(&old_sidecar, &new_sidecar);
+        }
+    }
+
+    /// Move hash/content cache entries after a local rename.
+    fn move_cached_state(&mut self, old_path: &str, new_path: &str) {
+        self.forget_flat_name(old_path);
+        if let Some(h) = self.local_hashes.remove(old_path) {
+            self.local_hashes.insert(new_path.to_string(), h);
+        }
+        self.local_content_cache.rename(old_path, new_path);
+    }
+
+    /// Find the single existing local file whose cached hash equals `hash`.
+    /// Returns None when there is no match or the match is ambiguous.
+    fn find_unique_local_by_hash(&self, hash: &str, exclude: &str) -> Option<String> {
+        let mut matches = self.local_hashes.iter().filter(|(path, h)| {
+            h.as_str() == hash && path.as_str() != exclude && self.local_file(path).exists()
+        });
+        let (first, _) = matches.next()?;
+        if matches.next().is_some() {
+            return None;
+        }
+        Some(first.clone())
+    }
+
+    /// Content of a note this device created but hasn't synced yet, if any (see `is_unsynced_new_local`).
+    fn untracked_local_content(&self, path: &str) -> Option<String> {
+        let new_local = is_unsynced_new_local(
+            self.last_sync.is_some(),
+            self.local_hashes.contains_key(path),
+            self.remote_listed.contains(path),
+        );
+        if !new_local {
+            return None;
+        }
+        fs::read_to_string(self.local_file(path)).ok()
+    }
+
+    /// A local edit that showed up after the download was decided (`conflict` was false then):
+    /// the file changed on disk since it was last synced.
+    fn edited_during_download(&self, path: &str, conflict: bool) -> bool {
+        let edited = self.recheck_before_download_write && !conflict && self.has_unsynced_local_edit(path);
+        if edited {
+            log_to_file(&format!("sync: {} edited locally during download → conflict", path));
+        }
+        edited
+    }
+
+    /// True when the file on disk changed since we last synced it.
+    fn has_unsynced_local_edit(&self, path: &str) -> bool {
+        match (fs::read_to_string(self.local_file(path)), self.local_hashes.get(path)) {
+            (Ok(content), Some(hash)) => self.content_hash(&content) != *hash,
+            _ => false,
+        }
+    }
+
+    /// Decide what to write locally for incoming remote content. On a conflict in an
+    /// append-merge path, both sides' new lines are kept and the merge is uploaded back.
+    fn resolve_incoming(&mut self, path: &str, remote: String, remote_ms: Option<i64>, conflict: bool) -> String {
+        let matches = self.append_merge.as_ref().is_some_and(|g| g.is_match(path));
+        if !conflict || !matches {
+            return remote;
+        }
+        let local_file = self.local_file(path);
+        let Ok(local) = fs::read_to_string(&local_file) else {
+            return remote;
+        };
+        if local == remote {
+            return remote;
+        }
+
+        let local_ms = fs::metadata(&local_file)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(|t| chrono::DateTime::<chrono::Utc>::from(t).timestamp_millis());
+        let merged = if remote_ms.unwrap_or(i64::MAX) <= local_ms.unwrap_or(0) {
+            append_merge(&remote, &local)
+        } else {
+            append_merge(&local, &remote)
+        };
+
+        if merged != remote {
+            match self.api.put_file(path, &merged) {
+                Ok(()) => println!("🔀 {} (append merge)", path),
+                Err(e) => log::error!("병합본 업로드 실패 {}: {}", path, e),
+            }
+        }
+        self.events.push("merge", path, "append merge");
+        merged
+    }
+
+    /// Like `resolve_incoming`, but when both sides changed and nothing could be merged the
+    /// local file wins: the incoming version is saved as a conflict copy and `None` is returned.
+    fn resolve_or_keep_local(
+        &mut self,
+        path: &str,
+        local_file: &Path,
+        remote: String,
+        remote_ms: Option<i64>,
+        conflict: bool,
+    ) -> Option<String> {
+        // 로컬이 마지막 동기화 버전 그대로면 (중간 버전만 놓친 경우) 원격을 그대로 받음
+        if !conflict || self.is_readonly(path) || !self.has_unsynced_local_edit(path) {
+            return Some(self.resolve_incoming(path, remote, remote_ms, conflict));
+        }
+        let incoming = remote.clone();
+        let resolved = self.resolve_incoming(path, remote, remote_ms, conflict);
+        match fs::read_to_string(local_file) {
+            Ok(local) if resolved == incoming && local != incoming => {
+                self.keep_local_on_conflict(path, local_file, incoming);
+                None
+            }
+            _ => Some(resolved),
+        }
+    }
+
+    /// Save the incoming version as `<name>.conflict-<ts>.<ext>`, leave the local file
+    /// untouched and push the local edit on top of the incoming version.
+    fn keep_local_on_conflict(&mut self, path: &str, local_file: &Path, remote: String) {
+        let copy = conflict_copy_path(local_file);
+        if let Err(e) = write_local_file(&copy, &remote) {
+            log::error!("충돌 사본 쓰기 실패 {}: {}", path, e);
+            self.events.push("error", path, &format!("conflict copy: {}", e));
+            return;
+        }
+        let rel = copy
+            .strip_prefix(&self.local_path)
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_else(|_| copy.display().to_string());
+        println!("⚠️ {} 충돌 → 원격 사본: {}", path, rel);
+        log_to_file(&format!("conflict: {} kept local, remote version → {}", path, rel));
+        notify_user(NotifyKind::Conflict, &format!("⚠️ 충돌: {} (원격 사본: {})", path, rel));
+        self.events.push("conflict", path, &format!("remote copy → {}", rel));
+        self.conflicts.push(rel);
+        // 원격 버전을 기준으로 삼아 로컬 수정을 그 위에 업로드 (diff/oldHash가 원격과 맞게)
+        self.local_hashes.insert(path.to_string(), self.content_hash(&remote));
+        self.local_content_cache.insert(path.to_string(), remote);
+        self.upload_local_file(path, local_file);
+    }
+
+    /// Conflict copies created by this agent that still exist (tray badge).
+    pub fn conflicts(&self) -> Vec<String> {
+        self.conflicts.iter().filter(|rel| self.local_path.join(rel).exists()).cloned().collect()
+    }
+
     fn fetch_from_r2(&mut self, path: &str) {
+        let conflict = self.has_unsynced_local_edit(path);
+        self.fetch_from_r2_with(path, None, conflict);
+    }
+
+    fn fetch_from_r2_with(&mut self, path: &str, remote_ms: Option<i64>, conflict: bool) {
         match self.api.get_file(path) {
             Ok(content) => {
-                let local_file = self.local_path.join(path);
-                if let Some(parent) = local_file.parent() {
-                    fs::create_dir_all(parent).ok();
-                }
-                if fs::write(&local_file, &content.content).is_ok() {
-                    self.local_hashes.insert(path.to_string(), Self::simple_hash(&content.content));
-                    self.local_content_cache.insert(path.to_string(), content.content);
+                let local_file = self.assign_local_file(path);
+                // conflict는 받기 전에 판단한 값 → 받는 사이 생긴 로컬 수정도 반영
+                let conflict = conflict || self.edited_during_download(path, conflict);
+                let Some(content) = self.resolve_or_keep_local(path, &local_file, content.content, remote_ms, conflict) else {
+                    return;
+                };
+                if self.write_downloaded(path, &local_file, content).is_ok() {
                     println!("⬇️ {} (r2)", path);
+                    self.events.push("download", path, "r2");
+                    self.download_sidecar(path);
                 }
             }
-            Err(e) => log::error!("R2 fetch 실패 {}: {}", path, e),
+            Err(e) => {
+                log::error!("R2 fetch 실패 {}: {}", path, e);
+                self.events.push("error", path, &format!("r2 fetch: {}", e));
+            }
         }
     }
 }
@@ -1090,7 +6400,9 @@ impl SyncEngine {
 // URL Scheme Handler
 // ============================================================================
 
-fn parse_oauth_callback(url_str: &str) -> Option<(String, String)> {
+/// Parse `mdflare://callback?username=..&token=..[&server=..]`.
+/// An invalid `server` value is dropped (None) rather than failing the login.
+fn parse_oauth_callback(url_str: &str) -> Option<(String, String, Option<String>)> {
     let url = url::Url::parse(url_str).ok()?;
     if url.host_str() != Some("callback") {
         return None;
@@ -1099,19 +6411,71 @@ fn parse_oauth_callback(url_str: &str) -> Option<(String, String)> {
     let params: HashMap<_, _> = url.query_pairs().collect();
     let username = params.get("username")?.to_string();
     let token = params.get("token")?.to_string();
+    let server = params.get("server").and_then(|s| validate_server_url(s));
+
+    Some((username, token, server))
+}
+
+/// Accept only absolute https URLs with a host (plain http only for localhost/loopback);
+/// returns the normalized base URL.
+fn validate_server_url(raw: &str) -> Option<String> {
+    let url = url::Url::parse(raw.trim()).ok()?;
+    let loopback = match url.host() {
+        Some(url::Host::Domain(host)) => host.eq_ignore_ascii_case("localhost"),
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    };
+    let allowed = match url.scheme() {
+        "https" => url.host().is_some(),
+        "http" => loopback,
+        _ => false,
+    };
+    if !allowed {
+        log_to_file(&format!("callback: rejected server url {}", raw));
+        return None;
+    }
+    Some(url.as_str().trim_end_matches('/').to_string())
+}
 
-    Some((username, token))
+/// Point the agent at the server that issued the login, if the callback named one.
+/// Any web page can open an `mdflare://` link, so switching to a different server needs
+/// the user's OK; returns false (nothing saved) when they decline or there is no display.
+fn apply_callback_server(config: &mut Config, server: Option<String>) -> bool {
+    let Some(server) = server else {
+        return true;
+    };
+    let mut settings = ServerSettings::load();
+    if settings.api_base != server {
+        let host = url::Url::parse(&server).ok().and_then(|u| u.host_str().map(str::to_string)).unwrap_or_else(|| server.clone());
+        let accepted = has_display()
+            && rfd::MessageDialog::new()
+                .set_title("MDFlare Agent - 서버 변경")
+                .set_description(format!(
+                    "로그인 링크가 다른 서버를 지정했습니다.\n\n현재: {}\n새 서버: {}\n\n이 서버로 바꾸면 모든 노트가 그 서버로 동기화됩니다.\n직접 로그인한 서버가 맞나요?",
+                    settings.api_base, host
+                ))
+                .set_level(rfd::MessageLevel::Warning)
+                .set_buttons(rfd::MessageButtons::YesNo)
+                .show()
+                == rfd::MessageDialogResult::Yes;
+        if !accepted {
+            log_to_file(&format!("callback: server change to {} declined", host));
+            return false;
+        }
+        log_to_file(&format!("callback: server changed → {}", server));
+        settings.api_base = server.clone();
+        settings.save();
+    }
+    config.api_base = server;
+    true
 }
 
 fn log_to_file(msg: &str) {
     use std::io::Write;
-    let log_path = ProjectDirs::from("com", "mdflare", "agent")
-        .map(|p| {
-            let dir = p.config_dir().to_path_buf();
-            fs::create_dir_all(&dir).ok();
-            dir.join("agent.log")
-        })
-        .unwrap_or_else(|| PathBuf::from("/tmp/mdflare-agent.log"));
+    let dir = agent_config_dir();
+    fs::create_dir_all(&dir).ok();
+    let log_path = dir.join("agent.log");
     if let Ok(mut f) = fs::OpenOptions::new().create(true).append(true).open(&log_path) {
         let now = chrono::Local::now().format("%H:%M:%S%.3f");
         writeln!(f, "[{}] {}", now, msg).ok();
@@ -1119,17 +6483,16 @@ fn log_to_file(msg: &str) {
 }
 
 fn handle_url_callback(url: &str) -> bool {
-    log_to_file(&format!("handle_url_callback: {}", url));
+    // URL에 토큰이 들어 있으므로 전체를 남기지 않음
+    log_to_file("handle_url_callback: received");
 
     if !url.starts_with("mdflare://") {
         log_to_file("  → not mdflare:// scheme, skip");
         return false;
     }
-    if let Some((username, token)) = parse_oauth_callback(url) {
+    if let Some((username, token, server)) = parse_oauth_callback(url) {
         // 이미 같은 토큰이 저장되어 있으면 스킵 (재시작 시 URL 재전달 방지)
         let existing = Config::load();
-        log_to_file(&format!("  → existing token: [{}...]", &existing.api_token.get(..16).unwrap_or("empty")));
-        log_to_file(&format!("  → new token:      [{}...]", &token.get(..16).unwrap_or("empty")));
 
         if existing.api_token == token {
             log_to_file("  → SKIP: same token already saved");
@@ -1142,33 +6505,52 @@ fn handle_url_callback(url: &str) -> bool {
         config.storage_mode = StorageMode::Cloud;
         config.username = username;
         config.api_token = token;
+        if !apply_callback_server(&mut config, server) {
+            log_to_file("  → server change declined, login not saved");
+            return true;
+        }
 
-        if config.local_path.is_empty() {
-            config.local_path = dirs::document_dir()
-                .map(|d| d.join("MDFlare"))
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string();
+        if config.force_folder_prompt {
+            match pick_folder("동기화 폴더 선택") {
+                Some(folder) => config.local_path = folder,
+                None => {
+                    log_to_file("  → folder selection cancelled, login not saved");
+                    return true;
+                }
+            }
+        } else if config.local_path.is_empty() {
+            config.local_path = default_local_path();
+        }
+        if !confirm_local_path(&mut config) {
+            log_to_file("  → dangerous folder rejected, login not saved");
+            return true;
         }
+        prompt_initial_sync_authority(&mut config);
+        prompt_empty_vault_init(&mut config);
 
         fs::create_dir_all(&config.local_path).ok();
         config.save();
         log_to_file(&format!("  → config saved: {} ({})", config.username, config.local_path));
 
-        // 2초 딜레이 후 재시작 (URL 재전달 방지)
-        log_to_file("  → scheduling delayed restart");
-        std::process::Command::new("sh")
-            .args(["-c", "sleep 2 && open -a 'MDFlare Agent'"])
-            .spawn()
-            .ok();
-
-        log_to_file("  → exiting");
-        std::process::exit(0);
+        log_to_file(&format!(
+            "  → restarting ({})",
+            config.vault_name.as_deref().unwrap_or("-")
+        ));
+        restart_self();
     }
     log_to_file("  → parse_oauth_callback returned None");
     false
 }
 
+/// `.app` bundle containing the running executable (macOS), if any.
+#[cfg(target_os = "macos")]
+fn app_bundle_path() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    exe.ancestors()
+        .find(|p| p.extension().is_some_and(|e| e == "app"))
+        .map(Path::to_path_buf)
+}
+
 #[cfg(windows)]
 fn register_url_scheme() {
     use winreg::enums::*;
@@ -1286,7 +6668,118 @@ fn load_icon_setup() -> Icon {
     Icon::from_rgba(rgba, size, size).expect("Failed to create setup icon")
 }
 
-fn copy_to_clipboard(text: &str) {
+/// 알림 종류 - 종류별로 최소 간격을 둬서 같은 원인의 알림이 쏟아지지 않게 함
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NotifyKind {
+    SyncError,
+    DiskFull,
+    AuthExpired,
+    Conflict,
+    Warning,
+    PendingDelete,
+}
+
+impl NotifyKind {
+    fn min_interval(self) -> Duration {
+        match self {
+            NotifyKind::SyncError => Duration::from_secs(10 * 60),
+            NotifyKind::DiskFull | NotifyKind::AuthExpired => Duration::from_secs(30 * 60),
+            // 파일마다 다른 내용 → 짧게 (같은 메시지는 아래 중복 제거로 걸러짐)
+            NotifyKind::Conflict | NotifyKind::Warning => Duration::from_secs(10),
+            // 원격 폴더 삭제는 파일 수만큼 이벤트가 옴 → 묶어서
+            NotifyKind::PendingDelete => Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+// 같은 메시지는 이 시간 안에 다시 띄우지 않음
+const NOTIFY_DEDUP_WINDOW: Duration = Duration::from_secs(30 * 60);
+
+/// Error/warning notification with per-kind rate limiting and message dedup.
+/// Suppressed notifications still go to the log.
+fn notify_user(kind: NotifyKind, message: &str) {
+    use std::time::Instant;
+    struct NotifyState {
+        last_by_kind: HashMap<NotifyKind, Instant>,
+        recent: HashMap<String, Instant>,
+    }
+    static STATE: std::sync::OnceLock<Mutex<NotifyState>> = std::sync::OnceLock::new();
+
+    let state = STATE.get_or_init(|| Mutex::new(NotifyState { last_by_kind: HashMap::new(), recent: HashMap::new() }));
+    let Ok(mut state) = state.lock() else { return };
+    let now = Instant::now();
+    state.recent.retain(|_, at| now.duration_since(*at) < NOTIFY_DEDUP_WINDOW);
+    let duplicate = state.recent.contains_key(message);
+    let too_soon = state.last_by_kind.get(&kind).is_some_and(|at| now.duration_since(*at) < kind.min_interval());
+    if duplicate || too_soon {
+        log_to_file(&format!("notify: suppressed {:?}: {}", kind, message));
+        return;
+    }
+    state.recent.insert(message.to_string(), now);
+    state.last_by_kind.insert(kind, now);
+    drop(state);
+    show_notification(message);
+}
+
+/// Platform notification backend (no rate limiting - use `notify_user` for errors/warnings).
+fn show_notification(message: &str) {
+    #[cfg(target_os = "macos")]
+    let shown = {
+        let script = format!(
+            "display notification \"{}\" with title \"MDFlare\"",
+            message.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        std::process::Command::new("osascript")
+            .args(["-e", &script])
+            .spawn()
+            .is_ok()
+    };
+    #[cfg(windows)]
+    let shown = {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        // WinRT 토스트 (PowerShell 기본 제공, 추가 모듈 불필요)
+        let script = format!(
+            "[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null; \
+             $x = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+             $t = $x.GetElementsByTagName('text'); \
+             $t.Item(0).AppendChild($x.CreateTextNode('MDFlare')) > $null; \
+             $t.Item(1).AppendChild($x.CreateTextNode('{}')) > $null; \
+             [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('MDFlare').Show([Windows.UI.Notifications.ToastNotification]::new($x))",
+            message.replace('\'', "''")
+        );
+        std::process::Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+            .creation_flags(CREATE_NO_WINDOW)
+            .spawn()
+            .is_ok()
+    };
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let shown = std::process::Command::new("notify-send")
+        .args(["MDFlare", message])
+        .spawn()
+        .is_ok();
+    if !shown {
+        eprintln!("🔔 {}", message);
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn copy_to_clipboard(text: &str, notice: &str) {
     #[cfg(target_os = "macos")]
     {
         let ok = std::process::Command::new("pbcopy")
@@ -1303,39 +6796,245 @@ fn copy_to_clipboard(text: &str) {
             .unwrap_or(false);
 
         if ok {
-            std::process::Command::new("osascript")
-                .args(["-e", "display notification \"연결 토큰이 클립보드에 복사되었습니다\" with title \"MDFlare\""])
-                .spawn()
-                .ok();
+            show_notification(notice);
+        }
+    }
+}
+
+/// "📂 폴더 열기": open the vault folder, or with `folder_open_reveals_recent` select the
+/// most recently modified note in Finder/Explorer. Falls back to the folder otherwise.
+fn open_vault_folder(config: &Config) {
+    if config.folder_open_reveals_recent {
+        if let Some(recent) = most_recent_note(Path::new(&config.local_path), config.include_hidden, &config.extensions) {
+            if reveal_in_file_manager(&recent) {
+                return;
+            }
         }
     }
+    open::that(&config.local_path).ok();
+}
+
+#[cfg(target_os = "macos")]
+fn reveal_in_file_manager(path: &Path) -> bool {
+    std::process::Command::new("open").arg("-R").arg(path).spawn().is_ok()
+}
+
+#[cfg(windows)]
+fn reveal_in_file_manager(path: &Path) -> bool {
+    use std::os::windows::process::CommandExt;
+    // explorer는 "/select,<경로>"를 한 인자로 받아야 함
+    std::process::Command::new("explorer")
+        .raw_arg(format!("/select,\"{}\"", path.display()))
+        .spawn()
+        .is_ok()
+}
+
+/// 선택 표시를 지원하는 파일 관리자가 정해져 있지 않음 → 폴더 열기로
+#[cfg(not(any(target_os = "macos", windows)))]
+fn reveal_in_file_manager(_path: &Path) -> bool {
+    false
+}
+
+fn most_recent_note(root: &Path, include_hidden: bool, extensions: &[String]) -> Option<PathBuf> {
+    walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !is_ignored_name(&e.file_name().to_string_lossy(), include_hidden))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && is_note_file(e.path(), extensions))
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.into_path())))
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+fn shorten_path(path: &str) -> String {
+    if let Some(home) = dirs::home_dir() {
+        path.replace(&home.to_string_lossy().to_string(), "~")
+    } else {
+        path.to_string()
+    }
+}
+
+fn version_string() -> String {
+    if cfg!(debug_assertions) {
+        format!("{}-d", env!("CARGO_PKG_VERSION"))
+    } else {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// Tray tooltip, prefixed with `vault_name` so several agents can be told apart.
+fn tray_tooltip(config: &Config, detail: &str) -> String {
+    match &config.vault_name {
+        Some(name) => format!("MDFlare Agent [{}] ({})", name, detail),
+        None => format!("MDFlare Agent ({})", detail),
+    }
+}
+
+fn append_vault_name(menu: &Menu, config: &Config) {
+    if let Some(name) = &config.vault_name {
+        menu.append(&MenuItem::new(format!("🏷️ {}", name), false, None)).ok();
+    }
+}
+
+fn append_about(menu: &Menu) {
+    let about = MenuItem::with_id(
+        "about",
+        format!("about {}", version_string()),
+        true,
+        None,
+    );
+    menu.append(&about).ok();
+    let diagnostics = MenuItem::with_id("diagnostics", "ℹ️ 진단 정보 복사", true, None);
+    menu.append(&diagnostics).ok();
+    menu.append(&PredefinedMenuItem::separator()).ok();
+}
+
+fn mask_token(token: &str) -> String {
+    if token.is_empty() {
+        return "(없음)".to_string();
+    }
+    let head: String = token.chars().take(4).collect();
+    format!("{}… ({}자)", head, token.chars().count())
+}
+
+/// Plain-text snapshot of the agent state for bug reports (tokens masked).
+fn collect_diagnostics(config: &Config, engine: Option<&SyncEngine>) -> String {
+    let mode = match config.storage_mode {
+        StorageMode::Cloud => "Cloud",
+        StorageMode::PrivateVault => "Private Vault",
+        StorageMode::PeerVault => "연결 (Peer Vault)",
+    };
+    let (server, token) = match config.storage_mode {
+        StorageMode::PeerVault => (config.peer_url.as_str(), config.peer_token.as_str()),
+        _ => (config.api_base.as_str(), config.api_token.as_str()),
+    };
+    let file_count = SyncEngine::flatten_files(&scan_local_md_files(
+        Path::new(&config.local_path),
+        config.include_hidden,
+        &config.extensions,
+    ))
+    .len();
+    let last_sync = engine
+        .and_then(|e| e.last_sync)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "없음".to_string());
+    let last_sync = match engine.and_then(|e| e.last_stats.as_ref()) {
+        Some(stats) => format!("{} ({}, {}ms)", last_sync, stats.summary(), stats.duration.as_millis()),
+        None => last_sync,
+    };
+    let rtdb = match engine.and_then(|e| e.rtdb_connected) {
+        Some(true) => "연결됨",
+        Some(false) => "재연결 중",
+        None => "사용 안 함",
+    };
+    let status = engine
+        .and_then(|e| e.status.lock().ok().map(|s| s.clone()))
+        .unwrap_or_else(|| "-".to_string());
+
+    format!(
+        "MDFlare Agent 진단 정보\n\
+        버전: {}\n\
+        빌드: {}\n\
+        OS: {} {}\n\
+        모드: {}\n\
+        서버: {}\n\
+        사용자: {}\n\
+        토큰: {}\n\
+        로컬 폴더: {}\n\
+        로컬 파일 수: {}\n\
+        마지막 동기화: {}\n\
+        동기화 상태: {}\n\
+        RTDB: {}\n",
+        version_string(),
+        env!("BUILD_DATE"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        mode,
+        server,
+        if config.username.is_empty() { "-" } else { &config.username },
+        mask_token(token),
+        config.local_path,
+        file_count,
+        last_sync,
+        status,
+        rtdb,
+    )
+}
+
+/// Check the restart rate guard; on a crash-restart loop, tell the user instead of restarting.
+fn restart_allowed() -> bool {
+    let config = Config::load();
+    if RestartLog::load().try_record(config.restart_limit, config.restart_window_secs) {
+        return true;
+    }
+    let msg = format!(
+        "{}초 안에 재시작이 {}번 반복되어 자동 재시작을 멈췄습니다.\n설정을 확인한 뒤 직접 실행해 주세요.",
+        config.restart_window_secs, config.restart_limit
+    );
+    log_to_file(&format!("restart: blocked ({} within {}s)", config.restart_limit, config.restart_window_secs));
+    eprintln!("❌ {}", msg);
+    if has_display() {
+        rfd::MessageDialog::new()
+            .set_title("MDFlare Agent - 재시작 중단")
+            .set_description(msg)
+            .set_level(rfd::MessageLevel::Error)
+            .set_buttons(rfd::MessageButtons::Ok)
+            .show();
+    }
+    false
 }
 
-fn shorten_path(path: &str) -> String {
-    if let Some(home) = dirs::home_dir() {
-        path.replace(&home.to_string_lossy().to_string(), "~")
-    } else {
-        path.to_string()
+/// Relaunch this agent (subject to the restart rate guard) and exit. Every restart path
+/// (logout, disconnect, settings changes, login callback) goes through here.
+fn restart_self() -> ! {
+    if restart_allowed() {
+        match spawn_relaunch() {
+            Ok(how) => log_to_file(&format!("restart: {}", how)),
+            Err(e) => {
+                log_to_file(&format!("restart: cannot relaunch: {}", e));
+                eprintln!("❌ 재시작 실패: {} (직접 다시 실행해 주세요)", e);
+            }
+        }
     }
+    std::process::exit(0);
 }
 
-fn version_string() -> String {
-    if cfg!(debug_assertions) {
-        format!("{}-d", env!("CARGO_PKG_VERSION"))
-    } else {
-        env!("CARGO_PKG_VERSION").to_string()
+/// Start the replacement process. macOS `.app`: reopen the running bundle (not the app
+/// name, so renamed/duplicated agents come back as themselves) through Launch Services,
+/// which also re-registers the URL scheme. Otherwise spawn the current executable.
+fn spawn_relaunch() -> Result<String, String> {
+    #[cfg(target_os = "macos")]
+    if let Some(bundle) = app_bundle_path() {
+        // 이 프로세스가 살아 있으면 open -a는 기존 인스턴스만 활성화 → 종료 후 열리도록 2초 대기 (URL 재전달 방지도 겸함)
+        let quoted = bundle.to_string_lossy().replace('\'', "'\\''");
+        std::process::Command::new("sh")
+            .args(["-c", &format!("sleep 2 && open -a '{}'", quoted)])
+            .spawn()
+            .map_err(|e| format!("open -a {}: {}", bundle.display(), e))?;
+        return Ok(format!("open -a {}", bundle.display()));
+    }
+    let exe = std::env::current_exe().map_err(|e| format!("current_exe: {}", e))?;
+    // Linux: 실행 중 바이너리가 교체되면 "/path (deleted)"로 나옴 → 새 파일 경로로
+    let exe = match exe.to_string_lossy().strip_suffix(" (deleted)") {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    };
+    if !exe.is_file() {
+        return Err(format!("executable not found: {}", exe.display()));
     }
+    std::process::Command::new(&exe)
+        .spawn()
+        .map_err(|e| format!("{}: {}", exe.display(), e))?;
+    Ok(exe.display().to_string())
 }
 
-fn append_about(menu: &Menu) {
-    let about = MenuItem::with_id(
-        "about",
-        format!("about {}", version_string()),
-        true,
-        None,
-    );
-    menu.append(&about).ok();
-    menu.append(&PredefinedMenuItem::separator()).ok();
+/// 외부 접속 토글: 설정 저장 후 재시작 (터널은 서버 시작 시에만 결정됨)
+fn toggle_tunnel_and_restart() {
+    let mut config = Config::load();
+    config.enable_tunnel = !config.enable_tunnel;
+    config.save();
+    log_to_file(&format!("vault: enable_tunnel → {}, restarting", config.enable_tunnel));
+    restart_self();
 }
 
 fn show_about_dialog() {
@@ -1357,22 +7056,32 @@ fn show_about_dialog() {
 
 fn run_cloud_tray_app(config: Config) {
     let event_loop = EventLoop::new();
+    // 연결 모드도 같은 동기화 엔진/트레이 사용 (API 대상만 상대 Vault)
+    let peer = config.storage_mode == StorageMode::PeerVault;
     
     let menu = Menu::new();
     append_about(&menu);
+    append_vault_name(&menu, &config);
 
-    let mode_item = MenuItem::new("☁️ Cloud 모드", false, None);
-    let user_item = MenuItem::new(format!("👤 {}", config.username), false, None);
+    let (mode_label, user_label, logoff_label) = if peer {
+        ("🔗 연결 모드", format!("🔗 {}", config.peer_url.replace("https://", "").replace("http://", "")), "🔌 연결 해제")
+    } else {
+        ("☁️ Cloud 모드", format!("👤 {}", config.username), "🚪 로그아웃")
+    };
+    let mode_item = MenuItem::new(mode_label, false, None);
+    let user_item = MenuItem::new(user_label, false, None);
     let path_item = MenuItem::new(format!("📁 {}", shorten_path(&config.local_path)), false, None);
+    let status_item = MenuItem::new(SYNC_STATUS_OK, false, None);
     let sync_item = MenuItem::new("🔄 지금 동기화", true, None);
     let folder_item = MenuItem::new("📂 폴더 열기", true, None);
-    let web_item = MenuItem::new("🌐 웹에서 열기", true, None);
-    let logoff_item = MenuItem::new("🚪 로그아웃", true, None);
+    let web_item = MenuItem::new("🌐 웹에서 열기", !peer, None);
+    let logoff_item = MenuItem::new(logoff_label, true, None);
     let quit_item = MenuItem::new("종료", true, None);
 
     menu.append(&mode_item).ok();
     menu.append(&user_item).ok();
     menu.append(&path_item).ok();
+    menu.append(&status_item).ok();
     menu.append(&PredefinedMenuItem::separator()).ok();
     menu.append(&sync_item).ok();
     menu.append(&folder_item).ok();
@@ -1387,29 +7096,57 @@ fn run_cloud_tray_app(config: Config) {
     let logoff_id = logoff_item.id().clone();
     let quit_id = quit_item.id().clone();
     
-    let _tray = TrayIconBuilder::new()
+    let tray = TrayIconBuilder::new()
         .with_menu(Box::new(menu))
-        .with_tooltip("MDFlare Agent (Cloud)")
+        .with_tooltip(tray_tooltip(&config, if peer { "연결" } else { "Cloud" }))
         .with_icon(load_icon_active())
-        .build()
-        .expect("Failed to create tray icon");
+        .build();
+    let _tray = match tray {
+        Ok(tray) => tray,
+        Err(e) => {
+            eprintln!("⚠️ 트레이 생성 실패: {} → 트레이 없이 계속", e);
+            run_headless(config);
+        }
+    };
 
     let engine = Arc::new(Mutex::new(SyncEngine::new(&config)));
     let engine_clone = engine.clone();
+    let sync_status = engine.lock().unwrap().status.clone();
+    if let Some(port) = config.status_port {
+        start_status_server(port, engine.clone());
+    }
     let local_path = config.local_path.clone();
     
     // 파일 감시
     let engine_watcher = engine.clone();
     let watch_path = local_path.clone();
+    let metadata_suffix = config.metadata_suffix.clone();
+    let obsidian_sync = config.obsidian_sync;
+    let extensions = config.extensions.clone();
+    let (mut watch_mode, poll_ms, debounce_ms) =
+        (config.watch_mode.clone(), config.watch_poll_interval_ms, config.watch_debounce_ms);
     thread::spawn(move || {
         let (tx, rx) = std::sync::mpsc::channel();
-        let mut debouncer = new_debouncer(Duration::from_secs(1), tx).unwrap();
-        debouncer.watcher().watch(Path::new(&watch_path), RecursiveMode::Recursive).ok();
+        let mut watcher = watch_local_folder(Path::new(&watch_path), &watch_mode, poll_ms, debounce_ms, tx.clone());
 
-        for events in rx.iter().flatten() {
+        for result in rx.iter() {
+            let events = match result {
+                Ok(events) => events,
+                Err(e) => {
+                    let path = Path::new(&watch_path);
+                    recover_from_watch_error(&e, &mut watch_mode, &mut watcher, path, poll_ms, debounce_ms, &tx);
+                    continue;
+                }
+            };
             for event in events {
                 if event.kind == DebouncedEventKind::Any {
-                    if event.path.extension().map_or(false, |e| e == "md") {
+                    if event.path.file_name().is_some_and(|n| n == IGNORE_FILE_NAME) {
+                        if let Ok(mut eng) = engine_watcher.lock() {
+                            eng.refresh_ignore();
+                        }
+                    } else if is_synced_file(&event.path, &metadata_suffix, &extensions)
+                        || is_obsidian_event(&event.path, Path::new(&watch_path), obsidian_sync)
+                    {
                         if let Ok(mut eng) = engine_watcher.lock() {
                             eng.handle_local_change(&event.path);
                         }
@@ -1424,22 +7161,30 @@ fn run_cloud_tray_app(config: Config) {
         }
     });
     
-    // RTDB SSE 구독 (실시간 변경 감지)
+    if config.upload_idle_ms > 0 || config.upload_burst_limit > 0 || config.delete_grace_ms > 0 {
+        start_idle_upload_flusher(engine.clone());
+    }
+    start_hash_reconcile(engine.clone(), config.reconcile_interval_mins);
+    start_wake_monitor(engine.clone());
+
+    // RTDB SSE 구독 (실시간 변경 감지) - 연결 모드는 RTDB 없음 → 주기적 동기화만
     let engine_rtdb = engine.clone();
     let config_for_rtdb = config.clone();
     thread::spawn(move || {
-        let api = ApiClient::new(
-            &config_for_rtdb.api_base,
-            &config_for_rtdb.username,
-            &config_for_rtdb.api_token,
-        );
-        match api.get_sync_config() {
-            Ok(rtdb_config) => {
+        if peer {
+            return;
+        }
+        let watch = engine_rtdb.lock().unwrap().api.watch_config();
+        match watch {
+            Ok(None) => {}
+            Ok(Some(rtdb_config)) => {
                 println!("🔌 RTDB 접속 정보 수신: {}", rtdb_config.user_id);
                 start_rtdb_subscription(
                     rtdb_config.rtdb_url,
                     rtdb_config.rtdb_auth,
                     rtdb_config.user_id,
+                    config_for_rtdb.rtdb_root.clone(),
+                    Duration::from_secs(config_for_rtdb.sse_stall_timeout_secs),
                     engine_rtdb,
                 );
             }
@@ -1449,24 +7194,8 @@ fn run_cloud_tray_app(config: Config) {
         }
     });
 
-    // 주기적 동기화 (fallback)
-    let engine_timer = engine.clone();
-    thread::spawn(move || {
-        loop {
-            thread::sleep(Duration::from_secs(30));
-            if let Ok(mut eng) = engine_timer.lock() {
-                eng.full_sync().ok();
-            }
-        }
-    });
-
-    // 초기 동기화
-    if let Ok(mut eng) = engine.lock() {
-        match eng.full_sync() {
-            Ok((d, u)) => println!("✅ 초기 동기화 완료: ⬇️{} ⬆️{}", d, u),
-            Err(e) => eprintln!("❌ 동기화 실패: {}", e),
-        }
-    }
+    // 초기 동기화 (성공할 때까지 재시도) → 주기적 동기화 (fallback)
+    start_periodic_sync(engine.clone(), config.startup_retry_max_secs);
 
     let config_for_menu = config.clone();
     let menu_receiver = MenuEvent::receiver();
@@ -1476,12 +7205,18 @@ fn run_cloud_tray_app(config: Config) {
             if let Ok(event) = menu_receiver.recv() {
                 if event.id == MenuId::new("about") {
                     show_about_dialog();
+                } else if event.id == MenuId::new("diagnostics") {
+                    let report = match engine_clone.lock() {
+                        Ok(eng) => collect_diagnostics(&config_for_menu, Some(&eng)),
+                        Err(_) => collect_diagnostics(&config_for_menu, None),
+                    };
+                    copy_to_clipboard(&report, "진단 정보가 클립보드에 복사되었습니다");
                 } else if event.id == sync_id {
                     if let Ok(mut eng) = engine_clone.lock() {
                         eng.full_sync().ok();
                     }
                 } else if event.id == folder_id {
-                    open::that(&config_for_menu.local_path).ok();
+                    open_vault_folder(&config_for_menu);
                 } else if event.id == web_id {
                     let url = format!("{}/{}", config_for_menu.api_base, config_for_menu.username);
                     open::that(url).ok();
@@ -1489,18 +7224,31 @@ fn run_cloud_tray_app(config: Config) {
                     let path = Config::config_path();
                     fs::remove_file(&path).ok();
                     log_to_file("cloud: logoff → config deleted, restarting");
-                    let exe = std::env::current_exe().unwrap();
-                    std::process::Command::new(exe).spawn().ok();
-                    std::process::exit(0);
+                    restart_self();
                 } else if event.id == quit_id {
+                    // 미뤄둔 업로드는 종료 전에 즉시 처리
+                    if let Ok(mut eng) = engine_clone.lock() {
+                        eng.flush_idle_uploads(true);
+                    }
                     std::process::exit(0);
                 }
             }
         }
     });
 
+    let mut shown_status = SYNC_STATUS_OK.to_string();
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::Wait;
+        *control_flow = ControlFlow::WaitUntil(
+            std::time::Instant::now() + Duration::from_millis(500)
+        );
+
+        // 동기화 상태 변경 시 메뉴 갱신
+        let current = sync_status.lock().unwrap().clone();
+        if current != shown_status {
+            status_item.set_text(&current);
+            shown_status = current;
+        }
+
         if let Event::Opened { urls } = event {
             for url in urls {
                 handle_url_callback(url.as_str());
@@ -1509,6 +7257,35 @@ fn run_cloud_tray_app(config: Config) {
     });
 }
 
+/// Whether a graphical session is available (Linux: X11/Wayland env vars).
+fn has_display() -> bool {
+    if cfg!(target_os = "linux") {
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    } else {
+        true
+    }
+}
+
+/// Run sync (or the vault server) without a tray, e.g. on a headless server.
+fn run_headless(config: Config) -> ! {
+    log_to_file(&format!("headless: starting {:?} mode without tray", config.storage_mode));
+    println!("🖥️ 트레이 없이 실행 중 (종료: Ctrl+C)");
+    match config.storage_mode {
+        StorageMode::PrivateVault => {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let tunnel: TunnelStatus = Arc::new(Mutex::new(TunnelState::Starting));
+            rt.block_on(run_private_vault_server(config, tunnel));
+            std::process::exit(0);
+        }
+        StorageMode::Cloud | StorageMode::PeerVault => {
+            let _engine = start_cloud_sync(&config);
+            loop {
+                thread::park();
+            }
+        }
+    }
+}
+
 // ============================================================================
 // Tray App (Private Vault 모드)
 // ============================================================================
@@ -1518,25 +7295,31 @@ fn run_private_vault_tray_app(config: Config) {
 
     let menu = Menu::new();
     append_about(&menu);
+    append_vault_name(&menu, &config);
 
     let settings = ServerSettings::load();
     let web_label = settings.api_base.replace("https://", "").replace("http://", "");
     let mode_item = MenuItem::new("🔐 Private Vault 모드", false, None);
     let port_item = MenuItem::new(format!("🌐 {}", web_label), false, None);
     let path_item = MenuItem::new(format!("📁 {}", shorten_path(&config.local_path)), false, None);
+    let tunnel_status: TunnelStatus = Arc::new(Mutex::new(TunnelState::Starting));
+    let tunnel_item = MenuItem::new(TunnelState::Starting.label(), false, None);
     let folder_item = MenuItem::new("📂 폴더 열기", true, None);
     let web_item = MenuItem::new("🌐 웹페이지 열기", true, None);
     let copy_token_item = MenuItem::new("📋 연결 토큰 복사", true, None);
+    let tunnel_toggle_item = CheckMenuItem::new("🌍 외부 접속", true, config.enable_tunnel, None);
     let disconnect_item = MenuItem::new("🔌 연결 해제", true, None);
     let quit_item = MenuItem::new("종료", true, None);
 
     menu.append(&mode_item).ok();
     menu.append(&port_item).ok();
     menu.append(&path_item).ok();
+    menu.append(&tunnel_item).ok();
     menu.append(&PredefinedMenuItem::separator()).ok();
     menu.append(&folder_item).ok();
     menu.append(&web_item).ok();
     menu.append(&copy_token_item).ok();
+    menu.append(&tunnel_toggle_item).ok();
     menu.append(&PredefinedMenuItem::separator()).ok();
     menu.append(&disconnect_item).ok();
     menu.append(&quit_item).ok();
@@ -1544,21 +7327,29 @@ fn run_private_vault_tray_app(config: Config) {
     let folder_id = folder_item.id().clone();
     let web_id = web_item.id().clone();
     let copy_token_id = copy_token_item.id().clone();
+    let tunnel_toggle_id = tunnel_toggle_item.id().clone();
     let disconnect_id = disconnect_item.id().clone();
     let quit_id = quit_item.id().clone();
 
-    let _tray = TrayIconBuilder::new()
+    let tray = TrayIconBuilder::new()
         .with_menu(Box::new(menu))
-        .with_tooltip("MDFlare Agent (Private Vault)")
+        .with_tooltip(tray_tooltip(&config, "Private Vault"))
         .with_icon(load_icon_active())
-        .build()
-        .expect("Failed to create tray icon");
+        .build();
+    let _tray = match tray {
+        Ok(tray) => tray,
+        Err(e) => {
+            eprintln!("⚠️ 트레이 생성 실패: {} → 트레이 없이 계속", e);
+            run_headless(config);
+        }
+    };
 
     // HTTP 서버를 별도 스레드에서 실행
     let config_for_server = config.clone();
+    let tunnel_for_server = tunnel_status.clone();
     thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(run_private_vault_server(config_for_server));
+        rt.block_on(run_private_vault_server(config_for_server, tunnel_for_server));
     });
 
     let config_for_menu = config.clone();
@@ -1569,25 +7360,28 @@ fn run_private_vault_tray_app(config: Config) {
             if let Ok(event) = menu_receiver.recv() {
                 if event.id == MenuId::new("about") {
                     show_about_dialog();
+                } else if event.id == MenuId::new("diagnostics") {
+                    let report = collect_diagnostics(&config_for_menu, None);
+                    copy_to_clipboard(&report, "진단 정보가 클립보드에 복사되었습니다");
                 } else if event.id == folder_id {
-                    open::that(&config_for_menu.local_path).ok();
+                    open_vault_folder(&config_for_menu);
                 } else if event.id == web_id {
                     let settings = ServerSettings::load();
-                    let conn_token = generate_connection_token(config_for_menu.server_port, &config_for_menu.server_token);
+                    let conn_token = generate_connection_token(vault_port(&config_for_menu), &config_for_menu.server_token);
                     let url = format!("{}/?pvtoken={}", settings.api_base, urlencoding::encode(&conn_token));
                     open::that(url).ok();
                 } else if event.id == copy_token_id {
-                    let conn_token = generate_connection_token(config_for_menu.server_port, &config_for_menu.server_token);
-                    copy_to_clipboard(&conn_token);
+                    let conn_token = peer_connection_token(&config_for_menu);
+                    copy_to_clipboard(&conn_token, "연결 토큰이 클립보드에 복사되었습니다");
+                } else if event.id == tunnel_toggle_id {
+                    toggle_tunnel_and_restart();
                 } else if event.id == disconnect_id {
                     let mut config = Config::load();
                     config.local_path.clear();
-                    config.server_token.clear();
+                    config.clear_server_token();
                     config.save();
                     log_to_file("vault: disconnect → config cleared, restarting");
-                    let exe = std::env::current_exe().unwrap();
-                    std::process::Command::new(exe).spawn().ok();
-                    std::process::exit(0);
+                    restart_self();
                 } else if event.id == quit_id {
                     std::process::exit(0);
                 }
@@ -1595,8 +7389,19 @@ fn run_private_vault_tray_app(config: Config) {
         }
     });
     
+    let mut shown_tunnel_state = TunnelState::Starting;
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::Wait;
+        *control_flow = ControlFlow::WaitUntil(
+            std::time::Instant::now() + Duration::from_millis(500)
+        );
+
+        // 터널 상태 변경 시 메뉴 갱신
+        let current = tunnel_status.lock().unwrap().clone();
+        if current != shown_tunnel_state {
+            tunnel_item.set_text(current.label());
+            shown_tunnel_state = current;
+        }
+
         if let Event::Opened { urls } = event {
             for url in urls {
                 handle_url_callback(url.as_str());
@@ -1609,12 +7414,14 @@ fn run_private_vault_tray_app(config: Config) {
 // Setup Tray App (미설정 상태)
 // ============================================================================
 
-fn build_cloud_menu(config: &Config) -> (Menu, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId) {
+fn build_cloud_menu(config: &Config) -> (Menu, MenuItem, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId) {
     let menu = Menu::new();
     append_about(&menu);
+    append_vault_name(&menu, config);
     let mode_item = MenuItem::new("☁️ Cloud 모드", false, None);
     let user_item = MenuItem::new(format!("👤 {}", config.username), false, None);
     let path_item = MenuItem::new(format!("📁 {}", shorten_path(&config.local_path)), false, None);
+    let status_item = MenuItem::new(SYNC_STATUS_OK, false, None);
     let sync_item = MenuItem::new("🔄 지금 동기화", true, None);
     let folder_item = MenuItem::new("📂 폴더 열기", true, None);
     let web_item = MenuItem::new("🌐 웹에서 열기", true, None);
@@ -1630,6 +7437,7 @@ fn build_cloud_menu(config: &Config) -> (Menu, muda::MenuId, muda::MenuId, muda:
     menu.append(&mode_item).ok();
     menu.append(&user_item).ok();
     menu.append(&path_item).ok();
+    menu.append(&status_item).ok();
     menu.append(&PredefinedMenuItem::separator()).ok();
     menu.append(&sync_item).ok();
     menu.append(&folder_item).ok();
@@ -1638,7 +7446,279 @@ fn build_cloud_menu(config: &Config) -> (Menu, muda::MenuId, muda::MenuId, muda:
     menu.append(&logoff_item).ok();
     menu.append(&quit_item).ok();
 
-    (menu, sync_id, folder_id, web_id, logoff_id, quit_id)
+    (menu, status_item, sync_id, folder_id, web_id, logoff_id, quit_id)
+}
+
+/// Detect OS sleep/wake by a wall-clock jump (monotonic clocks pause while asleep),
+/// then force an RTDB reconnect and catch up with `full_sync`.
+fn start_wake_monitor(engine: Arc<Mutex<SyncEngine>>) {
+    const TICK: Duration = Duration::from_secs(5);
+    const WAKE_GAP: Duration = Duration::from_secs(30);
+    thread::spawn(move || {
+        let mut last = std::time::SystemTime::now();
+        loop {
+            thread::sleep(TICK);
+            let now = std::time::SystemTime::now();
+            let gap = now.duration_since(last).unwrap_or_default();
+            last = now;
+            if gap < TICK + WAKE_GAP {
+                continue;
+            }
+            println!("💤 슬립에서 깨어남 ({}초) → 재연결 + 동기화", gap.as_secs());
+            log_to_file(&format!("wake: {}s gap, reconnect rtdb + full_sync", gap.as_secs()));
+            if let Ok(mut eng) = engine.lock() {
+                eng.rtdb_reconnect.store(true, std::sync::atomic::Ordering::SeqCst);
+                if let Some(result) = eng.scheduled_sync() {
+                    result.ok();
+                }
+            }
+        }
+    });
+}
+
+/// Start the debounced watcher on `path` with the configured backend.
+/// Watching stops when the returned guard is dropped.
+/// Native watching that hits the OS limit (inotify watches/instances) falls back to polling.
+fn watch_local_folder<F: DebounceEventHandler + Clone>(
+    path: &Path,
+    mode: &WatchMode,
+    poll_ms: u64,
+    debounce_ms: u64,
+    handler: F,
+) -> Option<Box<dyn Send>> {
+    let poll = match mode {
+        WatchMode::Native => false,
+        WatchMode::Poll => true,
+        WatchMode::Auto => is_network_filesystem(path),
+    };
+    // 너무 짧으면 이벤트마다 깨어나 바쁘게 돎
+    let debounce_ms = debounce_ms.max(100);
+    println!("👀 폴더 감시 디바운스: {}ms", debounce_ms);
+    log_to_file(&format!("watch: debounce {}ms", debounce_ms));
+    let result = if poll {
+        log_to_file(&format!("watch: poll ({:?})", mode));
+        poll_watch(path, poll_ms, debounce_ms, handler)
+    } else {
+        let debounce = notify_debouncer_mini::Config::default().with_timeout(Duration::from_millis(debounce_ms));
+        let native = new_debouncer_opt::<F, notify::RecommendedWatcher>(debounce, handler.clone())
+            .and_then(|mut d| d.watcher().watch(path, RecursiveMode::Recursive).map(|()| Box::new(d) as Box<dyn Send>));
+        match native {
+            // 일부 하위 폴더만 감시된 상태 → 버리고 (watch 해제) 폴링으로
+            Err(e) if is_watch_limit_error(&e) => {
+                report_watch_limit(&e);
+                poll_watch(path, poll_ms, debounce_ms, handler)
+            }
+            other => other,
+        }
+    };
+    match result {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            eprintln!("⚠️ 폴더 감시 시작 실패: {} (주기적 동기화만 사용)", e);
+            log_to_file(&format!("watch: failed to start: {}", e));
+            None
+        }
+    }
+}
+
+fn poll_watch<F: DebounceEventHandler>(
+    path: &Path,
+    poll_ms: u64,
+    debounce_ms: u64,
+    handler: F,
+) -> Result<Box<dyn Send>, notify::Error> {
+    println!("👀 폴링 방식으로 폴더 감시 ({}ms 간격)", poll_ms);
+    log_to_file(&format!("watch: poll every {}ms", poll_ms));
+    let debounce = notify_debouncer_mini::Config::default().with_timeout(Duration::from_millis(debounce_ms));
+    let backend = notify::Config::default().with_poll_interval(Duration::from_millis(poll_ms.max(100)));
+    new_debouncer_opt::<F, notify::PollWatcher>(debounce.with_notify_config(backend), handler)
+        .and_then(|mut d| d.watcher().watch(path, RecursiveMode::Recursive).map(|()| Box::new(d) as Box<dyn Send>))
+}
+
+/// Out of inotify watches (ENOSPC) or instances/descriptors (EMFILE).
+fn is_watch_limit_error(e: &notify::Error) -> bool {
+    match &e.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        notify::ErrorKind::Io(io) => matches!(io.raw_os_error(), Some(28) | Some(24)),
+        _ => false,
+    }
+}
+
+fn report_watch_limit(e: &notify::Error) {
+    let remedy = if cfg!(target_os = "linux") {
+        "sudo sysctl fs.inotify.max_user_watches=524288 (재부팅 후에도 유지하려면 /etc/sysctl.d/에 추가) 또는 config watch_mode = \"poll\""
+    } else {
+        "config watch_mode = \"poll\""
+    };
+    eprintln!("⚠️ OS 폴더 감시 한도 초과 ({}) → 폴링으로 전환합니다. 해결: {}", e, remedy);
+    log_to_file(&format!("watch: OS watch limit hit ({}) → falling back to poll. remedy: {}", e, remedy));
+    notify_user(NotifyKind::Warning, "폴더 감시 한도를 넘어 폴링 방식으로 전환했습니다 (해결 방법은 로그 참고)");
+}
+
+/// Error delivered by a running watcher (e.g. inotify ran out of watches for a folder
+/// created later): on a watch limit, replace the native watcher with polling once.
+fn recover_from_watch_error(
+    e: &notify::Error,
+    mode: &mut WatchMode,
+    watcher: &mut Option<Box<dyn Send>>,
+    path: &Path,
+    poll_ms: u64,
+    debounce_ms: u64,
+    tx: &std::sync::mpsc::Sender<notify_debouncer_mini::DebounceEventResult>,
+) {
+    if !is_watch_limit_error(e) {
+        log_to_file(&format!("watch: error: {}", e));
+        return;
+    }
+    if *mode == WatchMode::Poll {
+        return;
+    }
+    report_watch_limit(e);
+    *mode = WatchMode::Poll;
+    // native 감시를 먼저 내려 watch를 반납
+    *watcher = None;
+    *watcher = watch_local_folder(path, mode, poll_ms, debounce_ms, tx.clone());
+}
+
+/// Best-effort check for SMB/NFS/etc. where native change notifications are unreliable.
+fn is_network_filesystem(path: &Path) -> bool {
+    const NETWORK_FS: &[&str] = &["nfs", "nfs4", "cifs", "smb", "smb2", "smb3", "smbfs", "afpfs", "webdav", "fuse.sshfs", "9p", "afs"];
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    if cfg!(windows) {
+        // UNC 경로 (\\server\share, \\?\UNC\...)
+        let s = path.to_string_lossy();
+        return s.starts_with(r"\\") && !s.starts_with(r"\\?\") || s.starts_with(r"\\?\UNC\");
+    }
+
+    // (마운트 지점, 파일시스템 종류) 중 path를 포함하는 가장 긴 마운트 지점
+    let mounts: Vec<(PathBuf, String)> = if cfg!(target_os = "linux") {
+        fs::read_to_string("/proc/mounts")
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                let _device = parts.next()?;
+                let mount_point = parts.next()?.replace("\\040", " ");
+                Some((PathBuf::from(mount_point), parts.next()?.to_string()))
+            })
+            .collect()
+    } else {
+        // macOS: "//user@nas/share on /Volumes/share (smbfs, nodev, ...)"
+        std::process::Command::new("mount")
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).to_string())
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let (_, rest) = line.split_once(" on ")?;
+                let (mount_point, opts) = rest.rsplit_once(" (")?;
+                let fs_type = opts.split(',').next()?.trim_end_matches(')');
+                Some((PathBuf::from(mount_point), fs_type.to_string()))
+            })
+            .collect()
+    };
+    mounts
+        .iter()
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        .is_some_and(|(_, fs_type)| NETWORK_FS.contains(&fs_type.as_str()))
+}
+
+/// Run the initial sync, retrying with backoff until it succeeds (e.g. launched
+/// before Wi-Fi is up), then hand off to the 30s fallback timer.
+fn start_periodic_sync(engine: Arc<Mutex<SyncEngine>>, retry_max_secs: u64) {
+    thread::spawn(move || {
+        let retry_max = Duration::from_secs(retry_max_secs.max(5));
+        let mut retry = Duration::from_secs(5);
+        loop {
+            let Ok(mut eng) = engine.lock() else { return };
+            // 재시도 대기 중 "지금 동기화"로 이미 성공했으면 끝
+            if eng.last_sync.is_some() {
+                break;
+            }
+            let Some(result) = eng.scheduled_sync() else {
+                // 예약 시간 밖 → 시간대가 열릴 때까지 대기
+                drop(eng);
+                thread::sleep(Duration::from_secs(30));
+                continue;
+            };
+            match result {
+                Ok(stats) => {
+                    println!("✅ 초기 동기화 완료: {}", stats.summary());
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("❌ 동기화 실패: {} ({}초 후 재시도)", e, retry.as_secs());
+                    log_to_file(&format!("sync: initial sync failed: {} → retry in {}s", e, retry.as_secs()));
+                    // 429는 자체 상태/대기 시간 유지
+                    let wait = match eng.rate_limit_remaining() {
+                        Some(remaining) => remaining,
+                        None => {
+                            eng.set_status(SYNC_STATUS_OFFLINE_RETRY);
+                            retry
+                        }
+                    };
+                    drop(eng);
+                    thread::sleep(wait);
+                    retry = (retry * 2).min(retry_max);
+                }
+            }
+        }
+
+        let mut wait = Duration::from_secs(30);
+        loop {
+            thread::sleep(wait);
+            wait = Duration::from_secs(30);
+            if let Ok(mut eng) = engine.lock() {
+                if let Some(result) = eng.scheduled_sync() {
+                    result.ok();
+                }
+                // 429 응답 시 Retry-After 만큼 정확히 대기
+                if let Some(remaining) = eng.rate_limit_remaining() {
+                    wait = remaining;
+                }
+            }
+        }
+    });
+}
+
+/// Run `reconcile_local_hashes` every `interval_mins` (0 = off).
+fn start_hash_reconcile(engine: Arc<Mutex<SyncEngine>>, interval_mins: u64) {
+    if interval_mins == 0 {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval_mins * 60));
+        if let Ok(mut eng) = engine.lock() {
+            let fixed = eng.reconcile_local_hashes();
+            if fixed > 0 {
+                println!("🩹 놓친 로컬 변경 {}개 업로드", fixed);
+            }
+        }
+    });
+}
+
+/// Periodically upload edits deferred by `upload_idle_ms` or upload throttling, and settle
+/// local deletes waiting out `delete_grace_ms`.
+fn start_idle_upload_flusher(engine: Arc<Mutex<SyncEngine>>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(500));
+        if let Ok(mut eng) = engine.lock() {
+            eng.flush_idle_uploads(false);
+        }
+    });
+}
+
+/// RTDB reconnect wait: 5s doubling per consecutive failure, capped at 5 minutes.
+const RTDB_RETRY_MIN: Duration = Duration::from_secs(5);
+const RTDB_RETRY_MAX: Duration = Duration::from_secs(300);
+/// A connection that lasted this long resets the backoff.
+const RTDB_STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// Wait before reconnect attempt `attempt` (0-based).
+fn rtdb_retry_delay(attempt: u32) -> Duration {
+    RTDB_RETRY_MIN.saturating_mul(1u32 << attempt.min(16)).min(RTDB_RETRY_MAX)
 }
 
 /// Start RTDB SSE subscription in a background thread.
@@ -1647,6 +7727,8 @@ fn start_rtdb_subscription(
     rtdb_url: String,
     rtdb_auth: String,
     username: String,
+    rtdb_root: String,
+    stall_timeout: Duration,
     engine: Arc<Mutex<SyncEngine>>,
 ) {
     thread::spawn(move || {
@@ -1655,11 +7737,49 @@ fn start_rtdb_subscription(
             .build()
             .unwrap();
 
+        let files_url = format!("{}/{}/{}/files.json", rtdb_url, rtdb_root.trim_matches('/'), username);
+        let mut cursor = RtdbCursor::load();
+        let (reconnect_requested, schedule) = {
+            let eng = engine.lock().unwrap();
+            (eng.rtdb_reconnect.clone(), eng.sync_schedule.clone())
+        };
+        // 첫 연결은 시작 시 full_sync가 이미 처리 → 재연결부터 보정
+        let mut reconnecting = false;
+        // 연속 실패 횟수 → 재연결 대기 5s, 10s, 20s ... 최대 5분
+        let mut attempt: u32 = 0;
+
         loop {
-            let url = format!(
-                "{}/mdflare/{}/files.json?auth={}",
-                rtdb_url, username, rtdb_auth
-            );
+            if !in_sync_window(&schedule, chrono::Local::now()) {
+                // 예약 시간 밖: 연결하지 않음 → 시간대가 열리면 재연결 보정으로 따라잡음
+                thread::sleep(Duration::from_secs(30));
+                continue;
+            }
+            if reconnecting {
+                // 놓친 이벤트 보정: 가능하면 last_seen_ts 이후 변경분만, 안 되면 전체 동기화
+                let caught_up = cursor.last_seen_ts.and_then(|since| {
+                    match rtdb_catch_up(&client, &files_url, &rtdb_auth, since, &engine) {
+                        Ok(newest) => Some(newest),
+                        Err(e) => {
+                            log_to_file(&format!("rtdb: catch-up failed ({}), full_sync", e));
+                            None
+                        }
+                    }
+                });
+                match caught_up {
+                    Some(newest) => {
+                        log_to_file(&format!("rtdb: caught up since {}", cursor.last_seen_ts.unwrap_or(0)));
+                        cursor.advance(newest);
+                    }
+                    None => {
+                        if let Ok(mut eng) = engine.lock() {
+                            eng.full_sync().ok();
+                        }
+                    }
+                }
+            }
+            reconnecting = true;
+
+            let url = format!("{}?auth={}", files_url, rtdb_auth);
             println!("🔌 RTDB SSE 연결 중...");
 
             let resp = client
@@ -1670,14 +7790,54 @@ fn start_rtdb_subscription(
             match resp {
                 Ok(response) => {
                     use std::io::{BufRead, BufReader};
-                    let reader = BufReader::new(response);
+                    use std::sync::mpsc::RecvTimeoutError;
+
+                    // 블로킹 lines()는 half-open 연결을 감지 못함 → 별도 스레드에서 읽고 recv_timeout으로 감시
+                    let (line_tx, line_rx) = std::sync::mpsc::channel();
+                    thread::spawn(move || {
+                        for line in BufReader::new(response).lines() {
+                            let failed = line.is_err();
+                            if line_tx.send(line).is_err() || failed {
+                                break;
+                            }
+                        }
+                    });
+
                     let mut event_type = String::new();
                     let mut data_buf = String::new();
                     let mut first_put = true; // 첫 "put"은 전체 스냅샷 (무시)
 
                     println!("✅ RTDB SSE 연결됨");
-
-                    for line in reader.lines() {
+                    if let Ok(mut eng) = engine.lock() {
+                        eng.rtdb_connected = Some(true);
+                    }
+                    reconnect_requested.store(false, std::sync::atomic::Ordering::SeqCst);
+                    let connected_at = std::time::Instant::now();
+                    let mut last_line = std::time::Instant::now();
+
+                    loop {
+                        // 슬립 복귀 재연결 요청을 확인하려고 짧게 끊어서 대기
+                        let line = match line_rx.recv_timeout(stall_timeout.min(Duration::from_secs(2))) {
+                            Ok(line) => line,
+                            Err(RecvTimeoutError::Timeout) => {
+                                if reconnect_requested.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                                    log_to_file("rtdb: wake → reconnecting");
+                                    break;
+                                }
+                                if !in_sync_window(&schedule, chrono::Local::now()) {
+                                    log_to_file("rtdb: outside sync_schedule, disconnecting");
+                                    break;
+                                }
+                                if last_line.elapsed() < stall_timeout {
+                                    continue;
+                                }
+                                eprintln!("⚠️ RTDB SSE {}초 동안 응답 없음 → 재연결", stall_timeout.as_secs());
+                                log_to_file("rtdb: SSE stalled, reconnecting");
+                                break;
+                            }
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        };
+                        last_line = std::time::Instant::now();
                         match line {
                             Ok(line) => {
                                 if line.starts_with("event:") {
@@ -1694,7 +7854,7 @@ fn start_rtdb_subscription(
                                             data_buf.clear();
                                             continue;
                                         }
-                                        handle_sse_data(&data_buf, &engine);
+                                        cursor.advance(handle_sse_data(&data_buf, &engine));
                                     } else if event_type == "keep-alive" {
                                         // ignore
                                     }
@@ -1709,44 +7869,42 @@ fn start_rtdb_subscription(
                         }
                     }
 
-                    eprintln!("⚠️ RTDB SSE 연결 끊어짐, 5초 후 재연결...");
+                    if connected_at.elapsed() > RTDB_STABLE_CONNECTION {
+                        // 한동안 잘 붙어 있었음 → 일시적 끊김으로 보고 처음 간격부터
+                        attempt = 0;
+                    }
+                    eprintln!("⚠️ RTDB SSE 연결 끊어짐");
                 }
                 Err(e) => {
-                    eprintln!("⚠️ RTDB SSE 연결 실패: {}, 5초 후 재시도...", e);
+                    eprintln!("⚠️ RTDB SSE 연결 실패: {}", e);
                 }
             }
 
-            thread::sleep(Duration::from_secs(5));
+            if let Ok(mut eng) = engine.lock() {
+                eng.rtdb_connected = Some(false);
+            }
+            let delay = rtdb_retry_delay(attempt);
+            attempt = attempt.saturating_add(1);
+            println!("🔌 {}초 후 재연결...", delay.as_secs());
+            log_to_file(&format!("rtdb: reconnect attempt {} in {}s", attempt, delay.as_secs()));
+            thread::sleep(delay);
         }
     });
 }
 
-/// Parse SSE data payload and dispatch to SyncEngine
-fn handle_sse_data(data: &str, engine: &Arc<Mutex<SyncEngine>>) {
+/// Parse SSE data payload and dispatch to SyncEngine.
+/// Returns the newest `modified` among the applied entries.
+fn handle_sse_data(data: &str, engine: &Arc<Mutex<SyncEngine>>) -> Option<u64> {
     // Firebase SSE data format: {"path":"/safeKey","data":{...}} or {"path":"/","data":{...}}
     let parsed: Result<serde_json::Value, _> = serde_json::from_str(data);
-    let val = match parsed {
-        Ok(v) => v,
-        Err(_) => return,
-    };
+    let val = parsed.ok()?;
 
     let path = val.get("path").and_then(|p| p.as_str()).unwrap_or("");
-    let data_val = match val.get("data") {
-        Some(d) => d,
-        None => return,
-    };
+    let data_val = val.get("data")?;
 
     if path == "/" {
         // 루트 업데이트: 여러 파일 변경 가능 (각 키가 safeKey)
-        if let Some(obj) = data_val.as_object() {
-            for (_key, entry_val) in obj {
-                if let Ok(entry) = serde_json::from_value::<RtdbFileEntry>(entry_val.clone()) {
-                    if let Ok(mut eng) = engine.lock() {
-                        eng.handle_rtdb_event(&entry);
-                    }
-                }
-            }
-        }
+        apply_rtdb_entries(data_val, engine)
     } else {
         // 개별 파일 변경: path = "/safeKey"
         if data_val.is_null() {
@@ -1760,6 +7918,8 @@ fn handle_sse_data(data: &str, engine: &Arc<Mutex<SyncEngine>>) {
                 action: "delete".to_string(),
                 hash: None,
                 old_hash: None,
+                algo: None,
+                device_id: None,
                 diff: None,
                 old_path: None,
                 modified: None,
@@ -1768,29 +7928,133 @@ fn handle_sse_data(data: &str, engine: &Arc<Mutex<SyncEngine>>) {
             if let Ok(mut eng) = engine.lock() {
                 eng.handle_rtdb_event(&entry);
             }
+            None
         } else if let Ok(entry) = serde_json::from_value::<RtdbFileEntry>(data_val.clone()) {
             if let Ok(mut eng) = engine.lock() {
                 eng.handle_rtdb_event(&entry);
             }
+            entry.modified
+        } else {
+            None
+        }
+    }
+}
+
+/// Apply an object of `safeKey → entry`, returning the newest `modified`.
+fn apply_rtdb_entries(data_val: &serde_json::Value, engine: &Arc<Mutex<SyncEngine>>) -> Option<u64> {
+    let mut newest = None;
+    if let Some(obj) = data_val.as_object() {
+        for (_key, entry_val) in obj {
+            if let Ok(entry) = serde_json::from_value::<RtdbFileEntry>(entry_val.clone()) {
+                newest = newest.max(apply_rtdb_entry(&entry, engine));
+            }
+        }
+    }
+    newest
+}
+
+/// Apply one snapshot entry unless the local copy already has its hash.
+fn apply_rtdb_entry(entry: &RtdbFileEntry, engine: &Arc<Mutex<SyncEngine>>) -> Option<u64> {
+    if let Ok(mut eng) = engine.lock() {
+        let current = entry
+            .hash
+            .as_deref()
+            .is_some_and(|hash| eng.local_matches_hash(&entry.path, hash, entry.algo.as_deref()));
+        if !current {
+            eng.handle_rtdb_event(entry);
+        }
+    }
+    entry.modified
+}
+
+/// Streams `{safeKey: entry, ...}` one entry at a time, so a large snapshot
+/// is never held in memory as a whole.
+struct RtdbSnapshotVisitor<'a> {
+    engine: &'a Arc<Mutex<SyncEngine>>,
+}
+
+impl<'de> serde::de::Visitor<'de> for RtdbSnapshotVisitor<'_> {
+    type Value = Option<u64>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("an object of RTDB file entries or null")
+    }
+
+    fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut newest = None;
+        while map.next_key::<serde::de::IgnoredAny>()?.is_some() {
+            // 항목 하나만 Value로 → 형식이 다른 항목은 건너뜀
+            let value: serde_json::Value = map.next_value()?;
+            if let Ok(entry) = serde_json::from_value::<RtdbFileEntry>(value) {
+                newest = newest.max(apply_rtdb_entry(&entry, self.engine));
+            }
         }
+        Ok(newest)
+    }
+}
+
+/// Fetch only entries modified since `since` (`orderBy="modified"&startAt=`) and apply them.
+/// Err when the server rejects filtered queries (e.g. no `.indexOn: modified`).
+fn rtdb_catch_up(
+    client: &reqwest::blocking::Client,
+    files_url: &str,
+    rtdb_auth: &str,
+    since: u64,
+    engine: &Arc<Mutex<SyncEngine>>,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let url = format!(
+        "{}?orderBy=%22modified%22&startAt={}&auth={}",
+        files_url, since, rtdb_auth
+    );
+    let resp = client.get(&url).timeout(Duration::from_secs(30)).send()?;
+    if !resp.status().is_success() {
+        return Err(format!("filtered query not supported: {}", resp.status()).into());
     }
+    let mut de = serde_json::Deserializer::from_reader(std::io::BufReader::new(resp));
+    let newest = serde::Deserializer::deserialize_any(&mut de, RtdbSnapshotVisitor { engine })?;
+    Ok(newest)
 }
 
 fn start_cloud_sync(config: &Config) -> Arc<Mutex<SyncEngine>> {
     let engine = Arc::new(Mutex::new(SyncEngine::new(config)));
     let local_path = config.local_path.clone();
+    if let Some(port) = config.status_port {
+        start_status_server(port, engine.clone());
+    }
 
     // 파일 감시
     let engine_watcher = engine.clone();
     let watch_path = local_path.clone();
+    let metadata_suffix = config.metadata_suffix.clone();
+    let obsidian_sync = config.obsidian_sync;
+    let extensions = config.extensions.clone();
+    let (mut watch_mode, poll_ms, debounce_ms) =
+        (config.watch_mode.clone(), config.watch_poll_interval_ms, config.watch_debounce_ms);
     thread::spawn(move || {
         let (tx, rx) = std::sync::mpsc::channel();
-        let mut debouncer = new_debouncer(Duration::from_secs(1), tx).unwrap();
-        debouncer.watcher().watch(Path::new(&watch_path), RecursiveMode::Recursive).ok();
-        for events in rx.iter().flatten() {
+        let mut watcher = watch_local_folder(Path::new(&watch_path), &watch_mode, poll_ms, debounce_ms, tx.clone());
+        for result in rx.iter() {
+            let events = match result {
+                Ok(events) => events,
+                Err(e) => {
+                    let path = Path::new(&watch_path);
+                    recover_from_watch_error(&e, &mut watch_mode, &mut watcher, path, poll_ms, debounce_ms, &tx);
+                    continue;
+                }
+            };
             for event in events {
                 if event.kind == DebouncedEventKind::Any {
-                    if event.path.extension().map_or(false, |e| e == "md") {
+                    if event.path.file_name().is_some_and(|n| n == IGNORE_FILE_NAME) {
+                        if let Ok(mut eng) = engine_watcher.lock() {
+                            eng.refresh_ignore();
+                        }
+                    } else if is_synced_file(&event.path, &metadata_suffix, &extensions)
+                        || is_obsidian_event(&event.path, Path::new(&watch_path), obsidian_sync)
+                    {
                         if let Ok(mut eng) = engine_watcher.lock() {
                             eng.handle_local_change(&event.path);
                         }
@@ -1804,23 +8068,31 @@ fn start_cloud_sync(config: &Config) -> Arc<Mutex<SyncEngine>> {
         }
     });
 
+    if config.upload_idle_ms > 0 || config.upload_burst_limit > 0 || config.delete_grace_ms > 0 {
+        start_idle_upload_flusher(engine.clone());
+    }
+    start_hash_reconcile(engine.clone(), config.reconcile_interval_mins);
+    start_wake_monitor(engine.clone());
+
     // RTDB SSE 구독 (실시간 변경 감지)
     let engine_rtdb = engine.clone();
     let config_for_rtdb = config.clone();
     thread::spawn(move || {
-        // sync-config에서 RTDB 접속 정보 가져오기
-        let api = ApiClient::new(
-            &config_for_rtdb.api_base,
-            &config_for_rtdb.username,
-            &config_for_rtdb.api_token,
-        );
-        match api.get_sync_config() {
-            Ok(rtdb_config) => {
+        if config_for_rtdb.storage_mode == StorageMode::PeerVault {
+            return;
+        }
+        // sync-config에서 RTDB 접속 정보 가져오기 (변경 피드가 없는 백엔드는 주기적 동기화만)
+        let watch = engine_rtdb.lock().unwrap().api.watch_config();
+        match watch {
+            Ok(None) => {}
+            Ok(Some(rtdb_config)) => {
                 println!("🔌 RTDB 접속 정보 수신: {}", rtdb_config.user_id);
                 start_rtdb_subscription(
                     rtdb_config.rtdb_url,
                     rtdb_config.rtdb_auth,
                     rtdb_config.user_id,
+                    config_for_rtdb.rtdb_root.clone(),
+                    Duration::from_secs(config_for_rtdb.sse_stall_timeout_secs),
                     engine_rtdb,
                 );
             }
@@ -1830,24 +8102,8 @@ fn start_cloud_sync(config: &Config) -> Arc<Mutex<SyncEngine>> {
         }
     });
 
-    // 주기적 동기화 (fallback: RTDB 연결 끊김 대비)
-    let engine_timer = engine.clone();
-    thread::spawn(move || {
-        loop {
-            thread::sleep(Duration::from_secs(30));
-            if let Ok(mut eng) = engine_timer.lock() {
-                eng.full_sync().ok();
-            }
-        }
-    });
-
-    // 초기 동기화
-    if let Ok(mut eng) = engine.lock() {
-        match eng.full_sync() {
-            Ok((d, u)) => println!("✅ 초기 동기화 완료: ⬇️{} ⬆️{}", d, u),
-            Err(e) => eprintln!("❌ 동기화 실패: {}", e),
-        }
-    }
+    // 초기 동기화 (성공할 때까지 재시도) → 주기적 동기화 (fallback: RTDB 연결 끊김 대비)
+    start_periodic_sync(engine.clone(), config.startup_retry_max_secs);
 
     engine
 }
@@ -2016,8 +8272,17 @@ fn run_setup_tray_app() {
         .with_menu(Box::new(menu))
         .with_tooltip("MDFlare Agent")
         .with_icon(load_icon_setup())
-        .build()
-        .expect("Failed to create tray icon");
+        .build();
+    let tray = match tray {
+        Ok(tray) => tray,
+        Err(e) => {
+            // 설정 전이라 동기화할 대상이 없음 → 안내 후 종료
+            eprintln!("⚠️ 트레이 생성 실패: {}", e);
+            eprintln!("트레이 없는 환경에서는 --connect <토큰> [폴더] 또는 데스크톱에서 만든 config.json으로 실행하세요.");
+            log_to_file(&format!("setup: tray unavailable ({}), exiting", e));
+            std::process::exit(1);
+        }
+    };
 
     let tray = std::cell::RefCell::new(tray);
 
@@ -2025,7 +8290,7 @@ fn run_setup_tray_app() {
     let phase = Arc::new(Mutex::new(AppPhase::Setup));
     let cloud_state: Arc<Mutex<Option<(Config, Arc<Mutex<SyncEngine>>)>>> = Arc::new(Mutex::new(None));
     let cloud_menu_ids: Arc<Mutex<Option<(muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId)>>> = Arc::new(Mutex::new(None));
-    let vault_menu_ids: Arc<Mutex<Option<(muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId)>>> = Arc::new(Mutex::new(None));
+    let vault_menu_ids: Arc<Mutex<Option<(muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId, muda::MenuId)>>> = Arc::new(Mutex::new(None));
     let needs_show_mode_dialog: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
     let dialog_choice: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
     let needs_show_folder_dialog: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
@@ -2033,6 +8298,7 @@ fn run_setup_tray_app() {
     let pending_cloud_config: Arc<Mutex<Option<Config>>> = Arc::new(Mutex::new(None));
     let needs_show_server_dialog: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
     let server_choice: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let vault_tunnel_status: TunnelStatus = Arc::new(Mutex::new(TunnelState::Starting));
 
     let phase_loop = phase.clone();
     let cloud_state_loop = cloud_state.clone();
@@ -2054,6 +8320,17 @@ fn run_setup_tray_app() {
                     show_about_dialog();
                     continue;
                 }
+                if event.id == MenuId::new("diagnostics") {
+                    let report = match cloud_state_menu.lock().unwrap().as_ref() {
+                        Some((config, engine)) => match engine.lock() {
+                            Ok(eng) => collect_diagnostics(config, Some(&eng)),
+                            Err(_) => collect_diagnostics(config, None),
+                        },
+                        None => collect_diagnostics(&Config::load(), None),
+                    };
+                    copy_to_clipboard(&report, "진단 정보가 클립보드에 복사되었습니다");
+                    continue;
+                }
                 let current_phase = phase_menu.lock().unwrap().clone();
 
                 match current_phase {
@@ -2074,6 +8351,12 @@ fn run_setup_tray_app() {
                     AppPhase::Cloud => {
                         if let Some((sync_id, folder_id, web_id, logoff_id, quit_id)) = cloud_menu_ids_menu.lock().unwrap().as_ref() {
                             if &event.id == quit_id {
+                                // 미뤄둔 업로드는 종료 전에 즉시 처리
+                                if let Some((_, engine)) = cloud_state_menu.lock().unwrap().as_ref() {
+                                    if let Ok(mut eng) = engine.lock() {
+                                        eng.flush_idle_uploads(true);
+                                    }
+                                }
                                 std::process::exit(0);
                             } else if &event.id == sync_id {
                                 if let Some((_, engine)) = cloud_state_menu.lock().unwrap().as_ref() {
@@ -2083,7 +8366,7 @@ fn run_setup_tray_app() {
                                 }
                             } else if &event.id == folder_id {
                                 if let Some((config, _)) = cloud_state_menu.lock().unwrap().as_ref() {
-                                    open::that(&config.local_path).ok();
+                                    open_vault_folder(config);
                                 }
                             } else if &event.id == web_id {
                                 if let Some((config, _)) = cloud_state_menu.lock().unwrap().as_ref() {
@@ -2097,39 +8380,37 @@ fn run_setup_tray_app() {
                                 config.local_path.clear();
                                 config.save();
                                 log_to_file("cloud: logoff → credentials cleared, restarting");
-                                let exe = std::env::current_exe().unwrap();
-                                std::process::Command::new(exe).spawn().ok();
-                                std::process::exit(0);
+                                restart_self();
                             }
                         }
                     }
                     AppPhase::Vault => {
-                        if let Some((folder_id, web_id, copy_token_id, disconnect_id, quit_id)) = vault_menu_ids_menu.lock().unwrap().as_ref() {
+                        if let Some((folder_id, web_id, copy_token_id, tunnel_toggle_id, disconnect_id, quit_id)) = vault_menu_ids_menu.lock().unwrap().as_ref() {
                             if &event.id == quit_id {
                                 std::process::exit(0);
                             } else if &event.id == folder_id {
                                 if let Some((config, _)) = cloud_state_menu.lock().unwrap().as_ref() {
-                                    open::that(&config.local_path).ok();
+                                    open_vault_folder(config);
                                 }
                             } else if &event.id == web_id {
                                 let settings = ServerSettings::load();
                                 let config = Config::load();
-                                let conn_token = generate_connection_token(config.server_port, &config.server_token);
+                                let conn_token = generate_connection_token(vault_port(&config), &config.server_token);
                                 let url = format!("{}/?pvtoken={}", settings.api_base, urlencoding::encode(&conn_token));
                                 open::that(url).ok();
                             } else if &event.id == copy_token_id {
                                 let config = Config::load();
-                                let conn_token = generate_connection_token(config.server_port, &config.server_token);
-                                copy_to_clipboard(&conn_token);
+                                let conn_token = peer_connection_token(&config);
+                                copy_to_clipboard(&conn_token, "연결 토큰이 클립보드에 복사되었습니다");
+                            } else if &event.id == tunnel_toggle_id {
+                                toggle_tunnel_and_restart();
                             } else if &event.id == disconnect_id {
                                 let mut config = Config::load();
                                 config.local_path.clear();
-                                config.server_token.clear();
+                                config.clear_server_token();
                                 config.save();
                                 log_to_file("vault: disconnect → config cleared, restarting");
-                                let exe = std::env::current_exe().unwrap();
-                                std::process::Command::new(exe).spawn().ok();
-                                std::process::exit(0);
+                                restart_self();
                             }
                         }
                     }
@@ -2184,6 +8465,8 @@ fn run_setup_tray_app() {
     let mut folder_dialog_window: Option<tao::window::Window> = None;
     let mut server_dialog_webview: Option<wry::WebView> = None;
     let mut server_dialog_window: Option<tao::window::Window> = None;
+    let mut vault_tunnel_item: Option<(MenuItem, TunnelState)> = None;
+    let mut cloud_status_item: Option<(MenuItem, SyncStatus, String)> = None;
 
     event_loop.run(move |event, target, control_flow| {
         *control_flow = ControlFlow::WaitUntil(
@@ -2230,24 +8513,28 @@ fn run_setup_tray_app() {
                     log_to_file("setup: cloud selected → waiting for browser login");
                 }
                 "vault" => {
-                    if let Some(folder) = pick_folder("Private Vault 폴더 선택") {
+                    let picked = pick_folder("Private Vault 폴더 선택").and_then(|folder| {
                         let mut config = Config::load();
                         config.storage_mode = StorageMode::PrivateVault;
                         config.local_path = folder;
+                        confirm_local_path(&mut config).then_some(config)
+                    });
+                    if let Some(config) = picked {
                         fs::create_dir_all(&config.local_path).ok();
                         config.save();
                         *phase_loop.lock().unwrap() = AppPhase::Vault;
                         log_to_file(&format!("setup: vault selected → {}", config.local_path));
 
                         let config_for_server = config.clone();
+                        let tunnel_for_server = vault_tunnel_status.clone();
                         thread::spawn(move || {
                             let rt = tokio::runtime::Runtime::new().unwrap();
-                            rt.block_on(run_private_vault_server(config_for_server));
+                            rt.block_on(run_private_vault_server(config_for_server, tunnel_for_server));
                         });
 
                         // 서버 준비 후 웹페이지 자동 열기 (토큰 포함)
                         let settings = ServerSettings::load();
-                        let conn_token = generate_connection_token(config.server_port, &config.server_token);
+                        let conn_token = generate_connection_token(vault_port(&config), &config.server_token);
                         let web_url = format!("{}/?pvtoken={}", settings.api_base, urlencoding::encode(&conn_token));
                         thread::spawn(move || {
                             thread::sleep(Duration::from_millis(500));
@@ -2266,11 +8553,13 @@ fn run_setup_tray_app() {
             let mut flag = needs_show_folder_dialog_loop.lock().unwrap();
             if *flag {
                 *flag = false;
-                let default_path = dirs::document_dir()
-                    .map(|d| d.join("MDFlare"))
-                    .unwrap_or_default()
-                    .to_string_lossy()
-                    .to_string();
+                let default_path = pending_cloud_config_loop
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|c| c.local_path.clone())
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or_else(default_local_path);
                 let html = FOLDER_SELECTION_HTML.replace("DEFAULT_PATH", &default_path);
 
                 let window = tao::window::WindowBuilder::new()
@@ -2319,19 +8608,32 @@ fn run_setup_tray_app() {
                     folder_dialog_webview.take();
                     folder_dialog_window.take();
 
-                    if let Some(mut config) = pending_cloud_config_loop.lock().unwrap().take() {
+                    let pending = pending_cloud_config_loop.lock().unwrap().take().and_then(|mut config| {
                         config.local_path = path;
+                        confirm_local_path(&mut config).then_some(config)
+                    });
+                    if pending.is_none() {
+                        // 위험 폴더 거부 → 취소와 동일하게 대기 상태로
+                        *phase_loop.lock().unwrap() = AppPhase::Setup;
+                    }
+                    if let Some(mut config) = pending {
+                        prompt_initial_sync_authority(&mut config);
+                        prompt_empty_vault_init(&mut config);
                         fs::create_dir_all(&config.local_path).ok();
                         config.save();
 
                         log_to_file(&format!("setup_tray: folder selected → {} → switching to cloud tray", config.local_path));
 
-                        let (cloud_menu, sync_id, folder_id, web_id, logoff_id, quit_id) = build_cloud_menu(&config);
+                        let (cloud_menu, status_item, sync_id, folder_id, web_id, logoff_id, quit_id) = build_cloud_menu(&config);
                         tray.borrow_mut().set_menu(Some(Box::new(cloud_menu)));
-                        let _ = tray.borrow_mut().set_tooltip(Some(&format!("MDFlare Agent (☁️ {})", config.username)));
+                        let _ = tray.borrow_mut().set_tooltip(Some(tray_tooltip(&config, &format!("☁️ {}", config.username))));
                         tray.borrow_mut().set_icon(Some(load_icon_active())).ok();
 
                         let engine = start_cloud_sync(&config);
+
+                        let status = engine.lock().unwrap().status.clone();
+
+                        cloud_status_item = Some((status_item, status, SYNC_STATUS_OK.to_string()));
                         *cloud_state_loop.lock().unwrap() = Some((config, engine));
                         *cloud_menu_ids_loop.lock().unwrap() = Some((sync_id, folder_id, web_id, logoff_id, quit_id));
                         *phase_loop.lock().unwrap() = AppPhase::Cloud;
@@ -2404,12 +8706,16 @@ fn run_setup_tray_app() {
 
         // 트레이 업데이트 폴링
         if let Some(config) = needs_cloud_update_loop.lock().unwrap().take() {
-            let (cloud_menu, sync_id, folder_id, web_id, logoff_id, quit_id) = build_cloud_menu(&config);
+            let (cloud_menu, status_item, sync_id, folder_id, web_id, logoff_id, quit_id) = build_cloud_menu(&config);
             tray.borrow_mut().set_menu(Some(Box::new(cloud_menu)));
-            let _ = tray.borrow_mut().set_tooltip(Some(&format!("MDFlare Agent (☁️ {})", config.username)));
+            let _ = tray.borrow_mut().set_tooltip(Some(tray_tooltip(&config, &format!("☁️ {}", config.username))));
             tray.borrow_mut().set_icon(Some(load_icon_active())).ok();
 
             let engine = start_cloud_sync(&config);
+
+            let status = engine.lock().unwrap().status.clone();
+
+            cloud_status_item = Some((status_item, status, SYNC_STATUS_OK.to_string()));
             *cloud_state_loop.lock().unwrap() = Some((config, engine));
             *cloud_menu_ids_loop.lock().unwrap() = Some((sync_id, folder_id, web_id, logoff_id, quit_id));
             *phase_loop.lock().unwrap() = AppPhase::Cloud;
@@ -2418,154 +8724,926 @@ fn run_setup_tray_app() {
         if let Some(config) = needs_vault_update_loop.lock().unwrap().take() {
             let vault_menu = Menu::new();
             append_about(&vault_menu);
+            append_vault_name(&vault_menu, &config);
             let settings = ServerSettings::load();
             let web_label = settings.api_base.replace("https://", "").replace("http://", "");
             let mode_item = MenuItem::new("🔐 Private Vault 모드", false, None);
             let port_item = MenuItem::new(format!("🌐 {}", web_label), false, None);
             let path_item = MenuItem::new(format!("📁 {}", shorten_path(&config.local_path)), false, None);
+            let tunnel_state = vault_tunnel_status.lock().unwrap().clone();
+            let tunnel_item = MenuItem::new(tunnel_state.label(), false, None);
             let folder_item = MenuItem::new("📂 폴더 열기", true, None);
             let web_item = MenuItem::new("🌐 웹페이지 열기", true, None);
             let copy_token_item = MenuItem::new("📋 연결 토큰 복사", true, None);
+            let tunnel_toggle_item = CheckMenuItem::new("🌍 외부 접속", true, config.enable_tunnel, None);
             let disconnect_item = MenuItem::new("🔌 연결 해제", true, None);
             let quit_item = MenuItem::new("종료", true, None);
 
             let folder_id = folder_item.id().clone();
             let web_id = web_item.id().clone();
             let copy_token_id = copy_token_item.id().clone();
+            let tunnel_toggle_id = tunnel_toggle_item.id().clone();
             let disconnect_id = disconnect_item.id().clone();
             let quit_id = quit_item.id().clone();
 
             vault_menu.append(&mode_item).ok();
             vault_menu.append(&port_item).ok();
             vault_menu.append(&path_item).ok();
+            vault_menu.append(&tunnel_item).ok();
             vault_menu.append(&PredefinedMenuItem::separator()).ok();
             vault_menu.append(&folder_item).ok();
             vault_menu.append(&web_item).ok();
             vault_menu.append(&copy_token_item).ok();
+            vault_menu.append(&tunnel_toggle_item).ok();
             vault_menu.append(&PredefinedMenuItem::separator()).ok();
             vault_menu.append(&disconnect_item).ok();
             vault_menu.append(&quit_item).ok();
 
             tray.borrow_mut().set_menu(Some(Box::new(vault_menu)));
-            let _ = tray.borrow_mut().set_tooltip(Some("MDFlare Agent (🔐 Private Vault)"));
+            let _ = tray.borrow_mut().set_tooltip(Some(tray_tooltip(&config, "🔐 Private Vault")));
             tray.borrow_mut().set_icon(Some(load_icon_active())).ok();
 
-            *vault_menu_ids_loop.lock().unwrap() = Some((folder_id, web_id, copy_token_id, disconnect_id, quit_id));
+            *vault_menu_ids_loop.lock().unwrap() = Some((folder_id, web_id, copy_token_id, tunnel_toggle_id, disconnect_id, quit_id));
+            vault_tunnel_item = Some((tunnel_item, tunnel_state));
+        }
+
+        // 동기화 상태 변경 시 Cloud 메뉴 갱신
+        if let Some((item, status, shown)) = cloud_status_item.as_mut() {
+            let current = status.lock().unwrap().clone();
+            if current != *shown {
+                item.set_text(&current);
+                *shown = current;
+            }
+        }
+
+        // 터널 상태 변경 시 Vault 메뉴 갱신
+        if let Some((item, shown)) = vault_tunnel_item.as_mut() {
+            let current = vault_tunnel_status.lock().unwrap().clone();
+            if current != *shown {
+                item.set_text(current.label());
+                *shown = current;
+            }
+        }
+
+        {
+            let mut flag = needs_cloud_waiting_update_loop.lock().unwrap();
+            if *flag {
+                *flag = false;
+                let waiting_menu = Menu::new();
+                append_about(&waiting_menu);
+                let status_item = MenuItem::new("☁️ 브라우저에서 로그인 중...", false, None);
+                let quit_item = MenuItem::new("종료", true, None);
+                waiting_menu.append(&status_item).ok();
+                waiting_menu.append(&PredefinedMenuItem::separator()).ok();
+                waiting_menu.append(&quit_item).ok();
+                tray.borrow_mut().set_menu(Some(Box::new(waiting_menu)));
+            }
+        }
+
+        // 이벤트 처리
+        match event {
+            Event::WindowEvent { event: tao::event::WindowEvent::CloseRequested, .. } => {
+                // 다이얼로그 닫기 (X 버튼)
+                mode_dialog_webview.take();
+                mode_dialog_window.take();
+                server_dialog_webview.take();
+                server_dialog_window.take();
+                if folder_dialog_webview.is_some() {
+                    folder_dialog_webview.take();
+                    folder_dialog_window.take();
+                    pending_cloud_config_loop.lock().unwrap().take();
+                    *phase_loop.lock().unwrap() = AppPhase::Setup;
+                }
+            }
+            Event::Opened { urls } => {
+                for url in urls {
+                    let url_str = url.as_str();
+                    if !url_str.starts_with("mdflare://") {
+                        continue;
+                    }
+                    log_to_file("setup_tray: received callback URL");
+
+                    if let Some((username, token, server)) = parse_oauth_callback(url_str) {
+                        let existing = Config::load();
+                        if existing.api_token == token {
+                            log_to_file("setup_tray: duplicate token, skip");
+                            continue;
+                        }
+
+                        let mut config = existing;
+                        config.storage_mode = StorageMode::Cloud;
+                        config.username = username;
+                        config.api_token = token;
+                        if !apply_callback_server(&mut config, server) {
+                            log_to_file("setup_tray: server change declined, login not saved");
+                            continue;
+                        }
+
+                        if config.local_path.is_empty() || config.force_folder_prompt {
+                            // 폴더 선택 다이얼로그 표시
+                            log_to_file(&format!("setup_tray: logged in as {} → showing folder dialog", config.username));
+                            *pending_cloud_config_loop.lock().unwrap() = Some(config);
+                            *needs_show_folder_dialog_loop.lock().unwrap() = true;
+                        } else {
+                            // 이미 폴더가 설정된 경우 (재로그인 등)
+                            fs::create_dir_all(&config.local_path).ok();
+                            config.save();
+
+                            log_to_file(&format!("setup_tray: logged in as {} → switching to cloud tray", config.username));
+
+                            let (cloud_menu, status_item, sync_id, folder_id, web_id, logoff_id, quit_id) = build_cloud_menu(&config);
+                            tray.borrow_mut().set_menu(Some(Box::new(cloud_menu)));
+                            let _ = tray.borrow_mut().set_tooltip(Some(tray_tooltip(&config, &format!("☁️ {}", config.username))));
+                            tray.borrow_mut().set_icon(Some(load_icon_active())).ok();
+
+                            let engine = start_cloud_sync(&config);
+
+                            let status = engine.lock().unwrap().status.clone();
+
+                            cloud_status_item = Some((status_item, status, SYNC_STATUS_OK.to_string()));
+                            *cloud_state_loop.lock().unwrap() = Some((config, engine));
+                            *cloud_menu_ids_loop.lock().unwrap() = Some((sync_id, folder_id, web_id, logoff_id, quit_id));
+                            *phase_loop.lock().unwrap() = AppPhase::Cloud;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    });
+}
+
+// ============================================================================
+// Main
+// ============================================================================
+
+fn default_local_path() -> String {
+    dirs::document_dir()
+        .map(|d| d.join("MDFlare"))
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+fn pick_folder(title: &str) -> Option<String> {
+    pick_folder_at(title, &default_local_path())
+}
+
+fn pick_folder_at(title: &str, default_path: &str) -> Option<String> {
+    rfd::FileDialog::new()
+        .set_title(title)
+        .set_directory(default_path)
+        .pick_folder()
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Remove empty folders under (and including) `dir`, deepest first.
+fn remove_empty_dirs(dir: &Path) {
+    for entry in walkdir::WalkDir::new(dir).contents_first(true).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_dir() {
+            // 비어 있지 않으면 실패 → 그대로 둠
+            fs::remove_dir(entry.path()).ok();
+        }
+    }
+}
+
+/// Watcher filter: a `.obsidian` config file in the `obsidian_sync` scope (also when deleted).
+fn is_obsidian_event(path: &Path, local_path: &Path, mode: ObsidianSync) -> bool {
+    path.strip_prefix(local_path)
+        .is_ok_and(|rel| is_obsidian_synced(&rel.to_string_lossy().replace('\\', "/"), mode))
+}
+
+/// Watcher filter: a note (`extensions`) or, when enabled, its metadata sidecar (`.md{suffix}`).
+fn is_synced_file(path: &Path, metadata_suffix: &str, extensions: &[String]) -> bool {
+    if is_note_file(path, extensions) {
+        return true;
+    }
+    !metadata_suffix.is_empty()
+        && path
+            .to_str()
+            .and_then(|p| p.strip_suffix(metadata_suffix))
+            .is_some_and(|note| note.ends_with(".md"))
+}
+
+/// Folder names of common sync clients (also matches "OneDrive - Company", "Dropbox (Personal)")
+/// and marker files they leave in their root.
+const DEFAULT_CLOUD_SYNC_MARKERS: &[&str] = &[
+    "Dropbox",
+    "OneDrive",
+    "iCloud Drive",
+    "Mobile Documents",
+    "CloudStorage",
+    "Google Drive",
+    "My Drive",
+    "Box",
+    "pCloud Drive",
+    "MEGA",
+    "Nextcloud",
+    "ownCloud",
+    "Yandex.Disk",
+    ".dropbox",
+    ".dropbox.cache",
+];
+
+/// The folder containing `path` that belongs to another sync client, if any.
+fn cloud_sync_parent(path: &Path, markers: &[String]) -> Option<PathBuf> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    path.ancestors().find_map(|dir| {
+        let name = dir.file_name()?.to_string_lossy().to_lowercase();
+        let matched = markers.iter().any(|marker| {
+            if marker.starts_with('.') {
+                return dir.join(marker).exists();
+            }
+            let marker = marker.to_lowercase();
+            name == marker || name.strip_prefix(&marker).is_some_and(|rest| rest.starts_with([' ', '(', '-']))
+        });
+        matched.then(|| dir.to_path_buf())
+    })
+}
+
+/// Warn once per `local_path` when it sits inside Dropbox/iCloud/OneDrive etc., where two
+/// sync engines would fight over the same files. Advisory only; "다시 보지 않기" is
+/// remembered in `config.cloud_sync_warning_dismissed` (caller saves).
+fn warn_if_in_cloud_sync_folder(config: &mut Config) {
+    if config.local_path.is_empty() || config.cloud_sync_warning_dismissed.contains(&config.local_path) {
+        return;
+    }
+    let Some(parent) = cloud_sync_parent(Path::new(&config.local_path), &config.cloud_sync_markers) else {
+        return;
+    };
+    log_to_file(&format!("local_path inside cloud-sync folder: {} ({})", config.local_path, parent.display()));
+    if !has_display() {
+        eprintln!("⚠️ 동기화 폴더가 다른 클라우드 동기화 폴더 안에 있습니다: {}", parent.display());
+        eprintln!("   두 동기화가 같은 파일을 다투면 중복/손상이 생길 수 있습니다. 클라우드 동기화 밖의 폴더를 권장합니다.");
+        return;
+    }
+    let dismiss = "다시 보지 않기".to_string();
+    let answer = rfd::MessageDialog::new()
+        .set_title("MDFlare Agent - 클라우드 동기화 폴더")
+        .set_description(format!(
+            "동기화 폴더가 다른 클라우드 동기화 폴더 안에 있습니다.\n\n{}\n→ {}\n\n두 동기화 프로그램이 같은 파일을 동시에 고치면 중복 파일이나 내용 손상이 생길 수 있습니다.\nDropbox/iCloud/OneDrive 등이 동기화하지 않는 폴더를 선택하는 것을 권장합니다.",
+            config.local_path,
+            parent.display()
+        ))
+        .set_level(rfd::MessageLevel::Warning)
+        .set_buttons(rfd::MessageButtons::OkCancelCustom("확인".to_string(), dismiss.clone()))
+        .show();
+    if answer == rfd::MessageDialogResult::Custom(dismiss) {
+        config.cloud_sync_warning_dismissed.push(config.local_path.clone());
+    }
+}
+
+/// Why `path` looks like the wrong sync folder, if it does.
+fn local_path_danger(path: &Path, max_files: usize) -> Option<String> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if path.parent().is_none() {
+        return Some("드라이브/파일시스템 루트입니다".to_string());
+    }
+    if dirs::home_dir().and_then(|h| h.canonicalize().ok()).as_deref() == Some(path.as_path()) {
+        return Some("홈 폴더 전체입니다".to_string());
+    }
+    for marker in [".git", "node_modules"] {
+        if path.join(marker).exists() {
+            return Some(format!("{} 가 있는 프로젝트 폴더입니다", marker));
+        }
+    }
+    if max_files > 0 {
+        let count = walkdir::WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .take(max_files + 1)
+            .count();
+        if count > max_files {
+            return Some(format!("파일이 {}개를 넘습니다", max_files));
+        }
+    }
+    None
+}
+
+/// Why `count` files / `total` bytes exceed `max_sync_files` / `max_sync_total_bytes` (0 = no limit), if they do.
+fn sync_limit_excess(count: usize, total: u64, max_files: usize, max_bytes: u64) -> Option<String> {
+    if max_files > 0 && count > max_files {
+        return Some(format!("동기화할 파일이 {}개({})로 max_sync_files({}개)를 넘습니다", count, format_bytes(total), max_files));
+    }
+    if max_bytes > 0 && total > max_bytes {
+        return Some(format!(
+            "동기화할 파일 {}개의 크기가 {}로 max_sync_total_bytes({})를 넘습니다",
+            count,
+            format_bytes(total),
+            format_bytes(max_bytes)
+        ));
+    }
+    None
+}
+
+/// `sync_limit_excess` for the notes under `config.local_path` (folder selection).
+fn local_path_limit_excess(config: &Config) -> Option<String> {
+    if config.max_sync_files == 0 && config.max_sync_total_bytes == 0 {
+        return None;
+    }
+    let scanned = scan_local_md_files(Path::new(&config.local_path), config.include_hidden, &config.extensions);
+    let files = SyncEngine::flatten_files(&scanned);
+    let total: u64 = files.iter().filter_map(|(_, _, size)| *size).sum();
+    sync_limit_excess(files.len(), total, config.max_sync_files, config.max_sync_total_bytes)
+}
+
+/// Ask before syncing a dangerous `local_path`. A "yes" is remembered in
+/// `config.confirmed_local_paths` (caller saves). Without a display, refuses.
+/// Also shows the (non-blocking) cloud-sync folder warning.
+fn confirm_local_path(config: &mut Config) -> bool {
+    warn_if_in_cloud_sync_folder(config);
+    if config.confirmed_local_paths.contains(&config.local_path) {
+        return true;
+    }
+    let danger = local_path_danger(Path::new(&config.local_path), config.max_safe_file_count);
+    let Some(reason) = danger.or_else(|| local_path_limit_excess(config)) else {
+        return true;
+    };
+    log_to_file(&format!("local_path danger: {} ({})", config.local_path, reason));
+
+    if !has_display() {
+        eprintln!("⚠️ 동기화 폴더가 위험해 보입니다: {} ({})", config.local_path, reason);
+        eprintln!("   의도한 폴더라면 config.json의 confirmed_local_paths에 추가하세요.");
+        return false;
+    }
+    let answer = rfd::MessageDialog::new()
+        .set_title("MDFlare Agent - 동기화 폴더 확인")
+        .set_description(format!(
+            "선택한 폴더가 동기화 대상으로 적절하지 않아 보입니다.\n\n{}\n→ {}\n\n관련 없는 파일까지 대량으로 검사·동기화될 수 있습니다.\n이 폴더로 계속할까요? (아니오: 다른 폴더 선택)",
+            config.local_path, reason
+        ))
+        .set_level(rfd::MessageLevel::Warning)
+        .set_buttons(rfd::MessageButtons::YesNo)
+        .show();
+    if answer == rfd::MessageDialogResult::Yes {
+        config.confirmed_local_paths.push(config.local_path.clone());
+        true
+    } else {
+        log_to_file("  → local_path rejected by user");
+        false
+    }
+}
+
+/// Onboarding: when the chosen folder already has notes, ask which side wins on the first sync.
+fn prompt_initial_sync_authority(config: &mut Config) {
+    if !has_display() || scan_local_md_files(Path::new(&config.local_path), config.include_hidden, &config.extensions).is_empty() {
+        return;
+    }
+    let local = "로컬 기준".to_string();
+    let cloud = "클라우드 기준".to_string();
+    let answer = rfd::MessageDialog::new()
+        .set_title("MDFlare Agent - 첫 동기화")
+        .set_description(
+            "선택한 폴더에 이미 노트가 있습니다.\n\n클라우드에도 같은 경로의 파일이 있으면 어느 쪽을 기준으로 할까요?\n\n• 로컬 기준: 로컬 내용으로 클라우드를 덮어씀\n• 클라우드 기준: 클라우드 내용으로 로컬을 덮어씀\n• 자동: 기존 방식대로 처리",
+        )
+        .set_level(rfd::MessageLevel::Info)
+        .set_buttons(rfd::MessageButtons::YesNoCancelCustom(local.clone(), cloud.clone(), "자동".to_string()))
+        .show();
+    config.initial_sync_authority = match answer {
+        rfd::MessageDialogResult::Custom(label) if label == local => Some(SyncAuthority::Local),
+        rfd::MessageDialogResult::Custom(label) if label == cloud => Some(SyncAuthority::Cloud),
+        _ => None,
+    };
+    log_to_file(&format!("initial sync authority: {:?}", config.initial_sync_authority));
+}
+
+/// Record that this account + folder starts out empty on purpose (caller saves). Returns the timestamp.
+fn record_empty_vault_init(config: &mut Config) -> String {
+    let date = chrono::Local::now().to_rfc3339();
+    let key = vault_key(config);
+    log_to_file(&format!("empty vault init: {} ({})", key, date));
+    config.empty_vault_inits.insert(key, date.clone());
+    date
+}
+
+/// Onboarding: when the chosen folder is empty, ask whether this is a brand-new vault so the
+/// first sync doesn't mistake the empty folder for a disconnected drive.
+fn prompt_empty_vault_init(config: &mut Config) {
+    if !has_display() || !scan_local_md_files(Path::new(&config.local_path), config.include_hidden, &config.extensions).is_empty() {
+        return;
+    }
+    let answer = rfd::MessageDialog::new()
+        .set_title("MDFlare Agent - 새 Vault")
+        .set_description(
+            "선택한 폴더가 비어 있습니다.\n\n노트 없이 새로 시작하는 Vault인가요?\n\n'예'를 누르면 빈 상태가 의도된 것으로 기록되어, 첫 동기화에서 빈 폴더를 드라이브 연결 문제로 오인하지 않습니다.",
+        )
+        .set_level(rfd::MessageLevel::Info)
+        .set_buttons(rfd::MessageButtons::YesNo)
+        .show();
+    if answer == rfd::MessageDialogResult::Yes {
+        record_empty_vault_init(config);
+    }
+}
+
+fn setup_private_vault(mut config: Config) {
+    config.storage_mode = StorageMode::PrivateVault;
+    if let Some(folder) = pick_folder("Private Vault 폴더 선택") {
+        config.local_path = folder;
+    } else {
+        println!("폴더 선택이 취소되었습니다.");
+        return;
+    }
+    if !confirm_local_path(&mut config) {
+        println!("폴더 선택이 취소되었습니다.");
+        return;
+    }
+    fs::create_dir_all(&config.local_path).ok();
+    config.save();
+
+    let conn_token = generate_connection_token(config.server_port, &config.server_token);
+    println!("🔐 Private Vault 모드");
+    println!("📁 {}", config.local_path);
+    println!("🔑 연결 토큰: {}", conn_token);
+
+    run_private_vault_tray_app(config);
+}
+
+/// `selftest`: round-trip random text pairs through generate_line_diff/apply_line_diff.
+/// Returns the number of failing cases.
+fn run_diff_selftest(cases: usize) -> usize {
+    // 외부 의존성 없이 재현 가능한 xorshift 난수
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(1)
+        | 1;
+    let mut state = seed;
+    let mut next = move |bound: usize| -> usize {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % bound.max(1) as u64) as usize
+    };
+
+    const LINES: &[&str] = &["", "# 제목", "- 항목", "본문 텍스트", "  들여쓰기", "```", "a", "b", "\t탭"];
+    let random_text = |next: &mut dyn FnMut(usize) -> usize| -> String {
+        let newline = if next(4) == 0 { "\r\n" } else { "\n" };
+        let text = match next(10) {
+            0 => String::new(),
+            1 => "x".repeat(next(200_000)),
+            _ => (0..next(30))
+                .map(|_| LINES[next(LINES.len())])
+                .collect::<Vec<_>>()
+                .join(newline),
+        };
+        if next(2) == 0 { text + newline } else { text }
+    };
+
+    let mut failures = 0;
+    for i in 0..cases {
+        let old = random_text(&mut next);
+        let new = if next(3) == 0 {
+            random_text(&mut next)
+        } else {
+            // 기존 내용 일부 수정 (실제 편집에 가까운 경우)
+            let mut lines: Vec<&str> = old.split('\n').collect();
+            let at = next(lines.len() + 1);
+            lines.insert(at, LINES[next(LINES.len())]);
+            if next(2) == 0 && lines.len() > 1 {
+                lines.remove(next(lines.len()));
+            }
+            lines.join("\n")
+        };
+        let diff = generate_line_diff(&old, &new);
+        let applied = diff.as_array().and_then(|ops| apply_line_diff(&old, ops));
+        if applied.as_deref() != Some(new.as_str()) {
+            failures += 1;
+            if failures <= 5 {
+                let short = |s: &str| s.chars().take(80).collect::<String>();
+                println!("❌ case {}: old={:?} new={:?} diff={}", i, short(&old), short(&new), short(&diff.to_string()));
+            }
+        }
+    }
+    println!("selftest (seed {}): {}/{} 통과", seed, cases - failures, cases);
+    failures
+}
+
+/// Switch to peer mode: sync a local folder against another agent's vault.
+fn connect_peer_vault(token: Option<&str>, folder: Option<&str>) {
+    let Some(parsed) = token.and_then(parse_connection_token) else {
+        println!("❌ 올바른 연결 토큰이 아닙니다. Vault 기기의 트레이에서 '연결 토큰 복사'로 받은 값을 붙여넣으세요.");
+        return;
+    };
+    let ConnectionToken { url, token, remote_subpath, vault_name, .. } = parsed;
+    if let Some(subpath) = &remote_subpath {
+        println!("📁 토큰의 기본 하위 경로: {}", subpath);
+    }
+    let folder = match folder {
+        Some(f) => Some(f.to_string()),
+        None => {
+            // v2 토큰이면 하위 경로/Vault 이름으로 폴더 위치 제안
+            let suggested = remote_subpath
+                .as_deref()
+                .and_then(|p| p.rsplit('/').next())
+                .or(vault_name.as_deref())
+                .map(|name| Path::new(&default_local_path()).join(name).to_string_lossy().to_string())
+                .unwrap_or_else(default_local_path);
+            pick_folder_at("연결할 Vault와 동기화할 폴더 선택", &suggested)
+        }
+    };
+    let Some(folder) = folder else {
+        println!("폴더 선택이 취소되었습니다.");
+        return;
+    };
+
+    let probe = ApiClient::new(&url, "", &token).with_request_signing(true);
+    if let Err(e) = probe.list_files() {
+        println!("❌ Vault 접속 실패 ({}): {}", url, e);
+        return;
+    }
+
+    let mut config = Config::load();
+    config.storage_mode = StorageMode::PeerVault;
+    config.peer_url = url.clone();
+    config.peer_token = token;
+    config.local_path = folder;
+    if config.vault_name.is_none() {
+        config.vault_name = vault_name;
+    }
+    if !confirm_local_path(&mut config) {
+        println!("폴더 선택이 취소되었습니다.");
+        return;
+    }
+    config.save();
+    println!("🔗 {} 에 연결됨 - mdflare-agent 를 실행하면 동기화가 시작됩니다", url);
+}
+
+/// `init [폴더]`: mark the configured (or given) folder as an intentionally empty new vault.
+fn run_init(folder: Option<&str>) {
+    let mut config = Config::load();
+    if let Some(folder) = folder {
+        config.local_path = folder.to_string();
+    }
+    if config.local_path.is_empty() {
+        println!("동기화 폴더가 설정되지 않았습니다. 사용법: mdflare-agent init <폴더>");
+        return;
+    }
+    if !scan_local_md_files(Path::new(&config.local_path), config.include_hidden, &config.extensions).is_empty() {
+        println!("❌ {} 에 이미 노트가 있습니다 - init은 빈 폴더에만 사용합니다.", config.local_path);
+        return;
+    }
+    if folder.is_some() && !confirm_local_path(&mut config) {
+        return;
+    }
+    if let Err(e) = fs::create_dir_all(&config.local_path) {
+        println!("❌ 폴더 생성 실패 ({}): {}", config.local_path, e);
+        return;
+    }
+    let date = record_empty_vault_init(&mut config);
+    config.save();
+    println!("🆕 빈 Vault로 시작 기록: {} ({})", config.local_path, date);
+}
+
+/// 이 기기가 동기화했는데 로컬에서 사라진 원격 파일 (`orphans`)
+struct Orphan {
+    path: String,
+    size: Option<u64>,
+    // 이 기기에 삭제 기록이 있음 (서버 삭제가 반영 안 된 경우)
+    tombstoned: bool,
+}
+
+/// `orphans [--delete]`: list remote files missing locally, optionally deleting them.
+fn run_orphans(delete: bool) {
+    let config = Config::load();
+    if !config.is_configured() || config.storage_mode == StorageMode::PrivateVault {
+        println!("Cloud 또는 연결 모드 설정이 필요합니다.");
+        return;
+    }
+    let mut engine = SyncEngine::new(&config);
+    let orphans = match engine.find_orphans() {
+        Ok(orphans) => orphans,
+        Err(e) => {
+            println!("❌ 원격 목록 조회 실패: {}", e);
+            return;
         }
+    };
+    if orphans.is_empty() {
+        println!("✅ 동기화된 뒤 로컬에서 사라진 원격 파일이 없습니다.");
+        return;
+    }
+    for orphan in &orphans {
+        let note = if orphan.tombstoned { "  (로컬 삭제 기록 있음)" } else { "" };
+        let size = orphan.size.map(format_bytes).unwrap_or_else(|| "-".to_string());
+        println!("{:>10}  {}{}", size, orphan.path, note);
+    }
+    println!("총 {}개", orphans.len());
+    if !delete {
+        return;
+    }
 
-        {
-            let mut flag = needs_cloud_waiting_update_loop.lock().unwrap();
-            if *flag {
-                *flag = false;
-                let waiting_menu = Menu::new();
-                append_about(&waiting_menu);
-                let status_item = MenuItem::new("☁️ 브라우저에서 로그인 중...", false, None);
-                let quit_item = MenuItem::new("종료", true, None);
-                waiting_menu.append(&status_item).ok();
-                waiting_menu.append(&PredefinedMenuItem::separator()).ok();
-                waiting_menu.append(&quit_item).ok();
-                tray.borrow_mut().set_menu(Some(Box::new(waiting_menu)));
+    if engine.exceeds_delete_limit(orphans.len()) {
+        println!(
+            "❌ {}개는 max_deletes_per_sync({})를 넘습니다. config.json에서 한도를 올린 뒤 다시 실행하세요.",
+            orphans.len(),
+            config.max_deletes_per_sync
+        );
+        return;
+    }
+    print!("위 {}개 파일을 서버에서 삭제할까요? [y/N] ", orphans.len());
+    use std::io::Write;
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).ok();
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        println!("취소했습니다.");
+        return;
+    }
+    for Orphan { path, .. } in orphans {
+        match engine.api.delete_file(&path) {
+            Ok(()) => {
+                // 로컬 사본이 없으니 해시 없이 기록 (다른 기기의 파일을 지우진 않음)
+                engine.record_tombstone(&path, None);
+                log_to_file(&format!("orphans: deleted {}", path));
+                println!("🗑️ {}", path);
             }
+            Err(e) => println!("❌ {}: {}", path, e),
         }
+    }
+}
 
-        // 이벤트 처리
-        match event {
-            Event::WindowEvent { event: tao::event::WindowEvent::CloseRequested, .. } => {
-                // 다이얼로그 닫기 (X 버튼)
-                mode_dialog_webview.take();
-                mode_dialog_window.take();
-                server_dialog_webview.take();
-                server_dialog_window.take();
-                if folder_dialog_webview.is_some() {
-                    folder_dialog_webview.take();
-                    folder_dialog_window.take();
-                    pending_cloud_config_loop.lock().unwrap().take();
-                    *phase_loop.lock().unwrap() = AppPhase::Setup;
-                }
+/// `diff <경로>`: unified diff of the server copy against the local file, both hashes and
+/// what sync would do with it. Read-only.
+fn run_diff(path: &str) {
+    let config = Config::load();
+    if !config.is_configured() || config.storage_mode == StorageMode::PrivateVault {
+        println!("Cloud 또는 연결 모드 설정이 필요합니다.");
+        return;
+    }
+    let path = path.trim_start_matches("./").replace('\\', "/");
+    let engine = SyncEngine::new(&config);
+    let listed = match engine.api.list_files() {
+        Ok(items) => SyncEngine::flatten_files(&items).into_iter().find(|(p, _, _)| *p == path),
+        Err(e) => {
+            println!("❌ 원격 목록 조회 실패: {}", e);
+            return;
+        }
+    };
+    let remote = match &listed {
+        Some(_) => match engine.api.get_file(&path) {
+            Ok(file) => Some(file.content),
+            Err(e) => {
+                println!("❌ 원격 파일 조회 실패: {}", e);
+                return;
             }
-            Event::Opened { urls } => {
-                for url in urls {
-                    let url_str = url.as_str();
-                    if !url_str.starts_with("mdflare://") {
-                        continue;
-                    }
-                    log_to_file(&format!("setup_tray: received URL {}", url_str));
+        },
+        None => None,
+    };
+    let local_file = engine.local_file(&path);
+    let local = fs::read_to_string(&local_file).ok();
 
-                    if let Some((username, token)) = parse_oauth_callback(url_str) {
-                        let existing = Config::load();
-                        if existing.api_token == token {
-                            log_to_file("setup_tray: duplicate token, skip");
-                            continue;
-                        }
+    let describe = |content: &Option<String>| match content {
+        Some(c) => format!("{} ({}, sha256 {})", SyncEngine::simple_hash(c), format_bytes(c.len() as u64), &sha256_hex(c.as_bytes())[..12]),
+        None => "없음".to_string(),
+    };
+    println!("경로:   {}", path);
+    println!("로컬:   {}  {}", describe(&local), local_file.display());
+    println!("원격:   {}", describe(&remote));
+    if let Some(modified) = listed.as_ref().and_then(|(_, m, _)| m.as_deref()) {
+        println!("원격 modified: {}", modified);
+    }
+    let decision = engine.sync_decision(
+        &path,
+        remote.as_deref().map(|r| (r, listed.as_ref().and_then(|(_, m, _)| m.as_deref()))),
+        local.as_deref(),
+    );
+    println!("동기화: {}", decision);
+
+    if let (Some(remote), Some(local)) = (&remote, &local) {
+        if remote != local {
+            println!();
+            let diff = similar::TextDiff::from_lines(remote.as_str(), local.as_str());
+            print!(
+                "{}",
+                diff.unified_diff()
+                    .context_radius(3)
+                    .header(&format!("remote/{}", path), &format!("local/{}", path))
+            );
+        }
+    }
+}
 
-                        let mut config = existing;
-                        config.storage_mode = StorageMode::Cloud;
-                        config.username = username;
-                        config.api_token = token;
+/// `deletes [approve|reject] [경로...]`: list deletes staged by `delete_policy = review`,
+/// or approve (remove) / reject (restore + re-upload) them. No paths = all.
+fn run_pending_deletes(action: Option<&str>, paths: &[String]) {
+    let config = Config::load();
+    if !config.is_configured() || config.storage_mode == StorageMode::PrivateVault {
+        println!("Cloud 또는 연결 모드 설정이 필요합니다.");
+        return;
+    }
+    let mut engine = SyncEngine::new(&config);
+    match action {
+        None | Some("list") => {
+            let pending = engine.pending_deletes();
+            if pending.is_empty() {
+                println!("✅ 검토 대기 중인 삭제가 없습니다.");
+                return;
+            }
+            for path in &pending {
+                println!("  {}", path);
+            }
+            println!("총 {}개 - 승인: deletes approve, 거부(복원): deletes reject", pending.len());
+        }
+        Some("approve") => println!("🗑️ {}개 삭제함", engine.approve_pending_deletes(paths)),
+        Some("reject") => println!("↩️ {}개 복원하고 다시 업로드함", engine.reject_pending_deletes(paths)),
+        Some(other) => println!("알 수 없는 동작: {} (approve 또는 reject)", other),
+    }
+}
 
-                        if config.local_path.is_empty() {
-                            // 폴더 선택 다이얼로그 표시
-                            log_to_file(&format!("setup_tray: logged in as {} → showing folder dialog", config.username));
-                            *pending_cloud_config_loop.lock().unwrap() = Some(config);
-                            *needs_show_folder_dialog_loop.lock().unwrap() = true;
-                        } else {
-                            // 이미 폴더가 설정된 경우 (재로그인 등)
-                            fs::create_dir_all(&config.local_path).ok();
-                            config.save();
+/// `reset [--yes]`: delete the agent's own state (config, settings, sync state, log, cache).
+/// Notes in `local_path`, first-sync backups and the keychain passphrase are kept.
+fn run_reset(yes: bool) {
+    let config = Config::load();
+    let Some(dir) = Config::config_path().parent().map(Path::to_path_buf) else {
+        println!("❌ 설정 폴더를 찾을 수 없습니다.");
+        return;
+    };
+    let notes = PathBuf::from(&config.local_path);
+    let targets: Vec<PathBuf> = [
+        Config::config_path(),
+        ServerSettings::settings_path(),
+        Tombstones::tombstones_path(),
+        SyncedPaths::synced_paths_path(),
+        RtdbCursor::cursor_path(),
+        FlatNames::flat_names_path(),
+        RestartLog::restart_log_path(),
+        dir.join("agent.log"),
+        dir.join("blobs"),
+    ]
+    .into_iter()
+    // 노트 폴더가 설정 폴더 안에 있어도 절대 건드리지 않음
+    .filter(|p| p.exists() && (config.local_path.is_empty() || !notes.starts_with(p)))
+    .collect();
+    if targets.is_empty() {
+        println!("✅ 지울 에이전트 상태가 없습니다.");
+        return;
+    }
 
-                            log_to_file(&format!("setup_tray: logged in as {} → switching to cloud tray", config.username));
+    println!("다음 에이전트 상태를 삭제합니다:");
+    for target in &targets {
+        println!("  {}", target.display());
+    }
+    if !config.local_path.is_empty() {
+        println!("노트 폴더는 그대로 둡니다: {}", config.local_path);
+    }
+    println!("첫 동기화 백업({})과 키체인의 암호화 패스프레이즈도 유지됩니다.", dir.join("backups").display());
+    if !yes {
+        print!("계속할까요? [y/N] ");
+        use std::io::Write;
+        std::io::stdout().flush().ok();
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).ok();
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("취소했습니다.");
+            return;
+        }
+    }
+    for target in targets {
+        let result = if target.is_dir() { fs::remove_dir_all(&target) } else { fs::remove_file(&target) };
+        match result {
+            Ok(()) => println!("🗑️ {}", target.display()),
+            Err(e) => println!("❌ {}: {}", target.display(), e),
+        }
+    }
+}
 
-                            let (cloud_menu, sync_id, folder_id, web_id, logoff_id, quit_id) = build_cloud_menu(&config);
-                            tray.borrow_mut().set_menu(Some(Box::new(cloud_menu)));
-                            let _ = tray.borrow_mut().set_tooltip(Some(&format!("MDFlare Agent (☁️ {})", config.username)));
-                            tray.borrow_mut().set_icon(Some(load_icon_active())).ok();
+fn set_encryption_passphrase() {
+    let mut config = Config::load();
+    if config.username.is_empty() {
+        println!("Cloud 로그인 후 설정할 수 있습니다.");
+        return;
+    }
+    println!("⚠️ E2E 암호화는 실험적 기능입니다. 패스프레이즈를 잃어버리면 복구할 수 없습니다.");
+    println!("패스프레이즈 입력 (모든 기기와 웹 에디터에서 동일해야 함):");
+    let mut passphrase = String::new();
+    if std::io::stdin().read_line(&mut passphrase).is_err() || passphrase.trim().is_empty() {
+        println!("입력이 비어 있어 취소되었습니다.");
+        return;
+    }
+    let saved = keychain_entry(&config.username)
+        .and_then(|entry| entry.set_password(passphrase.trim()));
+    match saved {
+        Ok(()) => {
+            config.encryption_enabled = true;
+            config.save();
+            println!("🔐 E2E 암호화 켜짐 (키체인에 저장됨)");
+        }
+        Err(e) => println!("❌ 키체인 저장 실패: {}", e),
+    }
+}
 
-                            let engine = start_cloud_sync(&config);
-                            *cloud_state_loop.lock().unwrap() = Some((config, engine));
-                            *cloud_menu_ids_loop.lock().unwrap() = Some((sync_id, folder_id, web_id, logoff_id, quit_id));
-                            *phase_loop.lock().unwrap() = AppPhase::Cloud;
-                        }
-                    }
-                }
-            }
-            _ => {}
+/// Mask secrets in one log line for `export-logs`: `token=` values, `Bearer` credentials
+/// and long hex/base64 runs that look like tokens.
+fn redact_log_line(line: &str) -> String {
+    const MASK: &str = "[REDACTED]";
+    let is_word_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '_' | '-');
+    let mut out = String::with_capacity(line.len());
+    let mut prev_word = "";
+    let mut gap = String::new();
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        if !is_word_char(c) {
+            out.push(c);
+            gap.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
         }
-    });
+        let end = rest.find(|c: char| !is_word_char(c)).unwrap_or(rest.len());
+        let word = &rest[..end];
+        let secret_value = (prev_word.eq_ignore_ascii_case("bearer") && gap == " ")
+            || (prev_word.to_ascii_lowercase().ends_with("token") && matches!(gap.trim_matches([' ', '"', '[']), "=" | ":"));
+        out.push_str(if secret_value || looks_like_token(word) { MASK } else { word });
+        prev_word = word;
+        gap.clear();
+        rest = &rest[end..];
+    }
+    out
 }
 
-// ============================================================================
-// Main
-// ============================================================================
+/// 32자 이상 hex, 또는 대소문자·숫자가 섞인 24자 이상 base64 문자열
+fn looks_like_token(word: &str) -> bool {
+    let hex = word.len() >= 32 && word.chars().all(|c| c.is_ascii_hexdigit());
+    let base64 = word.len() >= 24
+        && word.chars().any(|c| c.is_ascii_uppercase())
+        && word.chars().any(|c| c.is_ascii_lowercase())
+        && word.chars().any(|c| c.is_ascii_digit());
+    hex || base64
+}
 
-fn pick_folder(title: &str) -> Option<String> {
-    let default_path = dirs::document_dir()
-        .map(|d| d.join("MDFlare"))
+/// Bundle logs, redacted config, sync state and recent events into a zip for bug reports.
+fn export_logs(out: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    let config_dir = Config::config_path()
+        .parent()
+        .map(Path::to_path_buf)
         .unwrap_or_default();
+    let mut zip = zip::ZipWriter::new(fs::File::create(out)?);
+    let options = SimpleFileOptions::default();
+
+    // agent.log (+ 회전된 agent.log.1 등)
+    for entry in fs::read_dir(&config_dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("agent.log") {
+            // 예전 버전 로그에 토큰 일부가 남아 있을 수 있으므로 줄마다 가림
+            let data = fs::read(entry.path())?;
+            let redacted: Vec<String> = String::from_utf8_lossy(&data).lines().map(redact_log_line).collect();
+            zip.start_file(format!("logs/{}", name), options)?;
+            zip.write_all(redacted.join("\n").as_bytes())?;
+        }
+    }
 
-    rfd::FileDialog::new()
-        .set_title(title)
-        .set_directory(&default_path)
-        .pick_folder()
-        .map(|p| p.to_string_lossy().to_string())
-}
+    // 설정 (토큰은 값까지 완전히 제거)
+    for name in ["config.json", "server_settings.json"] {
+        let Ok(data) = fs::read_to_string(config_dir.join(name)) else { continue };
+        let mut value: serde_json::Value = serde_json::from_str(&data).unwrap_or_default();
+        if let Some(obj) = value.as_object_mut() {
+            obj.remove("api_token");
+            obj.remove("server_token");
+            obj.remove("server_token_hash");
+            obj.remove("peer_token");
+            // 웹훅 URL은 그 자체가 비밀 (URL만 알면 호출 가능)
+            obj.remove("sync_webhook_url");
+        }
+        zip.start_file(name, options)?;
+        zip.write_all(serde_json::to_string_pretty(&value)?.as_bytes())?;
+    }
 
-fn setup_private_vault(mut config: Config) {
-    config.storage_mode = StorageMode::PrivateVault;
-    if let Some(folder) = pick_folder("Private Vault 폴더 선택") {
-        config.local_path = folder;
-    } else {
-        println!("폴더 선택이 취소되었습니다.");
-        return;
+    // 영속 동기화 상태
+    if let Ok(data) = fs::read(Tombstones::tombstones_path()) {
+        zip.start_file("state/tombstones.json", options)?;
+        zip.write_all(&data)?;
     }
-    fs::create_dir_all(&config.local_path).ok();
-    config.save();
 
-    let conn_token = generate_connection_token(config.server_port, &config.server_token);
-    println!("🔐 Private Vault 모드");
-    println!("📁 {}", config.local_path);
-    println!("🔑 연결 토큰: {}", conn_token);
+    // 실행 중인 에이전트의 최근 이벤트 (상태 서버가 켜져 있을 때만)
+    let config = Config::load();
+    if let Some(port) = config.status_port {
+        let events = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(2))
+            .build()?
+            .get(format!("http://127.0.0.1:{}/events/recent", port))
+            .send()
+            .and_then(|r| r.text());
+        if let Ok(events) = events {
+            zip.start_file("events.json", options)?;
+            zip.write_all(events.as_bytes())?;
+        }
+    }
 
-    run_private_vault_tray_app(config);
+    let system = format!(
+        "agent_version: {}\nos: {}\narch: {}\nmode: {:?}\nexported_at: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        config.storage_mode,
+        chrono::Local::now().to_rfc3339(),
+    );
+    zip.start_file("system.txt", options)?;
+    zip.write_all(system.as_bytes())?;
+
+    zip.finish()?;
+    Ok(())
 }
 
 fn main() {
@@ -2591,6 +9669,71 @@ fn main() {
                 handle_url_callback(url);
                 return;
             }
+            "--connect" => {
+                connect_peer_vault(args.get(2).map(String::as_str), args.get(3).map(String::as_str));
+                return;
+            }
+            "--set-encryption-passphrase" => {
+                set_encryption_passphrase();
+                return;
+            }
+            "--disable-encryption" => {
+                let mut config = Config::load();
+                if let Ok(entry) = keychain_entry(&config.username) {
+                    entry.delete_credential().ok();
+                }
+                config.encryption_enabled = false;
+                config.save();
+                println!("🔓 E2E 암호화 해제됨 (이후 업로드는 평문)");
+                return;
+            }
+            "--export-logs" | "export-logs" => {
+                let out = args.get(2).map(String::as_str).unwrap_or("mdflare-report.zip");
+                match export_logs(Path::new(out)) {
+                    Ok(()) => println!("📦 {} 생성됨 (토큰 제거됨) - 이슈에 첨부해 주세요", out),
+                    Err(e) => println!("❌ 로그 내보내기 실패: {}", e),
+                }
+                return;
+            }
+            "init" | "--init" => {
+                run_init(args.get(2).map(String::as_str));
+                return;
+            }
+            "orphans" | "--orphans" => {
+                run_orphans(args.iter().any(|a| a == "--delete"));
+                return;
+            }
+            "deletes" | "--deletes" => {
+                run_pending_deletes(args.get(2).map(String::as_str), &args[args.len().min(3)..]);
+                return;
+            }
+            "diff" | "--diff" => {
+                match args.get(2) {
+                    Some(path) => run_diff(path),
+                    None => println!("사용법: mdflare-agent diff <상대 경로>"),
+                }
+                return;
+            }
+            "reset" | "--reset" => {
+                run_reset(args.iter().any(|a| a == "--yes"));
+                return;
+            }
+            "selftest" | "--selftest" => {
+                let cases = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(5000);
+                let failures = run_diff_selftest(cases);
+                std::process::exit(if failures == 0 { 0 } else { 1 });
+            }
+            "--headless" => {
+                let mut config = Config::load();
+                if !config.is_configured() {
+                    println!("설정이 없습니다. 데스크톱에서 먼저 설정하거나 --connect <토큰> [폴더]를 사용하세요.");
+                    return;
+                }
+                if !confirm_local_path(&mut config) {
+                    return;
+                }
+                run_headless(config);
+            }
             "--help" | "-h" => {
                 println!("MDFlare Agent - 마크다운 동기화");
                 println!();
@@ -2598,6 +9741,17 @@ fn main() {
                 println!("  mdflare-agent              저장된 설정으로 시작");
                 println!("  mdflare-agent -p           Private Vault 모드");
                 println!("  mdflare-agent -c           Cloud 모드");
+                println!("  --connect <토큰> [폴더]    다른 기기의 Vault에 연결해 동기화");
+                println!("  --set-encryption-passphrase  E2E 암호화 켜기 (실험적)");
+                println!("  --disable-encryption       E2E 암호화 끄기");
+                println!("  --export-logs [파일.zip]   버그 리포트용 로그/설정 묶기");
+                println!("  --headless                 트레이 없이 실행 (서버 환경)");
+                println!("  init [폴더]                빈 폴더를 새 Vault로 시작한다고 기록 (빈 폴더 안전 검사 생략)");
+                println!("  orphans [--delete]         동기화 후 로컬에서 사라진 원격 파일 목록 (삭제)");
+                println!("  diff <경로>                한 파일의 로컬/원격 차이와 동기화 판단 (읽기만)");
+                println!("  deletes [approve|reject] [경로...]  검토 대기 중인 원격 삭제 확인/승인/거부");
+                println!("  reset [--yes]              에이전트 설정/상태 초기화 (노트 파일은 유지)");
+                println!("  selftest [횟수]            diff 엔진 왕복 검사");
                 println!("  -h, --help                 도움말");
                 return;
             }
@@ -2608,9 +9762,27 @@ fn main() {
     // Windows URL scheme 등록
     register_url_scheme();
 
-    let config = Config::load();
+    let mut config = Config::load();
     log_to_file(&format!("main: mode={:?} configured={} api_base={}", config.storage_mode, config.is_configured(), config.api_base));
 
+    // 홈/루트 등 위험한 폴더로 설정돼 있으면 확인 → 거부 시 폴더 재설정
+    if config.is_configured() {
+        let confirmed_before = config.confirmed_local_paths.len();
+        let dismissed_before = config.cloud_sync_warning_dismissed.len();
+        if !confirm_local_path(&mut config) {
+            if !has_display() {
+                return;
+            }
+            config.local_path.clear();
+        }
+        if config.local_path.is_empty()
+            || config.confirmed_local_paths.len() != confirmed_before
+            || config.cloud_sync_warning_dismissed.len() != dismissed_before
+        {
+            config.save();
+        }
+    }
+
     if !config.is_configured() {
         // 미설정 → 트레이에 미연결 아이콘 + "동기화 시작" 메뉴
         log_to_file("main: not configured → setup tray");
@@ -2618,6 +9790,9 @@ fn main() {
     } else {
         // 설정 완료 → 바로 동작
         log_to_file(&format!("main: configured → starting {:?} mode", config.storage_mode));
+        if !has_display() {
+            run_headless(config);
+        }
         match config.storage_mode {
             StorageMode::Cloud => {
                 println!("☁️ Cloud 모드");
@@ -2630,6 +9805,292 @@ fn main() {
                 println!("📁 {}", config.local_path);
                 run_private_vault_tray_app(config);
             }
+            StorageMode::PeerVault => {
+                println!("🔗 연결 모드");
+                println!("🌐 {}", config.peer_url);
+                println!("📁 {}", config.local_path);
+                run_cloud_tray_app(config);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtdb_save_before_first_sync_is_not_a_new_local_file() {
+        // 시작 직후(해시 없음) 도착한 RTDB 저장 → 오래된 노트일 뿐, 충돌 사본 만들지 않음
+        assert!(!is_unsynced_new_local(false, false, false));
+        assert!(!is_unsynced_new_local(false, false, true));
+    }
+
+    #[test]
+    fn rtdb_save_for_listed_remote_note_is_not_a_new_local_file() {
+        assert!(!is_unsynced_new_local(true, false, true));
+        assert!(!is_unsynced_new_local(true, true, true));
+    }
+
+    #[test]
+    fn rtdb_save_racing_an_unuploaded_local_note_is_a_conflict() {
+        assert!(is_unsynced_new_local(true, false, false));
+        assert!(!is_unsynced_new_local(true, true, false));
+    }
+
+    /// Remote that serves fixed contents from memory; writes succeed without storing anything.
+    struct StubBackend {
+        files: HashMap<String, String>,
+    }
+
+    impl RemoteBackend for StubBackend {
+        fn list_files(&self) -> Result<Vec<FileItem>, Box<dyn std::error::Error>> {
+            Ok(Vec::new())
+        }
+
+        fn get_file(&self, path: &str) -> Result<FileContent, Box<dyn std::error::Error>> {
+            let content = self.files.get(path).ok_or("not found")?.clone();
+            Ok(FileContent { path: path.to_string(), size: content.len() as u64, content, modified: String::new(), encoding: None })
+        }
+
+        fn put_file_with_diff(
+            &self,
+            _path: &str,
+            _content: &str,
+            _old_hash: Option<&str>,
+            _diff: Option<&serde_json::Value>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        fn create_file(&self, _path: &str, _content: &str) -> Result<bool, Box<dyn std::error::Error>> {
+            Ok(true)
+        }
+
+        fn delete_file(&self, _path: &str) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    fn stub_engine(vault: &Path, files: &[(&str, &str)]) -> SyncEngine {
+        let config = Config { local_path: vault.to_string_lossy().to_string(), ..Default::default() };
+        let files = files.iter().map(|(path, content)| (path.to_string(), content.to_string())).collect();
+        SyncEngine::with_backend(&config, Box::new(StubBackend { files }))
+    }
+
+    #[test]
+    fn rtdb_event_racing_an_unuploaded_local_note_keeps_a_conflict_copy() {
+        for action in ["save", "create"] {
+            let vault = tempfile::tempdir().unwrap();
+            fs::write(vault.path().join("idea.md"), "local draft").unwrap();
+            let mut engine = stub_engine(vault.path(), &[("idea.md", "remote version")]);
+            engine.last_sync = Some(chrono::Local::now());
+
+            let entry: RtdbFileEntry = serde_json::from_value(serde_json::json!({ "path": "idea.md", "action": action })).unwrap();
+            engine.handle_rtdb_event(&entry);
+
+            assert_eq!(fs::read_to_string(vault.path().join("idea.md")).unwrap(), "remote version");
+            let copies: Vec<PathBuf> = fs::read_dir(vault.path())
+                .unwrap()
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.to_string_lossy().contains(".conflict-"))
+                .collect();
+            assert_eq!(copies.len(), 1, "{}: one conflict copy", action);
+            assert_eq!(fs::read_to_string(&copies[0]).unwrap(), "local draft");
+        }
+    }
+
+    fn b64(plain: &str) -> String {
+        use base64::{Engine as _, engine::general_purpose::STANDARD};
+        STANDARD.encode(plain)
+    }
+
+    #[test]
+    fn connection_token_localhost() {
+        let parsed = parse_connection_token(&generate_connection_token(8765, "tok123")).unwrap();
+        assert_eq!(parsed.v, 1);
+        assert_eq!(parsed.url, "http://localhost:8765");
+        assert_eq!(parsed.token, "tok123");
+        assert_eq!(parsed.remote_subpath, None);
+    }
+
+    #[test]
+    fn connection_token_cloudflared_url() {
+        let token = generate_connection_token_with_url("https://calm-river-1234.trycloudflare.com/", "tok123");
+        let parsed = parse_connection_token(&token).unwrap();
+        assert_eq!(parsed.v, 1);
+        assert_eq!(parsed.url, "https://calm-river-1234.trycloudflare.com");
+        assert_eq!(parsed.token, "tok123");
+    }
+
+    #[test]
+    fn connection_token_v2_json() {
+        let token = ConnectionToken {
+            v: 2,
+            url: "http://localhost:8765/".to_string(),
+            token: "tok123".to_string(),
+            remote_subpath: Some("work".to_string()),
+            vault_name: Some("Work".to_string()),
+        }
+        .encode();
+        let parsed = parse_connection_token(&token).unwrap();
+        assert_eq!(parsed.v, 2);
+        assert_eq!(parsed.url, "http://localhost:8765");
+        assert_eq!(parsed.remote_subpath.as_deref(), Some("work"));
+        assert_eq!(parsed.vault_name.as_deref(), Some("Work"));
+        // v1보다 낮은 버전을 자칭하는 JSON은 거부
+        assert!(parse_connection_token(&b64(r#"{"v":1,"url":"http://localhost:1","token":"t"}"#)).is_none());
+    }
+
+    #[test]
+    fn connection_token_strips_whitespace_and_newlines() {
+        let token = generate_connection_token(8765, "tok123");
+        let (head, tail) = token.split_at(10);
+        let pasted = format!("  {}\n {}\r\n", head, tail);
+        assert_eq!(parse_connection_token(&pasted).unwrap().token, "tok123");
+    }
+
+    #[test]
+    fn connection_token_rejects_garbage() {
+        assert!(parse_connection_token("").is_none());
+        assert!(parse_connection_token("not base64 at all!").is_none());
+        assert!(parse_connection_token(&b64("no separator")).is_none());
+        assert!(parse_connection_token(&b64("ftp://example.com|tok")).is_none());
+        assert!(parse_connection_token(&b64("localhost:8765|tok")).is_none());
+        assert!(parse_connection_token(&b64("http://localhost:8765|")).is_none());
+        assert!(parse_connection_token(&b64("http://localhost:8765|to\tk")).is_none());
+        assert!(parse_connection_token(&b64("{not json")).is_none());
+    }
+
+    fn vault_state(local_path: &Path, follow_file_symlinks: bool) -> ServerState {
+        ServerState {
+            local_path: local_path.to_path_buf(),
+            extensions: vec!["md".to_string()],
+            token: String::new(),
+            token_hash: String::new(),
+            include_hidden: false,
+            safe_filenames_only: false,
+            require_signed_requests: false,
+            follow_file_symlinks,
+            obsidian_sync: ObsidianSync::Off,
+            write_queue: VaultWriteQueue::new(0, false),
+            seen_nonces: Arc::new(Mutex::new(HashMap::new())),
+            link_index: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_vault_path_rejects_symlinks_escaping_the_vault() {
+        use std::os::unix::fs::symlink;
+        let vault = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret.md"), "secret").unwrap();
+        fs::write(vault.path().join("real.md"), "real").unwrap();
+        // GET 대상: vault 밖의 기존 파일을 가리키는 링크
+        symlink(outside.path().join("secret.md"), vault.path().join("leak.md")).unwrap();
+        // PUT 대상: 아직 없는 vault 밖 파일을 가리키는 링크 (쓰면 밖에 생성됨)
+        symlink(outside.path().join("new.md"), vault.path().join("dangling.md")).unwrap();
+        symlink(outside.path(), vault.path().join("out")).unwrap();
+        symlink(vault.path().join("real.md"), vault.path().join("alias.md")).unwrap();
+
+        for follow in [false, true] {
+            let state = vault_state(vault.path(), follow);
+            assert_eq!(resolve_vault_path(&state, "leak.md"), Err(StatusCode::FORBIDDEN));
+            assert_eq!(resolve_vault_path(&state, "dangling.md"), Err(StatusCode::FORBIDDEN));
+            assert_eq!(resolve_vault_path(&state, "out/secret.md"), Err(StatusCode::FORBIDDEN));
+            assert_eq!(resolve_vault_path(&state, "../secret.md"), Err(StatusCode::FORBIDDEN));
+            assert_eq!(resolve_vault_path(&state, "real.md"), Ok(vault.path().join("real.md")));
         }
+        // vault 안을 가리키는 링크는 follow_file_symlinks일 때만
+        assert_eq!(resolve_vault_path(&vault_state(vault.path(), false), "alias.md"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(
+            resolve_vault_path(&vault_state(vault.path(), true), "alias.md"),
+            Ok(vault.path().join("alias.md"))
+        );
+    }
+
+    #[test]
+    fn staging_a_delete_never_overwrites_an_earlier_staged_copy() {
+        let vault = tempfile::tempdir().unwrap();
+        let mut engine = stub_engine(vault.path(), &[]);
+        let note = vault.path().join("plan.md");
+        let staged = vault.path().join(PENDING_DELETES_DIR).join("plan.md");
+
+        fs::write(&note, "first").unwrap();
+        assert!(engine.stage_pending_delete("plan.md", &note));
+        fs::write(&note, "second").unwrap();
+        assert!(!engine.stage_pending_delete("plan.md", &note));
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&note).unwrap(), "second");
+
+        // 이미 대기 중인 사본과 같은 내용이면 로컬만 정리
+        fs::write(&note, "first").unwrap();
+        assert!(engine.stage_pending_delete("plan.md", &note));
+        assert!(!note.exists());
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "first");
+    }
+
+    #[test]
+    fn redact_log_line_masks_tokens() {
+        assert_eq!(redact_log_line("GET /api/me?token=abc123&x=1"), "GET /api/me?token=[REDACTED]&x=1");
+        assert_eq!(redact_log_line("Authorization: Bearer secret"), "Authorization: Bearer [REDACTED]");
+        assert_eq!(redact_log_line("  → existing token: [mdf_1a2b...]"), "  → existing token: [[REDACTED]...]");
+        assert_eq!(
+            redact_log_line("hash 0123456789abcdef0123456789abcdef ok"),
+            "hash [REDACTED] ok"
+        );
+        assert_eq!(redact_log_line("key dGhpc0lzQVNlY3JldFRva2VuMTIz="), "key [REDACTED]=");
+        assert_eq!(redact_log_line(r#"{"api_token":"mdf_x1"}"#), r#"{"api_token":"[REDACTED]"}"#);
+        assert_eq!(redact_log_line("[12:00:00.123] ⬆️ notes/회의록-2024.md"), "[12:00:00.123] ⬆️ notes/회의록-2024.md");
+    }
+
+    #[test]
+    fn webdav_folder_delete_refuses_non_note_entries() {
+        let vault = tempfile::tempdir().unwrap();
+        let extensions = vec!["md".to_string()];
+        let folder = vault.path().join("notes");
+        fs::create_dir_all(folder.join("sub")).unwrap();
+        fs::write(folder.join("a.md"), "a").unwrap();
+        fs::write(folder.join("sub/b.md"), "b").unwrap();
+        assert_eq!(folder_delete_forbidden_reason(&folder, false, &extensions), None);
+
+        fs::write(folder.join("sub/photo.png"), "png").unwrap();
+        assert!(folder_delete_forbidden_reason(&folder, false, &extensions).is_some());
+        fs::remove_file(folder.join("sub/photo.png")).unwrap();
+        fs::write(folder.join(".hidden.md"), "h").unwrap();
+        assert!(folder_delete_forbidden_reason(&folder, false, &extensions).is_some());
+        fs::remove_file(folder.join(".hidden.md")).unwrap();
+
+        remove_note_folder(&folder, &extensions).unwrap();
+        assert!(!folder.exists());
+    }
+
+    #[test]
+    fn first_sync_backup_is_off_for_configs_from_before_the_option() {
+        let existing: Config = serde_json::from_str(r#"{"local_path": "/notes"}"#).unwrap();
+        assert!(!existing.backup_before_first_sync);
+        assert!(Config::default().backup_before_first_sync);
+    }
+
+    #[test]
+    fn rtdb_rename_with_empty_hash_has_no_hash() {
+        let entry: RtdbFileEntry =
+            serde_json::from_str(r#"{"path": "b.md", "action": "rename", "hash": "", "oldPath": "a.md"}"#).unwrap();
+        assert_eq!(entry.hash, None);
+        let entry: RtdbFileEntry = serde_json::from_str(r#"{"path": "b.md", "action": "rename", "hash": "1x2y"}"#).unwrap();
+        assert_eq!(entry.hash.as_deref(), Some("1x2y"));
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc4231() {
+        let (key, msg) = (b"Jefe".as_slice(), b"what do ya want for nothing?".as_slice());
+        let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(hmac_sha256_hex(key, msg), expected);
+        assert!(verify_hmac_sha256_hex(key, msg, expected));
+        assert!(verify_hmac_sha256_hex(key, msg, &expected.to_uppercase()));
+        assert!(!verify_hmac_sha256_hex(key, msg, &expected[..62]));
+        assert!(!verify_hmac_sha256_hex(key, b"tampered", expected));
     }
 }
@@ -0,0 +1,13 @@
+// GET /api/:userId/capabilities — 에이전트가 쓸 수 있는 선택 기능
+export async function onRequestGet(context) {
+  const { data } = context;
+
+  if (!data.isOwner) {
+    return Response.json({ error: 'Unauthorized' }, { status: 403 });
+  }
+
+  return Response.json({
+    // PUT 본문 Content-Encoding: gzip 허용 (compress_uploads)
+    gzipUpload: true,
+  });
+}
@@ -35,6 +35,12 @@ function simpleHash(str) {
   return hash.toString(36);
 }
 
+// 에이전트 hash_algo = sha256 일 때 (oldHash와 같은 방식으로 hash 기록)
+async function sha256Hex(str) {
+  const digest = await crypto.subtle.digest('SHA-256', new TextEncoder().encode(str));
+  return [...new Uint8Array(digest)].map(b => b.toString(16).padStart(2, '0')).join('');
+}
+
 async function writeRtdb(env, username, filePath, data) {
   const secret = env.FIREBASE_DB_SECRET;
   if (!secret) return;
@@ -117,8 +123,58 @@ async function handleGet(env, r2Key, filePath, userId, data) {
   });
 }
 
+// JSON 본문 (에이전트 compress_uploads면 gzip)
+// gzip 본문의 압축 해제 후 최대 크기 (작은 요청이 거대한 본문으로 풀리는 것 방지)
+const MAX_DECOMPRESSED_BODY_BYTES = 100 * 1024 * 1024;
+// RTDB에 함께 기록하는 diff 상한 - 에이전트(MAX_DIFF_UPLOAD_BYTES)와 같이 gzip 후 크기 기준
+const MAX_DIFF_BYTES = 10240;
+
+async function readJsonBody(request) {
+  const encoding = (request.headers.get('Content-Encoding') || 'identity').toLowerCase();
+  if (encoding === 'identity') {
+    return request.json();
+  }
+  if (encoding !== 'gzip') {
+    throw Object.assign(new Error(`Unsupported Content-Encoding: ${encoding}`), { status: 415 });
+  }
+  const stream = request.body.pipeThrough(new DecompressionStream('gzip'));
+  return JSON.parse(await readLimitedText(stream, MAX_DECOMPRESSED_BODY_BYTES));
+}
+
+async function readLimitedText(stream, limit) {
+  const reader = stream.getReader();
+  const chunks = [];
+  let total = 0;
+  for (;;) {
+    const { done, value } = await reader.read();
+    if (done) break;
+    total += value.byteLength;
+    if (total > limit) {
+      await reader.cancel();
+      throw Object.assign(new Error('Request body too large'), { status: 413 });
+    }
+    chunks.push(value);
+  }
+  return new Blob(chunks).text();
+}
+
+// 작은 diff는 압축 없이 통과, 큰 것만 실제 gzip 크기로 판단
+async function diffFits(diff) {
+  const json = JSON.stringify(diff);
+  if (new Blob([json]).size <= MAX_DIFF_BYTES) {
+    return true;
+  }
+  const stream = new Blob([json]).stream().pipeThrough(new CompressionStream('gzip'));
+  return (await new Response(stream).arrayBuffer()).byteLength <= MAX_DIFF_BYTES;
+}
+
 async function handlePut(env, r2Key, filePath, request, username, data) {
-  const body = await request.json();
+  let body;
+  try {
+    body = await readJsonBody(request);
+  } catch (e) {
+    return Response.json({ error: e.message }, { status: e.status || 400 });
+  }
   const content = body.content;
   const size = new Blob([content]).size;
   const modified = new Date().toISOString();
@@ -129,7 +185,8 @@ async function handlePut(env, r2Key, filePath, request, username, data) {
 
   // 에이전트 업로드 시 RTDB 기록 (isOwner = API 토큰 인증된 소유자)
   if (data.isOwner && username) {
-    const hash = simpleHash(content);
+    const algo = body.algo === 'sha256' ? 'sha256' : 'simple_js';
+    const hash = algo === 'sha256' ? await sha256Hex(content) : simpleHash(content);
     const rtdbData = {
       path: filePath,
       action: body.oldHash ? 'save' : 'create',
@@ -137,9 +194,16 @@ async function handlePut(env, r2Key, filePath, request, username, data) {
       modified: Date.now(),
       size,
     };
+    if (algo !== 'simple_js') {
+      rtdbData.algo = algo;
+    }
+    // 올린 기기 → 그 기기는 자기 변경 이벤트를 무시
+    if (typeof body.deviceId === 'string' && body.deviceId) {
+      rtdbData.deviceId = body.deviceId;
+    }
     if (body.oldHash) {
       rtdbData.oldHash = body.oldHash;
-      if (body.diff && JSON.stringify(body.diff).length <= 10240) {
+      if (body.diff && await diffFits(body.diff)) {
         rtdbData.diff = body.diff;
       }
     }
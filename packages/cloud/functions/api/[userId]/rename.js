@@ -4,6 +4,21 @@ function toSafeKey(filePath) {
   return filePath.replace(/\./g, '_dot_').replace(/\//g, '_slash_');
 }
 
+function simpleHash(str) {
+  let hash = 0;
+  for (let i = 0; i < str.length; i++) {
+    const char = str.charCodeAt(i);
+    hash = ((hash << 5) - hash) + char;
+    hash |= 0;
+  }
+  return hash.toString(36);
+}
+
+async function sha256Hex(str) {
+  const digest = await crypto.subtle.digest('SHA-256', new TextEncoder().encode(str));
+  return [...new Uint8Array(digest)].map(b => b.toString(16).padStart(2, '0')).join('');
+}
+
 async function writeRtdb(env, username, filePath, data) {
   const secret = env.FIREBASE_DB_SECRET;
   if (!secret) return;
@@ -58,14 +73,20 @@ export async function onRequestPost(context) {
     // RTDB 업데이트: 이전 경로 삭제 + 새 경로에 rename 기록
     if (data.isOwner && username) {
       await deleteRtdb(env, username, oldPath);
-      await writeRtdb(env, username, newPath, {
+      // 에이전트가 같은 내용의 로컬 파일을 찾을 수 있게 내용 해시 기록 (file PUT과 같은 방식)
+      const algo = body.algo === 'sha256' ? 'sha256' : 'simple_js';
+      const rtdbData = {
         path: newPath,
         action: 'rename',
-        hash: '',
+        hash: algo === 'sha256' ? await sha256Hex(content) : simpleHash(content),
         modified: Date.now(),
         size: content.length,
         oldPath,
-      });
+      };
+      if (algo !== 'simple_js') {
+        rtdbData.algo = algo;
+      }
+      await writeRtdb(env, username, newPath, rtdbData);
     }
 
     return Response.json({ renamed: true, oldPath, newPath });